@@ -0,0 +1,45 @@
+use std::iter::FromIterator;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+use binstore::bucket::*;
+use binstore::prelude::DEFAULT_SPARSE_INDEX_STEP;
+
+const NUM_KEYS: u64 = 1_000_000;
+const NUM_LOOKUPS: u64 = 10_000;
+
+/// Times `NUM_LOOKUPS` lookups of the keys at position `position` of
+/// their sparse index window in a fixed dense index.  A linear scan of
+/// the window is slowest for the last position; a binary search takes
+/// about as long for any.
+fn bench(bucket: &mut Bucket<Checked>, si: &SparseIndex, position: u64) -> Duration {
+    let step = DEFAULT_SPARSE_INDEX_STEP as u64;
+    let windows = NUM_KEYS / step;
+    let t = Instant::now();
+    for i in 0 .. NUM_LOOKUPS {
+        let key = ((i * 7919 % windows) * step + position) * 3;
+        let (offset_1, offset_2) = si.try_get(key).expect("try_get");
+        bucket.try_get(key, offset_1, offset_2)
+            .expect("try_get (1)")
+            .expect("try_get (2)");
+    }
+    t.elapsed()
+}
+
+fn main() {
+    let mut bmap = BTreeMap::new();
+    for key in 0 .. NUM_KEYS {
+        bmap.insert(key * 3, BTreeSet::from_iter(0 .. (key % 4) as u128));
+    }
+    let tmp = NamedTempFile::new().unwrap();
+    create(tmp.path(), &bmap).expect("create");
+
+    let mut bucket = Bucket::open(tmp.path()).expect("open").check_headers().expect("check_headers");
+    let si = bucket.read_sparse_index().expect("sparse index");
+
+    let step = DEFAULT_SPARSE_INDEX_STEP as u64;
+    for &position in &[1, step / 2, step - 1] {
+        let elapsed = bench(&mut bucket, &si, position);
+        println!("entry {} of {}: {:?} per lookup", position, step, elapsed / NUM_LOOKUPS as u32);
+    }
+}
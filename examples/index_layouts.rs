@@ -0,0 +1,41 @@
+use std::iter::FromIterator;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+use binstore::bucket::*;
+
+const NUM_KEYS: u64 = 1_000_000;
+const NUM_LOOKUPS: u64 = 10_000;
+
+/// Times `NUM_LOOKUPS` lookups of existing keys in a bucket using the
+/// given dense index encoding.
+fn bench(bmap: &BTreeMap<u64, BTreeSet<u128>>, dense_index: DenseIndexEncoding) -> Duration {
+    let tmp = NamedTempFile::new().unwrap();
+    create_with_options(tmp.path(), bmap, &CreateOptions { dense_index, ..CreateOptions::default() }).expect("create");
+
+    let bucket = Bucket::open(tmp.path()).expect("open");
+    let mut bucket = bucket.check_headers().expect("check_headers");
+    let si = bucket.read_sparse_index().expect("sparse index");
+
+    let t = Instant::now();
+    for i in 0 .. NUM_LOOKUPS {
+        let key = (i * 7919 % NUM_KEYS) * 3;
+        let (offset_1, offset_2) = si.try_get(key).expect("try_get");
+        bucket.try_get(key, offset_1, offset_2)
+            .expect("try_get (1)")
+            .expect("try_get (2)");
+    }
+    t.elapsed()
+}
+
+fn main() {
+    let mut bmap = BTreeMap::new();
+    for key in 0 .. NUM_KEYS {
+        bmap.insert(key * 3, BTreeSet::from_iter(0 .. (key % 4) as u128));
+    }
+
+    for dense_index in &[DenseIndexEncoding::Fixed, DenseIndexEncoding::Varint, DenseIndexEncoding::Columnar] {
+        let elapsed = bench(&bmap, *dense_index);
+        println!("{:?}: {:?} per lookup", dense_index, elapsed / NUM_LOOKUPS as u32);
+    }
+}
@@ -0,0 +1,47 @@
+use std::iter::FromIterator;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Instant;
+use tempfile::NamedTempFile;
+use binstore::bucket::*;
+
+const NUM_KEYS: u64 = 500_000;
+
+/// Compares merging two buckets, which reads their data sections
+/// sequentially, with reading every key of both buckets one seek at a
+/// time.  Run with `RUST_LOG=debug` to see the number of seeks the
+/// merge performed.
+fn main() {
+    env_logger::init();
+
+    let mut bmap1 = BTreeMap::new();
+    let mut bmap2 = BTreeMap::new();
+    for key in 0 .. NUM_KEYS {
+        let values = BTreeSet::from_iter(0 .. (key % 8) as u128 + 1);
+        if key % 3 != 0 {
+            bmap1.insert(key, values.clone());
+        }
+        if key % 2 != 0 {
+            bmap2.insert(key, values);
+        }
+    }
+
+    let tmp1 = NamedTempFile::new().unwrap();
+    let tmp2 = NamedTempFile::new().unwrap();
+    let merged_file = NamedTempFile::new().unwrap();
+    create(tmp1.path(), &bmap1).unwrap();
+    create(tmp2.path(), &bmap2).unwrap();
+
+    let t = Instant::now();
+    for path in &[tmp1.path(), tmp2.path()] {
+        let mut bucket = Bucket::open(path).unwrap().check_headers().unwrap();
+        let mut data = Bucket::open(path).unwrap().check_headers().unwrap();
+        for entry in bucket.index_entries().unwrap() {
+            data.read_values_at(entry.unwrap().offset).unwrap();
+        }
+    }
+    println!("reading both buckets with a seek per key: {:?}", t.elapsed());
+
+    let t = Instant::now();
+    merge(tmp1.path(), tmp2.path(), merged_file.path()).unwrap();
+    println!("merging both buckets: {:?}", t.elapsed());
+}
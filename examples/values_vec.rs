@@ -0,0 +1,47 @@
+use std::iter::FromIterator;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+use binstore::bucket::*;
+
+const NUM_KEYS: u64 = 100;
+const NUM_VALUES: u128 = 100_000;
+const NUM_LOOKUPS: u64 = 200;
+
+/// Times `NUM_LOOKUPS` lookups of keys with many values, decoding the
+/// values either as a `BTreeSet` or as a `Vec`.
+fn bench(bucket: &mut Bucket<Checked>, as_vec: bool) -> Duration {
+    let si = bucket.read_sparse_index().expect("sparse index");
+
+    let t = Instant::now();
+    for i in 0 .. NUM_LOOKUPS {
+        let key = i % NUM_KEYS;
+        let (offset_1, offset_2) = si.try_get(key).expect("try_get");
+        if as_vec {
+            bucket.try_get_vec(key, offset_1, offset_2)
+                .expect("try_get_vec (1)")
+                .expect("try_get_vec (2)");
+        } else {
+            bucket.try_get(key, offset_1, offset_2)
+                .expect("try_get (1)")
+                .expect("try_get (2)");
+        }
+    }
+    t.elapsed()
+}
+
+fn main() {
+    let mut bmap = BTreeMap::new();
+    for key in 0 .. NUM_KEYS {
+        bmap.insert(key, BTreeSet::from_iter(0 .. NUM_VALUES));
+    }
+    let tmp = NamedTempFile::new().unwrap();
+    create(tmp.path(), &bmap).expect("create");
+
+    let bucket = Bucket::open(tmp.path()).expect("open");
+    let mut bucket = bucket.check_headers().expect("check_headers");
+    let set = bench(&mut bucket, false);
+    let vec = bench(&mut bucket, true);
+    println!("BTreeSet: {:?} per lookup", set / NUM_LOOKUPS as u32);
+    println!("Vec:      {:?} per lookup", vec / NUM_LOOKUPS as u32);
+}
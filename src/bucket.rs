@@ -1,20 +1,43 @@
 use chrono::prelude::*;
 use crate::prelude::*;
-use log::{debug};
+use crate::progress::Progress;
+use crate::trace::span;
+use log::{debug, info, warn};
 use lz4::{Decoder, EncoderBuilder};
 use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use std::convert::TryFrom;
 use std::collections::{BTreeMap, BTreeSet};
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::{BufReader, BufWriter};
 use std::io::{self, Seek, SeekFrom, Read, Write};
+use std::iter::FromIterator;
 use std::marker::PhantomData;
 use std::mem;
+use std::ops::{Range, RangeInclusive};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::Instant;
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 
 pub const INDEX_ENTRY_SIZE: usize = mem::size_of::<IndexEntry>();
 
+/// The largest header extension we accept; protects against
+/// allocating a garbage length read from a corrupt file.
+const MAX_HEADER_EXT_SIZE: u64 = 4096;
+
+/// With grouped data blocks, an offset in the dense index holds the
+/// offset of the block in its upper bits and the offset of the values
+/// in the decompressed block in its lower `BLOCK_SHIFT` bits.
+const BLOCK_SHIFT: u32 = 24;
+
+/// The largest `block_size`; offsets into a block must fit in
+/// `BLOCK_SHIFT` bits.
+pub const MAX_BLOCK_SIZE: u64 = 1 << BLOCK_SHIFT;
+
+/// The size of a `(HashedKey, Value)` record in the log.
+const LOG_RECORD_SIZE: u64 = (HASHED_KEY_SIZE + mem::size_of::<Value>()) as u64;
+
 /// Phantom type for Bucket<T>; state when the Bucket is opened, but headers
 /// haven't yet been checked.
 pub struct Initial;
@@ -29,13 +52,23 @@ pub struct Checked;
 pub struct Bucket<T> {
     phantom: PhantomData<T>,
     pub header: BucketHeader,
-    pub file: BufReader<File>,
+    pub file: BufReader<Box<dyn ReadSeek>>,
     pub path: PathBuf,
+    // The last grouped data block read, and its offset.
+    block: Option<(u64, Vec<u8>)>,
+    // The key passed to `prefetch` and its offset in the data section.
+    prefetched: Option<(HashedKey, Option<u64>)>,
+    // The whole dense index, once read, for `IndexKind::FlatInMemory`.
+    flat: Option<Vec<IndexEntry>>,
 }
 
 /// The headers of a database; they are used to determine if a
 /// database file can be opened by binstore.
-#[derive(Debug, Deserialize, Serialize)]
+///
+/// Version 0 headers end at `num_entries`.  Later versions append a
+/// length-prefixed extension with the remaining fields; fields missing
+/// from the extension take their default value.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct BucketHeader {
     pub magic: u32,
     pub version: u32,
@@ -44,10 +77,401 @@ pub struct BucketHeader {
     pub di_base_offset: u64,
     pub data_base_offset: u64,
     pub num_entries: u64,
+    pub dense_index: DenseIndexEncoding,
+    pub keys_base_offset: u64,
+    pub offsets_base_offset: u64,
+    /// Where the log of appended values begins; 0 if nothing was
+    /// appended since the bucket was written.
+    pub log_base_offset: u64,
+    /// The uncompressed size past which a grouped data block is
+    /// closed; 0 if every key has its own block.
+    pub block_size: u64,
+    pub codec: Codec,
+    /// The `HashedKey` and `Value` types the bucket was written with,
+    /// as `HASHED_KEY_TYPE_TAG` and `VALUE_TYPE_TAG`; 0 in buckets
+    /// written before they were recorded.
+    pub key_type_tag: u8,
+    pub value_type_tag: u8,
+    /// Only written for timestamped buckets, so that plain ones keep
+    /// the header of the builds before it.
+    pub value_layout: ValueLayout,
+    /// The application that wrote the bucket, which it can check when
+    /// opening it with `Bucket::check_headers_for_app`; 0, the
+    /// default, for none.  Only written when set.
+    pub app_id: u32,
+    /// How lookups search the dense index.  Only written when it isn't
+    /// the default.
+    pub index_kind: IndexKind,
+}
+
+/// How the data blocks are compressed.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    Lz4,
+    Zstd,
+    /// Blocks are stored as their length followed by their bytes.
+    Uncompressed,
+    /// Each block is tagged with how it is stored: as an LZ4 frame, or,
+    /// for a set of values whose bitmap is smaller than that frame, as
+    /// the bitmap, which suits large dense sets.
+    Adaptive,
+}
+
+/// How lookups find a key in the dense index, which is written the same
+/// way whatever the kind.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexKind {
+    /// The sparse index narrows each lookup down to a window of the
+    /// dense index, which is read from the file.
+    #[default]
+    SparseDense,
+    /// The whole dense index is read into memory on the first lookup,
+    /// and searched there; faster for small buckets that serve many
+    /// lookups.
+    FlatInMemory,
+}
+
+impl FromStr for IndexKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<IndexKind, String> {
+        match s {
+            "sparse-dense" => Ok(IndexKind::SparseDense),
+            "flat" => Ok(IndexKind::FlatInMemory),
+            _ => Err(format!("unknown index kind: {}", s)),
+        }
+    }
+}
+
+/// What the data block of a key holds.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValueLayout {
+    /// The set of values.
+    #[default]
+    Plain,
+    /// A map from each value to the Unix timestamp it was inserted
+    /// at, sorted by value.
+    Timestamped,
+    /// The set of values, with its length and each value as a LEB128
+    /// varint rather than in 8 and 16 bytes; small values then take a
+    /// byte or two before compression.
+    Varint,
+}
+
+impl ValueLayout {
+    /// The `value_type_tag` of buckets with this layout; builds that
+    /// don't know about timestamps see a value type they can't read.
+    fn value_type_tag(self) -> u8 {
+        match self {
+            ValueLayout::Plain => VALUE_TYPE_TAG,
+            ValueLayout::Timestamped => VALUE_TYPE_TAG + TIMESTAMP_SIZE as u8,
+            // Varint values have no fixed size.
+            ValueLayout::Varint => VALUE_TYPE_TAG | 0x80,
+        }
+    }
+}
+
+/// How the entries of the dense index are stored on disk.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DenseIndexEncoding {
+    /// Fixed-size `IndexEntry`s, stored before the data section.
+    #[default]
+    Fixed,
+    /// Each entry stores the varint-encoded difference of its key and
+    /// offset with the previous entry; entries pointed to by the sparse
+    /// index are stored as-is so decoding can start from them.  The
+    /// dense index is stored after the data section.
+    Varint,
+    /// All the keys, followed by all the offsets in the same order;
+    /// lookups binary search the keys and only read the offset of the
+    /// key they find.  The columns start at `keys_base_offset` and
+    /// `offsets_base_offset`.
+    Columnar,
+    /// Fixed-size `IndexEntry`s in lz4 frames of one sparse index step
+    /// each, after the data section; the sparse index points at the
+    /// frame holding its entry, so a lookup decompresses one frame.
+    /// Builds that predate it reject the header.
+    Compressed,
+}
+
+/// An inclusive range of format versions to read, written `MIN:MAX`.
+/// Every version up to `VERSION` is read, and accepted by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl Default for VersionRange {
+    fn default() -> VersionRange {
+        VersionRange { min: 0, max: VERSION }
+    }
+}
+
+impl VersionRange {
+    pub fn contains(&self, version: u32) -> bool {
+        self.min <= version && version <= self.max
+    }
+}
+
+impl FromStr for VersionRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<VersionRange, String> {
+        let (min, max) = s.split_once(':').ok_or_else(|| format!("expected MIN:MAX, got {:?}", s))?;
+        let min = min.parse::<u32>().map_err(|e| format!("{}: {:?}", e, min))?;
+        let max = max.parse::<u32>().map_err(|e| format!("{}: {:?}", e, max))?;
+        if min > max {
+            return Err(format!("empty version range {}:{}", min, max));
+        }
+        if max > VERSION {
+            return Err(format!("this build reads versions up to {}", VERSION));
+        }
+        Ok(VersionRange { min, max })
+    }
+}
+
+/// The header timestamp `merge` gives the merged bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeTimestamp {
+    /// The time of the merge.
+    Now,
+    /// The older of the two inputs' timestamps.
+    Min,
+    /// The newer of the two inputs' timestamps, so the merged bucket
+    /// is dated like the newer data.
+    #[default]
+    Max,
+    /// The timestamp of the first input.
+    Input1,
+}
+
+impl MergeTimestamp {
+    /// The timestamp for merging buckets stamped `t1` and `t2`; `None`
+    /// for the current time.
+    fn resolve(self, t1: i64, t2: i64) -> Option<i64> {
+        match self {
+            MergeTimestamp::Now => None,
+            MergeTimestamp::Min => Some(t1.min(t2)),
+            MergeTimestamp::Max => Some(t1.max(t2)),
+            MergeTimestamp::Input1 => Some(t1),
+        }
+    }
+}
+
+impl FromStr for MergeTimestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<MergeTimestamp, String> {
+        match s {
+            "now" => Ok(MergeTimestamp::Now),
+            "min" => Ok(MergeTimestamp::Min),
+            "max" => Ok(MergeTimestamp::Max),
+            "input1" => Ok(MergeTimestamp::Input1),
+            _ => Err(format!("expected now, min, max or input1, got {:?}", s)),
+        }
+    }
+}
+
+/// Which values a key keeps when it has more than
+/// `CreateOptions::max_values_per_key` of them.  Value sets don't
+/// record when each value was added, so both policies go by value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CapPolicy {
+    /// The smallest values.
+    #[default]
+    Smallest,
+    /// The largest values, which drops the oldest ones when values
+    /// grow over time, like sequence numbers.
+    Largest,
+}
+
+impl FromStr for CapPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<CapPolicy, String> {
+        match s {
+            "smallest" => Ok(CapPolicy::Smallest),
+            "largest" => Ok(CapPolicy::Largest),
+            _ => Err(format!("expected smallest or largest, got {:?}", s)),
+        }
+    }
+}
+
+/// Options controlling the layout of a newly written bucket.
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    pub dense_index: DenseIndexEncoding,
+    /// Called with the number of entries written so far.
+    pub progress: Option<Progress>,
+    /// Do all the work but write nothing; only the size the bucket
+    /// would have is computed.
+    pub dry_run: bool,
+    /// When non-zero, the value sets of consecutive keys share lz4
+    /// frames of about this many uncompressed bytes, which compresses
+    /// small sets much better at the cost of decompressing the whole
+    /// block to read one of them.  At most `MAX_BLOCK_SIZE`.
+    pub block_size: u64,
+    pub codec: Codec,
+    /// The creation timestamp to write in the header instead of the
+    /// current time, so the same entries always give the same bytes.
+    pub timestamp: Option<i64>,
+    /// Keep the keys `delete` leaves without values, as empty sets,
+    /// instead of dropping them; `get` then returns `Some` of an empty
+    /// set for them rather than `None`.
+    pub keep_empty: bool,
+    /// Sync the new bucket to disk before returning, so it survives a
+    /// crash once written.
+    pub sync: bool,
+    /// Give the bucket written by `delete` or `compact` the
+    /// modification time of the original one, and its header
+    /// timestamp unless `timestamp` is set.
+    pub preserve_mtime: bool,
+    /// How `merge` stamps its output when `timestamp` isn't set.
+    pub merge_timestamp: MergeTimestamp,
+    /// Make `merge` build the sparse index from a first pass over the
+    /// merged keys rather than from the set of all of them, so memory
+    /// stays in the size of the sparse index at the cost of reading
+    /// both dense indexes twice.
+    pub lowmem: bool,
+    /// Read the whole bucket back once written, decompressing every
+    /// data block, and remove it if that fails; only for buckets
+    /// written to a file.
+    pub validate_after_write: bool,
+    /// Drop the inputs of `merge` and `cat` naming a file given before
+    /// rather than failing with `Error::DuplicateInput`.
+    pub dedup_inputs: bool,
+    /// Keep at most this many values per key when creating or
+    /// merging, chosen by `cap_policy`; in a merge, the cap applies
+    /// to the union of the two sets.
+    pub max_values_per_key: Option<usize>,
+    pub cap_policy: CapPolicy,
+    /// Keys that `delete` and `compact` leave out of the new bucket,
+    /// such as those of a decommissioned shard; their values aren't
+    /// read.
+    pub drop_keys: Option<RangeInclusive<HashedKey>>,
+    pub index_kind: IndexKind,
+    /// Write the value sets as varints (`ValueLayout::Varint`), which
+    /// suits buckets whose values are mostly small numbers.
+    pub varint_values: bool,
+    /// The `BucketHeader::app_id` of the new bucket; buckets rewritten
+    /// from another one, or merged, keep the id of their (first) input
+    /// when this is 0.
+    pub app_id: u32,
+}
+
+impl CreateOptions {
+    /// The positions, in value order, of the `len` values of `key`
+    /// to keep; `None` if they are all kept.
+    fn kept_values(&self, key: HashedKey, len: usize) -> Option<Range<usize>> {
+        let max = self.max_values_per_key.filter(|&max| len > max)?;
+        info!("key {}: capping {} values to {}", key, len, max);
+        match self.cap_policy {
+            CapPolicy::Smallest => Some(0 .. max),
+            CapPolicy::Largest => Some(len - max .. len),
+        }
+    }
+
+    /// Removes the entries of `drop_keys` from `entries`, which are
+    /// sorted by key, and returns how many were removed.
+    fn drop_keys_from(&self, entries: &mut Vec<IndexEntry>) -> u64 {
+        let range = match self.drop_keys {
+            Some(ref range) => range,
+            None => return 0,
+        };
+        let start = entries.partition_point(|entry| entry.key < *range.start());
+        let end = entries.partition_point(|entry| entry.key <= *range.end());
+        if start >= end {
+            return 0;
+        }
+        entries.drain(start .. end);
+        (end - start) as u64
+    }
+
+    /// Whether `key` is outside of `drop_keys`.
+    fn keeps_key(&self, key: HashedKey) -> bool {
+        self.drop_keys.as_ref().map_or(true, |range| !range.contains(&key))
+    }
+
+    /// `values` cut down to `max_values_per_key`, or `None` if they
+    /// are under it.
+    fn capped(&self, key: HashedKey, values: &BTreeSet<Value>) -> Option<BTreeSet<Value>> {
+        let kept = self.kept_values(key, values.len())?;
+        Some(values.iter().skip(kept.start).take(kept.len()).cloned().collect())
+    }
+}
+
+/// What writing a bucket produced, or would have produced in a dry
+/// run.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WriteReport {
+    pub num_entries: u64,
+    pub size: u64,
+}
+
+/// What merging two buckets would give, found from their dense
+/// indexes alone.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MergeEstimate {
+    pub unique_keys: u64,
+    /// The keys in both buckets, whose value sets are unioned.
+    pub overlapping_keys: u64,
+    pub sparse_index_size: u64,
+    /// Exact for fixed-size encodings; for `Varint`, the size of the
+    /// same index with the `Fixed` encoding, which it rarely exceeds.
+    pub dense_index_size: u64,
+}
+
+/// How `merge_iter` combines two buckets, seen as sets of `(key,
+/// value)` pairs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    /// The values of either bucket, as `merge` writes them.
+    Union,
+    /// The values in both buckets.
+    Intersection,
+    /// The values of the first bucket that aren't in the second.
+    Difference,
+}
+
+/// Which of two buckets walked in key order hold the next key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Next {
+    First,
+    Second,
+    Both,
+}
+
+impl Next {
+    /// Where the next key is, given the current entries of both
+    /// buckets; `None` once both are done.
+    fn of(e1: Option<IndexEntry>, e2: Option<IndexEntry>) -> Option<Next> {
+        Next::of_keys(e1.map(|e| e.key), e2.map(|e| e.key))
+    }
+
+    fn of_keys(k1: Option<HashedKey>, k2: Option<HashedKey>) -> Option<Next> {
+        match (k1, k2) {
+            (Some(k1), Some(k2)) if k1 == k2 => Some(Next::Both),
+            (Some(k1), Some(k2)) if k1 > k2 => Some(Next::Second),
+            (Some(_), _) => Some(Next::First),
+            (None, Some(_)) => Some(Next::Second),
+            (None, None) => None,
+        }
+    }
+}
+
+/// An iterator over the keys of two buckets combined by a `SetOp`, in
+/// key order; made by `merge_iter`.
+pub struct MergeIter<'a> {
+    buckets: (&'a mut Bucket<Checked>, &'a mut Bucket<Checked>),
+    entries: (std::iter::Peekable<std::vec::IntoIter<LoggedEntry>>, std::iter::Peekable<std::vec::IntoIter<LoggedEntry>>),
+    op: SetOp,
+    failed: bool,
 }
 
 /// A small index that can be quickly loaded in memory.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct SparseIndex {
     step: usize,
     index: Vec<IndexEntry>,
@@ -55,22 +479,72 @@ pub struct SparseIndex {
 
 /// An entry in the full index; the offset points into the data
 /// section where the set of Values is stored.
-#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
 pub struct IndexEntry {
     pub key: u64,
     pub offset: u64,
 }
 
+/// A key of a bucket, with where its values are in the data section
+/// if it has an entry in the dense index, and its values in the log.
+#[derive(Debug, Clone)]
+struct LoggedEntry {
+    key: HashedKey,
+    offset: Option<u64>,
+    logged: BTreeSet<Value>,
+}
+
+/// Sequential reader over the entries of a bucket's dense index, in
+/// key order.
+pub struct IndexEntries<'a> {
+    bucket: &'a mut Bucket<Checked>,
+    step: usize,
+    next: u64,
+    prev: IndexEntry,
+    // Entries read ahead from the two columns of a columnar index, or
+    // from a frame of a compressed one.
+    buffered: Vec<IndexEntry>,
+    // Where the frames of a compressed index start.
+    frames: Vec<u64>,
+}
+
+/// A bucket with its sparse index loaded in memory.
+pub struct PreparedBucket {
+    bucket: Bucket<Checked>,
+    si: SparseIndex,
+}
+
+/// Streaming reader over the values of a single key, decoded one at
+/// a time from the lz4 stream.
+pub struct ValuesIter<'a> {
+    decoder: Box<dyn Read + 'a>,
+    remaining: u64,
+    // How the length and the values are encoded; in a timestamped
+    // bucket, each value is followed by a timestamp to skip.
+    layout: ValueLayout,
+}
+
 impl Default for BucketHeader {
     fn default() -> BucketHeader {
         BucketHeader {
             magic: MAGIC,
             version: VERSION,
-            timestamp: Local::now().timestamp(),
+            timestamp: Utc::now().timestamp(),
             si_base_offset: 0,
             di_base_offset: 0,
             data_base_offset: 0,
             num_entries: 0,
+            dense_index: DenseIndexEncoding::default(),
+            keys_base_offset: 0,
+            offsets_base_offset: 0,
+            log_base_offset: 0,
+            block_size: 0,
+            codec: Codec::default(),
+            key_type_tag: HASHED_KEY_TYPE_TAG,
+            value_type_tag: VALUE_TYPE_TAG,
+            value_layout: ValueLayout::default(),
+            app_id: 0,
+            index_kind: IndexKind::default(),
         }
     }
 }
@@ -84,24 +558,290 @@ impl Default for SparseIndex {
     }
 }
 
+impl BucketHeader {
+    /// The oldest version that reads every field set in this header the
+    /// way it was written; buckets written as version 0 are still read
+    /// by the builds from before the extension.
+    pub fn required_version(&self) -> u32 {
+        let changes_decoding = match self.dense_index {
+            DenseIndexEncoding::Columnar | DenseIndexEncoding::Compressed => true,
+            DenseIndexEncoding::Fixed | DenseIndexEncoding::Varint => false,
+        } || self.keys_base_offset != 0
+            || self.offsets_base_offset != 0
+            || self.log_base_offset != 0
+            || self.block_size != 0
+            || self.codec != Codec::default()
+            || self.value_layout != ValueLayout::Plain;
+        let ext_set = self.dense_index != DenseIndexEncoding::Fixed
+            || self.app_id != 0
+            || self.index_kind != IndexKind::default();
+        if changes_decoding {
+            2
+        } else if ext_set {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// Reads a header of any version up to `VERSION`.
+    pub fn read_from<R: Read>(r: &mut R) -> Result<BucketHeader> {
+        let magic: u32 = bincode_config().deserialize_from(&mut *r)?;
+        if magic != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        let version: u32 = bincode_config().deserialize_from(&mut *r)?;
+        if version > VERSION {
+            return Err(Error::BadVersion);
+        }
+        let mut header = BucketHeader {
+            magic,
+            version,
+            timestamp: bincode_config().deserialize_from(&mut *r)?,
+            si_base_offset: bincode_config().deserialize_from(&mut *r)?,
+            di_base_offset: bincode_config().deserialize_from(&mut *r)?,
+            data_base_offset: bincode_config().deserialize_from(&mut *r)?,
+            num_entries: bincode_config().deserialize_from(&mut *r)?,
+            ..BucketHeader::default()
+        };
+        if version > 0 {
+            let ext_len: u64 = bincode_config().deserialize_from(&mut *r)?;
+            if ext_len > MAX_HEADER_EXT_SIZE {
+                return Err(Error::BadHeader);
+            }
+            let mut ext = vec![0; ext_len as usize];
+            r.read_exact(&mut ext)?;
+            let mut ext: &[u8] = ext.as_ref();
+            header.dense_index = read_ext_field(&mut ext)?;
+            header.keys_base_offset = read_ext_field(&mut ext)?;
+            header.offsets_base_offset = read_ext_field(&mut ext)?;
+            header.log_base_offset = read_ext_field(&mut ext)?;
+            header.block_size = read_ext_field(&mut ext)?;
+            header.codec = read_ext_field(&mut ext)?;
+            header.key_type_tag = read_ext_field(&mut ext)?;
+            header.value_type_tag = read_ext_field(&mut ext)?;
+            header.value_layout = read_ext_field(&mut ext)?;
+            header.app_id = read_ext_field(&mut ext)?;
+            header.index_kind = read_ext_field(&mut ext)?;
+        }
+        Ok(header)
+    }
+
+    /// Writes the header in the layout of its `version`.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<()> {
+        bincode_config().serialize_into(&mut *w, &(
+            self.magic,
+            self.version,
+            self.timestamp,
+            self.si_base_offset,
+            self.di_base_offset,
+            self.data_base_offset,
+            self.num_entries,
+        ))?;
+        if self.version > 0 {
+            let mut ext = bincode_config().serialize(&(
+                self.dense_index,
+                self.keys_base_offset,
+                self.offsets_base_offset,
+                self.log_base_offset,
+                self.block_size,
+                self.codec,
+                self.key_type_tag,
+                self.value_type_tag,
+            ))?;
+            // Each optional field is written when it or one after it
+            // is set.
+            let kind_set = self.index_kind != IndexKind::default();
+            if self.value_layout != ValueLayout::Plain || self.app_id != 0 || kind_set {
+                bincode_config().serialize_into(&mut ext, &self.value_layout)?;
+            }
+            if self.app_id != 0 || kind_set {
+                bincode_config().serialize_into(&mut ext, &self.app_id)?;
+            }
+            if kind_set {
+                bincode_config().serialize_into(&mut ext, &self.index_kind)?;
+            }
+            bincode_config().serialize_into(&mut *w, &(ext.len() as u64))?;
+            w.write_all(&ext)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that this build can read a bucket with this header.
+    fn check(&self) -> Result<()> {
+        self.check_accepting(VersionRange::default())
+    }
+
+    /// Like `check`, accepting any version in `versions`.
+    fn check_accepting(&self, versions: VersionRange) -> Result<()> {
+        if !versions.contains(self.version) {
+            if versions == VersionRange::default() {
+                return Err(Error::BadVersion);
+            }
+            return Err(Error::UnacceptedVersion { version: self.version, min: versions.min, max: versions.max });
+        }
+        let tags = [(self.key_type_tag, HASHED_KEY_TYPE_TAG), (self.value_type_tag, self.value_layout.value_type_tag())];
+        if tags.iter().any(|&(tag, expected)| tag != 0 && tag != expected) {
+            return Err(Error::TypeMismatch);
+        }
+        Ok(())
+    }
+
+    /// The offset in the data section of the lz4 frame holding the
+    /// values at `offset`.
+    pub fn data_block_offset(&self, offset: u64) -> u64 {
+        if self.block_size > 0 {
+            offset >> BLOCK_SHIFT
+        } else {
+            offset
+        }
+    }
+}
+
+/// One line for logs: the sections and timestamp, which is shown in
+/// UTC like the dates of a `Db`.
+impl fmt::Display for BucketHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let written = Utc.timestamp_opt(self.timestamp, 0).single()
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or_else(|| format!("@{}", self.timestamp));
+        write!(f, "version {}, {} entries, sparse index at {:#x}, dense index ({:?}) at {:#x}, data ({:?}) at {:#x}",
+               self.version, self.num_entries, self.si_base_offset,
+               self.dense_index, self.di_base_offset, self.codec, self.data_base_offset)?;
+        if self.log_base_offset != 0 {
+            write!(f, ", log at {:#x}", self.log_base_offset)?;
+        }
+        write!(f, ", written {}", written)
+    }
+}
+
+/// Reads the next field of a header extension, or its default value
+/// if the extension was written by a build that didn't know about it.
+fn read_ext_field<T: DeserializeOwned + Default>(ext: &mut &[u8]) -> Result<T> {
+    if ext.is_empty() {
+        return Ok(T::default());
+    }
+    let field: T = bincode_config().deserialize_from(ext)?;
+    return Ok(field);
+}
+
+impl DenseIndexEncoding {
+    /// The size of a dense index of `num_entries` entries, if it can
+    /// be known before the offsets are.
+    fn fixed_size(self, num_entries: u64) -> Option<u64> {
+        match self {
+            DenseIndexEncoding::Fixed | DenseIndexEncoding::Columnar => {
+                Some(num_entries * INDEX_ENTRY_SIZE as u64)
+            }
+            DenseIndexEncoding::Varint | DenseIndexEncoding::Compressed => None,
+        }
+    }
+}
+
+impl CreateOptions {
+    /// The options that reproduce the layout of an existing bucket.
+    pub fn like(header: &BucketHeader) -> CreateOptions {
+        CreateOptions::default().with_layout_of(header)
+    }
+
+    /// These options, with the layout of an existing bucket.
+    pub fn with_layout_of(&self, header: &BucketHeader) -> CreateOptions {
+        CreateOptions {
+            dense_index: header.dense_index,
+            block_size: header.block_size,
+            codec: header.codec,
+            index_kind: header.index_kind,
+            varint_values: header.value_layout == ValueLayout::Varint,
+            ..self.clone()
+        }
+    }
+
+    /// The layout of the value sets these options write.
+    fn values_layout(&self) -> ValueLayout {
+        if self.varint_values {
+            ValueLayout::Varint
+        } else {
+            ValueLayout::Plain
+        }
+    }
+
+    /// The options to rewrite the bucket of `header` with.
+    fn rewriting(&self, header: &BucketHeader) -> CreateOptions {
+        let mut options = self.with_layout_of(header);
+        if options.preserve_mtime && options.timestamp.is_none() {
+            options.timestamp = Some(header.timestamp);
+        }
+        if options.app_id == 0 {
+            options.app_id = header.app_id;
+        }
+        options
+    }
+
+    /// Sets the modification time of `new_bucket` to that of `path`
+    /// with `preserve_mtime`.
+    fn finish_rewrite(&self, path: &Path, new_bucket: &Path) -> Result<()> {
+        if self.preserve_mtime && !self.dry_run {
+            let mtime = filetime::FileTime::from_last_modification_time(&std::fs::metadata(path)?);
+            filetime::set_file_mtime(new_bucket, mtime)?;
+        }
+        Ok(())
+    }
+}
+
 impl Bucket<Initial> {
     pub fn open<P: AsRef<Path>>(filename: P) -> Result<Bucket<Initial>> {
         let file = File::open(filename.as_ref())?;
-        let reader = BufReader::new(file);
-        let path = PathBuf::from(filename.as_ref());
-        let bucket = Bucket { phantom: PhantomData, file: reader, header: BucketHeader::default(), path };
+        Ok(Bucket::from_reader(Box::new(file), PathBuf::from(filename.as_ref())))
+    }
+
+    /// A bucket held in memory, such as one written by `create_into`;
+    /// its `path` is empty.
+    pub fn from_bytes(bytes: Vec<u8>) -> Bucket<Initial> {
+        Bucket::from_reader(Box::new(io::Cursor::new(bytes)), PathBuf::new())
+    }
+
+    /// A bucket read from any seekable reader, such as a
+    /// `http::RangeReader`; `path` only names it in messages.
+    pub fn from_reader(reader: Box<dyn ReadSeek>, path: PathBuf) -> Bucket<Initial> {
+        let file = BufReader::new(reader);
+        Bucket { phantom: PhantomData, file, header: BucketHeader::default(), path, block: None, prefetched: None, flat: None }
+    }
+
+    /// Reads and checks the header of the bucket at `filename`, without
+    /// keeping the file open.
+    pub fn read_header<P: AsRef<Path>>(filename: P) -> Result<BucketHeader> {
+        let bucket = Bucket::open(filename)?.check_headers()?;
+        Ok(bucket.header)
+    }
+
+    pub fn check_headers(self) -> Result<Bucket<Checked>> {
+        self.check_headers_accepting(VersionRange::default())
+    }
+
+    /// Like `check_headers`, accepting any version in `versions` rather
+    /// than only `VERSION`, such as while a fleet is being migrated.
+    pub fn check_headers_accepting(mut self, versions: VersionRange) -> Result<Bucket<Checked>> {
+        let header = BucketHeader::read_from(&mut self.file)?;
+        header.check_accepting(versions)?;
+        let bucket = Bucket { phantom: PhantomData, file: self.file, header, path: self.path, block: None, prefetched: None, flat: None };
         Ok(bucket)
     }
 
-    pub fn check_headers(mut self) -> Result<Bucket<Checked>> {
-        let header: BucketHeader = bincode::deserialize_from(&mut self.file)?;
-        if header.magic != MAGIC {
-            return Err(Error::BadMagic);
-        }
-        if header.version != VERSION {
-            return Err(Error::BadVersion);
+    /// Like `check_headers`, and also checks the order of the dense
+    /// index with `verify_data_ordering`, which reads all of it.
+    pub fn check_headers_strict(self) -> Result<Bucket<Checked>> {
+        let mut bucket = self.check_headers()?;
+        bucket.verify_data_ordering()?;
+        Ok(bucket)
+    }
+
+    /// Like `check_headers`, and fails with `Error::WrongApplication`
+    /// unless the bucket was written with `app_id`.
+    pub fn check_headers_for_app(self, app_id: u32) -> Result<Bucket<Checked>> {
+        let bucket = self.check_headers()?;
+        if bucket.header.app_id != app_id {
+            return Err(Error::WrongApplication { expected: app_id, found: bucket.header.app_id });
         }
-        let bucket = Bucket { phantom: PhantomData, file: self.file, header: header, path: self.path };
         Ok(bucket)
     }
 }
@@ -123,33 +863,42 @@ impl SparseIndex {
         }
     }
 
+    /// Fails with `Error::CorruptIndex` unless the keys and offsets
+    /// are in increasing order.
+    fn check(&self) -> Result<()> {
+        let sorted = self.index.windows(2).all(|w| w[0].key <= w[1].key && w[0].offset <= w[1].offset);
+        if self.step == 0 || !sorted {
+            return Err(Error::CorruptIndex);
+        }
+        Ok(())
+    }
+
     /// Creates a new SparseIndex.
     /// If `entries` is empty, we return an empty SparseIndex.
     /// If `entries` is not empty, we return a SparseIndex
     /// where the first pair in the index is the smallest key
     /// of `entries` and the last pair is the largest key of
     /// `entries`.
-
-    pub fn new(entries: &BTreeSet<HashedKey>) -> Self {
+    pub fn new(entries: &BTreeSet<HashedKey>) -> Result<Self> {
         return Self::new_with_step(DEFAULT_SPARSE_INDEX_STEP, entries);
     }
 
-    pub fn new_with_step(step: usize, entries: &BTreeSet<HashedKey>) -> Self {
-        let mut si = SparseIndex::default();
-        si.step = step;
+    /// Like `new`; fails if the dense index offset of an entry doesn't
+    /// fit in a `u64`.
+    pub fn new_with_step(step: usize, entries: &BTreeSet<HashedKey>) -> Result<Self> {
+        let mut si = SparseIndex { step, ..SparseIndex::default() };
 
         // If there is no max entry, that means `entries` is empty
         // and we should return an empty sparse index.
         let last_key = match entries.iter().max() {
             Some(key) => *key,
-            None => return si,
+            None => return Ok(si),
         };
 
         for (i, key) in entries.iter().enumerate().step_by(si.step) {
-            let di_offset = i * INDEX_ENTRY_SIZE;
             si.index.push(IndexEntry {
                 key: *key,
-                offset: di_offset as u64,
+                offset: dense_index_offset(i)?,
             });
         }
 
@@ -158,271 +907,2269 @@ impl SparseIndex {
             || (si.index[si.index.len() - 1].key != last_key);
 
         if needs_one_extra {
-            let di_offset = (entries.len() - 1) * INDEX_ENTRY_SIZE;
             si.index.push(IndexEntry {
                 key: last_key,
-                offset: di_offset as u64,
+                offset: dense_index_offset(entries.len() - 1)?,
             });
         }
 
-        return si;
-    }
-
-    pub fn size(&self) -> u64 {
-        bincode::serialized_size(&self).expect("SparseIndex::size()") as u64
+        return Ok(si);
     }
-}
 
-
-impl Bucket<Checked> {
-    pub fn read_sparse_index(&mut self) -> Result<SparseIndex> {
-        let si: SparseIndex = bincode::deserialize_from(&mut self.file)?;
+    /// Like `new_with_step`, in one pass over the `len` first keys of
+    /// `iter`, which must be distinct and in increasing order; fails if
+    /// `iter` ends before.
+    pub fn from_sorted_iter<I: Iterator<Item = HashedKey>>(step: usize, iter: I, len: usize) -> Result<Self> {
+        let (si, n) = Self::from_sorted_keys(step, iter.take(len).map(Ok))?;
+        if n != len as u64 {
+            return Err(Error::from(io::Error::new(io::ErrorKind::InvalidInput, "fewer keys than expected")));
+        }
         return Ok(si);
     }
 
-    fn locate(&mut self, key: HashedKey, di_off1: u64, di_off2: u64) -> Result<Option<u64>> {
-        let mut curr_offset = di_off1 + self.header.di_base_offset;
-        let last_offset = di_off2 + self.header.di_base_offset;
-        self.file.seek(SeekFrom::Start(curr_offset))?;
-        loop {
-            let IndexEntry {
-                key: k,
-                offset: off,
-            } = bincode::deserialize_from(&mut self.file)?;
-            if k == key {
-                return Ok(Some(off + self.header.data_base_offset));
-            }
-            curr_offset += INDEX_ENTRY_SIZE as u64;
-            if curr_offset > last_offset {
-                break;
+    /// Like `new_with_step`, from keys read one at a time in
+    /// increasing order, such as while streaming a dense index; also
+    /// returns how many keys there were.
+    fn from_sorted_keys<I: Iterator<Item = Result<HashedKey>>>(step: usize, keys: I) -> Result<(Self, u64)> {
+        let mut si = SparseIndex { step, ..SparseIndex::default() };
+        let mut len = 0;
+        let mut last_key = None;
+        for key in keys {
+            let key = key?;
+            if len % step.max(1) == 0 {
+                si.index.push(IndexEntry {
+                    key,
+                    offset: dense_index_offset(len)?,
+                });
             }
+            last_key = Some(key);
+            len += 1;
         }
-        return Ok(None);
-    }
 
-    pub fn try_get(&mut self, key: HashedKey, di_off1: u64, di_off2: u64) -> Result<Option<BTreeSet<Value>>> {
-        let t = Instant::now();
-        let off_option = self.locate(key, di_off1, di_off2)?;
-        debug!("dense index search time: {:?}", t.elapsed());
+        // If there is no last key, there were no keys and we should
+        // return an empty sparse index.
+        let last_key = match last_key {
+            Some(key) => key,
+            None => return Ok((si, 0)),
+        };
 
-        match off_option {
-            Some(offset) => {
-                let t = Instant::now();
-                self.file.seek(SeekFrom::Start(offset))?;
-                let values = read_values(&mut self.file)?;
-                debug!("read_values: {:?}", t.elapsed());
-                return Ok(Some(values));
-            }
-            None => {
-                return Ok(None);
-            }
+        let needs_one_extra =
+            len == 1
+            || (si.index[si.index.len() - 1].key != last_key);
+
+        if needs_one_extra {
+            si.index.push(IndexEntry {
+                key: last_key,
+                offset: dense_index_offset(len - 1)?,
+            });
         }
+
+        return Ok((si, len as u64));
     }
 
-    pub fn get(&mut self, hash: HashedKey) -> Result<Option<BTreeSet<Value>>> {
-        let si = self.read_sparse_index()?;
-        let (offset_1, offset_2) =
-            match si.try_get(hash) {
-                Some((off_1, off_2)) => (off_1, off_2),
-                None => {
-                    return Ok(None);
-                }
-            };
-        self.try_get(hash, offset_1, offset_2)
+    pub fn size(&self) -> u64 {
+        bincode_config().serialized_size(&self).expect("SparseIndex::size()")
     }
 }
 
-fn write_values<W: Write>(w: &mut W, values: &BTreeSet<Value>) -> Result<()> {
-    let values_bin: Vec<u8> = bincode::serialize(&values)?;
-    let mut refu8: &[u8] = values_bin.as_ref();
-    let mut encoder = EncoderBuilder::new()
-        .level(COMPRESSION_LEVEL)
-        .build(w)?;
-    io::copy(&mut refu8, &mut encoder)?;
-    encoder.finish();
-    return Ok(());
+
+/// The keys of `entries` and `log`, both sorted, as `LoggedEntry`s in
+/// key order.
+fn fold_log(entries: Vec<IndexEntry>, log: BTreeMap<HashedKey, BTreeSet<Value>>) -> Vec<LoggedEntry> {
+    let mut folded = Vec::with_capacity(entries.len() + log.len());
+    let mut log = log.into_iter().peekable();
+    for entry in entries {
+        while let Some((key, logged)) = log.next_if(|&(key, _)| key < entry.key) {
+            folded.push(LoggedEntry { key, offset: None, logged });
+        }
+        let logged = log.next_if(|&(key, _)| key == entry.key).map(|(_, logged)| logged).unwrap_or_default();
+        folded.push(LoggedEntry { key: entry.key, offset: Some(entry.offset), logged });
+    }
+    folded.extend(log.map(|(key, logged)| LoggedEntry { key, offset: None, logged }));
+    folded
 }
 
-fn read_values<R: Read>(r: &mut R) -> Result<BTreeSet<Value>> {
-    let mut bincode: Vec<u8> = Vec::new();
-    let mut lz4_decoder = Decoder::new(r)?;
-    io::copy(&mut lz4_decoder, &mut bincode)?;
-    let u8_ref: &[u8] = bincode.as_ref();
-    let values: BTreeSet<Value> = bincode::deserialize_from(u8_ref)?;
-    return Ok(values);
+/// The bincode configuration of everything written in a bucket:
+/// integers are little-endian and of fixed size, so a bucket reads the
+/// same on every architecture and with any later bincode defaults.
+pub fn bincode_config() -> bincode::Config {
+    let mut config = bincode::config();
+    config.little_endian().no_limit();
+    config
 }
 
-pub fn delete<P: AsRef<Path> + Debug>(path: P, new_bucket: P, value_set: &[Value]) -> Result<()> {
-    let t = Instant::now();
-    // Open the database twice: once to have a cursor in the dense
-    // index; once to have a cursor in the data section.
-    let mut bucket = Bucket::open(&path)?.check_headers()?;
-    let mut bucket_data = Bucket::open(&path)?.check_headers()?;
-    debug!("opened {:?} in {:?}", path.as_ref(), t.elapsed());
+/// The offset of the `i`th entry of a fixed dense index.  The product
+/// is computed in `u64`, as it overflows a 32-bit `usize` long before
+/// the number of entries does.
+fn dense_index_offset(i: usize) -> Result<u64> {
+    (i as u64).checked_mul(INDEX_ENTRY_SIZE as u64)
+        .ok_or_else(|| Error::from(io::Error::new(io::ErrorKind::InvalidInput, "too many entries")))
+}
+
+impl Bucket<Checked> {
+    pub fn read_sparse_index(&mut self) -> Result<SparseIndex> {
+        self.file.seek(SeekFrom::Start(self.header.si_base_offset))?;
+        let si: SparseIndex = bincode_config().deserialize_from(&mut self.file)?;
+        return Ok(si);
+    }
 
-    // The BTreeMap that will be used to create a new binstore file.
-    let mut bmap: BTreeMap<HashedKey, BTreeSet<Value>> = BTreeMap::new();
+    /// Reads and checks the sparse index once, for buckets that serve
+    /// many lookups.
+    pub fn prepare(mut self) -> Result<PreparedBucket> {
+        let si = self.read_sparse_index()?;
+        self.prepare_with(si)
+    }
 
-    // Position the cursors.
-    bucket.file.seek(SeekFrom::Start(bucket.header.di_base_offset))?;
-    bucket_data.file.seek(SeekFrom::Start(bucket_data.header.data_base_offset))?;
+    /// Like `prepare`, with a sparse index read earlier from the same
+    /// file.
+    pub fn prepare_with(self, si: SparseIndex) -> Result<PreparedBucket> {
+        si.check()?;
+        Ok(PreparedBucket { bucket: self, si })
+    }
 
-    for _ in 0..bucket.header.num_entries {
-        let IndexEntry {
-            key: k,
-            offset: off,
-        } = bincode::deserialize_from(&mut bucket.file)?;
-        bucket_data.file.seek(SeekFrom::Start(bucket_data.header.data_base_offset + off))?;
-        let mut values = read_values(&mut bucket_data.file)?;
-        for t in value_set {
-            values.remove(t);
-        }
-        if !values.is_empty() {
-            bmap.insert(k, values);
+    /// Returns a reader over every entry of the dense index.
+    pub fn index_entries(&mut self) -> Result<IndexEntries<'_>> {
+        let mut frames = Vec::new();
+        let step = match self.header.dense_index {
+            DenseIndexEncoding::Fixed | DenseIndexEncoding::Columnar => DEFAULT_SPARSE_INDEX_STEP,
+            DenseIndexEncoding::Varint => self.read_sparse_index()?.step,
+            DenseIndexEncoding::Compressed => {
+                // Every frame starts with an entry of the sparse index.
+                let si = self.read_sparse_index()?;
+                frames = si.index.iter().map(|entry| entry.offset).collect();
+                frames.dedup();
+                si.step
+            }
+        };
+        if step == 0 {
+            return Err(Error::CorruptIndex);
         }
+        self.file.seek(SeekFrom::Start(self.header.di_base_offset))?;
+        Ok(IndexEntries { bucket: self, step, next: 0, prev: IndexEntry::zero(), buffered: Vec::new(), frames })
     }
 
-    create(new_bucket, &bmap)?;
+    /// Walks the dense index and fails with `Error::CorruptIndex` at the
+    /// first entry whose key isn't greater than the key before it, or
+    /// whose offset is smaller than the offset before it, logging the
+    /// pair.  Lookups binary search the index, so they may miss keys of
+    /// such a bucket rather than fail.
+    pub fn verify_data_ordering(&mut self) -> Result<()> {
+        let path = self.path.clone();
+        let mut prev: Option<IndexEntry> = None;
+        for entry in self.index_entries()? {
+            let entry = entry?;
+            if let Some(prev) = prev {
+                if entry.key <= prev.key || entry.offset < prev.offset {
+                    warn!("{:?}: dense index entry {:?} comes after {:?}", path, entry, prev);
+                    return Err(Error::CorruptIndex);
+                }
+            }
+            prev = Some(entry);
+        }
+        Ok(())
+    }
 
-    Ok(())
-}
+    /// The current size of the file; it grows when values are appended.
+    pub fn file_len(&mut self) -> Result<u64> {
+        let len = self.file.get_mut().seek(SeekFrom::End(0))?;
+        // Seeking the inner reader leaves the buffer stale.
+        self.file.seek(SeekFrom::Start(0))?;
+        Ok(len)
+    }
 
-pub fn create<P: AsRef<Path>>(filename: P, entries: &BTreeMap<u64, BTreeSet<Value>>) -> Result<()> {
-    let file = File::create(filename.as_ref())?;
-    let mut w = BufWriter::new(file);
+    /// The size of the data section, which ends where the dense index
+    /// or the log starts, or at the end of the file.
+    pub fn data_size(&mut self) -> Result<u64> {
+        let end = if self.header.di_base_offset > self.header.data_base_offset {
+            self.header.di_base_offset
+        } else if self.header.log_base_offset > 0 {
+            self.header.log_base_offset
+        } else {
+            self.file_len()?
+        };
+        Ok(end.saturating_sub(self.header.data_base_offset))
+    }
+
+    /// The compressed size of the data block holding the values of
+    /// `key`, from the dense index only: up to the next block, or to the
+    /// end of the data section for the last one.  The keys of a grouped
+    /// block all report the size of the whole block.
+    pub fn data_block_size(&mut self, key: HashedKey) -> Result<Option<u64>> {
+        Ok(self.data_block_range(key)?.map(|(start, end)| end.saturating_sub(start)))
+    }
+
+    /// The compressed data block holding the values of `key`, as it is
+    /// in the file, to be copied to another bucket with the same codec
+    /// and value layout.  The keys of a grouped block all return the
+    /// whole block.
+    pub fn get_raw_block(&mut self, key: HashedKey) -> Result<Option<Vec<u8>>> {
+        let (start, end) = match self.data_block_range(key)? {
+            Some(range) => range,
+            None => return Ok(None),
+        };
+        if end < start {
+            return Err(Error::CorruptIndex);
+        }
+        self.file.seek(SeekFrom::Start(self.header.data_base_offset + start))?;
+        let mut block = vec![0; (end - start) as usize];
+        self.file.read_exact(&mut block)?;
+        Ok(Some(block))
+    }
+
+    /// Where the data block of `key` starts and ends in the data
+    /// section, found by walking the dense index.
+    fn data_block_range(&mut self, key: HashedKey) -> Result<Option<(u64, u64)>> {
+        let header = self.header.clone();
+        let mut block = None;
+        for entry in self.index_entries()? {
+            let entry = entry?;
+            let offset = header.data_block_offset(entry.offset);
+            match block {
+                Some(start) if offset != start => return Ok(Some((start, offset))),
+                Some(_) => { }
+                None if entry.key == key => block = Some(offset),
+                None if entry.key > key => return Ok(None),
+                None => { }
+            }
+        }
+        match block {
+            Some(start) => Ok(Some((start, self.data_size()?))),
+            None => Ok(None),
+        }
+    }
+
+    /// The size of the data section once decompressed.  Every data
+    /// block is decompressed, so this is as slow as reading the whole
+    /// bucket.
+    pub fn uncompressed_data_size(&mut self) -> Result<u64> {
+        let entries = self.index_entries()?.collect::<Result<Vec<IndexEntry>>>()?;
+        let mut size = 0;
+        let mut last_block = None;
+        for entry in entries {
+            let block_offset = self.header.data_block_offset(entry.offset);
+            if last_block == Some(block_offset) {
+                continue;
+            }
+            last_block = Some(block_offset);
+            self.file.seek(SeekFrom::Start(self.header.data_base_offset + block_offset))?;
+            size += io::copy(&mut decoder(self.header.codec, &mut self.file)?, &mut io::sink())?;
+        }
+        Ok(size)
+    }
+
+    /// Reads the set of values stored at `offset` in the data section.
+    pub fn read_values_at(&mut self, offset: u64) -> Result<BTreeSet<Value>> {
+        self.read_at(offset)
+    }
+
+    /// Like `read_values_at`, but returns the values as a sorted `Vec`,
+    /// which is cheaper to build than a `BTreeSet`.
+    pub fn read_values_vec_at(&mut self, offset: u64) -> Result<Vec<Value>> {
+        self.read_at(offset)
+    }
+
+    /// A set of values is bincoded as its length followed by its
+    /// elements in order, so it can be decoded as any sequence.  The
+    /// timestamps of a timestamped bucket are dropped.
+    fn read_at<T: DeserializeOwned + FromIterator<Value>>(&mut self, offset: u64) -> Result<T> {
+        match self.header.value_layout {
+            ValueLayout::Plain => self.read_raw_at(offset),
+            ValueLayout::Timestamped => {
+                let values: Vec<(Value, i64)> = self.read_raw_at(offset)?;
+                return Ok(values.into_iter().map(|(value, _)| value).collect());
+            }
+            ValueLayout::Varint => {
+                if self.header.block_size == 0 {
+                    self.file.seek(SeekFrom::Start(self.header.data_base_offset + offset))?;
+                    let data = read_block(&mut self.file, self.header.codec)?;
+                    return read_varint_values(&mut data.as_slice());
+                }
+                return read_varint_values(&mut self.read_grouped(offset)?);
+            }
+        }
+    }
+
+    /// The data block at `offset` as it was written.
+    fn read_raw_at<T: DeserializeOwned>(&mut self, offset: u64) -> Result<T> {
+        if self.header.block_size == 0 {
+            self.file.seek(SeekFrom::Start(self.header.data_base_offset + offset))?;
+            return read_values(&mut self.file, self.header.codec);
+        }
+        let values = bincode_config().deserialize(self.read_grouped(offset)?)?;
+        return Ok(values);
+    }
+
+    /// The bytes of a grouped data block from the values at `offset`
+    /// on.  Consecutive keys usually share a block, so the last block
+    /// read is kept around.
+    fn read_grouped(&mut self, offset: u64) -> Result<&[u8]> {
+        let block_offset = self.header.data_block_offset(offset);
+        let start = (offset & (MAX_BLOCK_SIZE - 1)) as usize;
+        let cached = match self.block {
+            Some((cached_offset, _)) => cached_offset == block_offset,
+            None => false,
+        };
+        if !cached {
+            self.file.seek(SeekFrom::Start(self.header.data_base_offset + block_offset))?;
+            let mut data = Vec::new();
+            decoder(self.header.codec, &mut self.file)?.read_to_end(&mut data)?;
+            self.block = Some((block_offset, data));
+        }
+        match self.block {
+            Some((_, ref data)) if start <= data.len() => Ok(&data[start ..]),
+            _ => Err(Error::CorruptIndex),
+        }
+    }
+
+    fn locate(&mut self, key: HashedKey, di_off1: u64, di_off2: u64) -> Result<Option<u64>> {
+        let _span = span!("locate", key);
+        match self.header.dense_index {
+            DenseIndexEncoding::Fixed => self.locate_fixed(key, di_off1, di_off2),
+            DenseIndexEncoding::Varint => self.locate_varint(key, di_off1, di_off2),
+            DenseIndexEncoding::Columnar => self.locate_columnar(key, di_off1, di_off2),
+            DenseIndexEncoding::Compressed => self.locate_compressed(key, di_off1),
+        }
+    }
+
+    /// A key between two sparse index entries is in the frame of the
+    /// first one, so only that frame is decompressed and searched.
+    fn locate_compressed(&mut self, key: HashedKey, di_off1: u64) -> Result<Option<u64>> {
+        let entries = self.read_index_frame(di_off1)?;
+        match entries.binary_search_by_key(&key, |entry| entry.key) {
+            Ok(i) => Ok(Some(entries[i].offset)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// The entries of the frame of a compressed dense index at
+    /// `offset` in the dense index.
+    fn read_index_frame(&mut self, offset: u64) -> Result<Vec<IndexEntry>> {
+        self.file.seek(SeekFrom::Start(self.header.di_base_offset + offset))?;
+        let mut frame = Vec::new();
+        decoder(Codec::Lz4, &mut self.file)?.read_to_end(&mut frame)?;
+        if frame.len() % INDEX_ENTRY_SIZE != 0 {
+            return Err(Error::CorruptIndex);
+        }
+        let entries = frame.chunks(INDEX_ENTRY_SIZE)
+            .map(|bytes| bincode_config().deserialize(bytes))
+            .collect::<bincode::Result<Vec<IndexEntry>>>()?;
+        Ok(entries)
+    }
+
+    /// Read the whole window between the two sparse index entries at
+    /// once and binary search it.  The window must lie within the dense
+    /// index, so a corrupt sparse index can't make it huge.
+    fn locate_fixed(&mut self, key: HashedKey, di_off1: u64, di_off2: u64) -> Result<Option<u64>> {
+        let index_size = self.header.num_entries.checked_mul(INDEX_ENTRY_SIZE as u64)
+            .zip(self.header.data_base_offset.checked_sub(self.header.di_base_offset))
+            .map(|(size, room)| size.min(room));
+        let end = di_off2.checked_add(INDEX_ENTRY_SIZE as u64);
+        match (index_size, end) {
+            (Some(size), Some(end)) if di_off1 <= di_off2 && end <= size => {}
+            _ => return Err(Error::CorruptIndex),
+        }
+        let count = (di_off2 - di_off1) / INDEX_ENTRY_SIZE as u64 + 1;
+        let mut window = vec![0; (count * INDEX_ENTRY_SIZE as u64) as usize];
+        self.file.seek(SeekFrom::Start(di_off1 + self.header.di_base_offset))?;
+        self.file.read_exact(&mut window)?;
+        let entries = window.chunks(INDEX_ENTRY_SIZE)
+            .map(|bytes| bincode_config().deserialize(bytes))
+            .collect::<bincode::Result<Vec<IndexEntry>>>()?;
+        match entries.binary_search_by_key(&key, |entry| entry.key) {
+            Ok(i) => Ok(Some(entries[i].offset)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Varint entries can't be jumped over, so decode the sparse block
+    /// sequentially from its first entry.
+    fn locate_varint(&mut self, key: HashedKey, di_off1: u64, di_off2: u64) -> Result<Option<u64>> {
+        let mut curr_offset = di_off1 + self.header.di_base_offset;
+        let last_offset = di_off2 + self.header.di_base_offset;
+        self.file.seek(SeekFrom::Start(curr_offset))?;
+        let mut prev = IndexEntry::zero();
+        loop {
+            // The entry closing the block is pointed to by the sparse
+            // index, so it isn't relative to its predecessor.
+            if curr_offset == last_offset {
+                prev = IndexEntry::zero();
+            }
+            let (entry, len) = read_varint_entry(&mut self.file, prev)?;
+            if entry.key == key {
+                return Ok(Some(entry.offset));
+            }
+            if entry.key > key {
+                break;
+            }
+            curr_offset += len as u64;
+            if curr_offset > last_offset {
+                break;
+            }
+            prev = entry;
+        }
+        return Ok(None);
+    }
+
+    /// Binary search the keys column between the two sparse index
+    /// entries; the offsets column is only read for a matching key.
+    fn locate_columnar(&mut self, key: HashedKey, di_off1: u64, di_off2: u64) -> Result<Option<u64>> {
+        const KEY_SIZE: u64 = HASHED_KEY_SIZE as u64;
+        let mut lo = di_off1 / KEY_SIZE;
+        let mut hi = di_off2 / KEY_SIZE + 1;
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            self.file.seek(SeekFrom::Start(self.header.keys_base_offset + mid * KEY_SIZE))?;
+            let k: HashedKey = bincode_config().deserialize_from(&mut self.file)?;
+            if k == key {
+                self.file.seek(SeekFrom::Start(self.header.offsets_base_offset + mid * KEY_SIZE))?;
+                let off: u64 = bincode_config().deserialize_from(&mut self.file)?;
+                return Ok(Some(off));
+            } else if k < key {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        return Ok(None);
+    }
+
+    pub fn try_get(&mut self, key: HashedKey, di_off1: u64, di_off2: u64) -> Result<Option<BTreeSet<Value>>> {
+        self.try_get_as(key, di_off1, di_off2)
+    }
+
+    /// Like `try_get`, but returns the values as a sorted `Vec`.
+    pub fn try_get_vec(&mut self, key: HashedKey, di_off1: u64, di_off2: u64) -> Result<Option<Vec<Value>>> {
+        self.try_get_as(key, di_off1, di_off2)
+    }
+
+    /// Like `try_get`, with the timestamp each value was inserted at;
+    /// only for buckets written by `create_timestamped`.
+    pub fn try_get_timestamped(&mut self, key: HashedKey, di_off1: u64, di_off2: u64) -> Result<Option<BTreeMap<Value, i64>>> {
+        if self.header.value_layout != ValueLayout::Timestamped {
+            return Err(Error::NotTimestamped);
+        }
+        match self.locate(key, di_off1, di_off2)? {
+            Some(offset) => Ok(Some(self.read_raw_at(offset)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `get`, with the timestamp each value was inserted at; only
+    /// for buckets written by `create_timestamped`.  Values in the log
+    /// have no timestamp and are left out.
+    pub fn get_timestamped(&mut self, hash: HashedKey) -> Result<Option<BTreeMap<Value, i64>>> {
+        let si = self.read_sparse_index()?;
+        match si.try_get(hash) {
+            Some((offset_1, offset_2)) => self.try_get_timestamped(hash, offset_1, offset_2),
+            None if self.header.value_layout != ValueLayout::Timestamped => Err(Error::NotTimestamped),
+            None => Ok(None),
+        }
+    }
+
+    fn try_get_as<T: DeserializeOwned + FromIterator<Value>>(&mut self, key: HashedKey, di_off1: u64, di_off2: u64) -> Result<Option<T>> {
+        let t = Instant::now();
+        let off_option = self.locate(key, di_off1, di_off2)?;
+        debug!("dense index search time: {:?}", t.elapsed());
+
+        match off_option {
+            Some(offset) => {
+                let t = Instant::now();
+                let values = self.read_at(offset)?;
+                debug!("read_values: {:?}", t.elapsed());
+                return Ok(Some(values));
+            }
+            None => {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// The values of `hash`, from both the sorted section and the log.
+    /// The sparse index is read on every call; use `prepare` to read it
+    /// once for many lookups.
+    pub fn get(&mut self, hash: HashedKey) -> Result<Option<BTreeSet<Value>>> {
+        let _span = span!("get", key = hash, path = %self.path.display());
+        let offset = match self.prefetched_offset(hash) {
+            Some(offset) => offset,
+            None => self.locate_key(hash)?,
+        };
+        self.get_at(hash, offset)
+    }
+
+    fn get_with(&mut self, si: &SparseIndex, hash: HashedKey) -> Result<Option<BTreeSet<Value>>> {
+        let offset = match self.prefetched_offset(hash) {
+            Some(offset) => offset,
+            None => self.locate_with(si, hash)?,
+        };
+        self.get_at(hash, offset)
+    }
+
+    /// Reads the sparse index and the dense index window of `hash`, so
+    /// that the next `get` of `hash` only reads its values.  Only the
+    /// last key prefetched is remembered.
+    pub fn prefetch(&mut self, hash: HashedKey) -> Result<()> {
+        let offset = self.locate_key(hash)?;
+        self.prefetched = Some((hash, offset));
+        Ok(())
+    }
+
+    fn prefetch_with(&mut self, si: &SparseIndex, hash: HashedKey) -> Result<()> {
+        let offset = self.locate_with(si, hash)?;
+        self.prefetched = Some((hash, offset));
+        Ok(())
+    }
+
+    fn prefetched_offset(&self, hash: HashedKey) -> Option<Option<u64>> {
+        match self.prefetched {
+            Some((key, offset)) if key == hash => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// Where the data block holding the values of `hash` starts in the
+    /// file, as `json-dump` reports it; `None` if the key isn't in the
+    /// sorted section.  The keys of a grouped block share its offset.
+    pub fn locate_offset(&mut self, hash: HashedKey) -> Result<Option<u64>> {
+        let offset = self.locate_key(hash)?;
+        Ok(offset.map(|offset| self.header.data_base_offset + self.header.data_block_offset(offset)))
+    }
+
+    /// The offset of `hash`, reading the index its `IndexKind` searches.
+    fn locate_key(&mut self, hash: HashedKey) -> Result<Option<u64>> {
+        match self.header.index_kind {
+            IndexKind::SparseDense => {
+                let si = self.read_sparse_index()?;
+                self.locate_with(&si, hash)
+            }
+            IndexKind::FlatInMemory => self.locate_flat(hash),
+        }
+    }
+
+    fn locate_with(&mut self, si: &SparseIndex, hash: HashedKey) -> Result<Option<u64>> {
+        if self.header.index_kind == IndexKind::FlatInMemory {
+            return self.locate_flat(hash);
+        }
+        match si.try_get(hash) {
+            Some((offset_1, offset_2)) => self.locate(hash, offset_1, offset_2),
+            None => Ok(None),
+        }
+    }
+
+    /// Searches the whole dense index, read on the first call.
+    fn locate_flat(&mut self, hash: HashedKey) -> Result<Option<u64>> {
+        let _span = span!("locate", key = hash);
+        if self.flat.is_none() {
+            let entries = self.index_entries()?.collect::<Result<Vec<IndexEntry>>>()?;
+            self.flat = Some(entries);
+        }
+        let entries = self.flat.as_ref().expect("just read");
+        Ok(entries.binary_search_by_key(&hash, |entry| entry.key).ok().map(|i| entries[i].offset))
+    }
+
+    /// The values at `offset`, if any, along with those of `hash` in
+    /// the log.
+    fn get_at(&mut self, hash: HashedKey, offset: Option<u64>) -> Result<Option<BTreeSet<Value>>> {
+        let mut values = match offset {
+            Some(offset) => Some(self.read_at(offset)?),
+            None => None,
+        };
+        for (key, value) in self.read_log()? {
+            if key == hash {
+                values.get_or_insert_with(BTreeSet::new).insert(value);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Whether the bucket has values for `hash`; only the indexes and
+    /// the log are read, no values are decompressed.
+    pub fn contains(&mut self, hash: HashedKey) -> Result<bool> {
+        if self.locate_key(hash)?.is_some() {
+            return Ok(true);
+        }
+        Ok(self.read_log()?.iter().any(|&(key, _)| key == hash))
+    }
+
+    fn contains_with(&mut self, si: &SparseIndex, hash: HashedKey) -> Result<bool> {
+        if self.locate_with(si, hash)?.is_some() {
+            return Ok(true);
+        }
+        Ok(self.read_log()?.iter().any(|&(key, _)| key == hash))
+    }
+
+    /// The `(key, value)` records appended to the bucket, in the order
+    /// they were appended.  A record cut short by an interrupted
+    /// `append` is ignored.
+    pub fn read_log(&mut self) -> Result<Vec<(HashedKey, Value)>> {
+        if self.header.log_base_offset == 0 {
+            return Ok(Vec::new());
+        }
+        let file_len = self.file_len()?;
+        let count = file_len.saturating_sub(self.header.log_base_offset) / LOG_RECORD_SIZE;
+        self.file.seek(SeekFrom::Start(self.header.log_base_offset))?;
+        let mut records = Vec::with_capacity(count as usize);
+        for _ in 0 .. count {
+            records.push(bincode_config().deserialize_from(&mut self.file)?);
+        }
+        Ok(records)
+    }
+
+    /// The log grouped by key.
+    fn log_map(&mut self) -> Result<BTreeMap<HashedKey, BTreeSet<Value>>> {
+        let mut log: BTreeMap<HashedKey, BTreeSet<Value>> = BTreeMap::new();
+        for (key, value) in self.read_log()? {
+            log.entry(key).or_default().insert(value);
+        }
+        Ok(log)
+    }
+
+    /// The entries of the dense index and the keys of the log, in key
+    /// order.
+    fn logged_entries(&mut self) -> Result<Vec<LoggedEntry>> {
+        let entries = self.index_entries()?.collect::<Result<Vec<IndexEntry>>>()?;
+        let log = self.log_map()?;
+        Ok(fold_log(entries, log))
+    }
+
+    /// The values of `entry`, those in its log included.
+    fn read_logged(&mut self, entry: &LoggedEntry) -> Result<BTreeSet<Value>> {
+        let mut values = match entry.offset {
+            Some(offset) => self.read_values_at(offset)?,
+            None => BTreeSet::new(),
+        };
+        values.extend(&entry.logged);
+        Ok(values)
+    }
+
+    /// Like `read_logged`, with the timestamp of each value in a
+    /// timestamped bucket; values in the log, and in other buckets,
+    /// get `log_time`.
+    fn read_stamped(&mut self, entry: &LoggedEntry, log_time: i64) -> Result<BTreeMap<Value, i64>> {
+        let mut values: BTreeMap<Value, i64> = match entry.offset {
+            Some(offset) if self.header.value_layout == ValueLayout::Timestamped => self.read_raw_at(offset)?,
+            Some(offset) => self.read_values_at(offset)?.into_iter().map(|value| (value, log_time)).collect(),
+            None => BTreeMap::new(),
+        };
+        for &value in &entry.logged {
+            values.entry(value).or_insert(log_time);
+        }
+        Ok(values)
+    }
+
+    /// The time the last value was appended, the modification time of
+    /// the bucket, which stamps the values of the log when a
+    /// timestamped bucket is rewritten; 0 without a log.
+    fn log_time(&self) -> Result<i64> {
+        if self.header.log_base_offset == 0 {
+            return Ok(0);
+        }
+        let mtime = filetime::FileTime::from_last_modification_time(&std::fs::metadata(&self.path)?);
+        Ok(mtime.unix_seconds())
+    }
+
+    /// `logged_entries` without the keys of `options.drop_keys`, and
+    /// how many entries of the dense index were left out.
+    fn entries_to_rewrite(&mut self, options: &CreateOptions) -> Result<(Vec<LoggedEntry>, u64)> {
+        let mut entries = self.index_entries()?.collect::<Result<Vec<IndexEntry>>>()?;
+        let dropped = options.drop_keys_from(&mut entries);
+        let mut log = self.log_map()?;
+        log.retain(|&key, _| options.keeps_key(key));
+        Ok((fold_log(entries, log), dropped))
+    }
+
+    /// Fails with `Error::UncompactedLog` if values were appended to
+    /// the bucket, for the operations that only read its sorted
+    /// section.
+    fn check_no_log(&self) -> Result<()> {
+        if self.header.log_base_offset != 0 {
+            return Err(Error::UncompactedLog(self.path.clone()));
+        }
+        Ok(())
+    }
+
+    /// Loads the whole bucket, log included, in the form `create`
+    /// takes.
+    pub fn to_map(&mut self) -> Result<BTreeMap<HashedKey, BTreeSet<Value>>> {
+        let entries = self.index_entries()?.collect::<Result<Vec<IndexEntry>>>()?;
+        let mut map = BTreeMap::new();
+        for entry in entries {
+            map.insert(entry.key, self.read_values_at(entry.offset)?);
+        }
+        for (key, value) in self.read_log()? {
+            map.entry(key).or_insert_with(BTreeSet::new).insert(value);
+        }
+        Ok(map)
+    }
+
+    /// A 64-bit FNV-1a hash of the keys of the dense index, in order:
+    /// buckets with the same keys have the same fingerprint, and
+    /// buckets with different keys almost never do.  Only the index is
+    /// read; keys only in the log aren't counted.
+    pub fn key_fingerprint(&mut self) -> Result<u64> {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for entry in self.index_entries()? {
+            for byte in &entry?.key.to_le_bytes() {
+                hash ^= u64::from(*byte);
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+        Ok(hash)
+    }
+
+    /// Like `get`, but yields the values one by one instead of reading
+    /// the whole set into memory.  Values still in the log are not
+    /// included.
+    pub fn values_iter(&mut self, hash: HashedKey) -> Result<Option<ValuesIter<'_>>> {
+        match self.locate_key(hash)? {
+            Some(offset) if self.header.block_size == 0 => {
+                self.file.seek(SeekFrom::Start(self.header.data_base_offset + offset))?;
+                let layout = self.header.value_layout;
+                return Ok(Some(ValuesIter::new(decoder(self.header.codec, &mut self.file)?, layout)?));
+            }
+            Some(offset) => {
+                let layout = self.header.value_layout;
+                let values = io::Cursor::new(self.read_grouped(offset)?.to_vec());
+                return Ok(Some(ValuesIter::new(Box::new(values), layout)?));
+            }
+            None => {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+impl PreparedBucket {
+    /// Like `Bucket::get`, without reading the sparse index.
+    pub fn get(&mut self, hash: HashedKey) -> Result<Option<BTreeSet<Value>>> {
+        self.bucket.get_with(&self.si, hash)
+    }
+
+    /// Like `Bucket::contains`, without reading the sparse index.
+    pub fn contains(&mut self, hash: HashedKey) -> Result<bool> {
+        self.bucket.contains_with(&self.si, hash)
+    }
+
+    /// Like `Bucket::prefetch`, without reading the sparse index.
+    pub fn prefetch(&mut self, hash: HashedKey) -> Result<()> {
+        self.bucket.prefetch_with(&self.si, hash)
+    }
+
+    pub fn header(&self) -> &BucketHeader {
+        &self.bucket.header
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.bucket.path
+    }
+
+    pub fn sparse_index(&self) -> &SparseIndex {
+        &self.si
+    }
+
+    pub fn into_bucket(self) -> Bucket<Checked> {
+        self.bucket
+    }
+}
+
+impl IndexEntry {
+    fn zero() -> IndexEntry {
+        IndexEntry { key: 0, offset: 0 }
+    }
+}
+
+impl<'a> IndexEntries<'a> {
+    /// Reads the next chunk of a columnar index into `buffered`, in
+    /// reverse order so entries can be popped.
+    fn fill_columnar(&mut self) -> Result<()> {
+        const KEY_SIZE: u64 = HASHED_KEY_SIZE as u64;
+        let header = &self.bucket.header;
+        let count = (header.num_entries - self.next).min(self.step as u64);
+        let keys_offset = header.keys_base_offset + self.next * KEY_SIZE;
+        let offsets_offset = header.offsets_base_offset + self.next * KEY_SIZE;
+
+        let mut keys: Vec<HashedKey> = Vec::with_capacity(count as usize);
+        self.bucket.file.seek(SeekFrom::Start(keys_offset))?;
+        for _ in 0 .. count {
+            keys.push(bincode_config().deserialize_from(&mut self.bucket.file)?);
+        }
+        self.bucket.file.seek(SeekFrom::Start(offsets_offset))?;
+        for key in keys {
+            let offset: u64 = bincode_config().deserialize_from(&mut self.bucket.file)?;
+            self.buffered.push(IndexEntry { key, offset });
+        }
+        self.buffered.reverse();
+        Ok(())
+    }
+
+    /// Decompresses the next frame of a compressed index into
+    /// `buffered`, in reverse order so entries can be popped.
+    fn fill_compressed(&mut self) -> Result<()> {
+        let frame = (self.next / self.step as u64) as usize;
+        let offset = *self.frames.get(frame).ok_or(Error::CorruptIndex)?;
+        let count = (self.bucket.header.num_entries - self.next).min(self.step as u64) as usize;
+        let entries = self.bucket.read_index_frame(offset)?;
+        if entries.len() != count {
+            return Err(Error::CorruptIndex);
+        }
+        self.buffered.extend(entries.into_iter().rev());
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for IndexEntries<'a> {
+    type Item = Result<IndexEntry>;
+
+    fn next(&mut self) -> Option<Result<IndexEntry>> {
+        let num_entries = self.bucket.header.num_entries;
+        if self.next >= num_entries {
+            return None;
+        }
+        let entry = match self.bucket.header.dense_index {
+            DenseIndexEncoding::Fixed => {
+                bincode_config().deserialize_from(&mut self.bucket.file).map_err(Error::from)
+            }
+            DenseIndexEncoding::Varint => {
+                if is_sparse_entry(self.next, self.step, num_entries) {
+                    self.prev = IndexEntry::zero();
+                }
+                read_varint_entry(&mut self.bucket.file, self.prev).map(|(entry, _)| entry)
+            }
+            DenseIndexEncoding::Columnar => {
+                let filled = if self.buffered.is_empty() {
+                    self.fill_columnar()
+                } else {
+                    Ok(())
+                };
+                filled.and_then(|()| self.buffered.pop().ok_or(Error::CorruptIndex))
+            }
+            DenseIndexEncoding::Compressed => {
+                let filled = if self.buffered.is_empty() {
+                    self.fill_compressed()
+                } else {
+                    Ok(())
+                };
+                filled.and_then(|()| self.buffered.pop().ok_or(Error::CorruptIndex))
+            }
+        };
+        match entry {
+            Ok(entry) => {
+                self.next += 1;
+                self.prev = entry;
+                Some(Ok(entry))
+            }
+            Err(e) => {
+                // Nothing after a bad entry can be trusted.
+                self.next = num_entries;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<'a> ValuesIter<'a> {
+    /// A bincoded set is its length followed by its elements, so the
+    /// values can be decoded one by one after reading the length.
+    fn new(mut decoder: Box<dyn Read + 'a>, layout: ValueLayout) -> Result<ValuesIter<'a>> {
+        let remaining: u64 = if layout == ValueLayout::Varint {
+            read_varint(&mut decoder)?.0
+        } else {
+            bincode_config().deserialize_from(&mut decoder)?
+        };
+        Ok(ValuesIter { decoder, remaining, layout })
+    }
+
+    fn read_value(&mut self) -> Result<Value> {
+        if self.layout == ValueLayout::Varint {
+            return read_value_varint(&mut self.decoder);
+        }
+        let value = bincode_config().deserialize_from(&mut self.decoder)?;
+        if self.layout == ValueLayout::Timestamped {
+            let _: i64 = bincode_config().deserialize_from(&mut self.decoder)?;
+        }
+        Ok(value)
+    }
+}
+
+impl<'a> Iterator for ValuesIter<'a> {
+    type Item = Result<Value>;
+
+    fn next(&mut self) -> Option<Result<Value>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        match self.read_value() {
+            Ok(value) => {
+                self.remaining -= 1;
+                Some(Ok(value))
+            }
+            Err(e) => {
+                self.remaining = 0;
+                Some(Err(e))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.remaining as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+/// Whether the `i`-th of `len` dense index entries is pointed to by
+/// a sparse index built with `step`.
+fn is_sparse_entry(i: u64, step: usize, len: u64) -> bool {
+    i % step as u64 == 0 || i + 1 == len
+}
+
+fn write_varint<W: Write>(w: &mut W, mut n: u64) -> Result<usize> {
+    let mut buf = [0_u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    w.write_all(&buf[.. len])?;
+    return Ok(len);
+}
+
+fn read_varint<R: Read>(r: &mut R) -> Result<(u64, usize)> {
+    let mut n: u64 = 0;
+    let mut len = 0;
+    loop {
+        let mut byte = [0_u8; 1];
+        r.read_exact(&mut byte)?;
+        let shift = 7 * len;
+        len += 1;
+        if shift > 63 {
+            return Err(Error::CorruptIndex);
+        }
+        n |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok((n, len));
+        }
+    }
+}
+
+/// Writes `value` as a LEB128 varint, in up to 19 bytes.
+fn write_value_varint(w: &mut Vec<u8>, mut value: Value) {
+    while value >= 0x80 {
+        w.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    w.push(value as u8);
+}
+
+fn read_value_varint<R: Read>(r: &mut R) -> Result<Value> {
+    let mut value: Value = 0;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0_u8; 1];
+        r.read_exact(&mut byte)?;
+        if shift > 127 {
+            return Err(Error::CorruptIndex);
+        }
+        value |= Value::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+/// The varint encoding of `data`, the bincode of a set of values.
+fn varint_values(data: &[u8]) -> Result<Vec<u8>> {
+    let values: Vec<Value> = bincode_config().deserialize(data)?;
+    let mut varints = Vec::with_capacity(values.len() + 1);
+    write_varint(&mut varints, values.len() as u64)?;
+    for value in values {
+        write_value_varint(&mut varints, value);
+    }
+    return Ok(varints);
+}
+
+/// Reads a set of values written by `varint_values`.
+fn read_varint_values<R: Read, T: FromIterator<Value>>(r: &mut R) -> Result<T> {
+    let (len, _) = read_varint(r)?;
+    (0 .. len).map(|_| read_value_varint(r)).collect()
+}
+
+fn write_varint_entry<W: Write>(w: &mut W, entry: IndexEntry, prev: IndexEntry) -> Result<usize> {
+    let key_len = write_varint(w, entry.key.wrapping_sub(prev.key))?;
+    let offset_len = write_varint(w, entry.offset.wrapping_sub(prev.offset))?;
+    return Ok(key_len + offset_len);
+}
+
+fn read_varint_entry<R: Read>(r: &mut R, prev: IndexEntry) -> Result<(IndexEntry, usize)> {
+    let (key_delta, key_len) = read_varint(r)?;
+    let (offset_delta, offset_len) = read_varint(r)?;
+    let entry = IndexEntry {
+        key: prev.key.wrapping_add(key_delta),
+        offset: prev.offset.wrapping_add(offset_delta),
+    };
+    return Ok((entry, key_len + offset_len));
+}
+
+/// Writes `entries` as a dense index and returns the offset of each
+/// entry relative to the start of the dense index.
+fn write_dense_index<W: Write>(w: &mut W, encoding: DenseIndexEncoding, step: usize, entries: &[IndexEntry]) -> Result<Vec<u64>> {
+    if encoding == DenseIndexEncoding::Compressed {
+        return write_compressed_index(w, step, entries);
+    }
+    let mut offsets = Vec::with_capacity(entries.len());
+    let mut curr_offset: u64 = 0;
+    let mut prev = IndexEntry::zero();
+    for (i, entry) in entries.iter().enumerate() {
+        offsets.push(curr_offset);
+        match encoding {
+            DenseIndexEncoding::Fixed => {
+                bincode_config().serialize_into(&mut *w, entry)?;
+                curr_offset += INDEX_ENTRY_SIZE as u64;
+            }
+            DenseIndexEncoding::Varint => {
+                if is_sparse_entry(i as u64, step, entries.len() as u64) {
+                    prev = IndexEntry::zero();
+                }
+                curr_offset += write_varint_entry(w, *entry, prev)? as u64;
+                prev = *entry;
+            }
+            DenseIndexEncoding::Columnar => {
+                bincode_config().serialize_into(&mut *w, &entry.key)?;
+                curr_offset += HASHED_KEY_SIZE as u64;
+            }
+            DenseIndexEncoding::Compressed => unreachable!("written by write_compressed_index"),
+        }
+    }
+    if encoding == DenseIndexEncoding::Columnar {
+        for entry in entries {
+            bincode_config().serialize_into(&mut *w, &entry.offset)?;
+        }
+    }
+    return Ok(offsets);
+}
+
+/// Writes `entries` in lz4 frames of `step` entries; the offset of each
+/// entry is that of its frame.
+fn write_compressed_index<W: Write>(w: &mut W, step: usize, entries: &[IndexEntry]) -> Result<Vec<u64>> {
+    if step == 0 {
+        return Err(Error::CorruptIndex);
+    }
+    let mut offsets = Vec::with_capacity(entries.len());
+    let mut curr_offset: u64 = 0;
+    let mut frame = Vec::with_capacity(step * INDEX_ENTRY_SIZE);
+    let mut compressed = Vec::new();
+    for chunk in entries.chunks(step) {
+        frame.clear();
+        for entry in chunk {
+            bincode_config().serialize_into(&mut frame, entry)?;
+        }
+        compressed.clear();
+        write_block(&mut compressed, Codec::Lz4, &frame)?;
+        w.write_all(&compressed)?;
+        offsets.extend(std::iter::repeat(curr_offset).take(chunk.len()));
+        curr_offset += compressed.len() as u64;
+    }
+    return Ok(offsets);
+}
+
+#[cfg(test)]
+fn write_values<W: Write, S: Serialize>(w: &mut W, codec: Codec, values: &S) -> Result<()> {
+    let values_bin: Vec<u8> = bincode_config().serialize(&values)?;
+    write_block(w, codec, &values_bin)
+}
+
+/// Writes `data` as a single frame of `codec`.
+fn write_block<W: Write>(w: &mut W, codec: Codec, data: &[u8]) -> Result<()> {
+    match codec {
+        Codec::Lz4 => {
+            let mut refu8: &[u8] = data;
+            let mut encoder = EncoderBuilder::new()
+                .level(COMPRESSION_LEVEL)
+                .build(w)?;
+            io::copy(&mut refu8, &mut encoder)?;
+            let (_, result) = encoder.finish();
+            result?;
+        }
+        Codec::Zstd => {
+            zstd::stream::copy_encode(data, w, ZSTD_COMPRESSION_LEVEL)?;
+        }
+        Codec::Uncompressed => {
+            bincode_config().serialize_into(&mut *w, &(data.len() as u64))?;
+            w.write_all(data)?;
+        }
+        Codec::Adaptive => {
+            let mut lz4 = vec![ADAPTIVE_LZ4];
+            write_block(&mut lz4, Codec::Lz4, data)?;
+            if let Some(values) = value_set(data) {
+                let mut bitmap = vec![ADAPTIVE_BITMAP];
+                bincode_config().serialize_into(&mut bitmap, &bitmap_of(&values))?;
+                if bitmap.len() < lz4.len() {
+                    w.write_all(&bitmap)?;
+                    return Ok(());
+                }
+            }
+            w.write_all(&lz4)?;
+        }
+    }
+    return Ok(());
+}
+
+/// The tags of the blocks of `Codec::Adaptive`.
+const ADAPTIVE_LZ4: u8 = 0;
+const ADAPTIVE_BITMAP: u8 = 1;
+
+/// The values of a bitmap that share all but their lower 16 bits: the
+/// lower bits as an array, or as a bitmap once that is smaller.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+enum Container {
+    Array(Vec<u16>),
+    Bitmap(Vec<u64>),
+}
+
+/// The number of words in a `Container::Bitmap`.
+const CONTAINER_WORDS: usize = (1 << 16) / 64;
+
+/// The values of `data` if it is the bincode of a single set of values,
+/// such as an ungrouped block of a plain bucket.
+fn value_set(data: &[u8]) -> Option<Vec<Value>> {
+    let len: u64 = bincode_config().deserialize(data.get(.. 8)?).ok()?;
+    if (data.len() - 8) as u64 != len.checked_mul(mem::size_of::<Value>() as u64)? {
+        return None;
+    }
+    let values: Vec<Value> = bincode_config().deserialize(data).ok()?;
+    if values.windows(2).any(|w| w[0] >= w[1]) {
+        return None;
+    }
+    Some(values)
+}
+
+/// The bitmap of `values`, which are in increasing order: a container
+/// for each value of the upper bits, in increasing order.
+fn bitmap_of(values: &[Value]) -> Vec<(Value, Container)> {
+    let mut bitmap: Vec<(Value, Container)> = Vec::new();
+    let mut start = 0;
+    while start < values.len() {
+        let high = values[start] >> 16;
+        let end = start + values[start ..].iter().take_while(|&&value| value >> 16 == high).count();
+        let lows = values[start .. end].iter().map(|&value| value as u16);
+        // An array takes 2 bytes a value, a bitmap 8 KiB.
+        let container = if end - start > CONTAINER_WORDS * 4 {
+            let mut words = vec![0; CONTAINER_WORDS];
+            for low in lows {
+                words[low as usize / 64] |= 1 << (low % 64);
+            }
+            Container::Bitmap(words)
+        } else {
+            Container::Array(lows.collect())
+        };
+        bitmap.push((high, container));
+        start = end;
+    }
+    bitmap
+}
+
+/// The values of `bitmap`, in increasing order.
+fn bitmap_values(bitmap: &[(Value, Container)]) -> Result<Vec<Value>> {
+    let mut values = Vec::new();
+    for (high, container) in bitmap {
+        let high = high.checked_shl(16).filter(|shifted| shifted >> 16 == *high).ok_or(Error::CorruptIndex)?;
+        match container {
+            Container::Array(lows) => values.extend(lows.iter().map(|&low| high | low as Value)),
+            Container::Bitmap(words) if words.len() == CONTAINER_WORDS => {
+                for (i, &word) in words.iter().enumerate() {
+                    values.extend((0 .. 64).filter(|bit| word & (1 << bit) != 0).map(|bit| high | (i * 64 + bit) as Value));
+                }
+            }
+            Container::Bitmap(_) => return Err(Error::CorruptIndex),
+        }
+    }
+    Ok(values)
+}
+
+/// A reader over the decompressed contents of the frame of `codec`
+/// that `r` is positioned on.
+fn decoder<'a, R: Read + 'a>(codec: Codec, mut r: R) -> Result<Box<dyn Read + 'a>> {
+    match codec {
+        Codec::Lz4 => Ok(Box::new(Decoder::new(r)?)),
+        Codec::Zstd => Ok(Box::new(zstd::stream::read::Decoder::new(r)?.single_frame())),
+        Codec::Uncompressed => {
+            let len: u64 = bincode_config().deserialize_from(&mut r)?;
+            Ok(Box::new(r.take(len)))
+        }
+        Codec::Adaptive => {
+            let mut tag = [0];
+            r.read_exact(&mut tag)?;
+            match tag[0] {
+                ADAPTIVE_LZ4 => decoder(Codec::Lz4, r),
+                ADAPTIVE_BITMAP => {
+                    // Decoded to the bincode of the set, like the other
+                    // blocks.
+                    let bitmap: Vec<(Value, Container)> = bincode_config().deserialize_from(&mut r)?;
+                    let values = bitmap_values(&bitmap)?;
+                    Ok(Box::new(io::Cursor::new(bincode_config().serialize(&values)?)))
+                }
+                _ => Err(Error::CorruptIndex),
+            }
+        }
+    }
+}
+
+/// Reads the values of the block `r` is positioned on.
+fn read_values<R: Read, T: DeserializeOwned>(r: &mut R, codec: Codec) -> Result<T> {
+    let bincode = read_block(r, codec)?;
+    let u8_ref: &[u8] = bincode.as_ref();
+    let values: T = bincode_config().deserialize_from(u8_ref)?;
+    return Ok(values);
+}
+
+/// Decompresses the block `r` is positioned on.  A block that
+/// decompresses to no bytes holds no values, and reads as the bincode
+/// of an empty set; a block cut off before its first byte, as in a
+/// truncated file, fails with `UnexpectedEof`.
+fn read_block<R: Read>(r: &mut R, codec: Codec) -> Result<Vec<u8>> {
+    let span = span!("read_block", bytes = tracing::field::Empty);
+    let mut bincode: Vec<u8> = Vec::new();
+    let mut first = [0];
+    let read = loop {
+        match r.read(&mut first) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            read => break read?,
+        }
+    };
+    if read == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "data block is cut off").into());
+    }
+    let mut decoder = decoder(codec, (&first[..]).chain(r))?;
+    io::copy(&mut decoder, &mut bincode)?;
+    span.record("bytes", bincode.len() as u64);
+    if bincode.is_empty() {
+        // The bincode of an empty set or map is its length.
+        bincode.extend_from_slice(&0_u64.to_le_bytes());
+    }
+    return Ok(bincode);
+}
 
-    // Write default headers to reserve space in file.
-    let mut header = BucketHeader::default();
-    bincode::serialize_into(&mut w, &header)?;
+trait WriteSeek: Write + Seek {}
 
-    header.num_entries = entries.len() as u64;
+impl<T: Write + Seek> WriteSeek for T {}
 
-    // Build the sparse index
-    header.si_base_offset = tell(&mut w)?;
+/// What a bucket can be read from.
+pub trait ReadSeek: Read + Seek + Send {}
 
-    let b: BTreeSet<HashedKey> = entries.iter().map(|(key, _)| *key).collect();
+impl<T: Read + Seek + Send> ReadSeek for T {}
 
-    let si = SparseIndex::new(&b);
-    bincode::serialize_into(&mut w, &si)?;
+/// A sink that only keeps track of the size of what is written to
+/// it; used for dry runs.
+#[derive(Default)]
+struct SizeCounter {
+    pos: u64,
+    len: u64,
+}
 
-    // Figure out the size of the dense index and seek ahead, leaving
-    // zeros behind.  After we've written the data section, we'll come
-    // back to backpatch this section.
-    //
-    header.di_base_offset = tell(&mut w)?;
-    let di_size = (entries.len() * INDEX_ENTRY_SIZE) as u64;
-    header.data_base_offset = w.seek(SeekFrom::Current(di_size as i64))?;
+impl Write for SizeCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pos += buf.len() as u64;
+        self.len = self.len.max(self.pos);
+        Ok(buf.len())
+    }
 
-    // Populate the data section.
-    let mut curr_offset: u64 = 0;
-    let mut offsets: Vec<u64> = Vec::with_capacity(entries.len());
-    for (_, values) in entries.iter() {
-        offsets.push(curr_offset);
-        write_values(&mut w, values)?;
-        curr_offset = tell(&mut w)? - header.data_base_offset;
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SizeCounter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::Current(delta) => (self.pos, delta),
+            SeekFrom::End(delta) => (self.len, delta),
+        };
+        let new_pos = base as i64 + delta;
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+/// Writes a new bucket.  The headers and the sparse index are
+/// reserved up front, the data section is streamed with `push`, and
+/// `finish` writes the dense index once every data offset is known.
+struct BucketWriter<'a> {
+    w: BufWriter<Box<dyn WriteSeek + 'a>>,
+    // The file to sync once written, with `CreateOptions::sync`.
+    sync: Option<File>,
+    // The file to read back once written, with
+    // `CreateOptions::validate_after_write`.
+    validate: Option<PathBuf>,
+    header: BucketHeader,
+    si: SparseIndex,
+    entries: Vec<IndexEntry>,
+    progress: Option<Progress>,
+    // The grouped data block being filled, and where it will start.
+    block: Vec<u8>,
+    block_offset: u64,
+}
+
+impl<'a> BucketWriter<'a> {
+    /// `keys` are those of the entries that will be pushed, in
+    /// increasing order.
+    fn create<P: AsRef<Path>, I: ExactSizeIterator<Item = HashedKey>>(filename: P, keys: I, options: &CreateOptions) -> Result<BucketWriter<'a>> {
+        BucketWriter::create_with_layout(filename, keys, options, options.values_layout())
+    }
+
+    fn create_with_layout<P: AsRef<Path>, I: ExactSizeIterator<Item = HashedKey>>(filename: P, keys: I, options: &CreateOptions, layout: ValueLayout) -> Result<BucketWriter<'a>> {
+        let len = keys.len();
+        let si = SparseIndex::from_sorted_iter(DEFAULT_SPARSE_INDEX_STEP, keys, len)?;
+        BucketWriter::create_with_index(filename, len as u64, si, options, layout)
+    }
+
+    /// Like `create_with_layout`, for `num_entries` keys whose sparse
+    /// index is already built.
+    fn create_with_index<P: AsRef<Path>>(filename: P, num_entries: u64, si: SparseIndex, options: &CreateOptions, layout: ValueLayout) -> Result<BucketWriter<'a>> {
+        if options.dry_run {
+            return BucketWriter::new_with_index(Box::new(SizeCounter::default()), num_entries, si, options, layout);
+        }
+        let file = File::create(filename.as_ref())?;
+        let sync = if options.sync { Some(file.try_clone()?) } else { None };
+        let mut writer = BucketWriter::new_with_index(Box::new(file), num_entries, si, options, layout)?;
+        writer.sync = sync;
+        if options.validate_after_write {
+            writer.validate = Some(filename.as_ref().to_path_buf());
+        }
+        Ok(writer)
+    }
+
+    /// Writes to `sink` from its start.
+    fn new<I: ExactSizeIterator<Item = HashedKey>>(sink: Box<dyn WriteSeek + 'a>, keys: I, options: &CreateOptions) -> Result<BucketWriter<'a>> {
+        BucketWriter::new_with_layout(sink, keys, options, options.values_layout())
+    }
+
+    /// Writes to `sink` a bucket whose data blocks hold values in
+    /// `layout`; the values pushed must be of the matching type.
+    fn new_with_layout<I: ExactSizeIterator<Item = HashedKey>>(sink: Box<dyn WriteSeek + 'a>, keys: I, options: &CreateOptions, layout: ValueLayout) -> Result<BucketWriter<'a>> {
+        let len = keys.len();
+        let si = SparseIndex::from_sorted_iter(DEFAULT_SPARSE_INDEX_STEP, keys, len)?;
+        BucketWriter::new_with_index(sink, len as u64, si, options, layout)
+    }
+
+    /// Like `new_with_layout`, for `num_entries` keys whose sparse
+    /// index is already built.
+    fn new_with_index(sink: Box<dyn WriteSeek + 'a>, num_entries: u64, si: SparseIndex, options: &CreateOptions, layout: ValueLayout) -> Result<BucketWriter<'a>> {
+        if options.block_size > MAX_BLOCK_SIZE {
+            return Err(Error::from(io::Error::new(io::ErrorKind::InvalidInput, "block size too large")));
+        }
+        let mut w = BufWriter::new(sink);
+
+        // Write default headers to reserve space in file.
+        let mut header = BucketHeader {
+            dense_index: options.dense_index,
+            block_size: options.block_size,
+            codec: options.codec,
+            value_type_tag: layout.value_type_tag(),
+            value_layout: layout,
+            app_id: options.app_id,
+            index_kind: options.index_kind,
+            ..BucketHeader::default()
+        };
+        if let Some(timestamp) = options.timestamp {
+            header.timestamp = timestamp;
+        }
+        // Leave room for the header of the current version after that
+        // of the oldest one that fits, so `append` can still record
+        // where its log begins.
+        header.version = VERSION;
+        let mut reserved = Vec::new();
+        header.write_to(&mut reserved)?;
+        header.version = header.required_version();
+        let mut written = Vec::new();
+        header.write_to(&mut written)?;
+        reserved[.. written.len()].copy_from_slice(&written);
+        reserved[written.len() ..].iter_mut().for_each(|byte| *byte = 0);
+        w.write_all(&reserved)?;
+
+        header.num_entries = num_entries;
+
+        // Reserve the sparse index; its offsets are patched by `finish`.
+        header.si_base_offset = tell(&mut w)?;
+        bincode_config().serialize_into(&mut w, &si)?;
+
+        // A fixed-size dense index goes before the data section, so
+        // leave zeros behind and come back to backpatch it.  Other
+        // encodings are only sized once written, after the data.
+        header.di_base_offset = tell(&mut w)?;
+        header.data_base_offset = match options.dense_index.fixed_size(header.num_entries) {
+            Some(di_size) => w.seek(SeekFrom::Current(di_size as i64))?,
+            None => header.di_base_offset,
+        };
+
+        Ok(BucketWriter {
+            w,
+            sync: None,
+            validate: None,
+            header,
+            si,
+            entries: Vec::with_capacity(num_entries as usize),
+            progress: options.progress.clone(),
+            block: Vec::new(),
+            block_offset: 0,
+        })
+    }
+
+    /// Appends the values of `key`; keys must be pushed in increasing
+    /// order.
+    fn push<S: Serialize>(&mut self, key: HashedKey, values: &S) -> Result<()> {
+        self.check_order(key)?;
+        let mut data = bincode_config().serialize(values)?;
+        if self.header.value_layout == ValueLayout::Varint {
+            data = varint_values(&data)?;
+        }
+        let offset = if self.header.block_size > 0 {
+            self.push_grouped(&data)?
+        } else {
+            let offset = tell(&mut self.w)? - self.header.data_base_offset;
+            write_block(&mut self.w, self.header.codec, &data)?;
+            offset
+        };
+        self.entries.push(IndexEntry { key, offset });
+        if let Some(ref progress) = self.progress {
+            progress.update(self.entries.len() as u64, self.header.num_entries);
+        }
+        Ok(())
+    }
+
+    /// Like `push`, with the timestamps of `values` kept in a
+    /// timestamped bucket and dropped in others.
+    fn push_stamped(&mut self, key: HashedKey, values: &BTreeMap<Value, i64>) -> Result<()> {
+        if self.header.value_layout == ValueLayout::Timestamped {
+            return self.push(key, values);
+        }
+        let values: Vec<&Value> = values.keys().collect();
+        self.push(key, &values)
+    }
+
+    /// Appends the values of `key` as `block`, a data block already
+    /// compressed with the codec of the bucket; only for buckets
+    /// without grouped blocks.
+    fn push_block(&mut self, key: HashedKey, block: &[u8]) -> Result<()> {
+        debug_assert_eq!(self.header.block_size, 0);
+        self.check_order(key)?;
+        let offset = tell(&mut self.w)? - self.header.data_base_offset;
+        self.w.write_all(block)?;
+        self.entries.push(IndexEntry { key, offset });
+        if let Some(ref progress) = self.progress {
+            progress.update(self.entries.len() as u64, self.header.num_entries);
+        }
+        Ok(())
+    }
+
+    /// Adds `values` to the current grouped block, closing it first
+    /// if it is full, and returns their packed offset.
+    fn push_grouped(&mut self, data: &[u8]) -> Result<u64> {
+        if self.block.len() as u64 >= self.header.block_size {
+            self.flush_block()?;
+        }
+        if self.block.is_empty() {
+            self.block_offset = tell(&mut self.w)? - self.header.data_base_offset;
+            if self.block_offset.leading_zeros() < BLOCK_SHIFT {
+                return Err(Error::from(io::Error::new(io::ErrorKind::InvalidInput, "data section too large for grouped blocks")));
+            }
+        }
+        let offset = self.block_offset << BLOCK_SHIFT | self.block.len() as u64;
+        self.block.extend_from_slice(data);
+        Ok(offset)
+    }
+
+    /// Appends `size` bytes of a data section written with the same
+    /// codec and block size, as they are, along with its index
+    /// `entries`, whose offsets are moved past the data already
+    /// written.
+    fn push_data_section<R: Read>(&mut self, r: R, size: u64, entries: &[IndexEntry]) -> Result<()> {
+        self.flush_block()?;
+        let base = tell(&mut self.w)? - self.header.data_base_offset;
+        if io::copy(&mut r.take(size), &mut self.w)? != size {
+            return Err(Error::from(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+        for entry in entries {
+            self.check_order(entry.key)?;
+            let offset = if self.header.block_size > 0 {
+                let block_offset = (entry.offset >> BLOCK_SHIFT) + base;
+                if block_offset.leading_zeros() < BLOCK_SHIFT {
+                    return Err(Error::from(io::Error::new(io::ErrorKind::InvalidInput, "data section too large for grouped blocks")));
+                }
+                block_offset << BLOCK_SHIFT | entry.offset & (MAX_BLOCK_SIZE - 1)
+            } else {
+                entry.offset + base
+            };
+            self.entries.push(IndexEntry { key: entry.key, offset });
+        }
+        if let Some(ref progress) = self.progress {
+            progress.update(self.entries.len() as u64, self.header.num_entries);
+        }
+        Ok(())
+    }
+
+    /// Fails unless `key` comes after every key pushed so far; lookups
+    /// can't binary search an index with keys out of order.
+    fn check_order(&self, key: HashedKey) -> Result<()> {
+        match self.entries.last() {
+            Some(last) if key <= last.key => Err(Error::UnsortedKeys(key)),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush_block(&mut self) -> Result<()> {
+        if !self.block.is_empty() {
+            write_block(&mut self.w, self.header.codec, &self.block)?;
+            self.block.clear();
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<WriteReport> {
+        debug_assert_eq!(self.entries.len() as u64, self.header.num_entries);
+        self.flush_block()?;
+
+        match self.header.dense_index.fixed_size(self.header.num_entries) {
+            Some(_) => {
+                self.w.seek(SeekFrom::Start(self.header.di_base_offset))?;
+            }
+            None => {
+                self.header.di_base_offset = tell(&mut self.w)?;
+            }
+        }
+        if self.header.dense_index == DenseIndexEncoding::Columnar {
+            self.header.keys_base_offset = self.header.di_base_offset;
+            self.header.offsets_base_offset =
+                self.header.di_base_offset + self.header.num_entries * HASHED_KEY_SIZE as u64;
+        }
+        let di_offsets = write_dense_index(&mut self.w, self.header.dense_index, self.si.step, &self.entries)?;
+
+        // Point the sparse index at where its entries ended up.
+        for entry in self.si.index.iter_mut() {
+            let i = usize::try_from(entry.offset / INDEX_ENTRY_SIZE as u64).map_err(|_| Error::CorruptIndex)?;
+            entry.offset = di_offsets[i];
+        }
+        self.w.seek(SeekFrom::Start(self.header.si_base_offset))?;
+        bincode_config().serialize_into(&mut self.w, &self.si)?;
+
+        // Rewrite header
+        self.w.seek(SeekFrom::Start(0))?;
+        self.header.write_to(&mut self.w)?;
+
+        let size = self.w.seek(SeekFrom::End(0))?;
+        // Flush here rather than on drop, which would swallow errors.
+        self.w.flush()?;
+        if let Some(file) = self.sync {
+            file.sync_all()?;
+        }
+        if let Some(path) = self.validate {
+            if let Err(e) = validate_written(&path, self.header.num_entries) {
+                warn!("{:?}: removing the bucket, which doesn't read back: {}", path, e);
+                std::fs::remove_file(&path)?;
+                return Err(e);
+            }
+        }
+        Ok(WriteReport {
+            num_entries: self.header.num_entries,
+            size,
+        })
+    }
+}
+
+/// Reads back the whole bucket just written at `path`: its headers,
+/// its sparse index, the order of its `num_entries` keys and every
+/// data block.
+fn validate_written(path: &Path, num_entries: u64) -> Result<()> {
+    let mut bucket = Bucket::open(path)?.check_headers()?.prepare()?.into_bucket();
+    let entries = bucket.index_entries()?.collect::<Result<Vec<IndexEntry>>>()?;
+    if entries.len() as u64 != num_entries || entries.windows(2).any(|w| w[0].key >= w[1].key) {
+        return Err(Error::CorruptIndex);
+    }
+    let mut last_block = None;
+    for entry in entries {
+        let block = bucket.header.data_block_offset(entry.offset);
+        if last_block != Some(block) {
+            bucket.read_values_vec_at(entry.offset)?;
+            last_block = Some(block);
+        }
+    }
+    Ok(())
+}
+
+/// Appends `value` to the values of `key` without rewriting the
+/// bucket: the record goes to a log at the end of the file, which
+/// `get` reads along with the sorted section and `compact` folds into
+/// it.  Buckets whose header has no room for `log_base_offset` before
+/// the sparse index, such as version 0 buckets written by older builds,
+/// must be compacted before they can be appended to.
+pub fn append<P: AsRef<Path>>(path: P, key: HashedKey, value: Value) -> Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path.as_ref())?;
+    let mut reader = BufReader::new(&mut file);
+    let mut header = BucketHeader::read_from(&mut reader)?;
+    header.check()?;
+
+    if header.log_base_offset == 0 {
+        header.log_base_offset = file.seek(SeekFrom::End(0))?;
+        header.version = header.version.max(header.required_version());
+        let mut new = Vec::new();
+        header.write_to(&mut new)?;
+        if new.len() as u64 > header.si_base_offset {
+            return Err(Error::BadHeader);
+        }
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&new)?;
+    }
+
+    let record = bincode_config().serialize(&(key, value))?;
+    file.seek(SeekFrom::End(0))?;
+    file.write_all(&record)?;
+    Ok(())
+}
+
+pub fn compact<P: AsRef<Path> + Debug>(path: P, new_bucket: P) -> Result<WriteReport> {
+    compact_with_options(path, new_bucket, &CreateOptions::default())
+}
+
+/// Writes the sorted section of `path` with its log folded in to
+/// `new_bucket`, which starts with an empty log.  Keys with an empty
+/// set of values are copied as they are.  The new bucket keeps
+/// the layout of the original one whatever the layout in `options`,
+/// timestamps included; the values of the log are stamped with the
+/// modification time of `path`.
+pub fn compact_with_options<P: AsRef<Path> + Debug>(path: P, new_bucket: P, options: &CreateOptions) -> Result<WriteReport> {
+    let mut bucket = Bucket::open(&path)?.check_headers()?;
+    let (entries, _) = bucket.entries_to_rewrite(options)?;
+    debug!("{:?}: folding {} logged keys into {} entries", path,
+           entries.iter().filter(|entry| !entry.logged.is_empty()).count(), bucket.header.num_entries);
+
+    let log_time = bucket.log_time()?;
+    let mut output = BucketWriter::create_with_layout(&new_bucket, entries.iter().map(|entry| entry.key),
+                                                      &options.rewriting(&bucket.header), bucket.header.value_layout)?;
+    for entry in &entries {
+        let values = bucket.read_stamped(entry, log_time)?;
+        output.push_stamped(entry.key, &values)?;
+    }
+    let report = output.finish()?;
+    options.finish_rewrite(path.as_ref(), new_bucket.as_ref())?;
+    Ok(report)
+}
+
+/// The outcome of `delete`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DeleteReport {
+    pub output: WriteReport,
+    pub values_removed: u64,
+    pub keys_emptied: u64,
+    /// The keys left out by `CreateOptions::drop_keys`.
+    pub keys_dropped: u64,
+}
+
+pub fn delete<P: AsRef<Path> + Debug>(path: P, new_bucket: P, value_set: &[Value]) -> Result<()> {
+    delete_with_options(path, new_bucket, value_set, &CreateOptions::default())?;
+    Ok(())
+}
+
+/// Like `delete`; the new bucket keeps the layout of the original one
+/// whatever the layout in `options`, timestamps included, like
+/// `compact`.
+///
+/// The entries are streamed from one bucket to the other, so only the
+/// index is held in memory.  The keys of the new bucket must be known
+/// before its values are written, so unless `options.keep_empty` is set
+/// the values are read twice: once to find the keys left without
+/// values, then to write the others.
+pub fn delete_with_options<P: AsRef<Path> + Debug>(path: P, new_bucket: P, value_set: &[Value], options: &CreateOptions) -> Result<DeleteReport> {
+    let t = Instant::now();
+    let mut bucket = Bucket::open(&path)?.check_headers()?;
+    debug!("opened {:?} in {:?}", path.as_ref(), t.elapsed());
+    let (entries, keys_dropped) = bucket.entries_to_rewrite(options)?;
+    let removed: BTreeSet<Value> = value_set.iter().cloned().collect();
+
+    let keys: BTreeSet<HashedKey> = if options.keep_empty {
+        entries.iter().map(|entry| entry.key).collect()
+    } else {
+        let mut keys = BTreeSet::new();
+        for entry in &entries {
+            if !bucket.read_logged(entry)?.iter().all(|value| removed.contains(value)) {
+                keys.insert(entry.key);
+            }
+        }
+        keys
+    };
+
+    let mut report = DeleteReport { keys_dropped, ..DeleteReport::default() };
+    let log_time = bucket.log_time()?;
+    let mut output = BucketWriter::create_with_layout(&new_bucket, keys.iter().copied(),
+                                                      &options.rewriting(&bucket.header), bucket.header.value_layout)?;
+    for entry in &entries {
+        let mut values = bucket.read_stamped(entry, log_time)?;
+        let len = values.len();
+        values.retain(|value, _| !removed.contains(value));
+        report.values_removed += (len - values.len()) as u64;
+        if values.is_empty() {
+            report.keys_emptied += 1;
+            if !options.keep_empty {
+                continue;
+            }
+        }
+        output.push_stamped(entry.key, &values)?;
     }
+    report.output = output.finish()?;
+    options.finish_rewrite(path.as_ref(), new_bucket.as_ref())?;
 
-    // Go back to the dense index and insert the data section offsets.
-    w.seek(SeekFrom::Start(header.di_base_offset))?;
-    for ((key, _), offset) in entries.iter().zip(offsets.iter()) {
-        let entry = IndexEntry {
-            key: *key,
-            offset: *offset,
+    Ok(report)
+}
+
+/// The outcome of `repair`: how many entries of the original bucket
+/// were copied to the new bucket and how many were dropped.
+#[derive(Debug, Default, PartialEq)]
+pub struct RepairReport {
+    pub recovered: u64,
+    pub dropped: u64,
+}
+
+/// Copies every entry of `path` whose values can still be decoded
+/// into a fresh bucket; entries that are unreadable or that point
+/// past the end of the file are logged and dropped.
+pub fn repair<P: AsRef<Path> + Debug>(path: P, new_bucket: P) -> Result<RepairReport> {
+    repair_with_options(path, new_bucket, &CreateOptions::default())
+}
+
+/// Like `repair`; the new bucket keeps the layout of the original one
+/// whatever the layout in `options`, timestamps included, like
+/// `compact`.
+pub fn repair_with_options<P: AsRef<Path> + Debug>(path: P, new_bucket: P, options: &CreateOptions) -> Result<RepairReport> {
+    // Open the database twice: once to have a cursor in the dense
+    // index; once to have a cursor in the data section.
+    let mut bucket = Bucket::open(&path)?.check_headers()?;
+    let mut bucket_data = Bucket::open(&path)?.check_headers()?;
+
+    let file_len = bucket.file_len()?;
+    let num_entries = bucket.header.num_entries;
+    let options = options.with_layout_of(&bucket.header);
+
+    let log_time = bucket.log_time()?;
+    let mut bmap: BTreeMap<HashedKey, BTreeMap<Value, i64>> = BTreeMap::new();
+
+    for entry in bucket.index_entries()? {
+        // A truncated file may end in the middle of the dense index.
+        let IndexEntry {
+            key: k,
+            offset: off,
+        } = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("{:?}: dense index is unreadable from here on: {}", path, e);
+                break;
+            }
         };
-        bincode::serialize_into(&mut w, &entry)?;
+
+        let abs_offset = bucket_data.header.data_base_offset + bucket_data.header.data_block_offset(off);
+        if abs_offset >= file_len {
+            warn!("{:?}: dropping key {}: data offset {} is past the end of the file", path, k, abs_offset);
+            continue;
+        }
+        let entry = LoggedEntry { key: k, offset: Some(off), logged: BTreeSet::new() };
+        match bucket_data.read_stamped(&entry, log_time) {
+            Ok(values) => {
+                bmap.insert(k, values);
+            }
+            Err(e) => {
+                warn!("{:?}: dropping key {}: {}", path, k, e);
+            }
+        }
+    }
+
+    let recovered = bmap.len() as u64;
+    match bucket.read_log() {
+        Ok(log) => {
+            for (key, value) in log {
+                bmap.entry(key).or_default().entry(value).or_insert(log_time);
+            }
+        }
+        Err(e) => warn!("{:?}: dropping the log: {}", path, e),
+    }
+
+    let mut output = BucketWriter::create_with_layout(new_bucket, bmap.keys().copied(), &options, bucket.header.value_layout)?;
+    for (key, values) in &bmap {
+        match options.kept_values(*key, values.len()) {
+            Some(kept) => {
+                let capped: BTreeMap<Value, i64> = values.iter().skip(kept.start).take(kept.len()).map(|(v, t)| (*v, *t)).collect();
+                output.push_stamped(*key, &capped)?;
+            }
+            None => output.push_stamped(*key, values)?,
+        }
     }
+    output.finish()?;
+
+    Ok(RepairReport {
+        recovered,
+        dropped: num_entries.saturating_sub(recovered),
+    })
+}
 
-    // Rewrite header
-    w.seek(SeekFrom::Start(0))?;
-    bincode::serialize_into(&mut w, &header)?;
+/// Writes the entries of `path` to `new_bucket` under the key that
+/// `mapping` gives each of theirs, such as after a change of hash
+/// function; the values of keys mapped to the same new key are
+/// unioned.  Every key must be mapped, or this fails with
+/// `Error::UnmappedKey`.  The log is folded in, and insertion
+/// timestamps aren't kept.  Only the keys and offsets of the entries
+/// are held in memory while they are sorted under their new keys.
+pub fn rehash<P: AsRef<Path> + Debug>(path: P, new_bucket: P, mapping: &BTreeMap<HashedKey, HashedKey>, options: &CreateOptions) -> Result<WriteReport> {
+    let mut bucket = Bucket::open(&path)?.check_headers()?;
+    let mut rekeyed = Vec::with_capacity(bucket.header.num_entries as usize);
+    for entry in bucket.index_entries()? {
+        let entry = entry?;
+        match mapping.get(&entry.key) {
+            Some(key) => rekeyed.push(IndexEntry { key: *key, offset: entry.offset }),
+            None => return Err(Error::UnmappedKey(entry.key)),
+        }
+    }
+    // Keep the offsets of a new key in order, so the data section is
+    // read front to back within it.
+    rekeyed.sort_unstable_by_key(|entry| (entry.key, entry.offset));
+    let mut log: BTreeMap<HashedKey, BTreeSet<Value>> = BTreeMap::new();
+    for (key, values) in bucket.log_map()? {
+        match mapping.get(&key) {
+            Some(key) => log.entry(*key).or_default().extend(values),
+            None => return Err(Error::UnmappedKey(key)),
+        }
+    }
+    let mut keys: Vec<HashedKey> = rekeyed.iter().map(|entry| entry.key).chain(log.keys().copied()).collect();
+    keys.sort_unstable();
+    keys.dedup();
+    debug!("{:?}: rehashing {} entries to {} keys", path, rekeyed.len(), keys.len());
+
+    let mut output = BucketWriter::create(&new_bucket, keys.iter().copied(), options)?;
+    let mut rekeyed = rekeyed.into_iter().peekable();
+    for key in keys {
+        let mut values = log.remove(&key).unwrap_or_default();
+        while let Some(entry) = rekeyed.next_if(|entry| entry.key == key) {
+            values.extend(bucket.read_values_at(entry.offset)?);
+        }
+        output.push(key, &values)?;
+    }
+    output.finish()
+}
 
+pub fn create<P: AsRef<Path>>(filename: P, entries: &BTreeMap<u64, BTreeSet<Value>>) -> Result<()> {
+    create_with_options(filename, entries, &CreateOptions::default())?;
     Ok(())
 }
 
+pub fn create_with_options<P: AsRef<Path>>(filename: P, entries: &BTreeMap<u64, BTreeSet<Value>>, options: &CreateOptions) -> Result<WriteReport> {
+    write_entries(BucketWriter::create(filename, entries.keys().copied(), options)?, entries, options)
+}
+
+/// Like `create_with_options`, but if `filename` is already a bucket,
+/// replaces it with the union of its contents and `entries`, merged as
+/// by `merge_with_options`.  The merged bucket is written next to
+/// `filename`, then renamed over it, so `filename` is always either the
+/// old bucket or the new one.
+pub fn create_or_merge<P: AsRef<Path>>(filename: P, entries: &BTreeMap<u64, BTreeSet<Value>>, options: &CreateOptions) -> Result<WriteReport> {
+    let filename = filename.as_ref();
+    if !filename.exists() {
+        return create_with_options(filename, entries, options);
+    }
+    let sibling = |suffix: &str| {
+        let mut name = filename.as_os_str().to_owned();
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+    let (added, merged) = (sibling(".added"), sibling(".merged"));
+    // The added entries are written even for a dry run, to be merged.
+    let written = CreateOptions { dry_run: false, validate_after_write: false, ..options.clone() };
+    let result = create_with_options(&added, entries, &written)
+        .and_then(|_| merge_with_options(filename, &added, &merged, options));
+    let _ = std::fs::remove_file(&added);
+    match result {
+        Ok(report) => {
+            if !options.dry_run {
+                std::fs::rename(&merged, filename)?;
+            }
+            Ok(report)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&merged);
+            Err(e)
+        }
+    }
+}
+
+/// Like `create_with_options`, but each value keeps the Unix timestamp
+/// it was inserted at, which `get_timestamped` returns.  Everything
+/// else reads the values alone; `compact`, `delete` and `repair` keep
+/// the timestamps, while merged and rehashed buckets don't.
+pub fn create_timestamped<P: AsRef<Path>>(filename: P, entries: &BTreeMap<HashedKey, BTreeMap<Value, i64>>, options: &CreateOptions) -> Result<WriteReport> {
+    let mut w = BucketWriter::create_with_layout(filename, entries.keys().copied(), options, ValueLayout::Timestamped)?;
+    for (key, values) in entries {
+        match options.kept_values(*key, values.len()) {
+            Some(kept) => {
+                let capped: BTreeMap<Value, i64> = values.iter().skip(kept.start).take(kept.len()).map(|(v, t)| (*v, *t)).collect();
+                w.push(*key, &capped)?;
+            }
+            None => w.push(*key, values)?,
+        }
+    }
+    w.finish()
+}
+
+/// Like `create_with_options`, but writes the bucket to `w`.  The
+/// offsets in the bucket are relative to the start of `w`, which is
+/// seeked back to when the header is rewritten, so `w` should be
+/// empty.  `options.dry_run` is ignored.
+pub fn create_into<W: Write + Seek>(w: &mut W, entries: &BTreeMap<u64, BTreeSet<Value>>, options: &CreateOptions) -> Result<WriteReport> {
+    write_entries(BucketWriter::new(Box::new(w), entries.keys().copied(), options)?, entries, options)
+}
+
+fn write_entries(mut w: BucketWriter, entries: &BTreeMap<u64, BTreeSet<Value>>, options: &CreateOptions) -> Result<WriteReport> {
+    for (key, values) in entries.iter() {
+        match options.capped(*key, values) {
+            Some(capped) => w.push(*key, &capped)?,
+            None => w.push(*key, values)?,
+        }
+    }
+    w.finish()
+}
+
 /// Merges two binstore files, and write the result directly on disk.
 pub fn merge<P: AsRef<Path>>(filename1: P, filename2: P, output_file: P) -> Result<()> {
-    enum Origin {
-        Bucket1 { offset: u64 },
-        Bucket2 { offset: u64 },
-        Union { offset_1: u64, offset_2: u64 },
-    };
-    struct Source {
-        key: HashedKey,
-        origin: Origin
+    merge_with_options(filename1, filename2, output_file, &CreateOptions::default())?;
+    Ok(())
+}
+
+/// Like `merge`; unless `options.timestamp` is set, the merged bucket
+/// is stamped from the inputs' timestamps following
+/// `options.merge_timestamp`.  An input with a log fails with
+/// `Error::UncompactedLog`.
+pub fn merge_with_options<P: AsRef<Path>>(filename1: P, filename2: P, output_file: P, options: &CreateOptions) -> Result<WriteReport> {
+    let options = merge_options(filename1.as_ref(), filename2.as_ref(), options)?;
+    merge_to(filename1, filename2, &options, |num_entries, si| {
+        BucketWriter::create_with_index(output_file, num_entries, si, &options, options.values_layout())
+    })
+}
+
+/// `options` with the timestamp of the bucket merged from `filename1`
+/// and `filename2`.
+fn merge_options(filename1: &Path, filename2: &Path, options: &CreateOptions) -> Result<CreateOptions> {
+    let mut options = options.clone();
+    let header1 = Bucket::read_header(filename1)?;
+    if options.timestamp.is_none() {
+        let t2 = Bucket::read_header(filename2)?.timestamp;
+        options.timestamp = options.merge_timestamp.resolve(header1.timestamp, t2);
+    }
+    if options.app_id == 0 {
+        options.app_id = header1.app_id;
+    }
+    Ok(options)
+}
+
+/// Reads the dense indexes of two buckets to tell what merging them
+/// with `options` would give, without reading their data sections.
+pub fn estimate_merge<P: AsRef<Path>>(filename1: P, filename2: P, options: &CreateOptions) -> Result<MergeEstimate> {
+    let mut keys = BTreeSet::new();
+    let mut total = 0;
+    for filename in &[filename1, filename2] {
+        let mut bucket = Bucket::open(filename.as_ref())?.check_headers()?;
+        for entry in bucket.index_entries()? {
+            keys.insert(entry?.key);
+            total += 1;
+        }
+    }
+    let unique_keys = keys.len() as u64;
+    let si = SparseIndex::new(&keys)?;
+    Ok(MergeEstimate {
+        unique_keys,
+        overlapping_keys: total - unique_keys,
+        sparse_index_size: bincode_config().serialized_size(&si)?,
+        dense_index_size: options.dense_index.fixed_size(unique_keys)
+            .unwrap_or(unique_keys * INDEX_ENTRY_SIZE as u64),
+    })
+}
+
+/// Whether two buckets hold the same keys with the same values,
+/// whatever their header, codec or index encoding; stops at the first
+/// difference.  Values in the logs count as well, wherever they are.
+pub fn eq_contents<P: AsRef<Path>>(path_a: P, path_b: P) -> Result<bool> {
+    let mut bucket_a = Bucket::open(path_a.as_ref())?.check_headers()?;
+    let mut bucket_b = Bucket::open(path_b.as_ref())?.check_headers()?;
+    let entries_a = bucket_a.logged_entries()?;
+    let entries_b = bucket_b.logged_entries()?;
+    if entries_a.len() != entries_b.len() {
+        return Ok(false);
+    }
+    for (a, b) in entries_a.iter().zip(&entries_b) {
+        if a.key != b.key || bucket_a.read_logged(a)? != bucket_b.read_logged(b)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Like `merge_with_options`, but writes the bucket to `w`, which
+/// should be empty, like `create_into`.
+pub fn merge_into<P: AsRef<Path>, W: Write + Seek>(filename1: P, filename2: P, w: &mut W, options: &CreateOptions) -> Result<WriteReport> {
+    let options = merge_options(filename1.as_ref(), filename2.as_ref(), options)?;
+    merge_to(filename1, filename2, &options, |num_entries, si| {
+        BucketWriter::new_with_index(Box::new(w), num_entries, si, &options, options.values_layout())
+    })
+}
+
+/// Like `merge_with_options`, but splits the merged bucket into shards
+/// of at most about `max_size` bytes, named by `shard_name` from their
+/// number.  Each shard is a complete bucket over a range of keys, after
+/// the range of the shard before; a data block larger than `max_size`
+/// gets a shard of its own.  The merged bucket is written next to the
+/// first shard, then removed.
+pub fn merge_split<P, F>(filename1: P, filename2: P, shard_name: F, max_size: u64, options: &CreateOptions) -> Result<Vec<WriteReport>>
+where
+    P: AsRef<Path>,
+    F: Fn(usize) -> PathBuf,
+{
+    let mut merged = shard_name(0).into_os_string();
+    merged.push(".merged");
+    let merged = PathBuf::from(merged);
+    let written = CreateOptions { dry_run: false, validate_after_write: false, ..options.clone() };
+    let result = merge_with_options(filename1.as_ref(), filename2.as_ref(), &merged, &written)
+        .and_then(|_| split_to(&merged, shard_name, max_size, options));
+    let _ = std::fs::remove_file(&merged);
+    result
+}
+
+/// Copies the bucket at `path` to shards of at most about `max_size`
+/// bytes; see `merge_split`.
+fn split_to<F: Fn(usize) -> PathBuf>(path: &Path, shard_name: F, max_size: u64, options: &CreateOptions) -> Result<Vec<WriteReport>> {
+    let mut bucket = Bucket::open(path)?.check_headers()?;
+    bucket.check_no_log()?;
+    let header = bucket.header.clone();
+    let options = CreateOptions { timestamp: Some(header.timestamp), ..options.rewriting(&header) };
+    let entries = bucket.index_entries()?.collect::<Result<Vec<IndexEntry>>>()?;
+    let data_size = bucket.data_size()?;
+
+    // What every shard costs before its keys, the last key's entry in
+    // the sparse index included, and what each key adds besides its
+    // data: an entry in the dense index, and now and then one in the
+    // sparse index.
+    let empty = create_into(&mut io::Cursor::new(Vec::new()), &BTreeMap::new(), &options)?.size
+        + INDEX_ENTRY_SIZE as u64;
+    let key_size = |i: usize| {
+        let sparse = if i % DEFAULT_SPARSE_INDEX_STEP == 0 { INDEX_ENTRY_SIZE } else { 0 };
+        (INDEX_ENTRY_SIZE + sparse) as u64
     };
 
-    fn merge_into<W: Write + Seek>(source: Source,
-                  bucket_1_data: &mut Bucket<Checked>,
-                  bucket_2_data: &mut Bucket<Checked>,
-                  output_di: &mut W,
-                  output_data: &mut W,
-                  data_base_offset: u64)
-                  -> Result<()>
-    {
-        let offset = tell(output_data)?;
-        let relative_offset = offset - data_base_offset;
-        let di_entry = IndexEntry { key: source.key, offset: relative_offset };
-        bincode::serialize_into(output_di, &di_entry)?;
-        match source.origin {
-            Origin::Bucket1 { offset } => {
-                bucket_1_data.file.seek(SeekFrom::Start(bucket_1_data.header.data_base_offset + offset))?;
-                let values = read_values(&mut bucket_1_data.file)?;
-                write_values(output_data, &values)?;
-            },
-            Origin::Bucket2 { offset } => {
-                bucket_2_data.file.seek(SeekFrom::Start(bucket_2_data.header.data_base_offset + offset))?;
-                let values = read_values(&mut bucket_2_data.file)?;
-                write_values(output_data, &values)?;
-            },
-            Origin::Union { offset_1, offset_2 } => {
-                bucket_1_data.file.seek(SeekFrom::Start(bucket_1_data.header.data_base_offset + offset_1))?;
-                let mut values_1 = read_values(&mut bucket_1_data.file)?;
-                bucket_2_data.file.seek(SeekFrom::Start(bucket_2_data.header.data_base_offset + offset_2))?;
-                let values_2 = read_values(&mut bucket_2_data.file)?;
-                for value in values_2 {
-                    values_1.insert(value);
+    // The block of each entry, where it ends, and whether the entry is
+    // the first of it; shards are only cut between blocks.
+    let blocks: Vec<(u64, u64, bool)> = entries.iter().enumerate().map(|(i, entry)| {
+        let start = header.data_block_offset(entry.offset);
+        let end = entries[i + 1 ..].iter()
+            .map(|next| header.data_block_offset(next.offset))
+            .find(|&next| next != start)
+            .unwrap_or(data_size);
+        let first = i == 0 || header.data_block_offset(entries[i - 1].offset) != start;
+        (start, end, first)
+    }).collect();
+
+    let mut shards: Vec<Range<usize>> = Vec::new();
+    let (mut start, mut size) = (0, empty);
+    for (i, &(block_start, block_end, first)) in blocks.iter().enumerate() {
+        let added = key_size(i - start) + if first { block_end - block_start } else { 0 };
+        if first && i > start && size + added > max_size {
+            shards.push(start .. i);
+            start = i;
+            size = empty + key_size(0) + block_end - block_start;
+        } else {
+            size += added;
+        }
+    }
+    shards.push(start .. entries.len());
+
+    let mut reports = Vec::with_capacity(shards.len());
+    for (n, shard) in shards.into_iter().enumerate() {
+        let entries = &entries[shard.clone()];
+        let mut output = BucketWriter::create(shard_name(n), entries.iter().map(|entry| entry.key), &options)?;
+        for (entry, &(block_start, block_end, _)) in entries.iter().zip(&blocks[shard]) {
+            if header.block_size > 0 {
+                output.push(entry.key, &bucket.read_values_at(entry.offset)?)?;
+            } else {
+                bucket.file.seek(SeekFrom::Start(header.data_base_offset + block_start))?;
+                let mut block = vec![0; (block_end - block_start) as usize];
+                bucket.file.read_exact(&mut block)?;
+                output.push_block(entry.key, &block)?;
+            }
+        }
+        reports.push(output.finish()?);
+    }
+    Ok(reports)
+}
+
+/// Iterates over the keys of `b1` and `b2` with their values combined
+/// by `op`, in key order, without writing anything; keys left without
+/// values are skipped.  Both dense indexes and logs are read up front.
+pub fn merge_iter<'a>(b1: &'a mut Bucket<Checked>, b2: &'a mut Bucket<Checked>, op: SetOp) -> Result<MergeIter<'a>> {
+    let entries_1 = b1.logged_entries()?;
+    let entries_2 = b2.logged_entries()?;
+    Ok(MergeIter {
+        buckets: (b1, b2),
+        entries: (entries_1.into_iter().peekable(), entries_2.into_iter().peekable()),
+        op,
+        failed: false,
+    })
+}
+
+impl<'a> MergeIter<'a> {
+    fn next_entry(&mut self) -> Result<Option<(HashedKey, BTreeSet<Value>)>> {
+        loop {
+            let next = match Next::of_keys(self.entries.0.peek().map(|e| e.key), self.entries.1.peek().map(|e| e.key)) {
+                Some(next) => next,
+                None => return Ok(None),
+            };
+            let values = match (next, self.op) {
+                (Next::First, SetOp::Intersection) => {
+                    self.entries.0.next();
+                    continue;
+                }
+                (Next::Second, SetOp::Intersection) | (Next::Second, SetOp::Difference) => {
+                    self.entries.1.next();
+                    continue;
+                }
+                (Next::First, _) => {
+                    let entry = self.entries.0.next().expect("peeked");
+                    (entry.key, self.buckets.0.read_logged(&entry)?)
                 }
-                write_values(output_data, &values_1)?;
+                (Next::Second, _) => {
+                    let entry = self.entries.1.next().expect("peeked");
+                    (entry.key, self.buckets.1.read_logged(&entry)?)
+                }
+                (Next::Both, op) => {
+                    let (e1, e2) = (self.entries.0.next().expect("peeked"), self.entries.1.next().expect("peeked"));
+                    let mut values = self.buckets.0.read_logged(&e1)?;
+                    let other = self.buckets.1.read_logged(&e2)?;
+                    match op {
+                        SetOp::Union => values.extend(other),
+                        SetOp::Intersection => values.retain(|value| other.contains(value)),
+                        SetOp::Difference => values.retain(|value| !other.contains(value)),
+                    }
+                    (e1.key, values)
+                }
+            };
+            if values.1.is_empty() && self.op != SetOp::Union {
+                continue;
             }
+            return Ok(Some(values));
         }
-        return Ok(());
     }
+}
+
+impl<'a> Iterator for MergeIter<'a> {
+    type Item = Result<(HashedKey, BTreeSet<Value>)>;
 
-    fn accumulate_keys_in_bset(bucket_1: &mut Bucket<Checked>,
-                  bucket_2: &mut Bucket<Checked>)
-                   -> Result<BTreeSet<HashedKey>>
-    {
-        // The offsets in the dense indexes
-        let mut curr_offset_1 = bucket_1.header.di_base_offset;
-        let mut curr_offset_2 = bucket_2.header.di_base_offset;
+    /// Ends after the first error.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let next = self.next_entry().transpose();
+        self.failed = matches!(next, Some(Err(_)));
+        next
+    }
+}
 
-        // Where the dense indexes stop.
-        let data_start_1 = bucket_1.header.data_base_offset;
-        let data_start_2 = bucket_2.header.data_base_offset;
+/// Merges two buckets into the writer `output` makes for the keys of
+/// both.
+fn merge_to<'a, P, F>(filename1: P, filename2: P, options: &CreateOptions, output: F) -> Result<WriteReport>
+where
+    P: AsRef<Path>,
+    F: FnOnce(u64, SparseIndex) -> Result<BucketWriter<'a>>,
+{
+    let span = span!("merge", input_1 = %filename1.as_ref().display(), input_2 = %filename2.as_ref().display(),
+                     bytes = tracing::field::Empty);
+
+    /// One of the buckets being merged.  Its data section is read in
+    /// key order, so the cursor is usually already on the next block
+    /// and only needs to seek when the merge skips a block.
+    struct Source<'a> {
+        entries: IndexEntries<'a>,
+        data: &'a mut Bucket<Checked>,
+        data_size: u64,
+        curr: Option<IndexEntry>,
+        next: Option<IndexEntry>,
+        seeks: u64,
+    }
 
-        // Position the cursors.
-        bucket_1.file.seek(SeekFrom::Start(curr_offset_1))?;
-        bucket_2.file.seek(SeekFrom::Start(curr_offset_2))?;
+    impl<'a> Source<'a> {
+        fn new(bucket: &'a mut Bucket<Checked>, data: &'a mut Bucket<Checked>) -> Result<Source<'a>> {
+            let data_size = data.data_size()?;
+            let mut entries = bucket.index_entries()?;
+            let curr = entries.next().transpose()?;
+            let next = entries.next().transpose()?;
+            Ok(Source { entries, data, data_size, curr, next, seeks: 0 })
+        }
 
-        let mut bset = BTreeSet::new();
+        fn advance(&mut self) -> Result<()> {
+            self.curr = self.next;
+            self.next = self.entries.next().transpose()?;
+            Ok(())
+        }
 
-        while curr_offset_1 < data_start_1 {
-            let entry: IndexEntry = bincode::deserialize_from(&mut bucket_1.file)?;
-            bset.insert(entry.key);
-            curr_offset_1 += INDEX_ENTRY_SIZE as u64;
+        /// Reads the values of the current entry.
+        fn read_values(&mut self) -> Result<BTreeSet<Value>> {
+            let entry = self.curr.expect("Source::read_values");
+            if self.data.header.block_size > 0 || self.data.header.value_layout != ValueLayout::Plain {
+                return self.data.read_values_at(entry.offset);
+            }
+            let block = self.read_block()?;
+            read_values(&mut block.as_slice(), self.data.header.codec)
         }
 
-        while curr_offset_2 < data_start_2 {
-            let entry: IndexEntry = bincode::deserialize_from(&mut bucket_2.file)?;
-            bset.insert(entry.key);
-            curr_offset_2 += INDEX_ENTRY_SIZE as u64;
+        /// Reads the compressed data block of the current entry, which
+        /// ends where the block of the next entry starts; only for
+        /// buckets without grouped blocks.
+        fn read_block(&mut self) -> Result<Vec<u8>> {
+            let entry = self.curr.expect("Source::read_block");
+            let end = self.next.map_or(self.data_size, |next| next.offset);
+            if end < entry.offset || end > self.data_size {
+                return Err(Error::CorruptIndex);
+            }
+            let offset = self.data.header.data_base_offset + entry.offset;
+            if self.data.file.stream_position()? != offset {
+                self.data.file.seek(SeekFrom::Start(offset))?;
+                self.seeks += 1;
+            }
+            let mut block = vec![0; (end - entry.offset) as usize];
+            self.data.file.read_exact(&mut block)?;
+            Ok(block)
         }
 
-        // Restore the cursor positions
-        bucket_1.file.seek(SeekFrom::Start(bucket_1.header.di_base_offset))?;
-        bucket_2.file.seek(SeekFrom::Start(bucket_2.header.di_base_offset))?;
+        /// Whether the blocks of this bucket can be copied to `output`
+        /// as they are.
+        fn can_copy_to(&self, output: &BucketWriter) -> bool {
+            self.data.header.block_size == 0
+                && output.header.block_size == 0
+                && self.data.header.codec == output.header.codec
+                && self.data.header.value_layout == output.header.value_layout
+        }
 
-        Ok(bset)
+        /// Writes the values of the current entry to `output`, copying
+        /// the compressed block when the layouts allow it and no cap
+        /// needs the values counted.
+        fn copy_to(&mut self, output: &mut BucketWriter, options: &CreateOptions) -> Result<()> {
+            let key = self.curr.expect("Source::copy_to").key;
+            if self.can_copy_to(output) && options.max_values_per_key.is_none() {
+                output.push_block(key, &self.read_block()?)
+            } else {
+                let values = self.read_values()?;
+                output.push(key, &options.capped(key, &values).unwrap_or(values))
+            }
+        }
     }
 
     // Open the database twice: once to have a cursor in the dense
@@ -431,157 +3178,166 @@ pub fn merge<P: AsRef<Path>>(filename1: P, filename2: P, output_file: P) -> Resu
     let mut bucket_2 = Bucket::open(filename2.as_ref())?.check_headers()?;
     let mut data_1 = Bucket::open(filename1.as_ref())?.check_headers()?;
     let mut data_2 = Bucket::open(filename2.as_ref())?.check_headers()?;
+    bucket_1.check_no_log()?;
+    bucket_2.check_no_log()?;
+    let same_file = distinct_inputs(&[filename1.as_ref(), filename2.as_ref()], options)?.len() == 1;
+
+    // Set up the output bucket.
+    let (si, num_entries) = if options.lowmem {
+        merged_sparse_index(&mut bucket_1, &mut bucket_2)?
+    } else {
+        let mut b = BTreeSet::new();
+        for entry in bucket_1.index_entries()?.chain(bucket_2.index_entries()?) {
+            b.insert(entry?.key);
+        }
+        (SparseIndex::new(&b)?, b.len() as u64)
+    };
+    let mut output = output(num_entries, si)?;
+
+    let mut source_1 = Source::new(&mut bucket_1, &mut data_1)?;
+    let mut source_2 = Source::new(&mut bucket_2, &mut data_2)?;
+    if same_file {
+        // Both inputs are the same file, so only the first is read.
+        source_2.curr = None;
+        source_2.next = None;
+    }
 
-    // Where the dense indexes stop.
-    let data_start_1 = bucket_1.header.data_base_offset;
-    let data_start_2 = bucket_2.header.data_base_offset;
+    // Populate the data section, copying the blocks of the keys in
+    // only one bucket when possible. Each key written must be greater
+    // than the one before: a key out of order here is a bug in the
+    // stepping of the two sources, or an unsorted input.
+    let mut prev: Option<HashedKey> = None;
+    while let Some(next) = Next::of(source_1.curr, source_2.curr) {
+        let key = match next {
+            Next::Second => source_2.curr.expect("Next::Second").key,
+            _ => source_1.curr.expect("Next::First").key,
+        };
+        if prev.map_or(false, |prev| prev >= key) {
+            return Err(Error::UnsortedKeys(key));
+        }
+        prev = Some(key);
+        match next {
+            Next::Both => {
+                let mut values = source_1.read_values()?;
+                values.extend(source_2.read_values()?);
+                let values = options.capped(key, &values).unwrap_or(values);
+                output.push(key, &values)?;
+                source_1.advance()?;
+                source_2.advance()?;
+            }
+            Next::First => {
+                source_1.copy_to(&mut output, options)?;
+                source_1.advance()?;
+            }
+            Next::Second => {
+                source_2.copy_to(&mut output, options)?;
+                source_2.advance()?;
+            }
+        }
+    }
+    debug!("merge: {} + {} seeks in the data sections", source_1.seeks, source_2.seeks);
 
-    // The last key read from bucket_1 and bucket_2.
-    let mut ci_1 = 0;
-    let mut data_off_1 = 0;
-    let mut ci_2 = 0;
-    let mut data_off_2 = 0;
+    let report = output.finish()?;
+    span.record("bytes", report.size);
+    Ok(report)
+}
 
-    // If true, a read in the database dense index must be performed
-    // to obtain a new key.
-    let mut read_bucket_1 = true;
-    let mut read_bucket_2 = true;
+/// `filenames` without those naming a file given before, by canonical
+/// path; a repeat fails with `Error::DuplicateInput` unless
+/// `options.dedup_inputs` is set.
+fn distinct_inputs<'p, P: AsRef<Path>>(filenames: &'p [P], options: &CreateOptions) -> Result<Vec<&'p P>> {
+    let mut seen = BTreeSet::new();
+    let mut distinct = Vec::with_capacity(filenames.len());
+    for filename in filenames {
+        if seen.insert(filename.as_ref().canonicalize()?) {
+            distinct.push(filename);
+        } else if !options.dedup_inputs {
+            return Err(Error::DuplicateInput(filename.as_ref().to_path_buf()));
+        }
+    }
+    Ok(distinct)
+}
 
-    // The offsets in the dense indexes (this is an optimization to
-    // avoid using tell() all the time).
-    let mut curr_offset_1 = bucket_1.header.di_base_offset;
-    let mut curr_offset_2 = bucket_2.header.di_base_offset;
+/// Builds the sparse index of the merge of two buckets, and counts its
+/// keys, by walking both dense indexes in step.
+fn merged_sparse_index(b1: &mut Bucket<Checked>, b2: &mut Bucket<Checked>) -> Result<(SparseIndex, u64)> {
+    let mut entries_1 = b1.index_entries()?;
+    let mut entries_2 = b2.index_entries()?;
+    let mut e1 = entries_1.next().transpose()?;
+    let mut e2 = entries_2.next().transpose()?;
+    let keys = std::iter::from_fn(|| {
+        let next = Next::of(e1, e2)?;
+        let key = match next {
+            Next::Second => e2.expect("Next::Second").key,
+            _ => e1.expect("Next::First").key,
+        };
+        if next != Next::Second {
+            e1 = match entries_1.next().transpose() {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+        }
+        if next != Next::First {
+            e2 = match entries_2.next().transpose() {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+        }
+        Some(Ok(key))
+    });
+    SparseIndex::from_sorted_keys(DEFAULT_SPARSE_INDEX_STEP, keys)
+}
 
-    // Position the cursors.
-    data_1.file.seek(SeekFrom::Start(data_1.header.data_base_offset))?;
-    data_2.file.seek(SeekFrom::Start(data_2.header.data_base_offset))?;
+/// Concatenates buckets whose keys are disjoint and ascending from one
+/// bucket to the next, copying their data blocks as they are instead
+/// of decompressing and recompressing them like `merge`.  The buckets
+/// must share a codec and block size, which the new bucket keeps, and
+/// have no log: a bucket that was appended to must be compacted first,
+/// or this fails with `Error::UncompactedLog`.
+pub fn cat<P: AsRef<Path>>(filenames: &[P], output_file: P, options: &CreateOptions) -> Result<WriteReport> {
+    let filenames = distinct_inputs(filenames, options)?;
+    let mut buckets = Vec::with_capacity(filenames.len());
+    for filename in filenames {
+        let mut bucket = Bucket::open(filename.as_ref())?.check_headers()?;
+        bucket.check_no_log()?;
+        let entries = bucket.index_entries()?.collect::<Result<Vec<IndexEntry>>>()?;
+        buckets.push((bucket, entries));
+    }
 
-    // Set up the output bucket.
-    let file = File::create(output_file.as_ref())?;
-    let mut output = BufWriter::new(file);
-
-    let file = File::create(output_file.as_ref())?;
-    let mut output_data = BufWriter::new(file);
-
-    // Write default headers to reserve space in file.
-    let mut header = BucketHeader::default();
-    bincode::serialize_into(&mut output, &header)?;
-
-    // Build the sparse index.
-    header.si_base_offset = tell(&mut output)?;
-    let b = accumulate_keys_in_bset(&mut bucket_1, &mut bucket_2)?;
-    let si = SparseIndex::new(&b);
-    bincode::serialize_into(&mut output, &si)?;
-
-    header.num_entries = b.len() as u64;
-
-    // Figure out the size of the dense index and place the cursor of
-    // `output_data` at the end of it. Leave the cursor for `output` to point
-    // to the begining of the dense index.
-    header.di_base_offset = tell(&mut output)?;
-    let di_size = (b.len() * INDEX_ENTRY_SIZE) as u64;
-    header.data_base_offset = output_data.seek(SeekFrom::Current(header.di_base_offset as i64 + di_size as i64))?;
-
-    // Populate the data section.
-    while curr_offset_1 < data_start_1 && curr_offset_2 < data_start_2 {
-        if read_bucket_1 {
-            let entry: IndexEntry = bincode::deserialize_from(&mut bucket_1.file)?;
-            ci_1 = entry.key;
-            data_off_1 = entry.offset;
-        }
-
-        if read_bucket_2 {
-            let entry: IndexEntry = bincode::deserialize_from(&mut bucket_2.file)?;
-            ci_2 = entry.key;
-            data_off_2 = entry.offset;
-        }
-
-        if ci_1 < ci_2 {
-            merge_into(Source {
-                    key: ci_1,
-                    origin: Origin::Bucket1 { offset: data_off_1 }
-                },
-                &mut data_1,
-                &mut data_2,
-                &mut output,
-                &mut output_data,
-                header.data_base_offset)?;
-            curr_offset_1 += INDEX_ENTRY_SIZE as u64;
-            read_bucket_1 = true;
-            read_bucket_2 = false;
-        } else if ci_1 > ci_2 {
-            merge_into(Source {
-                    key: ci_2,
-                    origin: Origin::Bucket2 { offset: data_off_2 }
-                },
-                &mut data_1,
-                &mut data_2,
-                &mut output,
-                &mut output_data,
-                header.data_base_offset)?;
-            curr_offset_2 += INDEX_ENTRY_SIZE as u64;
-            read_bucket_1 = false;
-            read_bucket_2 = true;
-        } else {
-            merge_into(Source {
-                    key: ci_1,
-                    origin: Origin::Union { offset_1: data_off_1, offset_2: data_off_2 }
-                },
-                &mut data_1,
-                &mut data_2,
-                &mut output,
-                &mut output_data,
-                header.data_base_offset)?;
-            curr_offset_1 += INDEX_ENTRY_SIZE as u64;
-            curr_offset_2 += INDEX_ENTRY_SIZE as u64;
-            read_bucket_1 = true;
-            read_bucket_2 = true;
-        }
-    }
-
-    while curr_offset_1 < data_start_1 {
-        if read_bucket_1 {
-            let entry: IndexEntry = bincode::deserialize_from(&mut bucket_1.file)?;
-            ci_1 = entry.key;
-            data_off_1 = entry.offset;
-        }
-        merge_into(Source {
-                key: ci_1,
-                origin: Origin::Bucket1 { offset: data_off_1 }
-            },
-            &mut data_1,
-            &mut data_2,
-            &mut output,
-            &mut output_data,
-            header.data_base_offset)?;
-        curr_offset_1 += INDEX_ENTRY_SIZE as u64;
-        read_bucket_1 = true;
-    }
-
-    while curr_offset_2 < data_start_2 {
-        if read_bucket_2 {
-            let entry: IndexEntry = bincode::deserialize_from(&mut bucket_2.file)?;
-            ci_2 = entry.key;
-            data_off_2 = entry.offset;
-        };
-        merge_into(Source {
-                key: ci_2,
-                origin: Origin::Bucket2 { offset: data_off_2 }
-            },
-            &mut data_1,
-            &mut data_2,
-            &mut output,
-            &mut output_data,
-            header.data_base_offset)?;
-        curr_offset_2 += INDEX_ENTRY_SIZE as u64;
-        read_bucket_2 = true;
-    }
-
-    // Rewrite header
-    output.seek(SeekFrom::Start(0))?;
-    bincode::serialize_into(&mut output, &header)?;
+    let mut options = options.clone();
+    let mut layout = ValueLayout::Plain;
+    if let Some((first, _)) = buckets.first() {
+        options.codec = first.header.codec;
+        options.block_size = first.header.block_size;
+        layout = first.header.value_layout;
+        if options.app_id == 0 {
+            options.app_id = first.header.app_id;
+        }
+    }
+    let mut keys = BTreeSet::new();
+    let mut last: Option<(HashedKey, &Path)> = None;
+    for (bucket, entries) in &buckets {
+        if bucket.header.codec != options.codec || bucket.header.block_size != options.block_size || bucket.header.value_layout != layout {
+            return Err(Error::LayoutMismatch(bucket.path.clone()));
+        }
+        if let (Some(first), Some((last_key, last_path))) = (entries.first(), last) {
+            if first.key <= last_key {
+                return Err(Error::OverlappingBuckets(last_path.to_path_buf(), bucket.path.clone()));
+            }
+        }
+        if let Some(entry) = entries.last() {
+            last = Some((entry.key, &bucket.path));
+        }
+        keys.extend(entries.iter().map(|entry| entry.key));
+    }
 
-    return Ok(());
+    let mut output = BucketWriter::create_with_layout(output_file, keys.iter().copied(), &options, layout)?;
+    for (bucket, entries) in &mut buckets {
+        let size = bucket.data_size()?;
+        bucket.file.seek(SeekFrom::Start(bucket.header.data_base_offset))?;
+        output.push_data_section(&mut bucket.file, size, entries)?;
+    }
+    output.finish()
 }
 
 #[cfg(test)]
@@ -600,7 +3356,7 @@ mod tests {
         {
             let mut tmp = NamedTempFile::new().unwrap();
             let header = BucketHeader::default();
-            bincode::serialize_into(&mut tmp, &header).expect("bincode");
+            header.write_to(&mut tmp).expect("write_to");
             let bucket = Bucket::open(tmp.path()).expect("Bucket::open");
             assert!(bucket.check_headers().is_ok());
         }
@@ -609,38 +3365,65 @@ mod tests {
         {
             let mut tmp = NamedTempFile::new().unwrap();
             let header = BucketHeader { magic: MAGIC+1, ..BucketHeader::default() };
-            bincode::serialize_into(&mut tmp, &header).expect("bincode");
+            header.write_to(&mut tmp).expect("write_to");
             let bucket = Bucket::open(tmp.path()).expect("Bucket::open");
-            assert!(match bucket.check_headers() {
-                Err(Error::BadMagic) => true,
-                _ => false,
-            });
+            assert!(matches!(bucket.check_headers(), Err(Error::BadMagic)));
         }
 
         // Incorrect version
         {
             let mut tmp = NamedTempFile::new().unwrap();
             let header = BucketHeader { version: VERSION+1, ..BucketHeader::default() };
-            bincode::serialize_into(&mut tmp, &header).expect("bincode");
+            header.write_to(&mut tmp).expect("write_to");
             let bucket = Bucket::open(tmp.path()).expect("Bucket::open");
-            assert!(match bucket.check_headers() {
-                Err(Error::BadVersion) => true,
-                _ => false,
-            });
+            assert!(matches!(bucket.check_headers(), Err(Error::BadVersion)));
+        }
+
+
+        // Incorrect key or value type
+        {
+            for header in &[
+                BucketHeader { key_type_tag: 4, ..BucketHeader::default() },
+                BucketHeader { value_type_tag: 8, ..BucketHeader::default() },
+            ] {
+                let mut tmp = NamedTempFile::new().unwrap();
+                header.write_to(&mut tmp).expect("write_to");
+                let bucket = Bucket::open(tmp.path()).expect("Bucket::open");
+                assert!(matches!(bucket.check_headers(), Err(Error::TypeMismatch)));
+            }
         }
 
+        // Type tags not recorded
+        {
+            let mut tmp = NamedTempFile::new().unwrap();
+            let header = BucketHeader { key_type_tag: 0, value_type_tag: 0, ..BucketHeader::default() };
+            header.write_to(&mut tmp).expect("write_to");
+            let bucket = Bucket::open(tmp.path()).expect("Bucket::open");
+            assert!(bucket.check_headers().is_ok());
+        }
 
         // Incorrect magic and version
         {
             let mut tmp = NamedTempFile::new().unwrap();
             let header = BucketHeader { magic: MAGIC+1, version: VERSION+1, ..BucketHeader::default() };
-            bincode::serialize_into(&mut tmp, &header).expect("bincode");
+            header.write_to(&mut tmp).expect("write_to");
             let bucket = Bucket::open(tmp.path()).expect("Bucket::open");
-            assert!(match bucket.check_headers() {
-                Err(Error::BadMagic) => true,
-                Err(Error::BadVersion) => true,
-                _ => false
-            });
+            assert!(matches!(bucket.check_headers(), Err(Error::BadMagic) | Err(Error::BadVersion)));
+        }
+
+        // Written by another application
+        {
+            let tmp = NamedTempFile::new().unwrap();
+            let options = CreateOptions { app_id: 7, ..CreateOptions::default() };
+            create_with_options(tmp.path(), &BTreeMap::new(), &options).expect("create");
+            assert_eq!(Bucket::read_header(tmp.path()).expect("read_header").app_id, 7);
+            assert!(Bucket::open(tmp.path()).unwrap().check_headers_for_app(7).is_ok());
+            assert!(matches!(Bucket::open(tmp.path()).unwrap().check_headers_for_app(8),
+                             Err(Error::WrongApplication { expected: 8, found: 7 })));
+            assert!(matches!(Bucket::open(tmp.path()).unwrap().check_headers_for_app(0),
+                             Err(Error::WrongApplication { expected: 0, found: 7 })));
+            // Builds that don't know about ids still read the bucket.
+            assert!(Bucket::open(tmp.path()).unwrap().check_headers().is_ok());
         }
 
         // Invalid header (no bytes)
@@ -654,7 +3437,7 @@ mod tests {
         {
             let mut tmp = NamedTempFile::new().unwrap();
             let bucket = Bucket::open(tmp.path()).expect("Bucket::open");
-            bincode::serialize_into(&mut tmp, &MAGIC).expect("bincode");
+            bincode_config().serialize_into(&mut tmp, &MAGIC).expect("bincode");
             assert!(bucket.check_headers().is_err());
         }
 
@@ -662,8 +3445,8 @@ mod tests {
         {
             let mut tmp = NamedTempFile::new().unwrap();
             let bucket = Bucket::open(tmp.path()).expect("Bucket::open");
-            bincode::serialize_into(&mut tmp, &MAGIC).expect("bincode");
-            bincode::serialize_into(&mut tmp, &VERSION).expect("bincode");
+            bincode_config().serialize_into(&mut tmp, &MAGIC).expect("bincode");
+            bincode_config().serialize_into(&mut tmp, &VERSION).expect("bincode");
             assert!(bucket.check_headers().is_err());
         }
 
@@ -671,9 +3454,9 @@ mod tests {
         {
             let mut tmp = NamedTempFile::new().unwrap();
             let bucket = Bucket::open(tmp.path()).expect("Bucket::open");
-            bincode::serialize_into(&mut tmp, &MAGIC).expect("bincode");
-            bincode::serialize_into(&mut tmp, &VERSION).expect("bincode");
-            bincode::serialize_into(&mut tmp, &0_i32).expect("bincode");
+            bincode_config().serialize_into(&mut tmp, &MAGIC).expect("bincode");
+            bincode_config().serialize_into(&mut tmp, &VERSION).expect("bincode");
+            bincode_config().serialize_into(&mut tmp, &0_i32).expect("bincode");
             assert!(bucket.check_headers().is_err());
         }
     }
@@ -681,8 +3464,8 @@ mod tests {
     proptest! {
         #[test]
         fn prop_create_si_zero(step in 1_usize .. 100) {
-            let mut b = BTreeSet::new();
-            let si = SparseIndex::new_with_step(step, &b);
+            let b = BTreeSet::new();
+            let si = SparseIndex::new_with_step(step, &b).unwrap();
             prop_assert!(si.index.is_empty());
         }
     }
@@ -692,7 +3475,7 @@ mod tests {
         fn prop_create_si_one(step in 1_usize .. 100) {
             let mut b = BTreeSet::new();
             b.insert(1);
-            let si = SparseIndex::new_with_step(step, &b);
+            let si = SparseIndex::new_with_step(step, &b).unwrap();
             prop_assert_eq!(si.index.len(), 2);
             prop_assert_eq!(si.index[0].key, si.index[1].key);
             prop_assert_eq!(si.index[0].offset, si.index[1].offset);
@@ -709,7 +3492,7 @@ mod tests {
                 b.insert(i);
             }
 
-            let si = SparseIndex::new_with_step(step, &b);
+            let si = SparseIndex::new_with_step(step, &b).unwrap();
             prop_assert!(si.index.len() >= 2);
             prop_assert_eq!(si.index[0].key, 0);
             prop_assert_eq!(si.index[si.index.len() - 1].key, len-1);
@@ -722,6 +3505,16 @@ mod tests {
         }
     }
 
+    proptest! {
+        #[test]
+        fn prop_si_from_sorted_iter(b in prop::collection::btree_set(any::<u64>(), 0 .. 1000), step in 1_usize .. 100) {
+            let si = SparseIndex::from_sorted_iter(step, b.iter().copied(), b.len()).unwrap();
+            prop_assert_eq!(si.step, step);
+            prop_assert_eq!(si.index, SparseIndex::new_with_step(step, &b).unwrap().index);
+            prop_assert!(SparseIndex::from_sorted_iter(step, b.iter().copied(), b.len() + 1).is_err());
+        }
+    }
+
 
     proptest! {
         #[test]
@@ -731,7 +3524,7 @@ mod tests {
                 b.insert(key);
             }
 
-            let si = SparseIndex::new_with_step(step, &b);
+            let si = SparseIndex::new_with_step(step, &b).unwrap();
 
             for key in 0 .. len {
                 prop_assert!(si.try_get(key).is_some());
@@ -749,7 +3542,7 @@ mod tests {
                 max = key;
             }
 
-            let si = SparseIndex::new_with_step(step, &b);
+            let si = SparseIndex::new_with_step(step, &b).unwrap();
             for key in 0 .. max {
                 prop_assert!(si.try_get(key).is_some());
             }
@@ -757,6 +3550,751 @@ mod tests {
     }
 
 
+    proptest! {
+        #![proptest_config(Config::with_cases(32))]
+        #[test]
+        fn prop_locate_fixed_matches_scan(keys in prop::collection::btree_set(0_u64 .. 5000, 0 .. 1500),
+                                          lookups in prop::collection::vec(0_u64 .. 5001, 50)) {
+            let bmap: BTreeMap<HashedKey, BTreeSet<Value>> =
+                keys.iter().map(|&key| (key, BTreeSet::from([key as Value]))).collect();
+            let mut cursor = io::Cursor::new(Vec::new());
+            create_into(&mut cursor, &bmap, &CreateOptions::default()).expect("create_into");
+            let mut bucket = Bucket::from_bytes(cursor.into_inner()).check_headers().expect("check_headers");
+            let si = bucket.read_sparse_index().expect("sparse index");
+            let entries = bucket.index_entries().expect("index_entries").collect::<Result<Vec<_>>>().expect("entries");
+
+            for key in lookups.into_iter().chain(keys.iter().cloned()) {
+                let scanned = entries.iter().find(|entry| entry.key == key).map(|entry| entry.offset);
+                let located = match si.try_get(key) {
+                    Some((off1, off2)) => bucket.locate(key, off1, off2).expect("locate"),
+                    None => None,
+                };
+                prop_assert_eq!(located, scanned);
+            }
+        }
+    }
+
+    #[test]
+    fn cat_matches_merge() {
+        let shard = |keys: std::ops::Range<u64>| -> BTreeMap<HashedKey, BTreeSet<Value>> {
+            keys.map(|key| (key, (0 .. key as Value % 5 + 1).collect())).collect()
+        };
+        for &block_size in &[0, 64] {
+            let options = CreateOptions { block_size, codec: Codec::Zstd, ..CreateOptions::default() };
+            let tmp_1 = NamedTempFile::new().unwrap();
+            let tmp_2 = NamedTempFile::new().unwrap();
+            create_with_options(tmp_1.path(), &shard(0 .. 300), &options).expect("create");
+            create_with_options(tmp_2.path(), &shard(300 .. 1000), &options).expect("create");
+
+            let catted = NamedTempFile::new().unwrap();
+            let merged = NamedTempFile::new().unwrap();
+            let report = cat(&[tmp_1.path(), tmp_2.path()], catted.path(), &CreateOptions::default()).expect("cat");
+            assert_eq!(report.num_entries, 1000);
+            merge_with_options(tmp_1.path(), tmp_2.path(), merged.path(), &options).expect("merge");
+
+            let mut catted = Bucket::open(catted.path()).unwrap().check_headers().unwrap();
+            let mut merged = Bucket::open(merged.path()).unwrap().check_headers().unwrap();
+            assert_eq!(catted.header.codec, Codec::Zstd);
+            assert_eq!(catted.header.block_size, block_size);
+            let catted_entries = catted.index_entries().unwrap().collect::<Result<Vec<_>>>().unwrap();
+            let merged_entries = merged.index_entries().unwrap().collect::<Result<Vec<_>>>().unwrap();
+            assert_eq!(catted_entries.len(), merged_entries.len());
+            for (c, m) in catted_entries.iter().zip(&merged_entries) {
+                assert_eq!(c.key, m.key);
+                let c_values: Vec<u8> = bincode_config().serialize(&catted.read_values_at(c.offset).unwrap()).unwrap();
+                let m_values: Vec<u8> = bincode_config().serialize(&merged.read_values_at(m.offset).unwrap()).unwrap();
+                assert_eq!(c_values, m_values);
+            }
+        }
+    }
+
+    #[test]
+    fn rehash_permutation() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 100_u64).map(|key| (key, BTreeSet::from([key as Value]))).collect();
+        let tmp = NamedTempFile::new().unwrap();
+        create(tmp.path(), &bmap).expect("create");
+
+        // Reverse the keys, and send both 0 and 1 to 500.
+        let mut mapping: BTreeMap<HashedKey, HashedKey> = (2 .. 100_u64).map(|key| (key, 1_000 - key)).collect();
+        mapping.insert(0, 500);
+        mapping.insert(1, 500);
+        let output = NamedTempFile::new().unwrap();
+        let report = rehash(tmp.path(), output.path(), &mapping, &CreateOptions::default()).expect("rehash");
+        assert_eq!(report.num_entries, 99);
+
+        let mut bucket = Bucket::open(output.path()).unwrap().check_headers().unwrap();
+        for key in 2 .. 100_u64 {
+            assert_eq!(bucket.get(1_000 - key).unwrap(), Some(BTreeSet::from([key as Value])));
+            assert_eq!(bucket.get(key).unwrap(), None);
+        }
+        assert_eq!(bucket.get(500).unwrap(), Some(BTreeSet::from([0, 1])));
+
+        mapping.remove(&42);
+        assert_matches!(rehash(tmp.path(), output.path(), &mapping, &CreateOptions::default()),
+                        Err(Error::UnmappedKey(42)));
+    }
+
+    #[test]
+    fn duplicate_inputs() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 10_u64).map(|key| (key, BTreeSet::from([1, 2]))).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.binstore");
+        create(&input, &bmap).expect("create");
+        // The same file under another name.
+        let again = dir.path().join(".").join("input.binstore");
+        let output = dir.path().join("output.binstore");
+
+        assert_matches!(merge(&input, &again, &output), Err(Error::DuplicateInput(_)));
+        assert_matches!(cat(&[&input, &again], &output, &CreateOptions::default()), Err(Error::DuplicateInput(_)));
+
+        let options = CreateOptions { dedup_inputs: true, ..CreateOptions::default() };
+        let report = merge_with_options(&input, &again, &output, &options).expect("merge");
+        assert_eq!(report.num_entries, 10);
+        assert!(eq_contents(&input, &output).expect("eq_contents"));
+        cat(&[&input, &again], &output, &options).expect("cat");
+        assert!(eq_contents(&input, &output).expect("eq_contents"));
+    }
+
+    #[test]
+    fn cat_rejects_overlapping_buckets() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 10_u64).map(|key| (key, BTreeSet::from([1]))).collect();
+        let tmp_1 = NamedTempFile::new().unwrap();
+        let tmp_2 = NamedTempFile::new().unwrap();
+        create(tmp_1.path(), &bmap).expect("create");
+        create(tmp_2.path(), &bmap).expect("create");
+        let output = NamedTempFile::new().unwrap();
+        assert_matches!(cat(&[tmp_1.path(), tmp_2.path()], output.path(), &CreateOptions::default()),
+                        Err(Error::OverlappingBuckets(_, _)));
+
+        let options = CreateOptions { codec: Codec::Uncompressed, ..CreateOptions::default() };
+        create_with_options(tmp_2.path(), &BTreeMap::from([(20, BTreeSet::from([1]))]), &options).expect("create");
+        assert_matches!(cat(&[tmp_1.path(), tmp_2.path()], output.path(), &CreateOptions::default()),
+                        Err(Error::LayoutMismatch(_)));
+    }
+
+    #[test]
+    fn timestamped_values() {
+        let entries: BTreeMap<HashedKey, BTreeMap<Value, i64>> = (0 .. 300_u64)
+            .map(|key| (key * 2, (0 .. key as Value % 5 + 1).map(|value| (value, 1000 + key as i64 - value as i64)).collect()))
+            .collect();
+        for &block_size in &[0, 256] {
+            let tmp = NamedTempFile::new().unwrap();
+            let options = CreateOptions { block_size, ..CreateOptions::default() };
+            create_timestamped(tmp.path(), &entries, &options).expect("create_timestamped");
+
+            let mut bucket = Bucket::open(tmp.path()).unwrap().check_headers().unwrap();
+            assert_eq!(bucket.header.value_layout, ValueLayout::Timestamped);
+            for (key, values) in &entries {
+                assert_eq!(bucket.get_timestamped(*key).unwrap().as_ref(), Some(values));
+                let plain: BTreeSet<Value> = values.keys().cloned().collect();
+                assert_eq!(bucket.get(*key).unwrap(), Some(plain.clone()));
+                let streamed = bucket.values_iter(*key).unwrap().unwrap().collect::<Result<BTreeSet<Value>>>().unwrap();
+                assert_eq!(streamed, plain);
+            }
+            assert_eq!(bucket.get_timestamped(1).unwrap(), None);
+
+            // Merging keeps the values but not the timestamps.
+            let merged = NamedTempFile::new().unwrap();
+            let dedup = CreateOptions { dedup_inputs: true, ..CreateOptions::default() };
+            merge_with_options(tmp.path(), tmp.path(), merged.path(), &dedup).expect("merge");
+            let mut merged = Bucket::open(merged.path()).unwrap().check_headers().unwrap();
+            assert_eq!(merged.header.value_layout, ValueLayout::Plain);
+            assert_eq!(merged.get(4).unwrap(), Some(BTreeSet::from([0, 1, 2])));
+            assert_matches!(merged.get_timestamped(4), Err(Error::NotTimestamped));
+
+            // Deleting, compacting and repairing keep them.
+            let deleted = NamedTempFile::new().unwrap();
+            delete(tmp.path(), deleted.path(), &[0]).expect("delete");
+            let mut deleted = Bucket::open(deleted.path()).unwrap().check_headers().unwrap();
+            assert_eq!(deleted.header.value_layout, ValueLayout::Timestamped);
+            for (key, values) in &entries {
+                let mut kept = values.clone();
+                kept.remove(&0);
+                assert_eq!(deleted.get_timestamped(*key).unwrap(), Some(kept).filter(|kept| !kept.is_empty()));
+            }
+
+            append(tmp.path(), 4, 9).expect("append");
+            let log_time = filetime::FileTime::from_last_modification_time(&std::fs::metadata(tmp.path()).unwrap()).unix_seconds();
+            let mut expected = entries[&4].clone();
+            expected.insert(9, log_time);
+            for rewrite in &[|from: &Path, to: &Path| compact(from, to).map(|_| ()),
+                             |from: &Path, to: &Path| repair(from, to).map(|_| ())] {
+                let rewritten = NamedTempFile::new().unwrap();
+                rewrite(tmp.path(), rewritten.path()).expect("rewrite");
+                let mut rewritten = Bucket::open(rewritten.path()).unwrap().check_headers().unwrap();
+                assert_eq!(rewritten.get_timestamped(4).unwrap(), Some(expected.clone()));
+                assert_eq!(rewritten.get_timestamped(6).unwrap().as_ref(), entries.get(&6));
+            }
+        }
+
+        // Builds that don't know about timestamps see another value type.
+        let header = BucketHeader { value_layout: ValueLayout::Timestamped, ..BucketHeader::default() };
+        let mut tmp = NamedTempFile::new().unwrap();
+        header.write_to(&mut tmp).expect("write_to");
+        assert!(matches!(Bucket::open(tmp.path()).unwrap().check_headers(), Err(Error::TypeMismatch)));
+    }
+
+    #[test]
+    fn key_fingerprint() {
+        let fingerprint = |bmap: &BTreeMap<HashedKey, BTreeSet<Value>>, options: &CreateOptions| {
+            let tmp = NamedTempFile::new().unwrap();
+            create_with_options(tmp.path(), bmap, options).expect("create");
+            Bucket::open(tmp.path()).unwrap().check_headers().unwrap().key_fingerprint().expect("key_fingerprint")
+        };
+        let mut bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 500_u64).map(|key| (key * 7, BTreeSet::from([1]))).collect();
+        let fp = fingerprint(&bmap, &CreateOptions::default());
+
+        // Values and layout don't count.
+        let other_values: BTreeMap<HashedKey, BTreeSet<Value>> = bmap.keys().map(|key| (*key, BTreeSet::from([2, 3]))).collect();
+        let varint = CreateOptions { dense_index: DenseIndexEncoding::Varint, ..CreateOptions::default() };
+        assert_eq!(fingerprint(&other_values, &varint), fp);
+
+        bmap.insert(1, BTreeSet::from([1]));
+        assert_ne!(fingerprint(&bmap, &CreateOptions::default()), fp);
+    }
+
+    #[test]
+    fn data_block_sizes() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> =
+            (0 .. 200_u64).map(|key| (key * 2, (0 .. 1 + key as Value % 50).collect())).collect();
+        for block_size in &[0, 4096] {
+            let tmp = NamedTempFile::new().unwrap();
+            let options = CreateOptions { block_size: *block_size, ..CreateOptions::default() };
+            create_with_options(tmp.path(), &bmap, &options).expect("create");
+            let mut bucket = Bucket::open(tmp.path()).unwrap().check_headers().unwrap();
+
+            // Each block is counted once, from its first key.
+            let entries = bucket.index_entries().unwrap().collect::<Result<Vec<IndexEntry>>>().unwrap();
+            let header = bucket.header.clone();
+            let mut total = 0;
+            let mut last_block = None;
+            for entry in entries {
+                let size = bucket.data_block_size(entry.key).expect("data_block_size").expect("size");
+                let block = header.data_block_offset(entry.offset);
+                if last_block != Some(block) {
+                    total += size;
+                    last_block = Some(block);
+                }
+            }
+            assert_eq!(total, bucket.data_size().unwrap());
+            assert_eq!(bucket.data_block_size(1).expect("data_block_size"), None);
+            assert_eq!(bucket.data_block_size(1000).expect("data_block_size"), None);
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    type Shared<T> = std::sync::Arc<std::sync::Mutex<T>>;
+
+    /// Records the name of every span and of its parent.
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct SpanTree {
+        names: Shared<Vec<&'static str>>,
+        parents: Shared<Vec<(&'static str, Option<&'static str>)>>,
+        stack: Shared<Vec<u64>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for SpanTree {
+        fn enabled(&self, _: &tracing::Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &tracing::span::Attributes) -> tracing::span::Id {
+            let mut names = self.names.lock().unwrap();
+            let parent = if attrs.is_contextual() {
+                self.stack.lock().unwrap().last().copied()
+            } else {
+                attrs.parent().map(|id| id.into_u64())
+            };
+            let name = attrs.metadata().name();
+            self.parents.lock().unwrap().push((name, parent.map(|id| names[id as usize - 1])));
+            names.push(name);
+            tracing::span::Id::from_u64(names.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record) { }
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) { }
+
+        fn event(&self, _: &tracing::Event) { }
+
+        fn enter(&self, id: &tracing::span::Id) {
+            self.stack.lock().unwrap().push(id.into_u64());
+        }
+
+        fn exit(&self, _: &tracing::span::Id) {
+            self.stack.lock().unwrap().pop();
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn get_spans() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 10_u64).map(|key| (key, (0 .. 3).collect())).collect();
+        let tmp = NamedTempFile::new().unwrap();
+        create(tmp.path(), &bmap).expect("create");
+        let mut bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+
+        let spans = SpanTree::default();
+        tracing::subscriber::with_default(spans.clone(), || {
+            assert_eq!(bucket.get(4).expect("get"), Some((0 .. 3).collect()));
+        });
+        assert_eq!(*spans.parents.lock().unwrap(), vec![
+            ("get", None),
+            ("locate", Some("get")),
+            ("read_values", Some("get")),
+        ]);
+    }
+
+    #[test]
+    fn raw_blocks() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> =
+            (0 .. 100_u64).map(|key| (key * 2, (0 .. 1 + key as Value % 30).collect())).collect();
+        for &codec in &[Codec::Lz4, Codec::Zstd, Codec::Uncompressed, Codec::Adaptive] {
+            let tmp = NamedTempFile::new().unwrap();
+            create_with_options(tmp.path(), &bmap, &CreateOptions { codec, ..CreateOptions::default() }).expect("create");
+            let mut bucket = Bucket::open(tmp.path()).unwrap().check_headers().unwrap();
+            for key in bmap.keys() {
+                let block = bucket.get_raw_block(*key).expect("get_raw_block").expect("block");
+                let values: BTreeSet<Value> = read_values(&mut block.as_slice(), codec).expect("read_values");
+                assert_eq!(Some(values), bucket.get(*key).expect("get"));
+            }
+            assert_eq!(bucket.get_raw_block(1).expect("get_raw_block"), None);
+            assert_eq!(bucket.get_raw_block(1000).expect("get_raw_block"), None);
+        }
+    }
+
+    #[test]
+    fn create_or_merge_unions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("daily.binstore");
+        let first: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 100_u64).map(|key| (key * 2, BTreeSet::from([1, 2]))).collect();
+        let second: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 100_u64).map(|key| (key * 3, BTreeSet::from([2, 3]))).collect();
+
+        let report = create_or_merge(&path, &first, &CreateOptions::default()).expect("create");
+        assert_eq!(report.num_entries, 100);
+        let report = create_or_merge(&path, &second, &CreateOptions::default()).expect("merge");
+
+        let mut expected = first.clone();
+        for (key, values) in &second {
+            expected.entry(*key).or_default().extend(values.iter().cloned());
+        }
+        assert_eq!(report.num_entries, expected.len() as u64);
+        let mut bucket = Bucket::open(&path).unwrap().check_headers().unwrap();
+        assert_eq!(bucket.to_map().expect("to_map"), expected);
+        assert_eq!(bucket.get(6).expect("get"), Some(BTreeSet::from([1, 2, 3])));
+        let mut files: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        files.sort();
+        assert_eq!(files, vec![std::ffi::OsString::from("daily.binstore")]);
+
+        // An existing file that is not a bucket is left alone.
+        let bad = dir.path().join("bad.binstore");
+        std::fs::write(&bad, b"not a bucket").unwrap();
+        assert!(create_or_merge(&bad, &first, &CreateOptions::default()).is_err());
+        assert_eq!(std::fs::read(&bad).unwrap(), b"not a bucket");
+    }
+
+    #[test]
+    fn merge_split_shards() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_1 = dir.path().join("1.binstore");
+        let input_2 = dir.path().join("2.binstore");
+        let bmap_1: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 500_u64).map(|key| (key * 2, (0 .. key as Value % 50).collect())).collect();
+        let bmap_2: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 500_u64).map(|key| (key * 3, (100 .. 110).collect())).collect();
+        create(&input_1, &bmap_1).expect("create");
+        create(&input_2, &bmap_2).expect("create");
+
+        for &block_size in &[0, 256] {
+            let options = CreateOptions { block_size, timestamp: Some(1_000), ..CreateOptions::default() };
+            let merged = dir.path().join("merged.binstore");
+            merge_with_options(&input_1, &input_2, &merged, &options).expect("merge");
+            let expected = Bucket::open(&merged).unwrap().check_headers().unwrap().to_map().expect("to_map");
+
+            let shard_name = |n| dir.path().join(format!("shard.{}.{}.binstore", block_size, n));
+            let reports = merge_split(&input_1, &input_2, shard_name, 4_000, &options).expect("merge_split");
+            assert!(reports.len() > 2);
+            assert!(!dir.path().join(format!("shard.{}.0.binstore.merged", block_size)).exists());
+
+            let mut union = BTreeMap::new();
+            let mut last_key = None;
+            for (n, report) in reports.iter().enumerate() {
+                assert_eq!(report.size, std::fs::metadata(shard_name(n)).unwrap().len());
+                if block_size == 0 {
+                    assert!(report.size <= 4_000, "shard {} is {} bytes", n, report.size);
+                }
+                let mut bucket = Bucket::open(shard_name(n)).unwrap().check_headers().unwrap();
+                assert_eq!(bucket.header.timestamp, 1_000);
+                let map = bucket.to_map().expect("to_map");
+                assert_eq!(map.len() as u64, report.num_entries);
+                assert!(last_key < map.keys().next().copied());
+                last_key = map.keys().last().copied();
+                union.extend(map);
+            }
+            assert_eq!(union, expected);
+        }
+    }
+
+    #[test]
+    fn merge_timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_1 = dir.path().join("1.binstore");
+        let input_2 = dir.path().join("2.binstore");
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 10_u64).map(|key| (key, BTreeSet::from([1]))).collect();
+        create_with_options(&input_1, &bmap, &CreateOptions { timestamp: Some(2_000), ..CreateOptions::default() }).expect("create");
+        create_with_options(&input_2, &bmap, &CreateOptions { timestamp: Some(1_000), ..CreateOptions::default() }).expect("create");
+
+        let merged_timestamp = |options: &CreateOptions| {
+            let output = dir.path().join("merged.binstore");
+            merge_with_options(&input_1, &input_2, &output, options).expect("merge");
+            Bucket::read_header(&output).unwrap().timestamp
+        };
+        let policy = |merge_timestamp| CreateOptions { merge_timestamp, ..CreateOptions::default() };
+        assert_eq!(merged_timestamp(&CreateOptions::default()), 2_000);
+        assert_eq!(merged_timestamp(&policy(MergeTimestamp::Max)), 2_000);
+        assert_eq!(merged_timestamp(&policy(MergeTimestamp::Min)), 1_000);
+        assert_eq!(merged_timestamp(&policy(MergeTimestamp::Input1)), 2_000);
+        assert!(merged_timestamp(&policy(MergeTimestamp::Now)) > 1_000_000_000);
+        let fixed = CreateOptions { timestamp: Some(5), merge_timestamp: MergeTimestamp::Min, ..CreateOptions::default() };
+        assert_eq!(merged_timestamp(&fixed), 5);
+
+        // Swapping the inputs only changes input1.
+        let output = dir.path().join("swapped.binstore");
+        merge_with_options(&input_2, &input_1, &output, &policy(MergeTimestamp::Input1)).expect("merge");
+        assert_eq!(Bucket::read_header(&output).unwrap().timestamp, 1_000);
+        assert_eq!("input1".parse::<MergeTimestamp>(), Ok(MergeTimestamp::Input1));
+        assert!("newest".parse::<MergeTimestamp>().is_err());
+    }
+
+    /// A file that flips the bits of the byte at `at` as it is
+    /// written.
+    struct FlipByte {
+        file: File,
+        pos: u64,
+        at: u64,
+    }
+
+    impl Write for FlipByte {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut buf = buf.to_vec();
+            if (self.pos .. self.pos + buf.len() as u64).contains(&self.at) {
+                buf[(self.at - self.pos) as usize] ^= 0xff;
+            }
+            let n = self.file.write(&buf)?;
+            self.pos += n as u64;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl Seek for FlipByte {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.pos = self.file.seek(pos)?;
+            Ok(self.pos)
+        }
+    }
+
+    #[test]
+    fn validate_after_write() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 50_u64).map(|key| (key, (0 .. 10).collect())).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("written.binstore");
+        let options = CreateOptions { validate_after_write: true, ..CreateOptions::default() };
+        create_with_options(&path, &bmap, &options).expect("create");
+        let data_base_offset = Bucket::read_header(&path).unwrap().data_base_offset;
+
+        // Break the lz4 frame of the first data block on its way out.
+        let file = FlipByte { file: File::create(&path).unwrap(), pos: 0, at: data_base_offset };
+        let mut w = BucketWriter::new(Box::new(file), bmap.keys().copied(), &options).expect("new");
+        w.validate = Some(path.clone());
+        for (key, values) in &bmap {
+            w.push(*key, values).expect("push");
+        }
+        assert!(w.finish().is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn eq_contents_ignores_encoding() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 100_u64).map(|key| (key * 7, (0 .. key as Value).collect())).collect();
+        let tmp_1 = NamedTempFile::new().unwrap();
+        let tmp_2 = NamedTempFile::new().unwrap();
+        create_with_options(tmp_1.path(), &bmap, &CreateOptions { timestamp: Some(1), ..CreateOptions::default() }).expect("create");
+        let options = CreateOptions { timestamp: Some(2), codec: Codec::Zstd, ..CreateOptions::default() };
+        create_with_options(tmp_2.path(), &bmap, &options).expect("create");
+        assert_ne!(std::fs::read(tmp_1.path()).unwrap(), std::fs::read(tmp_2.path()).unwrap());
+        assert!(eq_contents(tmp_1.path(), tmp_2.path()).expect("eq_contents"));
+
+        let mut other = bmap.clone();
+        other.get_mut(&14).unwrap().insert(1_000);
+        create(tmp_2.path(), &other).expect("create");
+        assert!(!eq_contents(tmp_1.path(), tmp_2.path()).expect("eq_contents"));
+        other.remove(&14);
+        create(tmp_2.path(), &other).expect("create");
+        assert!(!eq_contents(tmp_1.path(), tmp_2.path()).expect("eq_contents"));
+    }
+
+    #[test]
+    fn merge_iter_set_ops() {
+        let bmap_1: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 30_u64).map(|key| (key * 2, (0 .. 4).collect())).collect();
+        let bmap_2: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 20_u64).map(|key| (key * 3, (2 .. 6).collect())).collect();
+        let tmp_1 = NamedTempFile::new().unwrap();
+        let tmp_2 = NamedTempFile::new().unwrap();
+        create(tmp_1.path(), &bmap_1).expect("create");
+        create(tmp_2.path(), &bmap_2).expect("create");
+
+        let combined = |op| {
+            let mut b1 = Bucket::open(tmp_1.path()).unwrap().check_headers().unwrap();
+            let mut b2 = Bucket::open(tmp_2.path()).unwrap().check_headers().unwrap();
+            merge_iter(&mut b1, &mut b2, op).expect("merge_iter")
+                .collect::<Result<BTreeMap<HashedKey, BTreeSet<Value>>>>()
+                .expect("entries")
+        };
+
+        let mut union = bmap_1.clone();
+        for (key, values) in &bmap_2 {
+            union.entry(*key).or_default().extend(values);
+        }
+        assert_eq!(combined(SetOp::Union), union);
+        let merged = NamedTempFile::new().unwrap();
+        merge(tmp_1.path(), tmp_2.path(), merged.path()).expect("merge");
+        assert_eq!(Bucket::open(merged.path()).unwrap().check_headers().unwrap().to_map().unwrap(), union);
+
+        let intersection: BTreeMap<HashedKey, BTreeSet<Value>> = bmap_1.keys()
+            .filter(|key| bmap_2.contains_key(key))
+            .map(|key| (*key, (2 .. 4).collect()))
+            .collect();
+        assert_eq!(combined(SetOp::Intersection), intersection);
+
+        let difference: BTreeMap<HashedKey, BTreeSet<Value>> = bmap_1.iter()
+            .map(|(key, values)| match bmap_2.contains_key(key) {
+                true => (*key, (0 .. 2).collect()),
+                false => (*key, values.clone()),
+            })
+            .collect();
+        assert_eq!(combined(SetOp::Difference), difference);
+    }
+
+    #[test]
+    fn merge_estimate() {
+        let bmap_1: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 100_u64).map(|key| (key, BTreeSet::from([1]))).collect();
+        let bmap_2: BTreeMap<HashedKey, BTreeSet<Value>> = (60 .. 300_u64).map(|key| (key, BTreeSet::from([2]))).collect();
+        let tmp_1 = NamedTempFile::new().unwrap();
+        let tmp_2 = NamedTempFile::new().unwrap();
+        create(tmp_1.path(), &bmap_1).expect("create");
+        create(tmp_2.path(), &bmap_2).expect("create");
+
+        let estimate = estimate_merge(tmp_1.path(), tmp_2.path(), &CreateOptions::default()).expect("estimate_merge");
+        assert_eq!(estimate.unique_keys, 300);
+        assert_eq!(estimate.overlapping_keys, 40);
+        assert_eq!(estimate.dense_index_size, 300 * INDEX_ENTRY_SIZE as u64);
+
+        let merged = NamedTempFile::new().unwrap();
+        merge(tmp_1.path(), tmp_2.path(), merged.path()).expect("merge");
+        let header = Bucket::read_header(merged.path()).expect("read_header");
+        assert_eq!(estimate.sparse_index_size, header.di_base_offset - header.si_base_offset);
+    }
+
+    #[test]
+    fn unsorted_keys_are_rejected() {
+        let keys: BTreeSet<HashedKey> = (0 .. 10).collect();
+        let mut cursor = io::Cursor::new(Vec::new());
+        let mut w = BucketWriter::new(Box::new(&mut cursor), keys.iter().copied(), &CreateOptions::default()).expect("new");
+        let values = BTreeSet::from([1]);
+        for key in &[0, 1, 4] {
+            w.push(*key, &values).expect("push");
+        }
+        assert_matches!(w.push(3, &values), Err(Error::UnsortedKeys(3)));
+        assert_matches!(w.push(4, &values), Err(Error::UnsortedKeys(4)));
+        w.push(5, &values).expect("push");
+    }
+
+    /// A writer failing once `budget` bytes have been written.
+    struct FailAfter {
+        budget: usize,
+    }
+
+    impl Write for FailAfter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.len() > self.budget {
+                return Err(io::Error::new(io::ErrorKind::Other, "out of space"));
+            }
+            self.budget -= buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn read_empty_blocks() {
+        for &codec in &[Codec::Lz4, Codec::Zstd, Codec::Uncompressed, Codec::Adaptive] {
+            let mut block = Vec::new();
+            write_values(&mut block, codec, &BTreeSet::<Value>::new()).expect("write_values");
+            let values: BTreeSet<Value> = read_values(&mut block.as_slice(), codec).expect("read_values");
+            assert!(values.is_empty());
+
+            // A frame of nothing holds no values, but no frame at all is
+            // a cut-off data section.
+            let mut block = Vec::new();
+            write_block(&mut block, codec, &[]).expect("write_block");
+            let values: BTreeMap<Value, i64> = read_values(&mut block.as_slice(), codec).expect("read_values");
+            assert!(values.is_empty());
+            let result: Result<BTreeSet<Value>> = read_values(&mut &[][..], codec);
+            assert_matches!(result, Err(Error::IoError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn huge_sparse_offset_is_corrupt() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 300_u64).map(|key| (key, BTreeSet::from([key as Value]))).collect();
+        let tmp = NamedTempFile::new().unwrap();
+        create(tmp.path(), &bmap).expect("create");
+        let header = Bucket::read_header(tmp.path()).expect("read_header");
+
+        // The sparse index is its step and length, then its entries;
+        // point the second entry past the end of the dense index.
+        let second_offset = header.si_base_offset + 16 + INDEX_ENTRY_SIZE as u64 + 8;
+        for &offset in &[u64::MAX, u64::MAX - 15, 1 << 62, header.num_entries * INDEX_ENTRY_SIZE as u64] {
+            let mut file = OpenOptions::new().write(true).open(tmp.path()).unwrap();
+            file.seek(SeekFrom::Start(second_offset)).unwrap();
+            file.write_all(&offset.to_le_bytes()).unwrap();
+            drop(file);
+
+            let mut bucket = Bucket::open(tmp.path()).unwrap().check_headers().unwrap();
+            assert_matches!(bucket.get(1), Err(Error::CorruptIndex));
+            assert_matches!(bucket.try_get(1, 0, offset), Err(Error::CorruptIndex));
+        }
+    }
+
+    #[test]
+    fn truncated_at_block_boundary() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> =
+            (0 .. 20_u64).map(|key| (key, (0 .. 5).map(|value| value + key as Value).collect())).collect();
+        let tmp = NamedTempFile::new().unwrap();
+        create(tmp.path(), &bmap).expect("create");
+        let mut bucket = Bucket::open(tmp.path()).unwrap().check_headers().unwrap();
+        let cut = bucket.locate_offset(19).expect("locate_offset").expect("key 19");
+        OpenOptions::new().write(true).open(tmp.path()).unwrap().set_len(cut).expect("set_len");
+
+        let mut bucket = Bucket::open(tmp.path()).unwrap().check_headers().unwrap();
+        assert_eq!(bucket.get(18).expect("get"), bmap.get(&18).cloned());
+        let result = bucket.get(19);
+        assert!(matches!(result, Err(Error::IoError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+        assert_eq!(result.unwrap_err().exit_code(), EXIT_CORRUPT);
+        assert!(bucket.to_map().is_err());
+    }
+
+    #[test]
+    fn write_values_fails_on_unfinished_frame() {
+        let values: BTreeSet<Value> = (0 .. 100).collect();
+        let mut block = Vec::new();
+        write_values(&mut block, Codec::Lz4, &values).expect("write_values");
+
+        // The end mark of the frame is only written by `finish`.
+        let mut w = FailAfter { budget: block.len() - 1 };
+        assert_matches!(write_values(&mut w, Codec::Lz4, &values), Err(Error::IoError(_)));
+    }
+
+    /// A writer whose flushes fail.
+    struct FailFlush(io::Cursor<Vec<u8>>);
+
+    impl Write for FailFlush {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::Other, "flush failed"))
+        }
+    }
+
+    impl Seek for FailFlush {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    #[test]
+    fn flush_errors_are_returned() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 10_u64).map(|key| (key, BTreeSet::from([key as Value]))).collect();
+        let mut w = FailFlush(io::Cursor::new(Vec::new()));
+        assert_matches!(create_into(&mut w, &bmap, &CreateOptions::default()), Err(Error::IoError(_)));
+
+        let tmp = NamedTempFile::new().unwrap();
+        let options = CreateOptions { sync: true, ..CreateOptions::default() };
+        create_with_options(tmp.path(), &bmap, &options).expect("create");
+        let mut bucket = Bucket::open(tmp.path()).unwrap().check_headers().unwrap().prepare().unwrap();
+        assert_eq!(bucket.get(7).unwrap(), Some(BTreeSet::from([7])));
+    }
+
+    /// A reader recording the offset of every read of the inner reader.
+    struct ReadLog<R> {
+        inner: R,
+        reads: std::sync::Arc<std::sync::Mutex<Vec<u64>>>,
+    }
+
+    impl<R: Read + Seek> Read for ReadLog<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let offset = self.inner.stream_position()?;
+            self.reads.lock().unwrap().push(offset);
+            self.inner.read(buf)
+        }
+    }
+
+    impl<R: Seek> Seek for ReadLog<R> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn get_after_prefetch_reads_only_values() {
+        use std::iter::FromIterator;
+
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> =
+            (0 .. 5000_u64).map(|key| (key * 2, BTreeSet::from_iter(0 .. key as Value % 7 + 1))).collect();
+        let mut cursor = io::Cursor::new(Vec::new());
+        create_into(&mut cursor, &bmap, &CreateOptions::default()).expect("create_into");
+        let reads = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let reader = ReadLog { inner: io::Cursor::new(cursor.into_inner()), reads: reads.clone() };
+        let mut bucket = Bucket::from_reader(Box::new(reader), PathBuf::new()).check_headers().expect("check_headers");
+        let data_base_offset = bucket.header.data_base_offset;
+
+        bucket.prefetch(4242).expect("prefetch");
+        assert!(reads.lock().unwrap().iter().any(|&offset| offset < data_base_offset));
+        reads.lock().unwrap().clear();
+        assert_eq!(bucket.get(4242).expect("get"), bmap.get(&4242).cloned());
+        let after = reads.lock().unwrap().clone();
+        assert!(!after.is_empty());
+        // Neither the sparse index nor the dense index is read again.
+        assert!(after.iter().all(|&offset| offset >= data_base_offset), "reads: {:?}", after);
+
+        // Other keys are looked up as usual.
+        bucket.prefetch(4243).expect("prefetch");
+        assert_eq!(bucket.get(4243).expect("get"), None);
+        assert_eq!(bucket.get(4244).expect("get"), bmap.get(&4244).cloned());
+    }
+
+    #[test]
+    fn dense_index_offset_bounds() {
+        assert_eq!(dense_index_offset(0).unwrap(), 0);
+        assert_eq!(dense_index_offset(3).unwrap(), 3 * INDEX_ENTRY_SIZE as u64);
+
+        // Past this index, `i * INDEX_ENTRY_SIZE` overflows `usize`.
+        let boundary = usize::MAX / INDEX_ENTRY_SIZE;
+        assert_eq!(dense_index_offset(boundary).unwrap(), boundary as u64 * INDEX_ENTRY_SIZE as u64);
+        #[cfg(target_pointer_width = "32")]
+        assert_eq!(dense_index_offset(boundary + 1).unwrap(), 1 << 32);
+        #[cfg(target_pointer_width = "64")]
+        assert!(dense_index_offset(boundary + 1).is_err());
+    }
+
     #[test]
     fn sparse_index_get() {
         {
@@ -765,17 +4303,21 @@ mod tests {
         }
 
         {
-            let mut si = SparseIndex::default();
-            si.index = vec![IndexEntry { key: 1, offset: 1 }];
+            let si = SparseIndex {
+                index: vec![IndexEntry { key: 1, offset: 1 }],
+                ..SparseIndex::default()
+            };
             assert!(si.try_get(0).is_none());
         }
 
         {
-            let mut si = SparseIndex::default();
-            si.index = vec![
-                IndexEntry { key: 1, offset: 1 },
-                IndexEntry { key: 4, offset: 4 },
-            ];
+            let si = SparseIndex {
+                index: vec![
+                    IndexEntry { key: 1, offset: 1 },
+                    IndexEntry { key: 4, offset: 4 },
+                ],
+                ..SparseIndex::default()
+            };
             assert_matches!(si.try_get(0), None);
             assert_matches!(si.try_get(8), None);
             assert_matches!(si.try_get(1), Some((1, 1)));
@@ -807,7 +4349,7 @@ mod tests {
                 let si = bucket.read_sparse_index().expect("sparse index");
 
                 for (key, actual_values) in &bmap {
-                    let key = *key as u64;
+                    let key = *key;
                     let (offset_1, offset_2) = si.try_get(key).expect("try_get (1)");
                     let values = bucket.try_get(key, offset_1, offset_2)
                         .expect("try_get (1)")
@@ -857,20 +4399,225 @@ mod tests {
             create(tmp2.path(), &bmap2).expect("create");
             merge(tmp1.path(), tmp2.path(), merged_file.path()).expect("merge");
 
-            // union bmap1 & bmap2
-            for (key, values) in bmap1.iter() {
-                let set = bmap2.entry(*key).or_insert(BTreeSet::new());
-                let union: BTreeSet<Value> = set.union(values).cloned().collect();
-                bmap2.insert(*key, union);
+            // union bmap1 & bmap2
+            for (key, values) in bmap1.iter() {
+                let set = bmap2.entry(*key).or_insert(BTreeSet::new());
+                let union: BTreeSet<Value> = set.union(values).cloned().collect();
+                bmap2.insert(*key, union);
+            }
+
+            let mut merged = Bucket::open(merged_file).expect("open").check_headers().expect("headers");
+
+            let si = merged.read_sparse_index().expect("read_sparse_index");
+            for (key, values) in bmap2.iter()  {
+                let (data_off_1, data_off_2) = si.try_get(*key).expect("try_get");
+                let merged_values = merged.try_get(*key, data_off_1, data_off_2).expect("try_get");
+                assert_eq!(*values, merged_values.expect("try_get"));
+            }
+        }
+    }
+
+    fn any_dense_index() -> impl Strategy<Value = DenseIndexEncoding> {
+        prop_oneof![Just(DenseIndexEncoding::Fixed),
+                    Just(DenseIndexEncoding::Varint),
+                    Just(DenseIndexEncoding::Columnar),
+                    Just(DenseIndexEncoding::Compressed)]
+    }
+
+    fn any_codec() -> impl Strategy<Value = Codec> {
+        prop_oneof![Just(Codec::Lz4), Just(Codec::Zstd), Just(Codec::Uncompressed), Just(Codec::Adaptive)]
+    }
+
+    proptest! {
+        #![proptest_config(Config::with_cases(64))]
+        #[test]
+        fn prop_merge_keys_strictly_increasing(bmap_1 in prop::collection::btree_map(0_u64 .. 300, prop::collection::btree_set(0_u128 .. 50, 0 .. 8), 0 .. 100),
+                                               bmap_2 in prop::collection::btree_map(0_u64 .. 300, prop::collection::btree_set(0_u128 .. 50, 0 .. 8), 0 .. 100),
+                                               dense_index in any_dense_index(),
+                                               codec in any_codec(),
+                                               block_size in prop_oneof![Just(0_u64), 1_u64 .. 512],
+                                               lowmem in any::<bool>()) {
+            let tmp_1 = NamedTempFile::new().unwrap();
+            let tmp_2 = NamedTempFile::new().unwrap();
+            let merged = NamedTempFile::new().unwrap();
+            let options = CreateOptions { dense_index, codec, block_size, lowmem, ..CreateOptions::default() };
+            create_with_options(tmp_1.path(), &bmap_1, &options).expect("create");
+            create_with_options(tmp_2.path(), &bmap_2, &options).expect("create");
+            merge_with_options(tmp_1.path(), tmp_2.path(), merged.path(), &options).expect("merge");
+
+            let mut bucket = Bucket::open(merged.path()).and_then(|b| b.check_headers()).expect("open");
+            let keys = bucket.index_entries().expect("index_entries")
+                .map(|entry| entry.map(|entry| entry.key))
+                .collect::<Result<Vec<_>>>()
+                .expect("entry");
+            prop_assert!(keys.windows(2).all(|pair| pair[0] < pair[1]), "keys out of order: {:?}", keys);
+            let expected: Vec<HashedKey> = bmap_1.keys().chain(bmap_2.keys()).cloned().collect::<BTreeSet<_>>().into_iter().collect();
+            prop_assert_eq!(keys, expected);
+        }
+    }
+
+    #[test]
+    fn merge_mixed_codecs() {
+        use std::iter::FromIterator;
+
+        let mut bmap_1 = BTreeMap::new();
+        let mut bmap_2 = BTreeMap::new();
+        for key in 0 .. 100_u64 {
+            bmap_1.insert(key * 2, BTreeSet::from_iter(0 .. (key as Value % 7)));
+            bmap_2.insert(key * 3, BTreeSet::from_iter(100 .. 100 + (key as Value % 5)));
+        }
+        let lz4 = NamedTempFile::new().unwrap();
+        let zstd = NamedTempFile::new().unwrap();
+        let output = NamedTempFile::new().unwrap();
+        create_with_options(lz4.path(), &bmap_1, &CreateOptions { codec: Codec::Lz4, ..CreateOptions::default() }).expect("create");
+        create_with_options(zstd.path(), &bmap_2, &CreateOptions { codec: Codec::Zstd, ..CreateOptions::default() }).expect("create");
+
+        let options = CreateOptions { codec: Codec::Zstd, ..CreateOptions::default() };
+        merge_with_options(lz4.path(), zstd.path(), output.path(), &options).expect("merge");
+
+        let mut expected = bmap_1;
+        for (key, values) in bmap_2 {
+            expected.entry(key).or_default().extend(values);
+        }
+        let mut bucket = Bucket::open(output.path()).and_then(|b| b.check_headers()).expect("open");
+        assert_eq!(bucket.header.codec, Codec::Zstd);
+        assert_eq!(bucket.to_map().expect("to_map"), expected);
+    }
+
+    #[test]
+    fn max_values_per_key() {
+        use std::iter::FromIterator;
+
+        // Key 1 goes over the cap once created; key 2 only once merged.
+        let mut bmap_1 = BTreeMap::new();
+        bmap_1.insert(0, BTreeSet::from_iter(0 .. 3));
+        bmap_1.insert(1, BTreeSet::from_iter(0 .. 10));
+        bmap_1.insert(2, BTreeSet::from_iter(0 .. 3));
+        let mut bmap_2 = BTreeMap::new();
+        bmap_2.insert(2, BTreeSet::from_iter(10 .. 13));
+        bmap_2.insert(3, BTreeSet::from_iter(0 .. 2));
+
+        let tmp_1 = NamedTempFile::new().unwrap();
+        let tmp_2 = NamedTempFile::new().unwrap();
+        let options = CreateOptions { max_values_per_key: Some(4), ..CreateOptions::default() };
+        create_with_options(tmp_1.path(), &bmap_1, &options).expect("create");
+        create(tmp_2.path(), &bmap_2).expect("create");
+        let created = Bucket::open(tmp_1.path()).and_then(|b| b.check_headers()).expect("open")
+            .to_map().expect("to_map");
+        assert_eq!(created[&1], BTreeSet::from_iter(0 .. 4));
+        assert_eq!(created[&0], bmap_1[&0]);
+
+        for &(cap_policy, expected) in &[(CapPolicy::Smallest, [0, 1, 2, 10]), (CapPolicy::Largest, [2, 10, 11, 12])] {
+            let merged = NamedTempFile::new().unwrap();
+            let options = CreateOptions { max_values_per_key: Some(4), cap_policy, ..CreateOptions::default() };
+            merge_with_options(tmp_1.path(), tmp_2.path(), merged.path(), &options).expect("merge");
+            let map = Bucket::open(merged.path()).and_then(|b| b.check_headers()).expect("open")
+                .to_map().expect("to_map");
+            assert_eq!(map[&2], BTreeSet::from_iter(expected.iter().cloned()));
+            assert_eq!(map[&1].len(), 4);
+            assert_eq!(map[&3], bmap_2[&3]);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_merge_sparse(bmap1 in prop::collection::btree_map(0_u64 .. 200, prop::collection::btree_set(any::<Value>(), 1 .. 20), 0 .. 60),
+                             bmap2 in prop::collection::btree_map(0_u64 .. 200, prop::collection::btree_set(any::<Value>(), 1 .. 20), 0 .. 60),
+                             dense_index_1 in any_dense_index(),
+                             dense_index_2 in any_dense_index()) {
+            let tmp1 = NamedTempFile::new().unwrap();
+            let tmp2 = NamedTempFile::new().unwrap();
+            let merged_file = NamedTempFile::new().unwrap();
+
+            create_with_options(tmp1.path(), &bmap1, &CreateOptions { dense_index: dense_index_1, ..CreateOptions::default() }).expect("create");
+            create_with_options(tmp2.path(), &bmap2, &CreateOptions { dense_index: dense_index_2, ..CreateOptions::default() }).expect("create");
+            merge(tmp1.path(), tmp2.path(), merged_file.path()).expect("merge");
+
+            let mut expected = bmap1.clone();
+            for (key, values) in bmap2 {
+                expected.entry(key).or_insert_with(BTreeSet::new).extend(values);
+            }
+
+            let mut merged = Bucket::open(merged_file.path()).expect("open").check_headers().expect("headers");
+            let mut data = Bucket::open(merged_file.path()).expect("open").check_headers().expect("headers");
+            let mut actual = BTreeMap::new();
+            for entry in merged.index_entries().expect("index_entries") {
+                let entry = entry.expect("entry");
+                actual.insert(entry.key, data.read_values_at(entry.offset).expect("read_values_at"));
+            }
+            prop_assert_eq!(actual, expected);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_merge_copied_blocks(bmap1 in prop::collection::btree_map(0_u64 .. 200, prop::collection::btree_set(any::<Value>(), 0 .. 20), 0 .. 60),
+                                    bmap2 in prop::collection::btree_map(0_u64 .. 200, prop::collection::btree_set(any::<Value>(), 0 .. 20), 0 .. 60),
+                                    codec in any_codec()) {
+            let tmp1 = NamedTempFile::new().unwrap();
+            let tmp2 = NamedTempFile::new().unwrap();
+            let options = CreateOptions { codec, ..CreateOptions::default() };
+            create_with_options(tmp1.path(), &bmap1, &options).expect("create");
+            create_with_options(tmp2.path(), &bmap2, &options).expect("create");
+
+            // Same codec: the blocks of keys in one bucket are copied.
+            // Grouped blocks: every set is decoded and encoded again.
+            let copied = NamedTempFile::new().unwrap();
+            let recoded = NamedTempFile::new().unwrap();
+            merge_with_options(tmp1.path(), tmp2.path(), copied.path(), &options).expect("merge");
+            let recode_options = CreateOptions { block_size: 1, ..options };
+            merge_with_options(tmp1.path(), tmp2.path(), recoded.path(), &recode_options).expect("merge");
+
+            let mut copied = Bucket::open(copied.path()).and_then(|b| b.check_headers()).expect("open");
+            let mut recoded = Bucket::open(recoded.path()).and_then(|b| b.check_headers()).expect("open");
+            prop_assert_eq!(copied.to_map().expect("to_map"), recoded.to_map().expect("to_map"));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_try_get_vec(bmap in prop::collection::btree_map(any::<u64>(), prop::collection::btree_set(any::<Value>(), 0 .. 300), 1 .. 40),
+                            block_size in prop_oneof![Just(0_u64), Just(1024)],
+                            dense_index in any_dense_index()) {
+            let tmp = NamedTempFile::new().unwrap();
+            let options = CreateOptions { dense_index, block_size, ..CreateOptions::default() };
+            create_with_options(tmp.path(), &bmap, &options).expect("create");
+
+            let mut bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+            let si = bucket.read_sparse_index().expect("sparse index");
+            for (key, expected) in &bmap {
+                let (offset_1, offset_2) = si.try_get(*key).expect("try_get");
+                let vec = bucket.try_get_vec(*key, offset_1, offset_2).expect("try_get_vec").expect("present");
+                prop_assert_eq!(vec, expected.iter().cloned().collect::<Vec<_>>());
             }
+        }
+    }
 
-            let mut merged = Bucket::open(merged_file).expect("open").check_headers().expect("headers");
-
-            let si = merged.read_sparse_index().expect("read_sparse_index");
-            for (key, values) in bmap2.iter()  {
-                let (data_off_1, data_off_2) = si.try_get(*key).expect("try_get");
-                let merged_values = merged.try_get(*key, data_off_1, data_off_2).expect("try_get");
-                assert_eq!(*values, merged_values.expect("try_get"));
+    proptest! {
+        #[test]
+        fn prop_values_iter(bmap in prop::collection::btree_map(any::<u64>(), prop::collection::btree_set(any::<Value>(), 0 .. 300), 0 .. 40),
+                            missing in any::<u64>(),
+                            dense_index in any_dense_index(),
+                            codec in any_codec()) {
+            let tmp = NamedTempFile::new().unwrap();
+            let options = CreateOptions { dense_index, codec, ..CreateOptions::default() };
+            create_with_options(tmp.path(), &bmap, &options).expect("create");
+
+            let mut bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+            for (key, expected) in &bmap {
+                let streamed: Vec<Value> = bucket.values_iter(*key)
+                    .expect("values_iter")
+                    .expect("present")
+                    .collect::<Result<_>>()
+                    .expect("values");
+                let set = bucket.get(*key).expect("get").expect("present");
+                prop_assert_eq!(&streamed, &set.iter().cloned().collect::<Vec<_>>());
+                prop_assert_eq!(&streamed, &expected.iter().cloned().collect::<Vec<_>>());
+                prop_assert!(bucket.contains(*key).expect("contains"));
+            }
+            if !bmap.contains_key(&missing) {
+                prop_assert!(bucket.values_iter(missing).expect("values_iter").is_none());
+                prop_assert!(!bucket.contains(missing).expect("contains"));
             }
         }
     }
@@ -962,13 +4709,1046 @@ mod tests {
         }
     }
 
+    #[test]
+    fn repair_drops_corrupt_blocks() {
+        use std::iter::FromIterator;
+        use std::os::unix::fs::FileExt;
+
+        let mut bmap = BTreeMap::new();
+        for key in 0 .. 20_u64 {
+            bmap.insert(key, BTreeSet::from_iter(0 .. (key as Value + 1)));
+        }
+
+        let tmp = NamedTempFile::new().unwrap();
+        let repaired = NamedTempFile::new().unwrap();
+        create(tmp.path(), &bmap).expect("create");
+
+        // Clobber the lz4 frames of a few keys.
+        let corrupted: BTreeSet<HashedKey> = BTreeSet::from_iter(vec![3, 7, 19]);
+        {
+            let mut bucket = Bucket::open(tmp.path()).expect("open").check_headers().expect("check_headers");
+            let data_base_offset = bucket.header.data_base_offset;
+            let file = std::fs::OpenOptions::new().write(true).open(tmp.path()).expect("open");
+            for entry in bucket.index_entries().expect("index_entries") {
+                let entry = entry.expect("entry");
+                if corrupted.contains(&entry.key) {
+                    let abs_offset = data_base_offset + entry.offset;
+                    file.write_all_at(&[0; 4], abs_offset).expect("write_all_at");
+                }
+            }
+        }
+
+        let report = repair(tmp.path(), repaired.path()).expect("repair");
+        assert_eq!(report, RepairReport { recovered: 17, dropped: 3 });
+
+        let mut bucket = Bucket::open(repaired.path()).expect("open").check_headers().expect("check_headers");
+        let si = bucket.read_sparse_index().expect("sparse index");
+        for (key, actual_values) in &bmap {
+            let values = match si.try_get(*key) {
+                Some((offset_1, offset_2)) => bucket.try_get(*key, offset_1, offset_2).expect("try_get"),
+                None => None,
+            };
+            if corrupted.contains(key) {
+                assert!(values.is_none());
+            } else {
+                assert_eq!(values.as_ref(), Some(actual_values));
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_delete_streaming_matches_map(bmap in prop::collection::btree_map(0_u64 .. 300, prop::collection::btree_set(0_u128 .. 30, 0 .. 10), 0 .. 80),
+                                             deleted in prop::collection::vec(0_u128 .. 30, 0 .. 15),
+                                             block_size in prop_oneof![Just(0_u64), Just(256_u64)],
+                                             keep_empty in any::<bool>()) {
+            let dir = tempfile::tempdir().unwrap();
+            let input = dir.path().join("input.binstore");
+            let options = CreateOptions { block_size, timestamp: Some(0), keep_empty, ..CreateOptions::default() };
+            create_with_options(&input, &bmap, &options).expect("create");
+
+            // The bucket the map-building delete wrote.
+            let mut expected = BTreeMap::new();
+            for (key, values) in &bmap {
+                let values: BTreeSet<Value> = values.iter().filter(|value| !deleted.contains(value)).cloned().collect();
+                if keep_empty || !values.is_empty() {
+                    expected.insert(*key, values);
+                }
+            }
+            let built = dir.path().join("built.binstore");
+            create_with_options(&built, &expected, &options).expect("create");
+
+            let streamed = dir.path().join("streamed.binstore");
+            let report = delete_with_options(&input, &streamed, &deleted, &options).expect("delete");
+            prop_assert_eq!(report.output.num_entries, expected.len() as u64);
+            prop_assert_eq!(std::fs::read(&streamed).unwrap(), std::fs::read(&built).unwrap());
+        }
+    }
+
+    proptest! {
+        #![proptest_config(Config::with_cases(32))]
+        #[test]
+        fn prop_merge_lowmem_matches_default(bmap_1 in prop::collection::btree_map(0_u64 .. 5_000, prop::collection::btree_set(0_u128 .. 30, 1 .. 5), 0 .. 1_500),
+                                             bmap_2 in prop::collection::btree_map(0_u64 .. 5_000, prop::collection::btree_set(0_u128 .. 30, 1 .. 5), 0 .. 1_500),
+                                             dense_index in any_dense_index()) {
+            let dir = tempfile::tempdir().unwrap();
+            let input_1 = dir.path().join("1.binstore");
+            let input_2 = dir.path().join("2.binstore");
+            let options = CreateOptions { dense_index, timestamp: Some(0), ..CreateOptions::default() };
+            create_with_options(&input_1, &bmap_1, &options).expect("create");
+            create_with_options(&input_2, &bmap_2, &options).expect("create");
+
+            let default = dir.path().join("default.binstore");
+            let lowmem = dir.path().join("lowmem.binstore");
+            merge_with_options(&input_1, &input_2, &default, &options).expect("merge");
+            let report = merge_with_options(&input_1, &input_2, &lowmem, &CreateOptions { lowmem: true, ..options.clone() })
+                .expect("merge");
+            let keys: BTreeSet<HashedKey> = bmap_1.keys().chain(bmap_2.keys()).cloned().collect();
+            prop_assert_eq!(report.num_entries, keys.len() as u64);
+            prop_assert_eq!(std::fs::read(&lowmem).unwrap(), std::fs::read(&default).unwrap());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_to_map_roundtrip(bmap in prop::collection::btree_map(any::<u64>(), prop::collection::btree_set(any::<Value>(), 0 .. 20), 0 .. 100),
+                                 dense_index in any_dense_index(),
+                                 block_size in prop_oneof![Just(0_u64), Just(1024)],
+                                 codec in any_codec()) {
+            let tmp = NamedTempFile::new().unwrap();
+            let options = CreateOptions { dense_index, block_size, codec, ..CreateOptions::default() };
+            create_with_options(tmp.path(), &bmap, &options).expect("create");
+
+            let mut bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+            prop_assert_eq!(bucket.to_map().expect("to_map"), bmap);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_grouped_blocks(bmap in prop::collection::btree_map(any::<u64>(), prop::collection::btree_set(any::<Value>(), 0 .. 200), 0 .. 60),
+                               other in prop::collection::btree_map(any::<u64>(), prop::collection::btree_set(any::<Value>(), 0 .. 3), 0 .. 60),
+                               block_size in prop_oneof![Just(1_u64), 1_u64 .. 4096, Just(64 * 1024)],
+                               dense_index in any_dense_index()) {
+            let tmp = NamedTempFile::new().unwrap();
+            let options = CreateOptions { dense_index, block_size, ..CreateOptions::default() };
+            create_with_options(tmp.path(), &bmap, &options).expect("create");
+
+            let mut bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+            prop_assert_eq!(bucket.header.block_size, block_size);
+            prop_assert_eq!(&bucket.to_map().expect("to_map"), &bmap);
+            for (key, expected) in &bmap {
+                prop_assert_eq!(bucket.get(*key).expect("get"), Some(expected.clone()));
+                let streamed: BTreeSet<Value> = bucket.values_iter(*key)
+                    .expect("values_iter")
+                    .expect("present")
+                    .collect::<Result<_>>()
+                    .expect("values");
+                prop_assert_eq!(&streamed, expected);
+            }
+
+            // Merging with an ungrouped bucket keeps the grouping asked for.
+            let tmp_other = NamedTempFile::new().unwrap();
+            create(tmp_other.path(), &other).expect("create");
+            let merged = NamedTempFile::new().unwrap();
+            merge_with_options(tmp.path(), tmp_other.path(), merged.path(), &options).expect("merge");
+            let mut expected = bmap.clone();
+            for (key, values) in &other {
+                expected.entry(*key).or_default().extend(values.iter().cloned());
+            }
+            let mut bucket = Bucket::open(merged.path()).and_then(|b| b.check_headers()).expect("open");
+            prop_assert_eq!(bucket.header.block_size, block_size);
+            prop_assert_eq!(bucket.to_map().expect("to_map"), expected);
+        }
+    }
+
+    #[test]
+    fn grouped_blocks_are_smaller() {
+        use std::iter::FromIterator;
+
+        let mut bmap = BTreeMap::new();
+        for key in 0 .. 1000_u64 {
+            bmap.insert(key, BTreeSet::from_iter(vec![key as Value]));
+        }
+        let tmp = NamedTempFile::new().unwrap();
+        let single = create_with_options(tmp.path(), &bmap, &CreateOptions::default()).expect("create");
+        let options = CreateOptions { block_size: 64 * 1024, ..CreateOptions::default() };
+        let grouped = create_with_options(tmp.path(), &bmap, &options).expect("create");
+        assert!(grouped.size < single.size, "{} >= {}", grouped.size, single.size);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_append_then_compact(bmap in prop::collection::btree_map(0_u64 .. 100, prop::collection::btree_set(any::<Value>(), 1 .. 10), 0 .. 30),
+                                    appended in prop::collection::vec((0_u64 .. 200, any::<Value>()), 0 .. 50),
+                                    dense_index in any_dense_index()) {
+            let tmp = NamedTempFile::new().unwrap();
+            let options = CreateOptions { dense_index, ..CreateOptions::default() };
+            create_with_options(tmp.path(), &bmap, &options).expect("create");
+
+            let mut expected = bmap.clone();
+            for &(key, value) in &appended {
+                append(tmp.path(), key, value).expect("append");
+                expected.entry(key).or_default().insert(value);
+            }
+
+            let mut bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+            prop_assert_eq!(bucket.read_log().expect("read_log"), appended.clone());
+            for key in 0 .. 200 {
+                prop_assert_eq!(bucket.get(key).expect("get"), expected.get(&key).cloned());
+            }
+
+            let compacted = NamedTempFile::new().unwrap();
+            let report = compact(tmp.path(), compacted.path()).expect("compact");
+            prop_assert_eq!(report.num_entries, expected.len() as u64);
+
+            let mut bucket = Bucket::open(compacted.path()).and_then(|b| b.check_headers()).expect("open");
+            prop_assert_eq!(bucket.header.dense_index, dense_index);
+            prop_assert_eq!(bucket.header.log_base_offset, 0);
+            for key in 0 .. 200 {
+                prop_assert_eq!(bucket.get(key).expect("get"), expected.get(&key).cloned());
+            }
+        }
+    }
+
+    #[test]
+    fn rewrites_keep_appended_values() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> =
+            (0 .. 50_u64).map(|key| (key * 2, BTreeSet::from([key as Value, 1_000]))).collect();
+        let tmp = NamedTempFile::new().unwrap();
+        create(tmp.path(), &bmap).expect("create");
+        let mut expected = bmap.clone();
+        for &(key, value) in &[(4, 7), (5, 8), (500, 1_000)] {
+            append(tmp.path(), key, value).expect("append");
+            expected.entry(key).or_default().insert(value);
+        }
+
+        let deleted = NamedTempFile::new().unwrap();
+        delete(tmp.path(), deleted.path(), &[1_000]).expect("delete");
+        let mut bucket = Bucket::open(deleted.path()).unwrap().check_headers().expect("check_headers");
+        assert_eq!(bucket.get(4).expect("get"), Some(BTreeSet::from([2, 7])));
+        assert_eq!(bucket.get(5).expect("get"), Some(BTreeSet::from([8])));
+        assert_eq!(bucket.get(500).expect("get"), None);
+
+        let repaired = NamedTempFile::new().unwrap();
+        repair(tmp.path(), repaired.path()).expect("repair");
+        assert_eq!(Bucket::open(repaired.path()).unwrap().check_headers().unwrap().to_map().expect("to_map"), expected);
+
+        let mapping: BTreeMap<HashedKey, HashedKey> = (0 .. 1_000).map(|key| (key, key / 2)).collect();
+        let rehashed = NamedTempFile::new().unwrap();
+        rehash(tmp.path(), rehashed.path(), &mapping, &CreateOptions::default()).expect("rehash");
+        let mut bucket = Bucket::open(rehashed.path()).unwrap().check_headers().expect("check_headers");
+        assert_eq!(bucket.get(2).expect("get"), Some(BTreeSet::from([2, 7, 8, 1_000])));
+        assert_eq!(bucket.get(250).expect("get"), Some(BTreeSet::from([1_000])));
+
+        let compacted = NamedTempFile::new().unwrap();
+        compact(tmp.path(), compacted.path()).expect("compact");
+        assert!(eq_contents(tmp.path(), compacted.path()).expect("eq_contents"));
+        assert!(!eq_contents(tmp.path(), deleted.path()).expect("eq_contents"));
+
+        let mut b1 = Bucket::open(tmp.path()).unwrap().check_headers().expect("check_headers");
+        let mut b2 = Bucket::open(compacted.path()).unwrap().check_headers().expect("check_headers");
+        let merged = merge_iter(&mut b1, &mut b2, SetOp::Union).expect("merge_iter")
+            .collect::<Result<BTreeMap<HashedKey, BTreeSet<Value>>>>().expect("merge_iter");
+        assert_eq!(merged, expected);
+
+        // Merging copies data blocks, so a bucket with a log is refused.
+        let output = NamedTempFile::new().unwrap();
+        let result = merge(tmp.path(), compacted.path(), output.path());
+        assert_matches!(result, Err(Error::UncompactedLog(ref path)) if path == tmp.path());
+        let result = cat(&[tmp.path()], output.path(), &CreateOptions::default());
+        assert!(matches!(result, Err(Error::UncompactedLog(_))));
+    }
+
+    #[test]
+    fn append_ignores_torn_record() {
+        use std::iter::FromIterator;
+
+        let tmp = NamedTempFile::new().unwrap();
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1_u64, BTreeSet::from_iter(0 .. 10_u128));
+        create(tmp.path(), &bmap).expect("create");
+        append(tmp.path(), 2, 42).expect("append");
+
+        // An interrupted append leaves part of a record behind.
+        let mut file = OpenOptions::new().append(true).open(tmp.path()).expect("open");
+        file.write_all(&[0xff; 5]).expect("write");
+        drop(file);
+
+        let mut bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+        assert_eq!(bucket.read_log().expect("read_log"), vec![(2, 42)]);
+        assert_eq!(bucket.get(2).expect("get"), Some(BTreeSet::from_iter(vec![42])));
+    }
+
+    #[test]
+    fn prepared_bucket_keeps_sparse_index() {
+        use std::iter::FromIterator;
+        use std::os::unix::fs::FileExt;
+
+        let mut bmap = BTreeMap::new();
+        for key in 0 .. 100_u64 {
+            bmap.insert(key * 2, BTreeSet::from_iter(0 .. (key as Value)));
+        }
+        let tmp = NamedTempFile::new().unwrap();
+        create_with_options(tmp.path(), &bmap, &CreateOptions::default()).expect("create");
+
+        let bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+        let si_base_offset = bucket.header.si_base_offset;
+        let mut prepared = bucket.prepare().expect("prepare");
+
+        // Clobber the sparse index on disk: only lookups that read it
+        // again would notice.
+        let file = OpenOptions::new().write(true).open(tmp.path()).expect("open");
+        file.write_all_at(&[0xff; 16], si_base_offset).expect("write_all_at");
+        let mut unprepared = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+        assert!(unprepared.get(0).is_err());
+
+        for key in 0 .. 200_u64 {
+            assert_eq!(prepared.get(key).expect("get"), bmap.get(&key).cloned());
+            assert_eq!(prepared.contains(key).expect("contains"), bmap.contains_key(&key));
+        }
+    }
+
+    #[test]
+    fn verify_data_ordering() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 10_u64).map(|key| (key * 2, (0 .. key as Value + 1).collect())).collect();
+        let tmp = NamedTempFile::new().unwrap();
+        create(tmp.path(), &bmap).expect("create");
+        let mut bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers_strict()).expect("open");
+        bucket.verify_data_ordering().expect("verify_data_ordering");
+        let di_base_offset = bucket.header.di_base_offset;
+        let entries = bucket.index_entries().expect("index_entries").collect::<Result<Vec<IndexEntry>>>().expect("entries");
+
+        let write_entry = |i: u64, entry: IndexEntry| {
+            let mut file = OpenOptions::new().write(true).open(tmp.path()).expect("open");
+            file.seek(SeekFrom::Start(di_base_offset + i * INDEX_ENTRY_SIZE as u64)).expect("seek");
+            bincode_config().serialize_into(&mut file, &entry).expect("serialize");
+        };
+        let check = || Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open").verify_data_ordering();
+
+        // Keys 6 and 8 swapped, with their offsets.
+        write_entry(3, entries[4]);
+        write_entry(4, entries[3]);
+        assert_matches!(check(), Err(Error::CorruptIndex));
+        assert!(matches!(Bucket::open(tmp.path()).and_then(|b| b.check_headers_strict()), Err(Error::CorruptIndex)));
+        // The same key twice.
+        write_entry(3, IndexEntry { key: entries[2].key, ..entries[3] });
+        write_entry(4, entries[4]);
+        assert_matches!(check(), Err(Error::CorruptIndex));
+        // Keys in order, offsets swapped.
+        write_entry(3, IndexEntry { key: entries[3].key, offset: entries[4].offset });
+        write_entry(4, IndexEntry { key: entries[4].key, offset: entries[3].offset });
+        assert_matches!(check(), Err(Error::CorruptIndex));
+
+        write_entry(3, entries[3]);
+        write_entry(4, entries[4]);
+        check().expect("verify_data_ordering");
+    }
+
+    #[test]
+    fn prepare_rejects_unsorted_sparse_index() {
+        let tmp = NamedTempFile::new().unwrap();
+        create(tmp.path(), &BTreeMap::new()).expect("create");
+        let bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+
+        let si = SparseIndex {
+            step: 1,
+            index: vec![IndexEntry { key: 2, offset: 0 }, IndexEntry { key: 1, offset: 16 }],
+        };
+        let mut file = OpenOptions::new().write(true).open(tmp.path()).expect("open");
+        file.seek(SeekFrom::Start(bucket.header.si_base_offset)).expect("seek");
+        bincode_config().serialize_into(&mut file, &si).expect("serialize");
+
+        assert!(matches!(bucket.prepare(), Err(Error::CorruptIndex)));
+    }
+
+    #[test]
+    fn empty_bucket() {
+        for &dense_index in &[DenseIndexEncoding::Fixed, DenseIndexEncoding::Varint, DenseIndexEncoding::Columnar, DenseIndexEncoding::Compressed] {
+            for &block_size in &[0, 1024] {
+                let tmp = NamedTempFile::new().unwrap();
+                let options = CreateOptions { dense_index, block_size, ..CreateOptions::default() };
+                let report = create_with_options(tmp.path(), &BTreeMap::new(), &options).expect("create");
+                assert_eq!(report.num_entries, 0);
+
+                let mut bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+                assert_eq!(bucket.header.num_entries, 0);
+                assert_eq!(bucket.header.di_base_offset, bucket.header.data_base_offset);
+                assert_eq!(bucket.data_size().expect("data_size"), 0);
+                assert_eq!(bucket.uncompressed_data_size().expect("uncompressed_data_size"), 0);
+                assert_eq!(bucket.index_entries().expect("index_entries").count(), 0);
+                assert!(bucket.to_map().expect("to_map").is_empty());
+                assert_eq!(bucket.get(0).expect("get"), None);
+                assert!(!bucket.contains(0).expect("contains"));
+                assert!(bucket.values_iter(0).expect("values_iter").is_none());
+                let mut prepared = bucket.prepare().expect("prepare");
+                assert_eq!(prepared.get(0).expect("get"), None);
+
+                let output = NamedTempFile::new().unwrap();
+                let dedup = CreateOptions { dedup_inputs: true, ..options.clone() };
+                let merged = merge_with_options(tmp.path(), tmp.path(), output.path(), &dedup).expect("merge");
+                assert_eq!(merged.num_entries, 0);
+                let deleted = delete_with_options(tmp.path(), output.path(), &[1], &options).expect("delete");
+                assert_eq!(deleted.output.num_entries, 0);
+                let repaired = repair(tmp.path(), output.path()).expect("repair");
+                assert_eq!(repaired, RepairReport { recovered: 0, dropped: 0 });
+                let compacted = compact(tmp.path(), output.path()).expect("compact");
+                assert_eq!(compacted.num_entries, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn read_header_closes_file() {
+        let tmp = NamedTempFile::new().unwrap();
+        create(tmp.path(), &BTreeMap::new()).expect("create");
+        let header = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open").header;
+
+        // Count our own descriptors on the file only, since other tests
+        // open files concurrently.
+        let open_fds = || {
+            std::fs::read_dir("/proc/self/fd").expect("read_dir")
+                .filter_map(|entry| std::fs::read_link(entry.ok()?.path()).ok())
+                .filter(|target| target == tmp.path())
+                .count()
+        };
+        // The temporary file itself holds one.
+        let before = open_fds();
+        assert_eq!(before, 1);
+        for _ in 0 .. 2000 {
+            assert_eq!(Bucket::read_header(tmp.path()).expect("read_header"), header);
+        }
+        assert_eq!(open_fds(), before);
+
+        std::fs::write(tmp.path(), b"not a bucket").expect("write");
+        assert!(matches!(Bucket::read_header(tmp.path()), Err(Error::BadMagic)));
+    }
+
+    fn report_timestamp(bytes: &[u8]) -> i64 {
+        BucketHeader::read_from(&mut &bytes[..]).expect("read_from").timestamp
+    }
+
+    #[test]
+    fn fixed_timestamp_is_reproducible() {
+        use std::iter::FromIterator;
+
+        let mut bmap = BTreeMap::new();
+        for key in 0 .. 50_u64 {
+            bmap.insert(key * 7, BTreeSet::from_iter(0 .. (key as Value)));
+        }
+        let options = CreateOptions { timestamp: Some(1_700_000_000), ..CreateOptions::default() };
+        let tmp_1 = NamedTempFile::new().unwrap();
+        let tmp_2 = NamedTempFile::new().unwrap();
+        create_with_options(tmp_1.path(), &bmap, &options).expect("create");
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        create_with_options(tmp_2.path(), &bmap, &options).expect("create");
+
+        let bytes = std::fs::read(tmp_1.path()).expect("read");
+        assert_eq!(bytes, std::fs::read(tmp_2.path()).expect("read"));
+        assert_eq!(report_timestamp(&bytes), 1_700_000_000);
+    }
+
+    #[test]
+    fn create_into_cursor() {
+        use std::iter::FromIterator;
+
+        let mut bmap = BTreeMap::new();
+        for key in 0 .. 50_u64 {
+            bmap.insert(key * 7, BTreeSet::from_iter(0 .. (key as Value)));
+        }
+        let mut cursor = io::Cursor::new(Vec::new());
+        let report = create_into(&mut cursor, &bmap, &CreateOptions::default()).expect("create_into");
+        let bytes = cursor.into_inner();
+        assert_eq!(report.size, bytes.len() as u64);
+
+        let tmp = NamedTempFile::new().unwrap();
+        let options = CreateOptions { timestamp: Some(report_timestamp(&bytes)), ..CreateOptions::default() };
+        create_with_options(tmp.path(), &bmap, &options).expect("create");
+        assert_eq!(std::fs::read(tmp.path()).expect("read"), bytes);
+
+        let mut bucket = Bucket::from_bytes(bytes).check_headers().expect("check_headers");
+        assert_eq!(bucket.to_map().expect("to_map"), bmap);
+        assert_eq!(bucket.get(7).expect("get"), bmap.get(&7).cloned());
+    }
+
+    #[test]
+    fn dry_run_writes_nothing() {
+        use std::iter::FromIterator;
+
+        let mut bmap = BTreeMap::new();
+        for key in 0 .. 50_u64 {
+            bmap.insert(key, BTreeSet::from_iter(0 .. (key as Value % 5 + 1)));
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.binstore");
+        create(&input, &bmap).expect("create");
+
+        let dry_run = CreateOptions { dry_run: true, ..CreateOptions::default() };
+        let values: Vec<Value> = vec![0, 1, 3];
+
+        let output = dir.path().join("deleted.binstore");
+        let expected = delete_with_options(&input, &output, &values, &dry_run).expect("delete");
+        assert!(!output.exists());
+        let actual = delete_with_options(&input, &output, &values, &CreateOptions::default()).expect("delete");
+        assert_eq!(expected, actual);
+        assert_eq!(actual.keys_emptied, 20);
+        assert_eq!(actual.output.num_entries, 30);
+        assert_eq!(actual.output.size, std::fs::metadata(&output).unwrap().len());
+
+        let output = dir.path().join("merged.binstore");
+        let dedup = CreateOptions { dedup_inputs: true, ..CreateOptions::default() };
+        let expected = merge_with_options(&input, &input, &output, &CreateOptions { dry_run: true, ..dedup.clone() }).expect("merge");
+        assert!(!output.exists());
+        let actual = merge_with_options(&input, &input, &output, &dedup).expect("merge");
+        assert_eq!(expected, actual);
+        assert_eq!(actual.size, std::fs::metadata(&output).unwrap().len());
+    }
+
+    #[test]
+    fn delete_keep_empty() {
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1, BTreeSet::from([10, 11]));
+        bmap.insert(2, BTreeSet::from([10]));
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.binstore");
+        create(&input, &bmap).expect("create");
+
+        let dropped = dir.path().join("dropped.binstore");
+        delete(&input, &dropped, &[10]).expect("delete");
+        let mut bucket = Bucket::open(&dropped).unwrap().check_headers().unwrap();
+        assert_eq!(bucket.get(2).expect("get"), None);
+
+        let kept = dir.path().join("kept.binstore");
+        let options = CreateOptions { keep_empty: true, ..CreateOptions::default() };
+        let report = delete_with_options(&input, &kept, &[10], &options).expect("delete");
+        assert_eq!(report.keys_emptied, 1);
+        assert_eq!(report.output.num_entries, 2);
+        let mut bucket = Bucket::open(&kept).unwrap().check_headers().unwrap();
+        assert_eq!(bucket.get(1).expect("get"), Some(BTreeSet::from([11])));
+        assert_eq!(bucket.get(2).expect("get"), Some(BTreeSet::new()));
+        assert!(bucket.contains(2).expect("contains"));
+        assert_eq!(bucket.get(3).expect("get"), None);
+
+        // Compacting keeps the empty set.
+        let compacted = dir.path().join("compacted.binstore");
+        compact(&kept, &compacted).expect("compact");
+        let mut bucket = Bucket::open(&compacted).unwrap().check_headers().unwrap();
+        assert_eq!(bucket.get(2).expect("get"), Some(BTreeSet::new()));
+    }
+
+    #[test]
+    fn delete_key_range() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 1000_u64).map(|key| (key * 7, BTreeSet::from([key as Value, key as Value + 1]))).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.binstore");
+        create(&input, &bmap).expect("create");
+
+        let dropped = dir.path().join("dropped.binstore");
+        let options = CreateOptions { drop_keys: Some(2000 ..= 5000), ..CreateOptions::default() };
+        let report = delete_with_options(&input, &dropped, &[1], &options).expect("delete");
+        let expected: BTreeMap<HashedKey, BTreeSet<Value>> = bmap.iter()
+            .filter(|(key, _)| !(2000 ..= 5000).contains(*key))
+            .map(|(&key, values)| (key, values.iter().cloned().filter(|&value| value != 1).collect::<BTreeSet<Value>>()))
+            .filter(|(_, values)| !values.is_empty())
+            .collect();
+        assert_eq!(report.keys_dropped, bmap.keys().filter(|key| (2000 ..= 5000).contains(*key)).count() as u64);
+        assert_eq!(report.output.num_entries, expected.len() as u64);
+        let mut bucket = Bucket::open(&dropped).unwrap().check_headers().unwrap();
+        assert_eq!(bucket.get(2002).expect("get"), None);
+        assert_eq!(bucket.get(5005).expect("get"), Some(BTreeSet::from([715, 716])));
+        assert_eq!(bucket.to_map().expect("to_map"), expected);
+
+        // A range past every key copies the bucket; one over every key
+        // leaves it empty.
+        for (range, expected) in [(7000 ..= u64::MAX, bmap.clone()), (0 ..= u64::MAX, BTreeMap::new())] {
+            let output = dir.path().join("output.binstore");
+            let _ = std::fs::remove_file(&output);
+            let options = CreateOptions { drop_keys: Some(range), ..CreateOptions::default() };
+            delete_with_options(&input, &output, &[], &options).expect("delete");
+            let mut bucket = Bucket::open(&output).unwrap().check_headers().unwrap();
+            assert_eq!(bucket.to_map().expect("to_map"), expected);
+        }
+
+        // Compacting drops logged keys in the range as well.
+        append(&input, 3000, 42).expect("append");
+        append(&input, 3001, 42).expect("append");
+        append(&input, 9000, 42).expect("append");
+        let compacted = dir.path().join("compacted.binstore");
+        compact_with_options(&input, &compacted, &options).expect("compact");
+        let mut bucket = Bucket::open(&compacted).unwrap().check_headers().unwrap();
+        assert_eq!(bucket.get(3001).expect("get"), None);
+        assert_eq!(bucket.get(9000).expect("get"), Some(BTreeSet::from([42])));
+        assert_eq!(bucket.get(1001).expect("get"), Some(BTreeSet::from([143, 144])));
+        assert_eq!(bucket.get(4998).expect("get"), None);
+    }
+
+    #[test]
+    fn delete_preserve_mtime() {
+        use filetime::FileTime;
+
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1, BTreeSet::from([10, 11]));
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.binstore");
+        let options = CreateOptions { timestamp: Some(1_500_000_000), ..CreateOptions::default() };
+        create_with_options(&input, &bmap, &options).expect("create");
+        let mtime = FileTime::from_unix_time(1_500_000_100, 0);
+        filetime::set_file_mtime(&input, mtime).unwrap();
+        let modified = |path: &Path| FileTime::from_last_modification_time(&std::fs::metadata(path).unwrap());
+
+        let fresh = dir.path().join("fresh.binstore");
+        delete(&input, &fresh, &[10]).expect("delete");
+        assert_ne!(modified(&fresh), mtime);
+        assert_ne!(Bucket::read_header(&fresh).unwrap().timestamp, 1_500_000_000);
+
+        let preserved = dir.path().join("preserved.binstore");
+        let options = CreateOptions { preserve_mtime: true, ..CreateOptions::default() };
+        delete_with_options(&input, &preserved, &[10], &options).expect("delete");
+        assert_eq!(modified(&preserved), mtime);
+        assert_eq!(Bucket::read_header(&preserved).unwrap().timestamp, 1_500_000_000);
+
+        let compacted = dir.path().join("compacted.binstore");
+        compact_with_options(&preserved, &compacted, &options).expect("compact");
+        assert_eq!(modified(&compacted), mtime);
+        assert_eq!(Bucket::read_header(&compacted).unwrap().timestamp, 1_500_000_000);
+    }
+
     #[test]
     fn bucketheader_size() {
-        const HEADER_SIZE: usize = mem::size_of::<BucketHeader>();
+        // The header is reserved before the offsets are known and
+        // rewritten afterwards, so its size must not depend on them.
+        let mut default = Vec::new();
+        BucketHeader::default().write_to(&mut default).expect("write_to");
+
+        let header = BucketHeader {
+            si_base_offset: u64::MAX,
+            di_base_offset: u64::MAX,
+            data_base_offset: u64::MAX,
+            num_entries: u64::MAX,
+            dense_index: DenseIndexEncoding::Columnar,
+            keys_base_offset: u64::MAX,
+            offsets_base_offset: u64::MAX,
+            log_base_offset: u64::MAX,
+            block_size: u64::MAX,
+            codec: Codec::Uncompressed,
+            key_type_tag: u8::MAX,
+            value_type_tag: u8::MAX,
+            ..BucketHeader::default()
+        };
+        let mut filled = Vec::new();
+        header.write_to(&mut filled).expect("write_to");
+        assert_eq!(default.len(), filled.len());
+
+        let read = BucketHeader::read_from(&mut filled.as_slice()).expect("read_from");
+        assert_eq!(read, header);
+    }
+
+    #[test]
+    fn header_display() {
+        let header = BucketHeader {
+            timestamp: 1_700_000_000,
+            si_base_offset: 0x40,
+            di_base_offset: 0x80,
+            data_base_offset: 0x1000,
+            num_entries: 12,
+            ..BucketHeader::default()
+        };
+        assert_eq!(header.to_string(),
+                   format!("version {}, 12 entries, sparse index at 0x40, dense index (Fixed) at 0x80, data (Lz4) at 0x1000, written 2023-11-14 22:13:20 UTC",
+                           VERSION));
+        let logged = BucketHeader { log_base_offset: 0x2000, ..header };
+        assert!(logged.to_string().contains(", log at 0x2000, "));
+    }
+
+    #[test]
+    fn header_byte_layout() {
+        let header = BucketHeader {
+            timestamp: -2,
+            si_base_offset: 1,
+            di_base_offset: 2,
+            data_base_offset: 3,
+            num_entries: 4,
+            dense_index: DenseIndexEncoding::Varint,
+            keys_base_offset: 5,
+            offsets_base_offset: 6,
+            log_base_offset: 7,
+            block_size: 0x0102_0304,
+            codec: Codec::Zstd,
+            key_type_tag: 8,
+            value_type_tag: 16,
+            ..BucketHeader::default()
+        };
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&MAGIC.to_le_bytes());
+        expected.extend_from_slice(&VERSION.to_le_bytes());
+        expected.extend_from_slice(&(-2_i64).to_le_bytes());
+        for n in 1 ..= 4_u64 {
+            expected.extend_from_slice(&n.to_le_bytes());
+        }
+        // The extension: its length, then enum variants as u32s.
+        expected.extend_from_slice(&42_u64.to_le_bytes());
+        expected.extend_from_slice(&1_u32.to_le_bytes());
+        for n in &[5, 6, 7, 0x0102_0304_u64] {
+            expected.extend_from_slice(&n.to_le_bytes());
+        }
+        expected.extend_from_slice(&1_u32.to_le_bytes());
+        expected.extend_from_slice(&[8, 16]);
+
+        let mut written = Vec::new();
+        header.write_to(&mut written).expect("write_to");
+        assert_eq!(written, expected);
+        assert_eq!(BucketHeader::read_from(&mut expected.as_slice()).expect("read_from"), header);
+    }
+
+    #[test]
+    fn accept_older_version() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> =
+            (0 .. 100_u64).map(|key| (key, BTreeSet::from([key as Value]))).collect();
+        let tmp = NamedTempFile::new().unwrap();
+        create(tmp.path(), &bmap).expect("create");
+
+        // A bucket with only the fields of version 0 is written as
+        // version 0, which is accepted by default.
+        let mut bucket = Bucket::open(tmp.path()).unwrap().check_headers().expect("check_headers");
+        assert_eq!(bucket.header.version, 0);
+        assert_eq!(bucket.to_map().expect("to_map"), bmap);
+
+        // A build from before the extension reads the same offsets.
+        let mut file = File::open(tmp.path()).unwrap();
+        let v0: (u32, u32, i64, u64, u64, u64, u64) = bincode_config().deserialize_from(&mut file).expect("deserialize");
+        assert_eq!(v0, (MAGIC, 0, bucket.header.timestamp, bucket.header.si_base_offset,
+                        bucket.header.di_base_offset, bucket.header.data_base_offset, 100));
+
+        let bucket = Bucket::open(tmp.path()).unwrap();
+        let only_current = VersionRange { min: 1, max: 1 };
+        let result = bucket.check_headers_accepting(only_current);
+        assert!(matches!(result, Err(Error::UnacceptedVersion { version: 0, min: 1, max: 1 })));
+
+        // There is room left to record a log, which takes version 2.
+        append(tmp.path(), 1_000, 7).expect("append");
+        let mut bucket = Bucket::open(tmp.path()).unwrap().check_headers().expect("check_headers");
+        assert_eq!(bucket.header.version, 2);
+        assert_eq!(bucket.get(1_000).expect("get"), Some(BTreeSet::from([7])));
+        assert_eq!(bucket.get(3).expect("get"), Some(BTreeSet::from([3])));
+
+        // A version 1 bucket outside of a narrower range.
+        let current = NamedTempFile::new().unwrap();
+        create_with_options(current.path(), &bmap, &CreateOptions { dense_index: DenseIndexEncoding::Varint, ..CreateOptions::default() }).expect("create");
+        let bucket = Bucket::open(current.path()).unwrap();
+        let result = bucket.check_headers_accepting("0:0".parse().unwrap());
+        assert!(matches!(result, Err(Error::UnacceptedVersion { version: 1, min: 0, max: 0 })));
+
+        // Fields that change how the data is decoded take version 2, so
+        // version 1 readers reject them rather than misread the data.
+        for options in &[CreateOptions { codec: Codec::Zstd, ..CreateOptions::default() },
+                         CreateOptions { block_size: 4, ..CreateOptions::default() },
+                         CreateOptions { varint_values: true, ..CreateOptions::default() },
+                         CreateOptions { dense_index: DenseIndexEncoding::Columnar, ..CreateOptions::default() }] {
+            let decoding = NamedTempFile::new().unwrap();
+            create_with_options(decoding.path(), &bmap, options).expect("create");
+            let bucket = Bucket::open(decoding.path()).unwrap();
+            let result = bucket.check_headers_accepting("0:1".parse().unwrap());
+            assert!(matches!(result, Err(Error::UnacceptedVersion { version: 2, min: 0, max: 1 })));
+        }
+
+        assert!("1:0".parse::<VersionRange>().is_err());
+        assert!("0".parse::<VersionRange>().is_err());
+        assert!(format!("0:{}", VERSION + 1).parse::<VersionRange>().is_err());
+    }
+
+    #[test]
+    fn bucketheader_version_0() {
+        const HEADER_SIZE: usize = 48;
+
+        let header = BucketHeader { version: 0, ..BucketHeader::default() };
+        let mut buf = Vec::new();
+        header.write_to(&mut buf).expect("write_to");
+        assert_eq!(buf.len(), HEADER_SIZE);
+
+        let read = BucketHeader::read_from(&mut buf.as_slice()).expect("read_from");
+        assert_eq!(read, header);
+        assert_eq!(read.dense_index, DenseIndexEncoding::Fixed);
+    }
+
+    proptest! {
+        #![proptest_config(Config::with_cases(32))]
+        #[test]
+        fn prop_compressed_index_finds_every_key(bmap in prop::collection::btree_map(any::<u64>(), prop::collection::btree_set(any::<Value>(), 1 .. 4), 0 .. 2_000)) {
+            let tmp = NamedTempFile::new().unwrap();
+            let options = CreateOptions { dense_index: DenseIndexEncoding::Compressed, ..CreateOptions::default() };
+            create_with_options(tmp.path(), &bmap, &options).expect("create");
+
+            let mut bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+            for (key, values) in &bmap {
+                prop_assert_eq!(bucket.get(*key).expect("get"), Some(values.clone()));
+            }
+            let mut prepared = bucket.prepare().expect("prepare");
+            for key in bmap.keys() {
+                prop_assert!(prepared.contains(*key).expect("contains"));
+                let next = key.wrapping_add(1);
+                prop_assert_eq!(prepared.contains(next).expect("contains"), bmap.contains_key(&next));
+            }
+            let mut bucket = prepared.into_bucket();
+            prop_assert_eq!(bucket.to_map().expect("to_map"), bmap);
+        }
+    }
+
+    proptest! {
+        #![proptest_config(Config::with_cases(32))]
+        #[test]
+        fn prop_lookups_under_each_index_kind(bmap in prop::collection::btree_map(0_u64 .. 5_000, prop::collection::btree_set(any::<Value>(), 1 .. 4), 0 .. 1_000),
+                                               dense_index in any_dense_index(),
+                                               index_kind in prop_oneof![Just(IndexKind::SparseDense), Just(IndexKind::FlatInMemory)],
+                                               app_id in prop_oneof![Just(0_u32), any::<u32>()]) {
+            let tmp = NamedTempFile::new().unwrap();
+            let options = CreateOptions { dense_index, index_kind, app_id, ..CreateOptions::default() };
+            create_with_options(tmp.path(), &bmap, &options).expect("create");
+            let header = Bucket::read_header(tmp.path()).expect("read_header");
+            prop_assert_eq!(header.index_kind, index_kind);
+            prop_assert_eq!(header.app_id, app_id);
+
+            let mut bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+            for key in 0 .. 5_001 {
+                prop_assert_eq!(bucket.get(key).expect("get"), bmap.get(&key).cloned());
+            }
+            let mut prepared = bucket.prepare().expect("prepare");
+            for key in (0 .. 5_001).step_by(7) {
+                prop_assert_eq!(prepared.get(key).expect("get"), bmap.get(&key).cloned());
+                prop_assert_eq!(prepared.contains(key).expect("contains"), bmap.contains_key(&key));
+            }
+
+            // Rewriting a bucket keeps its kind.
+            let compacted = NamedTempFile::new().unwrap();
+            compact(tmp.path(), compacted.path()).expect("compact");
+            prop_assert_eq!(Bucket::read_header(compacted.path()).expect("read_header").index_kind, index_kind);
+        }
+    }
+
+    #[test]
+    fn compressed_index_is_smaller() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 20_000_u64).map(|key| (key * 7, BTreeSet::from([key as Value]))).collect();
+        let size = |dense_index| {
+            let tmp = NamedTempFile::new().unwrap();
+            create_with_options(tmp.path(), &bmap, &CreateOptions { dense_index, ..CreateOptions::default() }).expect("create");
+            let mut bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+            assert_eq!(bucket.to_map().expect("to_map"), bmap);
+            std::fs::metadata(tmp.path()).unwrap().len()
+        };
+        let fixed = size(DenseIndexEncoding::Fixed);
+        let compressed = size(DenseIndexEncoding::Compressed);
+        // Uncompressed, the dense index is 16 bytes per entry; sorted
+        // keys and offsets save at least a quarter of it.
+        assert!(compressed + 20_000 * 4 < fixed, "compressed {} vs fixed {}", compressed, fixed);
+    }
+
+    /// Writes `values` as a block of `Codec::Adaptive` and reads it back.
+    fn adaptive_roundtrip(values: &BTreeSet<Value>) -> (Vec<u8>, BTreeSet<Value>) {
+        let mut block = Vec::new();
+        write_values(&mut block, Codec::Adaptive, values).expect("write_values");
+        let read = read_values(&mut block.as_slice(), Codec::Adaptive).expect("read_values");
+        (block, read)
+    }
+
+    proptest! {
+        #![proptest_config(Config::with_cases(32))]
+        #[test]
+        fn prop_adaptive_dense_roundtrip(base in any::<Value>(), len in 1_u128 .. 20_000, step in 1_u128 .. 4) {
+            let base = base >> 1;
+            let values: BTreeSet<Value> = (0 .. len).step_by(step as usize).map(|i| base + i).collect();
+            let (block, read) = adaptive_roundtrip(&values);
+            prop_assert_eq!(&read, &values);
+            if values.len() > 1_000 {
+                prop_assert_eq!(block[0], ADAPTIVE_BITMAP);
+            }
+        }
+
+        #[test]
+        fn prop_adaptive_sparse_roundtrip(values in prop::collection::btree_set(any::<Value>(), 0 .. 500)) {
+            let (_, read) = adaptive_roundtrip(&values);
+            prop_assert_eq!(read, values);
+        }
+    }
+
+    #[test]
+    fn adaptive_codec() {
+        // Key k holds a dense run of 10_000 values, except key 0 whose
+        // values are scattered.
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 20_u64).map(|key| {
+            let values = if key == 0 {
+                (0 .. 100).map(|i| (i as Value) << 70 | 12_345).collect()
+            } else {
+                (0 .. 10_000).map(|i| (key as Value) << 40 | i).collect()
+            };
+            (key, values)
+        }).collect();
+        let size = |codec, block_size| {
+            let tmp = NamedTempFile::new().unwrap();
+            create_with_options(tmp.path(), &bmap, &CreateOptions { codec, block_size, ..CreateOptions::default() }).expect("create");
+            let mut bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+            assert_eq!(bucket.header.codec, codec);
+            assert_eq!(bucket.to_map().expect("to_map"), bmap);
+            assert_eq!(bucket.get(3).expect("get"), bmap.get(&3).cloned());
+            std::fs::metadata(tmp.path()).unwrap().len()
+        };
+        let lz4 = size(Codec::Lz4, 0);
+        let adaptive = size(Codec::Adaptive, 0);
+        assert!(adaptive * 4 < lz4, "adaptive {} vs lz4 {}", adaptive, lz4);
+        // Grouped blocks hold several sets, so they stay LZ4 frames.
+        size(Codec::Adaptive, 4096);
+
+        let (block, _) = adaptive_roundtrip(&bmap[&0]);
+        assert_eq!(block[0], ADAPTIVE_LZ4);
+        assert_eq!(value_set(&bincode_config().serialize(&BTreeMap::from([(1_u128, 2_i64)])).unwrap()), None);
+    }
+
+    #[test]
+    fn varint_values_are_smaller() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 5_000_u64).map(|key| {
+            (key, (0 .. 20).map(|i| Value::from(key * 31 + i * 7) % 1_000).collect())
+        }).collect();
+        let size = |varint_values, block_size| {
+            let tmp = NamedTempFile::new().unwrap();
+            let options = CreateOptions { varint_values, block_size, ..CreateOptions::default() };
+            create_with_options(tmp.path(), &bmap, &options).expect("create");
+            let mut bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+            assert_eq!(bucket.header.value_layout == ValueLayout::Varint, varint_values);
+            assert_eq!(bucket.to_map().expect("to_map"), bmap);
+            bucket.data_size().expect("data_size")
+        };
+        for &block_size in &[0, 4096] {
+            let plain = size(false, block_size);
+            let varint = size(true, block_size);
+            // A block per set keeps the overhead of its lz4 frame.
+            assert!(varint * 3 < plain * 2, "varint {} vs plain {} (block size {})", varint, plain, block_size);
+        }
+    }
+
+    proptest! {
+        #![proptest_config(Config::with_cases(32))]
+        #[test]
+        fn prop_varint_values_roundtrip(bmap in prop::collection::btree_map(0_u64 .. 1_000, prop::collection::btree_set(prop_oneof![0_u128 .. 128, 0_u128 .. 1 << 20, any::<Value>()], 0 .. 8), 0 .. 200),
+                                        codec in any_codec(),
+                                        block_size in prop_oneof![Just(0_u64), Just(256_u64)]) {
+            let tmp = NamedTempFile::new().unwrap();
+            let options = CreateOptions { varint_values: true, codec, block_size, ..CreateOptions::default() };
+            create_with_options(tmp.path(), &bmap, &options).expect("create");
+            let mut bucket = Bucket::open(tmp.path()).and_then(|b| b.check_headers()).expect("open");
+            prop_assert_eq!(bucket.header.value_layout, ValueLayout::Varint);
+            prop_assert_eq!(bucket.to_map().expect("to_map"), bmap.clone());
+            for (key, values) in &bmap {
+                let iter = bucket.values_iter(*key).expect("values_iter").expect("some");
+                prop_assert_eq!(iter.collect::<Result<BTreeSet<Value>>>().expect("values"), values.clone());
+            }
+
+            // Merging into plain values reads them back, and rewriting
+            // keeps the layout.
+            let merged = NamedTempFile::new().unwrap();
+            merge_with_options(tmp.path(), tmp.path(), merged.path(), &CreateOptions { dedup_inputs: true, ..CreateOptions::default() }).expect("merge");
+            let mut merged_bucket = Bucket::open(merged.path()).and_then(|b| b.check_headers()).expect("open");
+            prop_assert_eq!(merged_bucket.header.value_layout, ValueLayout::Plain);
+            prop_assert_eq!(merged_bucket.to_map().expect("to_map"), bmap.clone());
+            let compacted = NamedTempFile::new().unwrap();
+            compact(tmp.path(), compacted.path()).expect("compact");
+            let mut compacted_bucket = Bucket::open(compacted.path()).and_then(|b| b.check_headers()).expect("open");
+            prop_assert_eq!(compacted_bucket.header.value_layout, ValueLayout::Varint);
+            prop_assert_eq!(compacted_bucket.to_map().expect("to_map"), bmap);
+        }
 
-        assert_eq!(
-            HEADER_SIZE as u64,
-            bincode::serialized_size(&BucketHeader::default()).unwrap()
-        );
+        #[test]
+        fn prop_value_varint_roundtrip(value in prop_oneof![0_u128 .. 128, any::<u64>().prop_map(Value::from), any::<Value>()]) {
+            let mut buf = Vec::new();
+            write_value_varint(&mut buf, value);
+            prop_assert!(buf.len() <= 19);
+            prop_assert_eq!(read_value_varint(&mut buf.as_slice()).expect("read_value_varint"), value);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_varint_roundtrip(n in any::<u64>()) {
+            let mut buf = Vec::new();
+            let len = write_varint(&mut buf, n).expect("write_varint");
+            prop_assert_eq!(len, buf.len());
+            let (m, read_len) = read_varint(&mut buf.as_slice()).expect("read_varint");
+            prop_assert_eq!(m, n);
+            prop_assert_eq!(read_len, len);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_varint_dense_index_roundtrip(keys in prop::collection::btree_set(any::<u64>(), 0 .. 500),
+                                             step in 1_usize .. 50) {
+            let entries: Vec<IndexEntry> = keys.iter().enumerate()
+                .map(|(i, key)| IndexEntry { key: *key, offset: (i * i) as u64 })
+                .collect();
+            let mut buf = Vec::new();
+            let offsets = write_dense_index(&mut buf, DenseIndexEncoding::Varint, step, &entries).expect("write_dense_index");
+
+            // Sequential decoding
+            let mut r = buf.as_slice();
+            let mut prev = IndexEntry::zero();
+            for (i, entry) in entries.iter().enumerate() {
+                if is_sparse_entry(i as u64, step, entries.len() as u64) {
+                    prev = IndexEntry::zero();
+                }
+                let (decoded, _) = read_varint_entry(&mut r, prev).expect("read_varint_entry");
+                prop_assert_eq!(decoded, *entry);
+                prev = decoded;
+            }
+            prop_assert!(r.is_empty());
+
+            // Decoding from any entry pointed to by a sparse index
+            for (i, entry) in entries.iter().enumerate() {
+                if is_sparse_entry(i as u64, step, entries.len() as u64) {
+                    let mut r = &buf[offsets[i] as usize ..];
+                    let (decoded, _) = read_varint_entry(&mut r, IndexEntry::zero()).expect("read_varint_entry");
+                    prop_assert_eq!(decoded, *entry);
+                }
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_create_bucket_encodings(keys in prop::collection::btree_set(any::<u64>(), 0 .. 300),
+                                        dense_index in any_dense_index()) {
+            use std::iter::FromIterator;
+
+            let mut bmap = BTreeMap::new();
+            for (i, key) in keys.iter().enumerate() {
+                bmap.insert(*key, BTreeSet::from_iter(0 .. (i as Value % 7 + 1)));
+            }
+
+            let tmp = NamedTempFile::new().unwrap();
+            let options = CreateOptions { dense_index, ..CreateOptions::default() };
+            create_with_options(tmp.path(), &bmap, &options).expect("create");
+
+            let mut bucket = Bucket::open(tmp.path()).expect("open").check_headers().expect("check_headers");
+            prop_assert_eq!(bucket.header.dense_index, dense_index);
+
+            let read_keys: Vec<HashedKey> = bucket.index_entries().expect("index_entries")
+                .map(|entry| entry.expect("entry").key)
+                .collect();
+            prop_assert_eq!(read_keys, keys.iter().cloned().collect::<Vec<_>>());
+
+            let si = bucket.read_sparse_index().expect("sparse index");
+            for (key, actual_values) in &bmap {
+                let (offset_1, offset_2) = si.try_get(*key).expect("try_get");
+                let values = bucket.try_get(*key, offset_1, offset_2)
+                    .expect("try_get (1)")
+                    .expect("try_get (2)");
+                prop_assert_eq!(actual_values, &values);
+            }
+
+            for key in keys.iter().filter_map(|key| key.checked_add(1)) {
+                if bmap.contains_key(&key) {
+                    continue;
+                }
+                if let Some((offset_1, offset_2)) = si.try_get(key) {
+                    prop_assert!(bucket.try_get(key, offset_1, offset_2).expect("try_get").is_none());
+                }
+            }
+        }
     }
 }
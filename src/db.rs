@@ -1,66 +1,953 @@
 use chrono::prelude::*;
 use crate::prelude::*;
 use crate::bucket::*;
+use crate::trace::span;
 use log::{debug, warn};
-use std::collections::BTreeMap;
+use std::collections::{btree_map, BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::RangeBounds;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
-/// A Database consists of multiple buckets; each indexed by a Date.
+/// The default number of buckets a `Db` keeps open.
+pub const DEFAULT_MAX_OPEN_FILES: usize = 64;
+
+/// A Database consists of multiple buckets; each indexed by the UTC
+/// date of its creation timestamp, so the same files map to the same
+/// dates regardless of the host's time zone.
+///
+/// Only the sparse index of each bucket is kept in memory; files are
+/// opened when queried, and at most `max_open_files` of them are kept
+/// open, closing the least recently used first.
+///
+/// A `Db` made with `with_cache` also keeps the values of the most
+/// recently queried keys of each bucket, so that querying hot keys
+/// again doesn't decompress them again.
 pub struct Db {
-    buckets: BTreeMap<Date<Local>, Bucket<Checked>>,
+    buckets: BTreeMap<Date<Utc>, DbBucket>,
+    // Open buckets, least recently used first.
+    open: Vec<(Date<Utc>, PreparedBucket)>,
+    max_open_files: usize,
+    // Cached lookups, least recently used first.
+    cache: Vec<CachedLookup>,
+    cache_capacity: usize,
+    // The number of lookups that read a bucket.
+    lookups: u64,
+    // The bytes read from bucket files, by all the open buckets.
+    bytes_read: Arc<AtomicU64>,
+    // Whether buckets are also looked for in the subdirectories of
+    // `root`.
+    recursive: bool,
+    retry: RetryPolicy,
+    // Opens the file of a bucket to query it.
+    open_file: fn(&Path) -> io::Result<Box<dyn ReadSeek>>,
     pub root: PathBuf,
 }
 
+/// How a `Db` retries a lookup in a bucket that fails with a transient
+/// I/O error, as on a flaky network mount; other errors fail the query
+/// at once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// How many times a lookup is tried, the first time included; 1
+    /// never retries.
+    pub max_attempts: u32,
+    /// The wait before the first retry, doubled before each next one.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy { max_attempts: 1, backoff: Duration::from_millis(100) }
+    }
+}
+
+impl RetryPolicy {
+    /// The wait before the `retry`th retry, counting from 1.
+    fn delay(&self, retry: u32) -> Duration {
+        self.backoff.saturating_mul(1_u32.checked_shl(retry - 1).unwrap_or(u32::MAX))
+    }
+}
+
+/// How a `Db::query_verbose` went.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct QueryStats {
+    /// The buckets in the range whose files were read.
+    pub buckets_searched: u64,
+    /// The buckets in the range answered from the cache.
+    pub buckets_skipped: u64,
+    pub bytes_read: u64,
+}
+
+/// A bucket file counting the bytes read from it.
+struct CountingReader {
+    file: Box<dyn ReadSeek>,
+    count: Arc<AtomicU64>,
+}
+
+impl Read for CountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl Seek for CountingReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+/// What a `Db` remembers of a bucket while its file is closed.
+struct DbBucket {
+    path: PathBuf,
+    si: SparseIndex,
+    // The modification time and length of the file when it was
+    // loaded, to tell when it was rewritten.
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+/// An iterator over the dates and paths of the buckets of a `Db`, in
+/// date order.
+pub struct Buckets<'a> {
+    inner: btree_map::Iter<'a, Date<Utc>, DbBucket>,
+}
+
+/// An iterator over the buckets of a `Db` in date order, each opened
+/// and checked when it is reached; made by `Db::open_buckets`.
+pub struct OpenBuckets<'a> {
+    inner: btree_map::Iter<'a, Date<Utc>, DbBucket>,
+    open_file: fn(&Path) -> io::Result<Box<dyn ReadSeek>>,
+}
+
+/// The values of `hash` in the bucket for `date`.
+struct CachedLookup {
+    date: Date<Utc>,
+    hash: HashedKey,
+    values: Option<BTreeSet<Value>>,
+}
+
+/// An inclusive range of bucket dates, written `START:END` with both
+/// dates in the `%Y-%m-%d` format.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateRange {
+    pub start: Date<Utc>,
+    pub end: Date<Utc>,
+}
+
+impl FromStr for DateRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<DateRange> {
+        let mut parts = s.splitn(2, ':');
+        let start = parse_date(parts.next().unwrap_or(""))?;
+        let end = parse_date(parts.next().ok_or(Error::DateParseError)?)?;
+        if start > end {
+            return Err(Error::DateParseError);
+        }
+        Ok(DateRange { start, end })
+    }
+}
+
+impl From<DateRange> for (Date<Utc>, Date<Utc>) {
+    fn from(range: DateRange) -> (Date<Utc>, Date<Utc>) {
+        (range.start, range.end)
+    }
+}
+
+/// The dates of the first and last buckets a value is in; see
+/// `Db::history`.
+pub type FirstLastSeen = (Date<Utc>, Date<Utc>);
+
+/// Parses a `%Y-%m-%d` date as a UTC date, matching how `Db` keys buckets.
+pub fn parse_date(s: &str) -> Result<Date<Utc>> {
+    let naive = NaiveDate::parse_from_str(s, "%Y-%m-%d")?;
+    Ok(Utc.from_utc_date(&naive))
+}
+
 impl Db {
     pub fn new<P: AsRef<Path>>(root: P) -> Db {
         Db {
             buckets: BTreeMap::new(),
+            open: Vec::new(),
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            cache: Vec::new(),
+            cache_capacity: 0,
+            lookups: 0,
+            bytes_read: Arc::new(AtomicU64::new(0)),
+            recursive: false,
+            retry: RetryPolicy::default(),
+            open_file: |path| Ok(Box::new(File::open(path)?)),
             root: root.as_ref().to_path_buf(),
         }
     }
 
+    /// Like `open`, and caches the values of up to `capacity` lookups.
+    pub fn with_cache<P: AsRef<Path>>(root: P, capacity: usize) -> Result<Db> {
+        let mut db = Db::open(root)?;
+        db.set_cache_capacity(capacity);
+        Ok(db)
+    }
+
+    /// Sets how many lookups are cached; 0 disables the cache.
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.cache_capacity = capacity;
+        self.trim_cache(capacity);
+    }
+
+    fn trim_cache(&mut self, max: usize) {
+        if self.cache.len() > max {
+            let excess = self.cache.len() - max;
+            self.cache.drain(.. excess);
+        }
+    }
+
+    /// Sets how many bucket files may be open at once; at least one
+    /// always is while querying.
+    pub fn set_max_open_files(&mut self, max_open_files: usize) {
+        self.max_open_files = max_open_files.max(1);
+        self.close_excess(self.max_open_files);
+    }
+
+    /// Sets how lookups that fail with a transient I/O error are
+    /// retried; by default they aren't.
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    /// The number of bucket files currently open.
+    pub fn open_files(&self) -> usize {
+        self.open.len()
+    }
+
+    fn close_excess(&mut self, max: usize) {
+        while self.open.len() > max {
+            let (date, _) = self.open.remove(0);
+            debug!("closed bucket for date: {}", date);
+        }
+    }
+
+    /// Opens every bucket found in `root`; files that cannot be opened
+    /// or whose headers cannot be validated are logged and skipped.
     pub fn open<P: AsRef<Path>>(root: P) -> Result<Db> {
-        use std::fs::*;
+        Db::open_with(root, false, false)
+    }
+
+    /// Like `open`, but fails with `Error::InvalidBuckets` listing
+    /// every file that cannot be opened or whose headers cannot be
+    /// validated.
+    pub fn open_strict<P: AsRef<Path>>(root: P) -> Result<Db> {
+        Db::open_with(root, true, false)
+    }
+
+    /// Like `open`, also loading the buckets in every subdirectory of
+    /// `root`, such as `root/2024/01/15.binstore`; `reload` looks in
+    /// them too.
+    pub fn open_recursive<P: AsRef<Path>>(root: P) -> Result<Db> {
+        Db::open_with(root, false, true)
+    }
+
+    /// Like `open_recursive`, failing like `open_strict`.
+    pub fn open_recursive_strict<P: AsRef<Path>>(root: P) -> Result<Db> {
+        Db::open_with(root, true, true)
+    }
+
+    fn open_with<P: AsRef<Path>>(root: P, strict: bool, recursive: bool) -> Result<Db> {
         let mut db = Db::new(root.as_ref());
-        let entries = read_dir(root.as_ref()).expect("root is not a directory!");
-        for entry in entries {
-            let entry = entry?;
-            let path = entry.path();
-            if !path.is_dir() {
-                let bucket = Bucket::open(&path)?;
-                match bucket.check_headers() {
-                    Ok(bucket) => {
-                        let datetime = Local.timestamp(bucket.header.timestamp, 0);
-                        db.buckets.insert(datetime.date(), bucket);
-                    },
-                    Err(e) => {
-                        warn!("could not load bucket from file {:?} with error: {}", &path, e);
-                    }
+        db.recursive = recursive;
+        db.scan(strict)?;
+        Ok(db)
+    }
+
+    /// Re-scans `root`, loading the buckets whose files appeared or
+    /// were rewritten since, by modification time or length, and
+    /// dropping those whose files are gone; other buckets keep their
+    /// sparse index and file handle.  Invalid buckets are logged and
+    /// skipped, as in `open`.  The cached values are dropped, as the
+    /// files may have been rewritten.
+    pub fn reload(&mut self) -> Result<()> {
+        self.cache.clear();
+        self.scan(false)
+    }
+
+    fn scan(&mut self, strict: bool) -> Result<()> {
+        let mut seen = BTreeSet::new();
+        let mut invalid = Vec::new();
+        for path in bucket_files(&self.root, self.recursive)? {
+            let (modified, len) = match std::fs::metadata(&path) {
+                Ok(metadata) => (metadata.modified().ok(), metadata.len()),
+                Err(e) => {
+                    warn!("could not load bucket from file {:?} with error: {}", &path, e);
+                    invalid.push(path);
+                    continue;
+                }
+            };
+            seen.insert(path.clone());
+            let loaded = self.buckets.iter().find(|(_, bucket)| bucket.path == path)
+                .map(|(date, bucket)| (*date, bucket.modified == modified && bucket.len == len));
+            match loaded {
+                Some((_, true)) => continue,
+                Some((date, false)) => {
+                    debug!("bucket {:?} for date {} changed, reloading it", &path, date);
+                    self.buckets.remove(&date);
+                    self.open.retain(|&(open_date, _)| open_date != date);
+                }
+                None => {}
+            }
+            // The file is closed as soon as its sparse index is read.
+            match Bucket::open(&path).and_then(|bucket| bucket.check_headers()).and_then(|bucket| bucket.prepare()) {
+                Ok(bucket) => {
+                    let date = Utc.timestamp(bucket.header().timestamp, 0).date();
+                    debug!("loaded bucket {:?} for date {}: {}", &path, date, bucket.header());
+                    self.open.retain(|&(open_date, _)| open_date != date);
+                    self.buckets.insert(date, DbBucket { path, si: bucket.sparse_index().clone(), modified, len });
+                },
+                Err(e) => {
+                    warn!("could not load bucket from file {:?} with error: {}", &path, e);
+                    invalid.push(path);
                 }
             }
         }
-        Ok(db)
+        self.buckets.retain(|_, bucket| seen.contains(&bucket.path));
+        let buckets = &self.buckets;
+        self.open.retain(|(date, _)| buckets.contains_key(date));
+        if strict && !invalid.is_empty() {
+            invalid.sort();
+            return Err(Error::InvalidBuckets(invalid));
+        }
+        Ok(())
+    }
+
+    /// The values of `hash` in the buckets from `start_date` to
+    /// `end_date`: the values of each bucket in increasing order, the
+    /// buckets in date order.  A value found in several buckets is
+    /// repeated; see `query_sorted`.
+    pub fn query(&mut self, hash: HashedKey, start_date: Date<Utc>, end_date: Date<Utc>) -> Result<Vec<Value>> {
+        self.query_bounds(hash, start_date ..= end_date)
+    }
+
+    /// Like `query`, over the buckets of any range of dates, such as
+    /// `start_date ..` for every bucket from `start_date` on.
+    pub fn query_bounds<R: RangeBounds<Date<Utc>>>(&mut self, hash: HashedKey, range: R) -> Result<Vec<Value>> {
+        Ok(self.query_bounds_verbose(hash, range)?.0)
+    }
+
+    /// Like `query`, also telling which buckets of the range were
+    /// searched, and how much was read from their files.
+    pub fn query_verbose(&mut self, hash: HashedKey, start_date: Date<Utc>, end_date: Date<Utc>) -> Result<(Vec<Value>, QueryStats)> {
+        self.query_bounds_verbose(hash, start_date ..= end_date)
     }
 
-    pub fn query(&mut self, hash: HashedKey, start_date: Date<Local>, end_date: Date<Local>) -> Result<Vec<Value>> {
-        let range = self.buckets.range_mut(start_date ..= end_date);
+    /// Like `query_bounds`, also telling which buckets of the range
+    /// were searched, and how much was read from their files.
+    pub fn query_bounds_verbose<R: RangeBounds<Date<Utc>>>(&mut self, hash: HashedKey, range: R) -> Result<(Vec<Value>, QueryStats)> {
+        let span = span!("query", key = hash, bytes = tracing::field::Empty);
+        let dates: Vec<Date<Utc>> = self.buckets.range(range).map(|(date, _)| *date).collect();
         let mut v = Vec::new();
+        let lookups = self.lookups;
+        let bytes_read = self.bytes_read.load(Ordering::Relaxed);
 
-        for (date, bucket) in range {
+        for &date in &dates {
             debug!("querying bucket for date: {} with hash: {}", date, hash);
-            match bucket.get(hash)? {
-                Some(set) => {
-                    for e in set {
-                        v.push(e);
-                    }
-                },
-                None => ()
-            };
+            if let Some(set) = self.get(date, hash)? {
+                v.extend(set);
+            }
+        }
+        let searched = self.lookups - lookups;
+        let stats = QueryStats {
+            buckets_searched: searched,
+            buckets_skipped: dates.len() as u64 - searched,
+            bytes_read: self.bytes_read.load(Ordering::Relaxed) - bytes_read,
+        };
+        span.record("bytes", stats.bytes_read);
+        Ok((v, stats))
+    }
+
+    /// Like `query`, with every value once, in increasing order, however
+    /// the values are spread over the buckets.
+    pub fn query_sorted(&mut self, hash: HashedKey, start_date: Date<Utc>, end_date: Date<Utc>) -> Result<BTreeSet<Value>> {
+        Ok(self.query(hash, start_date, end_date)?.into_iter().collect())
+    }
+
+    /// Every value of `hash` in any bucket, with the dates of the first
+    /// and last buckets it is in.  Buckets whose sparse index rules out
+    /// `hash` are not read.
+    pub fn history(&mut self, hash: HashedKey) -> Result<BTreeMap<Value, FirstLastSeen>> {
+        let dates: Vec<Date<Utc>> = self.dates().collect();
+        let mut history: BTreeMap<Value, FirstLastSeen> = BTreeMap::new();
+        for date in dates {
+            if let Some(set) = self.get(date, hash)? {
+                for value in set {
+                    // Dates only increase, so the first date seen stays.
+                    history.entry(value).or_insert((date, date)).1 = date;
+                }
+            }
+        }
+        Ok(history)
+    }
+
+    /// The values of `hash` in the bucket for `date`, from the cache if
+    /// possible.
+    fn get(&mut self, date: Date<Utc>, hash: HashedKey) -> Result<Option<BTreeSet<Value>>> {
+        if self.cache_capacity == 0 {
+            return self.lookup(date, hash);
+        }
+        let lookup = match self.cache.iter().position(|lookup| lookup.date == date && lookup.hash == hash) {
+            Some(i) => self.cache.remove(i),
+            None => {
+                let values = self.lookup(date, hash)?;
+                self.trim_cache(self.cache_capacity - 1);
+                CachedLookup { date, hash, values }
+            }
+        };
+        let values = lookup.values.clone();
+        self.cache.push(lookup);
+        Ok(values)
+    }
+
+    /// The values of `hash` in the bucket for `date`, read from its
+    /// file.  Transient errors are retried following the retry policy,
+    /// reopening the file each time.
+    fn lookup(&mut self, date: Date<Utc>, hash: HashedKey) -> Result<Option<BTreeSet<Value>>> {
+        self.lookups += 1;
+        let mut attempt = 1;
+        loop {
+            match self.bucket(date).and_then(|bucket| bucket.get(hash)) {
+                Err(e) if e.is_transient() && attempt < self.retry.max_attempts => {
+                    let delay = self.retry.delay(attempt);
+                    warn!("retrying bucket for date {} in {:?} after error: {}", date, delay, e);
+                    self.open.retain(|&(open_date, _)| open_date != date);
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
         }
-        Ok(v)
+    }
+
+    /// The open bucket for `date`, opening it if needed.
+    fn bucket(&mut self, date: Date<Utc>) -> Result<&mut PreparedBucket> {
+        let prepared = match self.open.iter().position(|&(open_date, _)| open_date == date) {
+            Some(i) => self.open.remove(i).1,
+            None => {
+                let bucket = &self.buckets[&date];
+                let file = CountingReader { file: (self.open_file)(&bucket.path)?, count: self.bytes_read.clone() };
+                let prepared = Bucket::from_reader(Box::new(file), bucket.path.clone())
+                    .check_headers()?
+                    .prepare_with(bucket.si.clone())?;
+                self.close_excess(self.max_open_files - 1);
+                prepared
+            }
+        };
+        self.open.push((date, prepared));
+        Ok(&mut self.open.last_mut().expect("just pushed").1)
     }
 
     pub fn len(&self) -> usize {
         self.buckets.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// The dates of the buckets, in order.
+    pub fn dates(&self) -> impl Iterator<Item = Date<Utc>> + '_ {
+        self.buckets.keys().cloned()
+    }
+
+    /// The dates and paths of the buckets, in date order.  The files
+    /// are not opened.
+    pub fn iter(&self) -> Buckets<'_> {
+        Buckets { inner: self.buckets.iter() }
+    }
+
+    /// The dates and buckets, in date order.  Each file is opened when
+    /// the iterator reaches it and closed when the caller drops its
+    /// bucket, outside of the files `max_open_files` counts; a bucket
+    /// that can no longer be opened or checked yields its error.
+    pub fn open_buckets(&self) -> OpenBuckets<'_> {
+        OpenBuckets { inner: self.buckets.iter(), open_file: self.open_file }
+    }
+}
+
+/// The files in `dir`, and with `recursive`, in its subdirectories;
+/// fails if any of these directories can't be read.
+fn bucket_files(dir: &Path, recursive: bool) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let entries = std::fs::read_dir(dir)?;
+    for entry in entries {
+        let path = entry?.path();
+        if !path.is_dir() {
+            files.push(path);
+        } else if recursive {
+            files.extend(bucket_files(&path, true)?);
+        }
+    }
+    Ok(files)
+}
+
+/// Yields the dates and paths of the buckets, like `Db::iter`, rather
+/// than `Bucket<Checked>`s: a `Db` only keeps the sparse indexes, and
+/// a few files open for queries, so it has no buckets to lend.
+/// `Db::open_buckets` opens them one at a time, and as they are owned
+/// there is no mutable variant.
+impl<'a> IntoIterator for &'a Db {
+    type Item = (&'a Date<Utc>, &'a Path);
+    type IntoIter = Buckets<'a>;
+
+    fn into_iter(self) -> Buckets<'a> {
+        self.iter()
+    }
+}
+
+impl<'a> Iterator for Buckets<'a> {
+    type Item = (&'a Date<Utc>, &'a Path);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(date, bucket)| (date, bucket.path.as_path()))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a> Iterator for OpenBuckets<'a> {
+    type Item = (Date<Utc>, Result<Bucket<Checked>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (date, bucket) = self.inner.next()?;
+        let opened = (self.open_file)(&bucket.path)
+            .map_err(Error::from)
+            .and_then(|file| Bucket::from_reader(file, bucket.path.clone()).check_headers());
+        Some((*date, opened))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bucket;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::ops::Bound;
+    use std::iter::FromIterator;
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn open_with_corrupt_bucket() {
+        let dir = tempdir().unwrap();
+
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1_u64, BTreeSet::from_iter(0 .. 10_u128));
+        bucket::create(dir.path().join("valid.binstore"), &bmap).expect("create");
+
+        let corrupt = dir.path().join("corrupt.binstore");
+        std::fs::write(&corrupt, b"not a bucket").expect("write");
+
+        let db = Db::open(dir.path()).expect("lenient open");
+        assert_eq!(db.len(), 1);
+
+        match Db::open_strict(dir.path()) {
+            Err(Error::InvalidBuckets(paths)) => assert_eq!(paths, vec![corrupt.clone()]),
+            _ => panic!("strict open should fail on a corrupt bucket"),
+        }
+
+        // A file that can't be opened counts as invalid too.
+        let dangling = dir.path().join("dangling.binstore");
+        std::os::unix::fs::symlink(dir.path().join("missing"), &dangling).expect("symlink");
+        assert_eq!(Db::open(dir.path()).expect("lenient open").len(), 1);
+        match Db::open_strict(dir.path()) {
+            Err(Error::InvalidBuckets(paths)) => assert_eq!(paths, vec![corrupt, dangling]),
+            _ => panic!("strict open should fail on a file it can't open"),
+        }
+
+        // A root that isn't a directory fails to open, without a panic.
+        assert!(matches!(Db::open(dir.path().join("missing")), Err(Error::IoError(_))));
+        assert!(matches!(Db::open(dir.path().join("valid.binstore")), Err(Error::IoError(_))));
+    }
+
+    /// Creates a bucket at `path` stamped with `timestamp`.
+    fn create_at(path: &Path, bmap: &BTreeMap<HashedKey, BTreeSet<Value>>, timestamp: i64) {
+        use std::fs::OpenOptions;
+
+        bucket::create(path, bmap).expect("create");
+        let mut header = Bucket::open(path).and_then(|b| b.check_headers()).expect("open").header;
+        header.timestamp = timestamp;
+        let mut file = OpenOptions::new().write(true).open(path).expect("open for write");
+        header.write_to(&mut file).expect("write_to");
+    }
+
+    #[test]
+    fn buckets_keyed_by_utc_date() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("late.binstore");
+
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1_u64, BTreeSet::from_iter(0 .. 10_u128));
+
+        // 2024-01-01T23:30:00Z falls on a different calendar date in
+        // most zones east of UTC.
+        let timestamp = Utc.ymd(2024, 1, 1).and_hms(23, 30, 0).timestamp();
+        create_at(&path, &bmap, timestamp);
+
+        let mut db = Db::open(dir.path()).expect("open");
+        let day = parse_date("2024-01-01").unwrap();
+        assert_eq!(db.query(1, day, day).expect("query"), Vec::from_iter(0 .. 10_u128));
+
+        let next = parse_date("2024-01-02").unwrap();
+        assert!(db.query(1, next, next).expect("query").is_empty());
+    }
+
+    #[test]
+    fn history() {
+        let dir = tempdir().unwrap();
+        let days: Vec<Date<Utc>> = (1 .. 5).map(|day| Utc.ymd(2024, 1, day)).collect();
+        let values: [&[Value]; 4] = [&[1, 2], &[2, 3], &[], &[1, 4]];
+        for (date, values) in days.iter().zip(&values) {
+            let mut bmap = BTreeMap::new();
+            bmap.insert(0_u64, BTreeSet::from_iter(values.iter().cloned().map(|value| value + 100)));
+            if !values.is_empty() {
+                bmap.insert(1_u64, BTreeSet::from_iter(values.iter().cloned()));
+            }
+            create_at(&dir.path().join(format!("{}.binstore", date)), &bmap, date.and_hms(12, 0, 0).timestamp());
+        }
+
+        let mut db = Db::open(dir.path()).expect("open");
+        let expected = BTreeMap::from([
+            (1, (days[0], days[3])),
+            (2, (days[0], days[1])),
+            (3, (days[1], days[1])),
+            (4, (days[3], days[3])),
+        ]);
+        assert_eq!(db.history(1).expect("history"), expected);
+        assert!(db.history(2).expect("history").is_empty());
+    }
+
+    #[test]
+    fn reload_picks_up_changes() {
+        let dir = tempdir().unwrap();
+        let day_1 = parse_date("2024-01-01").unwrap();
+        let day_2 = parse_date("2024-01-02").unwrap();
+
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1_u64, BTreeSet::from_iter(0 .. 10_u128));
+        let first = dir.path().join("first.binstore");
+        create_at(&first, &bmap, day_1.and_hms(12, 0, 0).timestamp());
+
+        let mut db = Db::open(dir.path()).expect("open");
+        assert_eq!(db.len(), 1);
+        assert!(db.query(1, day_2, day_2).expect("query").is_empty());
+
+        bmap.insert(1_u64, BTreeSet::from_iter(10 .. 20_u128));
+        create_at(&dir.path().join("second.binstore"), &bmap, day_2.and_hms(12, 0, 0).timestamp());
+        db.reload().expect("reload");
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.query(1, day_2, day_2).expect("query"), Vec::from_iter(10 .. 20_u128));
+        assert_eq!(db.query(1, day_1, day_2).expect("query"), Vec::from_iter(0 .. 20_u128));
+
+        std::fs::remove_file(&first).expect("remove");
+        db.reload().expect("reload");
+        assert_eq!(db.len(), 1);
+        assert!(db.query(1, day_1, day_1).expect("query").is_empty());
+    }
+
+    #[test]
+    fn reload_rewritten_bucket() {
+        let dir = tempdir().unwrap();
+        let day = parse_date("2024-01-01").unwrap();
+        let path = dir.path().join("bucket.binstore");
+
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1_u64, BTreeSet::from_iter(0 .. 10_u128));
+        create_at(&path, &bmap, day.and_hms(12, 0, 0).timestamp());
+        let mut db = Db::open(dir.path()).expect("open");
+        assert_eq!(db.query(1, day, day).expect("query"), Vec::from_iter(0 .. 10_u128));
+        assert_eq!(db.open_files(), 1);
+
+        // Rewrite the bucket in place, with more keys and other values.
+        for key in 2 .. 200_u64 {
+            bmap.insert(key, BTreeSet::from_iter(0 .. 3_u128));
+        }
+        bmap.insert(1_u64, BTreeSet::from_iter(100 .. 105_u128));
+        create_at(&path, &bmap, day.and_hms(12, 0, 0).timestamp());
+        db.reload().expect("reload");
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.open_files(), 0);
+        assert_eq!(db.query(1, day, day).expect("query"), Vec::from_iter(100 .. 105_u128));
+        assert_eq!(db.query(150, day, day).expect("query"), Vec::from_iter(0 .. 3_u128));
+    }
+
+    #[test]
+    fn bounded_open_files() {
+        let dir = tempdir().unwrap();
+        let mut expected = Vec::new();
+        for day in 1 .. 6 {
+            let mut bmap = BTreeMap::new();
+            let values = BTreeSet::from_iter(day * 10 .. day * 10 + 3);
+            expected.extend(values.iter().cloned());
+            bmap.insert(1_u64, values);
+            let date = Utc.ymd(2024, 1, day as u32);
+            create_at(&dir.path().join(format!("{}.binstore", day)), &bmap, date.and_hms(12, 0, 0).timestamp());
+        }
+
+        let mut db = Db::open(dir.path()).expect("open");
+        assert_eq!(db.open_files(), 0);
+        db.set_max_open_files(2);
+
+        let start = parse_date("2024-01-01").unwrap();
+        let end = parse_date("2024-01-05").unwrap();
+        for _ in 0 .. 3 {
+            assert_eq!(db.query(1, start, end).expect("query"), expected);
+            assert_eq!(db.open_files(), 2);
+        }
+
+        db.set_max_open_files(0);
+        assert_eq!(db.query(1, end, end).expect("query"), vec![50, 51, 52]);
+        assert_eq!(db.open_files(), 1);
+    }
+
+    #[test]
+    fn open_ended_ranges() {
+        let dir = tempdir().unwrap();
+        for day in 1 .. 6 {
+            let bmap = BTreeMap::from([(1_u64, BTreeSet::from([day as Value]))]);
+            let date = Utc.ymd(2024, 1, day as u32);
+            create_at(&dir.path().join(format!("{}.binstore", day)), &bmap, date.and_hms(12, 0, 0).timestamp());
+        }
+        let mut db = Db::open(dir.path()).expect("open");
+        let (day_2, day_4) = (parse_date("2024-01-02").unwrap(), parse_date("2024-01-04").unwrap());
+
+        assert_eq!(db.query_bounds(1, .. day_4).expect("query_bounds"), vec![1, 2, 3]);
+        assert_eq!(db.query_bounds(1, ..= day_4).expect("query_bounds"), vec![1, 2, 3, 4]);
+        assert_eq!(db.query_bounds(1, day_4 ..).expect("query_bounds"), vec![4, 5]);
+        assert_eq!(db.query_bounds(1, day_2 ..= day_4).expect("query_bounds"), db.query(1, day_2, day_4).expect("query"));
+        assert_eq!(db.query_bounds(1, ..).expect("query_bounds"), vec![1, 2, 3, 4, 5]);
+        let (values, stats) = db.query_bounds_verbose(1, (Bound::Excluded(day_2), Bound::Unbounded)).expect("query_bounds_verbose");
+        assert_eq!(values, vec![3, 4, 5]);
+        assert_eq!(stats.buckets_searched + stats.buckets_skipped, 3);
+    }
+
+    #[test]
+    fn cached_queries() {
+        let dir = tempdir().unwrap();
+        let day = parse_date("2024-01-01").unwrap();
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1_u64, BTreeSet::from_iter(0 .. 10_u128));
+        bmap.insert(2_u64, BTreeSet::from_iter(10 .. 20_u128));
+        create_at(&dir.path().join("bucket.binstore"), &bmap, day.and_hms(12, 0, 0).timestamp());
+
+        let mut db = Db::with_cache(dir.path(), 1).expect("open");
+        assert_eq!(db.query(1, day, day).expect("query"), Vec::from_iter(0 .. 10_u128));
+        assert_eq!(db.lookups, 1);
+        assert_eq!(db.query(1, day, day).expect("query"), Vec::from_iter(0 .. 10_u128));
+        assert_eq!(db.lookups, 1);
+
+        // Only one lookup fits, so key 1 is evicted by key 2.
+        assert_eq!(db.query(2, day, day).expect("query"), Vec::from_iter(10 .. 20_u128));
+        assert_eq!(db.query(1, day, day).expect("query"), Vec::from_iter(0 .. 10_u128));
+        assert_eq!(db.lookups, 3);
+
+        db.reload().expect("reload");
+        assert_eq!(db.query(1, day, day).expect("query"), Vec::from_iter(0 .. 10_u128));
+        assert_eq!(db.lookups, 4);
+
+        // Without a cache, every query reads the bucket.
+        let mut db = Db::open(dir.path()).expect("open");
+        db.query(1, day, day).expect("query");
+        db.query(1, day, day).expect("query");
+        assert_eq!(db.lookups, 2);
+    }
+
+    #[test]
+    fn nested_buckets() {
+        use std::fs;
+
+        let dir = tempdir().unwrap();
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1_u64, BTreeSet::from_iter(0 .. 10_u128));
+        create_at(&dir.path().join("2023-12-31.binstore"), &bmap, Utc.ymd(2023, 12, 31).and_hms(12, 0, 0).timestamp());
+        for day in 14 ..= 15 {
+            let month = dir.path().join("2024").join("01");
+            fs::create_dir_all(&month).unwrap();
+            let timestamp = Utc.ymd(2024, 1, day).and_hms(12, 0, 0).timestamp();
+            create_at(&month.join(format!("2024-01-{}.binstore", day)), &bmap, timestamp);
+        }
+
+        assert_eq!(Db::open(dir.path()).expect("open").len(), 1);
+        let mut db = Db::open_recursive(dir.path()).expect("open_recursive");
+        assert_eq!(db.len(), 3);
+        let day = parse_date("2024-01-15").unwrap();
+        assert_eq!(db.query(1, day, day).expect("query"), Vec::from_iter(0 .. 10_u128));
+
+        // Reloading keeps looking in the subdirectories.
+        let timestamp = Utc.ymd(2024, 2, 1).and_hms(12, 0, 0).timestamp();
+        fs::create_dir_all(dir.path().join("2024").join("02")).unwrap();
+        create_at(&dir.path().join("2024").join("02").join("2024-02-01.binstore"), &bmap, timestamp);
+        db.reload().expect("reload");
+        assert_eq!(db.len(), 4);
+    }
+
+    #[test]
+    fn retried_transient_errors() {
+        use std::sync::atomic::AtomicU32;
+
+        // The reads of the flaky files left to fail.
+        static FAILURES: AtomicU32 = AtomicU32::new(0);
+
+        struct Flaky(File);
+
+        impl Read for Flaky {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if FAILURES.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "flaky"));
+                }
+                self.0.read(buf)
+            }
+        }
+
+        impl Seek for Flaky {
+            fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+                self.0.seek(pos)
+            }
+        }
+
+        let dir = tempdir().unwrap();
+        let day = parse_date("2024-01-01").unwrap();
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1_u64, BTreeSet::from_iter(0 .. 10_u128));
+        create_at(&dir.path().join("bucket.binstore"), &bmap, day.and_hms(12, 0, 0).timestamp());
+
+        let mut db = Db::open(dir.path()).expect("open");
+        db.open_file = |path| Ok(Box::new(Flaky(File::open(path)?)));
+        FAILURES.store(3, Ordering::SeqCst);
+        assert!(db.query(1, day, day).is_err());
+
+        db.set_retry_policy(RetryPolicy { max_attempts: 3, backoff: Duration::from_millis(1) });
+        FAILURES.store(3, Ordering::SeqCst);
+        assert!(db.query(1, day, day).is_err());
+        FAILURES.store(3, Ordering::SeqCst);
+        db.set_retry_policy(RetryPolicy { max_attempts: 4, backoff: Duration::from_millis(1) });
+        assert_eq!(db.query(1, day, day).expect("query"), Vec::from_iter(0 .. 10_u128));
+        assert_eq!(RetryPolicy { max_attempts: 4, backoff: Duration::from_millis(1) }.delay(3), Duration::from_millis(4));
+
+        // Other errors aren't retried.
+        assert!(!Error::from(io::Error::new(io::ErrorKind::NotFound, "gone")).is_transient());
+    }
+
+    #[test]
+    fn query_stats() {
+        let dir = tempdir().unwrap();
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1_u64, BTreeSet::from_iter(0 .. 10_u128));
+        for day in 1 ..= 5 {
+            let timestamp = Utc.ymd(2024, 1, day).and_hms(12, 0, 0).timestamp();
+            create_at(&dir.path().join(format!("{}.binstore", day)), &bmap, timestamp);
+        }
+        let start = parse_date("2024-01-02").unwrap();
+        let end = parse_date("2024-01-04").unwrap();
+
+        let mut db = Db::with_cache(dir.path(), 16).expect("open");
+        let (values, stats) = db.query_verbose(1, start, end).expect("query_verbose");
+        assert_eq!(values.len(), 30);
+        assert_eq!(stats.buckets_searched, 3);
+        assert_eq!(stats.buckets_skipped, 0);
+        assert!(stats.bytes_read > 0);
+
+        // The values of the range are cached now.
+        let (_, stats) = db.query_verbose(1, start, parse_date("2024-01-05").unwrap()).expect("query_verbose");
+        assert_eq!(stats.buckets_searched, 1);
+        assert_eq!(stats.buckets_skipped, 3);
+        let (_, stats) = db.query_verbose(1, start, end).expect("query_verbose");
+        assert_eq!(stats, QueryStats { buckets_searched: 0, buckets_skipped: 3, bytes_read: 0 });
+    }
+
+    #[test]
+    fn sorted_query_is_stable() {
+        let days = [(1, 20 .. 30_u128), (2, 0 .. 10), (3, 5 .. 25)];
+        let mut outputs = Vec::new();
+        for order in &[[0, 1, 2], [2, 0, 1]] {
+            let dir = tempdir().unwrap();
+            for (n, &i) in order.iter().enumerate() {
+                let (day, ref values) = days[i];
+                let mut bmap = BTreeMap::new();
+                bmap.insert(1_u64, BTreeSet::from_iter(values.clone()));
+                let timestamp = Utc.ymd(2024, 1, day).and_hms(12, 0, 0).timestamp();
+                create_at(&dir.path().join(format!("{}.binstore", n)), &bmap, timestamp);
+            }
+            let mut db = Db::open(dir.path()).expect("open");
+            let (start, end) = "2024-01-01:2024-01-03".parse::<DateRange>().unwrap().into();
+            outputs.push(db.query_sorted(1, start, end).expect("query_sorted"));
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[0], BTreeSet::from_iter(0 .. 30));
+    }
+
+    #[test]
+    fn iterate_buckets() {
+        let dir = tempdir().unwrap();
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1_u64, BTreeSet::from_iter(0 .. 3_u128));
+        for day in &[3, 1, 2] {
+            let date = Utc.ymd(2024, 1, *day);
+            create_at(&dir.path().join(format!("{}.binstore", day)), &bmap, date.and_hms(12, 0, 0).timestamp());
+        }
+
+        let db = Db::open(dir.path()).expect("open");
+        assert_eq!((&db).into_iter().count(), db.len());
+        let mut paths = Vec::new();
+        for (date, path) in &db {
+            assert_eq!(Bucket::read_header(path).expect("read_header").timestamp, date.and_hms(12, 0, 0).timestamp());
+            paths.push(path.to_path_buf());
+        }
+        let expected: Vec<PathBuf> = (1 .. 4).map(|day| dir.path().join(format!("{}.binstore", day))).collect();
+        assert_eq!(paths, expected);
+
+        let dates: Vec<Date<Utc>> = db.dates().collect();
+        assert_eq!(dates, vec![Utc.ymd(2024, 1, 1), Utc.ymd(2024, 1, 2), Utc.ymd(2024, 1, 3)]);
+
+        let mut opened = 0;
+        for (date, bucket) in db.open_buckets() {
+            let mut bucket = bucket.expect("open");
+            assert_eq!(bucket.header.timestamp, date.and_hms(12, 0, 0).timestamp());
+            assert_eq!(bucket.get(1).expect("get"), Some(BTreeSet::from_iter(0 .. 3_u128)));
+            opened += 1;
+        }
+        assert_eq!(opened, db.len());
+        assert_eq!(db.open_files(), 0);
+
+        std::fs::write(dir.path().join("2.binstore"), b"not a bucket").expect("write");
+        let errors: Vec<Date<Utc>> = db.open_buckets().filter(|(_, bucket)| bucket.is_err()).map(|(date, _)| date).collect();
+        assert_eq!(errors, vec![Utc.ymd(2024, 1, 2)]);
+    }
+
+    #[test]
+    fn date_range_from_str() {
+        let range: DateRange = "2024-01-01:2024-01-31".parse().expect("valid range");
+        assert_eq!(range.start, parse_date("2024-01-01").unwrap());
+        assert_eq!(range.end, parse_date("2024-01-31").unwrap());
+
+        let (start, end) = "2024-02-29:2024-02-29".parse::<DateRange>().expect("single day").into();
+        assert_eq!(start, end);
+
+        assert!(matches!("2024-01-31:2024-01-01".parse::<DateRange>(), Err(Error::DateParseError)));
+
+        assert!("2024-01-01".parse::<DateRange>().is_err());
+        assert!("2024-01-01:".parse::<DateRange>().is_err());
+        assert!(":2024-01-31".parse::<DateRange>().is_err());
+        assert!("2024-01-01:2024-13-01".parse::<DateRange>().is_err());
+        assert!("2024-01-01-2024-01-31".parse::<DateRange>().is_err());
+        assert!("".parse::<DateRange>().is_err());
+    }
 }
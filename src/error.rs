@@ -2,6 +2,15 @@ use std::fmt;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// The exit status of the errors that fit no other category.
+pub const EXIT_FAILURE: i32 = 1;
+/// The exit status of a failure to read or write a file.
+pub const EXIT_IO: i32 = 2;
+/// The exit status of a bucket that cannot be read back.
+pub const EXIT_CORRUPT: i32 = 3;
+/// The exit status of invalid arguments, as `EX_USAGE` of sysexits.h.
+pub const EXIT_USAGE: i32 = 64;
+
 #[derive(Debug)]
 pub enum Error {
     IoError(std::io::Error),
@@ -10,19 +19,115 @@ pub enum Error {
     ChronoError(chrono::ParseError),
     BadMagic,
     BadVersion,
+    UnacceptedVersion { version: u32, min: u32, max: u32 },
+    WrongApplication { expected: u32, found: u32 },
+    BadHeader,
+    TypeMismatch,
+    CorruptIndex,
     DateParseError,
+    InvalidBuckets(Vec<std::path::PathBuf>),
+    OverlappingBuckets(std::path::PathBuf, std::path::PathBuf),
+    LayoutMismatch(std::path::PathBuf),
+    DuplicateInput(std::path::PathBuf),
+    UnmappedKey(u64),
+    UnsortedKeys(u64),
+    NotTimestamped,
+    UncompactedLog(std::path::PathBuf),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &Error::IoError(ref err) => write!(f, "io error: {}", err),
-            &Error::BincodeError(ref err) => write!(f, "bincode error: {}", err),
-            &Error::JsonError(ref err) => write!(f, "json error: {}", err),
-            &Error::ChronoError(ref err) => write!(f, "chrono error: {}", err),
-            &Error::BadMagic => write!(f, "bad magic number"),
-            &Error::BadVersion => write!(f, "bad version number"),
-            &Error::DateParseError => write!(f, "invalid date format"),
+        match *self {
+            Error::IoError(ref err) => write!(f, "io error: {}", err),
+            Error::BincodeError(ref err) => write!(f, "bincode error: {}", err),
+            Error::JsonError(ref err) => write!(f, "json error: {}", err),
+            Error::ChronoError(ref err) => write!(f, "chrono error: {}", err),
+            Error::BadMagic => write!(f, "bad magic number"),
+            Error::BadVersion => write!(f, "bad version number"),
+            Error::UnacceptedVersion { version, min, max } => {
+                write!(f, "version {} is outside the accepted range {}:{}", version, min, max)
+            }
+            Error::WrongApplication { expected, found } => {
+                write!(f, "bucket belongs to application {}, not {}", found, expected)
+            }
+            Error::BadHeader => write!(f, "malformed header"),
+            Error::TypeMismatch => write!(f, "key or value type differs from this build"),
+            Error::CorruptIndex => write!(f, "corrupt index"),
+            Error::DateParseError => write!(f, "invalid date format"),
+            Error::InvalidBuckets(ref paths) => {
+                let names: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+                write!(f, "invalid buckets: {}", names.join(", "))
+            }
+            Error::OverlappingBuckets(ref a, ref b) => {
+                write!(f, "keys of {} do not all come before those of {}", a.display(), b.display())
+            }
+            Error::LayoutMismatch(ref path) => {
+                write!(f, "{} has a different codec or block size", path.display())
+            }
+            Error::DuplicateInput(ref path) => write!(f, "{} is given more than once", path.display()),
+            Error::UnmappedKey(key) => write!(f, "key {} has no new key in the mapping", key),
+            Error::UnsortedKeys(key) => write!(f, "key {} is not greater than the key before it", key),
+            Error::NotTimestamped => write!(f, "bucket has no insertion timestamps"),
+            Error::UncompactedLog(ref path) => {
+                write!(f, "{} has appended values; compact it first", path.display())
+            }
+        }
+    }
+}
+
+impl Error {
+    /// Whether this is an I/O error that may not happen again if the
+    /// same read is retried: `Interrupted`, `WouldBlock` or `TimedOut`.
+    pub fn is_transient(&self) -> bool {
+        fn is_transient(err: &std::io::Error) -> bool {
+            use std::io::ErrorKind::*;
+            matches!(err.kind(), Interrupted | WouldBlock | TimedOut)
+        }
+        match *self {
+            Error::IoError(ref err) => is_transient(err),
+            Error::BincodeError(ref err) => match **err {
+                bincode::ErrorKind::Io(ref err) => is_transient(err),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// The exit status for this error: `EXIT_IO`, `EXIT_CORRUPT`,
+    /// `EXIT_USAGE` or `EXIT_FAILURE`.  A file that ends early is a
+    /// truncated bucket, so it counts as corrupt.
+    pub fn exit_code(&self) -> i32 {
+        fn io_exit_code(err: &std::io::Error) -> i32 {
+            match err.kind() {
+                std::io::ErrorKind::UnexpectedEof => EXIT_CORRUPT,
+                _ => EXIT_IO,
+            }
+        }
+        match *self {
+            Error::IoError(ref err) => io_exit_code(err),
+            Error::BincodeError(ref err) => match **err {
+                bincode::ErrorKind::Io(ref err) => io_exit_code(err),
+                _ => EXIT_CORRUPT,
+            },
+            Error::JsonError(ref err) if err.is_io() => EXIT_IO,
+            Error::JsonError(_) => EXIT_FAILURE,
+            Error::BadMagic
+            | Error::BadVersion
+            | Error::UnacceptedVersion { .. }
+            | Error::WrongApplication { .. }
+            | Error::BadHeader
+            | Error::TypeMismatch
+            | Error::CorruptIndex
+            | Error::InvalidBuckets(_) => EXIT_CORRUPT,
+            Error::ChronoError(_)
+            | Error::DateParseError
+            | Error::OverlappingBuckets(..)
+            | Error::LayoutMismatch(_)
+            | Error::DuplicateInput(_)
+            | Error::UnmappedKey(_)
+            | Error::UnsortedKeys(_)
+            | Error::NotTimestamped
+            | Error::UncompactedLog(_) => EXIT_USAGE,
         }
     }
 }
@@ -42,3 +147,29 @@ impl_error!(std::io::Error, Error::IoError);
 impl_error!(bincode::Error, Error::BincodeError);
 impl_error!(serde_json::Error, Error::JsonError);
 impl_error!(chrono::ParseError, Error::ChronoError);
+
+#[cfg(test)]
+mod tests {
+    use crate::bucket::Bucket;
+    use std::io::Write;
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn exit_codes() {
+        let mut tmp = NamedTempFile::new().unwrap();
+        tmp.write_all(&[0; 64]).unwrap();
+        let e = Bucket::open(tmp.path()).unwrap().check_headers().err().expect("bad magic");
+        assert_eq!(e.exit_code(), EXIT_CORRUPT);
+
+        // A truncated header is corrupt; a missing file is not.
+        let tmp = NamedTempFile::new().unwrap();
+        let e = Bucket::open(tmp.path()).unwrap().check_headers().err().expect("empty file");
+        assert_eq!(e.exit_code(), EXIT_CORRUPT);
+        let e = Bucket::open("/nonexistent/bucket").err().expect("missing file");
+        assert_eq!(e.exit_code(), EXIT_IO);
+
+        assert_eq!(Error::DateParseError.exit_code(), EXIT_USAGE);
+        assert_eq!(Error::CorruptIndex.exit_code(), EXIT_CORRUPT);
+    }
+}
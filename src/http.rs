@@ -0,0 +1,182 @@
+//! Reading buckets over HTTP, fetching only the byte ranges a query
+//! touches.  Requires the `http` feature.
+
+use crate::bucket::{Bucket, Initial};
+use crate::prelude::*;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+/// A `Read + Seek` view of a remote file; every read is a range
+/// request for the bytes asked for, so wrap it in a `BufReader` (as
+/// `Bucket` does) to fetch them in larger pieces.
+pub struct RangeReader {
+    agent: ureq::Agent,
+    url: String,
+    len: u64,
+    pos: u64,
+}
+
+impl RangeReader {
+    /// Finds the length of the file at `url` with a `HEAD` request.
+    pub fn new(url: &str) -> Result<RangeReader> {
+        let agent = ureq::Agent::new();
+        let response = agent.head(url).call().map_err(to_io_error)?;
+        let len = response.header("Content-Length")
+            .and_then(|len| len.parse::<u64>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no Content-Length"))?;
+        Ok(RangeReader { agent, url: url.to_string(), len, pos: 0 })
+    }
+
+    /// The length of the remote file.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+        let end = self.len.min(self.pos + buf.len() as u64) - 1;
+        let response = self.agent.get(&self.url)
+            .set("Range", &format!("bytes={}-{}", self.pos, end))
+            .call()
+            .map_err(to_io_error)?;
+        // A server ignoring the range would send the whole file.
+        if response.status() != 206 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "range requests not supported"));
+        }
+        let want = (end + 1 - self.pos) as usize;
+        let mut reader = response.into_reader();
+        let mut read = 0;
+        while read < want {
+            match reader.read(&mut buf[read .. want])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for RangeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => offset_by(self.len, delta),
+            SeekFrom::Current(delta) => offset_by(self.pos, delta),
+        };
+        self.pos = pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the file"))?;
+        Ok(self.pos)
+    }
+}
+
+/// `base` moved by `delta`, or `None` if that falls outside `u64`.
+fn offset_by(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.unsigned_abs())
+    }
+}
+
+fn to_io_error(e: ureq::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+impl Bucket<Initial> {
+    /// A bucket read from `url` with range requests.
+    pub fn from_url(url: &str) -> Result<Bucket<Initial>> {
+        let reader = RangeReader::new(url)?;
+        Ok(Bucket::from_reader(Box::new(reader), PathBuf::from(url)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bucket;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::thread;
+    use super::*;
+
+    /// Serves `bytes` at `http://{addr}/bucket`, one request per
+    /// connection, and counts the body bytes sent.
+    fn serve(bytes: Vec<u8>) -> (String, Arc<AtomicU64>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/bucket", listener.local_addr().unwrap());
+        let sent = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&sent);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                    request.push(line);
+                }
+                let range = request.iter()
+                    .find_map(|line| line.strip_prefix("Range: bytes="))
+                    .map(|range| {
+                        let (start, end) = range.trim().split_once('-').unwrap();
+                        (start.parse::<usize>().unwrap(), end.parse::<usize>().unwrap())
+                    });
+                if request[0].starts_with("HEAD") {
+                    write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", bytes.len()).unwrap();
+                } else if let Some((start, end)) = range {
+                    let end = end.min(bytes.len() - 1);
+                    let body = &bytes[start ..= end];
+                    write!(stream, "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                           start, end, bytes.len(), body.len()).unwrap();
+                    stream.write_all(body).unwrap();
+                    counter.fetch_add(body.len() as u64, Ordering::SeqCst);
+                } else {
+                    write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", bytes.len()).unwrap();
+                    stream.write_all(&bytes).unwrap();
+                    counter.fetch_add(bytes.len() as u64, Ordering::SeqCst);
+                }
+            }
+        });
+        (url, sent)
+    }
+
+    #[test]
+    fn query_over_range_requests() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> =
+            (0 .. 100_000_u64).map(|key| (key * 7, (0 .. key as Value % 50).collect())).collect();
+        let mut cursor = io::Cursor::new(Vec::new());
+        bucket::create_into(&mut cursor, &bmap, &bucket::CreateOptions::default()).expect("create_into");
+        let bytes = cursor.into_inner();
+        let size = bytes.len() as u64;
+        let (url, sent) = serve(bytes);
+
+        let mut bucket = Bucket::from_url(&url).expect("from_url").check_headers().expect("check_headers");
+        assert_eq!(bucket.get(7 * 4321).expect("get"), bmap.get(&(7 * 4321)).cloned());
+        assert_eq!(bucket.get(7 * 4321 + 1).expect("get"), None);
+        let sent = sent.load(Ordering::SeqCst);
+        assert!(sent < size / 20, "fetched {} of {} bytes", sent, size);
+    }
+
+    #[test]
+    fn offset_by_stays_in_u64() {
+        assert_eq!(offset_by(10, 5), Some(15));
+        assert_eq!(offset_by(10, -10), Some(0));
+        assert_eq!(offset_by(10, -11), None);
+        assert_eq!(offset_by(u64::MAX, 1), None);
+        assert_eq!(offset_by(u64::MAX, i64::MIN), Some(u64::MAX / 2));
+        assert_eq!(offset_by(0, i64::MAX), Some(i64::MAX as u64));
+    }
+}
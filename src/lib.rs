@@ -1,5 +1,43 @@
+#![allow(clippy::needless_return)]
+
 pub mod bucket;
 pub mod error;
 pub mod prelude;
+#[cfg(feature = "cli")]
 pub mod custom_logger;
 pub mod db;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod progress;
+mod trace;
+
+#[cfg(test)]
+mod tests {
+    use crate::bucket::{self, Bucket};
+    use crate::db::{Db, parse_date};
+    use crate::prelude::*;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    /// The library is whole without the `cli` feature: run with
+    /// `cargo test --no-default-features`.
+    #[test]
+    fn library_api() {
+        let dir = tempfile::tempdir().unwrap();
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 10_u64).map(|key| (key, BTreeSet::from([key as Value]))).collect();
+        let day = parse_date("2024-01-01").unwrap();
+        let options = bucket::CreateOptions { timestamp: Some(day.and_hms(12, 0, 0).timestamp()), ..Default::default() };
+        let first = dir.path().join("first.binstore");
+        let second = dir.path().join("second.binstore");
+        bucket::create_with_options(&first, &bmap, &options).expect("create");
+        bucket::create_with_options(&second, &bmap, &options).expect("create");
+
+        let mut bucket = Bucket::open(&first).and_then(|b| b.check_headers()).expect("open");
+        assert_eq!(bucket.get(3).expect("get"), Some(BTreeSet::from([3])));
+
+        let merged = dir.path().join("merged");
+        std::fs::create_dir(&merged).unwrap();
+        bucket::merge(&first, &second, &merged.join("merged.binstore")).expect("merge");
+        let mut db = Db::open(&merged).expect("open");
+        assert_eq!(db.query(3, day, day).expect("query"), vec![3]);
+    }
+}
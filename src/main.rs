@@ -1,3 +1,5 @@
+#![allow(clippy::needless_return)]
+
 mod subcommands;
 mod custom_logger;
 
@@ -5,17 +7,207 @@ use clap::{App, Arg, SubCommand, crate_name, crate_version};
 
 fn main() {
     custom_logger::init();
-    let app = App::new(crate_name!())
+    let matches = app().get_matches();
+    if let Err(e) = subcommands::thread_pool(subcommands::threads(&matches)).build_global() {
+        eprintln!("binstore: cannot start the thread pool: {}", e);
+        std::process::exit(1);
+    }
+    match matches.subcommand() {
+        ("json-dump", Some(matches)) => subcommands::json_dump::main(matches),
+        ("peek", Some(matches)) => subcommands::peek::main(matches),
+        ("stats", Some(matches)) => subcommands::stats::main(matches),
+        ("verify", Some(matches)) => subcommands::verify::main(matches),
+        ("benchmark", Some(matches)) => subcommands::benchmark::main(matches),
+        ("query-bucket", Some(matches)) => subcommands::query_bucket::main(matches),
+        ("find", Some(matches)) => subcommands::find::main(matches),
+        ("tail", Some(matches)) => subcommands::tail::main(matches),
+        ("prune", Some(matches)) => subcommands::prune::main(matches),
+        ("warm", Some(matches)) => subcommands::warm::main(matches),
+        ("merge", Some(matches)) => subcommands::merge::main(matches),
+        ("cat", Some(matches)) => subcommands::cat::main(matches),
+        ("delete", Some(matches)) => subcommands::delete::main(matches),
+        ("query", Some(matches)) => subcommands::query::main(matches),
+        ("repair", Some(matches)) => subcommands::repair::main(matches),
+        ("rehash", Some(matches)) => subcommands::rehash::main(matches),
+        _ => {
+            println!("{}", matches.usage());
+        }
+    }
+}
+
+/// The command line of binstore.
+fn app() -> App<'static, 'static> {
+    App::new(crate_name!())
         .version(crate_version!())
+        .arg(Arg::with_name("threads")
+             .help("run parallel work on at most N threads; 0 uses all cores")
+             .long("threads")
+             .value_name("N")
+             .takes_value(true)
+             .global(true))
         .subcommand(SubCommand::with_name("json-dump")
                     .about("Dump a bucket in JSON")
+                    .arg(Arg::with_name("key-format")
+                         .help("write keys in decimal or in hex")
+                         .long("key-format")
+                         .value_name("FORMAT")
+                         .possible_values(&["dec", "hex"])
+                         .takes_value(true))
+                    .arg(Arg::with_name("value-format")
+                         .help("write values in decimal or in hex")
+                         .long("value-format")
+                         .value_name("FORMAT")
+                         .possible_values(&["dec", "hex"])
+                         .takes_value(true))
+                    .arg(Arg::with_name("format")
+                         .help("dump the header, the sparse index and an object per key with its data offset, or only a line per key with its values (jsonl)")
+                         .long("format")
+                         .value_name("FORMAT")
+                         .possible_values(&["dump", "jsonl"])
+                         .takes_value(true))
+                    .arg(Arg::with_name("accept-version-range")
+                         .help("also read buckets whose format version is between MIN and MAX, such as during a migration")
+                         .long("accept-version-range")
+                         .value_name("MIN:MAX")
+                         .takes_value(true))
+                    .arg(Arg::with_name("skip-errors")
+                         .help("warn about the entries whose values cannot be read and dump the others")
+                         .long("skip-errors"))
+                    .arg(Arg::with_name("skip-bad-files")
+                         .help("warn about the files that fail and exit with 0 if any other file succeeded")
+                         .long("skip-bad-files"))
+                    .arg(Arg::with_name("fail-fast")
+                         .help("stop at the first file that fails")
+                         .long("fail-fast")
+                         .conflicts_with("skip-bad-files"))
+                    .arg(Arg::with_name("min-key")
+                         .help("only dump the keys from KEY on")
+                         .long("min-key")
+                         .value_name("KEY")
+                         .takes_value(true))
+                    .arg(Arg::with_name("max-key")
+                         .help("only dump the keys up to KEY")
+                         .long("max-key")
+                         .value_name("KEY")
+                         .takes_value(true))
+                    .arg(Arg::with_name("min-values")
+                         .help("only dump the keys with at least N values")
+                         .long("min-values")
+                         .value_name("N")
+                         .takes_value(true))
+                    .arg(Arg::with_name("contains-value")
+                         .help("only dump the keys whose values include VALUE")
+                         .long("contains-value")
+                         .value_name("VALUE")
+                         .takes_value(true))
+                    .arg(Arg::with_name("input-files")
+                         .help("the list of files to accumulate; use `-` for stdin, which is also read when no files are given and it is not a terminal; a directory or a glob such as db/*.binstore stands for the buckets it holds or matches.")
+                         .value_name("FILES")
+                         .takes_value(true)
+                         .multiple(true)))
+        .subcommand(SubCommand::with_name("peek")
+                    .about("Prints the header of buckets in JSON")
+                    .arg(Arg::with_name("accept-version-range")
+                         .help("also read buckets whose format version is between MIN and MAX, such as during a migration")
+                         .long("accept-version-range")
+                         .value_name("MIN:MAX")
+                         .takes_value(true))
+                    .arg(Arg::with_name("raw")
+                         .help("print the header fields only")
+                         .long("raw"))
+                    .arg(Arg::with_name("input-files")
+                         .help("the buckets to inspect; a directory or a glob such as db/*.binstore stands for the buckets it holds or matches.")
+                         .required(true)
+                         .value_name("FILES")
+                         .takes_value(true)
+                         .multiple(true)))
+        .subcommand(SubCommand::with_name("stats")
+                    .about("Prints statistics about buckets in JSON")
+                    .arg(Arg::with_name("accept-version-range")
+                         .help("also read buckets whose format version is between MIN and MAX, such as during a migration")
+                         .long("accept-version-range")
+                         .value_name("MIN:MAX")
+                         .takes_value(true))
+                    .arg(Arg::with_name("compression-stats")
+                         .help("also report how well the data compresses; much slower, as every value is decompressed")
+                         .long("compression-stats"))
+                    .arg(Arg::with_name("format")
+                         .help("write a JSON object per bucket, or metrics in the Prometheus text format, as for node_exporter's textfile collector")
+                         .long("format")
+                         .value_name("FORMAT")
+                         .possible_values(&["json", "prometheus"])
+                         .takes_value(true))
+                    .arg(Arg::with_name("find-duplicates")
+                         .help("instead of statistics, print a JSON object for each group of buckets with the same contents")
+                         .long("find-duplicates")
+                         .conflicts_with_all(&["compression-stats", "format"]))
+                    .arg(Arg::with_name("input-files")
+                         .help("the buckets to inspect; a directory or a glob such as db/*.binstore stands for the buckets it holds or matches.")
+                         .required(true)
+                         .value_name("FILES")
+                         .takes_value(true)
+                         .multiple(true)))
+        .subcommand(SubCommand::with_name("verify")
+                    .about("Checks that buckets are readable")
+                    .arg(Arg::with_name("accept-version-range")
+                         .help("also read buckets whose format version is between MIN and MAX, such as during a migration")
+                         .long("accept-version-range")
+                         .value_name("MIN:MAX")
+                         .takes_value(true))
+                    .arg(Arg::with_name("sample")
+                         .help("check the whole index but only decompress N data blocks picked at random")
+                         .long("sample")
+                         .value_name("N")
+                         .takes_value(true))
+                    .arg(Arg::with_name("seed")
+                         .help("the seed picking the blocks of --sample, to check the same ones again")
+                         .long("seed")
+                         .value_name("SEED")
+                         .requires("sample")
+                         .takes_value(true))
                     .arg(Arg::with_name("input-files")
-                         .help("the list of files to accumulate; use `-` for stdin.")
+                         .help("the buckets to verify; a directory or a glob such as db/*.binstore stands for the buckets it holds or matches.")
+                         .required(true)
                          .value_name("FILES")
                          .takes_value(true)
                          .multiple(true)))
+        .subcommand(SubCommand::with_name("benchmark")
+                    .about("Measures the latency of random lookups in a bucket")
+                    .arg(Arg::with_name("lookups")
+                         .help("the number of lookups to time")
+                         .short("n")
+                         .value_name("N")
+                         .default_value("100000")
+                         .takes_value(true))
+                    .arg(Arg::with_name("seed")
+                         .help("the seed picking the keys, to time the same lookups again")
+                         .long("seed")
+                         .value_name("SEED")
+                         .takes_value(true))
+                    .arg(Arg::with_name("input-file")
+                         .help("the bucket to query.")
+                         .required(true)
+                         .value_name("FILE")
+                         .takes_value(true)))
         .subcommand(SubCommand::with_name("query-bucket")
                     .about("Queries a single bucket file to find if the provided key exists or not.")
+                    .arg(Arg::with_name("key-format")
+                         .help("write keys in decimal or in hex")
+                         .long("key-format")
+                         .value_name("FORMAT")
+                         .possible_values(&["dec", "hex"])
+                         .takes_value(true))
+                    .arg(Arg::with_name("value-format")
+                         .help("write values in decimal or in hex")
+                         .long("value-format")
+                         .value_name("FORMAT")
+                         .possible_values(&["dec", "hex"])
+                         .takes_value(true))
+                    .arg(Arg::with_name("accept-version-range")
+                         .help("also read buckets whose format version is between MIN and MAX, such as during a migration")
+                         .long("accept-version-range")
+                         .value_name("MIN:MAX")
+                         .takes_value(true))
                     .arg(Arg::with_name("key")
                         .help("The key used to retrieve the value in the database")
                         .short("k")
@@ -23,12 +215,130 @@ fn main() {
                         .value_name("KEY")
                         .takes_value(true)
                         .multiple(true))
+                    .arg(Arg::with_name("keys-file")
+                         .help("also look up the keys in FILE, one per line; - reads them from stdin")
+                         .long("keys-file")
+                         .value_name("FILE")
+                         .takes_value(true))
+                    .arg(Arg::with_name("output-format")
+                         .help("write text, or a stream of bincoded (key, values) pairs")
+                         .long("output-format")
+                         .value_name("FORMAT")
+                         .possible_values(&["text", "bincode"])
+                         .takes_value(true))
+                    .arg(Arg::with_name("output-file")
+                         .help("write the results to FILE instead of stdout")
+                         .long("output-file")
+                         .value_name("FILE")
+                         .takes_value(true))
+                    .arg(Arg::with_name("since")
+                         .help("with a timestamped bucket, only print the values inserted from this Unix timestamp on")
+                         .long("since")
+                         .value_name("SECS")
+                         .takes_value(true))
+                    .arg(Arg::with_name("until")
+                         .help("with a timestamped bucket, only print the values inserted before this Unix timestamp")
+                         .long("until")
+                         .value_name("SECS")
+                         .takes_value(true))
+                    .arg(Arg::with_name("quiet")
+                         .help("print nothing; exit with 0 if all the keys are found, 1 if not, 2 on error")
+                         .short("q")
+                         .long("quiet"))
+                    .arg(Arg::with_name("any")
+                         .help("with --quiet, exit with 0 if any of the keys is found")
+                         .long("any")
+                         .requires("quiet"))
+                    .arg(Arg::with_name("show-offset")
+                         .help("with text output, also print where the data block of each key found starts in the file")
+                         .long("show-offset"))
+                    .arg(Arg::with_name("skip-bad-files")
+                         .help("warn about the files that fail and exit with 0 if any other file succeeded")
+                         .long("skip-bad-files"))
+                    .arg(Arg::with_name("fail-fast")
+                         .help("stop at the first file that fails")
+                         .long("fail-fast")
+                         .conflicts_with("skip-bad-files"))
                     .arg(Arg::with_name("input-files")
-                         .help("the list of buckets to search in.")
+                         .help("the list of buckets to search in; a directory or a glob such as db/*.binstore stands for the buckets it holds or matches.")
                          .required(true)
                          .value_name("FILES")
                          .takes_value(true)
                          .multiple(true)))
+        .subcommand(SubCommand::with_name("find")
+                    .about("Lists the buckets of a directory that contain a key.")
+                    .arg(Arg::with_name("key")
+                        .help("the key to look for")
+                        .short("k")
+                        .long("key")
+                        .value_name("KEY")
+                        .required(true)
+                        .takes_value(true))
+                    .arg(Arg::with_name("json")
+                         .help("print the paths as a JSON array")
+                         .long("json"))
+                    .arg(Arg::with_name("db-dir")
+                         .help("the directory of buckets to search.")
+                         .required(true)
+                         .value_name("DIR")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("tail")
+                    .about("Lists the most recently written buckets of a directory.")
+                    .arg(Arg::with_name("count")
+                         .help("the number of buckets to list")
+                         .short("n")
+                         .value_name("N")
+                         .default_value("10")
+                         .takes_value(true))
+                    .arg(Arg::with_name("json")
+                         .help("print the buckets as a JSON array")
+                         .long("json"))
+                    .arg(Arg::with_name("db-dir")
+                         .help("the directory of buckets to list.")
+                         .required(true)
+                         .value_name("DIR")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("prune")
+                    .about("Lists, and with --apply removes, the buckets of a directory dated before a day, as `query` dates them.")
+                    .arg(Arg::with_name("before")
+                         .help("prune the buckets dated before this day, as %Y-%m-%d in UTC")
+                         .long("before")
+                         .required(true)
+                         .value_name("DATE")
+                         .takes_value(true))
+                    .arg(Arg::with_name("archive-dir")
+                         .help("move the pruned buckets to DIR instead of removing them")
+                         .long("archive-dir")
+                         .value_name("DIR")
+                         .takes_value(true))
+                    .arg(Arg::with_name("apply")
+                         .help("prune the buckets; without it, they are only listed")
+                         .long("apply"))
+                    .arg(Arg::with_name("db-dir")
+                         .help("the directory of buckets to prune.")
+                         .required(true)
+                         .value_name("DIR")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("warm")
+                    .about("Reads the indexes of the buckets of a date range, so that the OS caches them before queries.")
+                    .arg(Arg::with_name("start-date")
+                         .help("format: %Y-%m-%d (UTC); without it, every bucket up to --end-date is read")
+                         .short("s")
+                         .long("start-date")
+                         .takes_value(true))
+                    .arg(Arg::with_name("end-date")
+                         .help("format: %Y-%m-%d (UTC); without it, every bucket from --start-date on is read")
+                         .short("e")
+                         .long("end-date")
+                         .takes_value(true))
+                    .arg(Arg::with_name("full")
+                         .help("also read the data sections")
+                         .long("full"))
+                    .arg(Arg::with_name("dbdir")
+                         .help("the directory of the database.")
+                         .required(true)
+                         .value_name("DIR")
+                         .takes_value(true)))
         .subcommand(SubCommand::with_name("merge")
                     .about("Merges two buckets together, leaving the two original files intact.")
                     .arg(Arg::with_name("input-files")
@@ -37,6 +347,87 @@ fn main() {
                          .value_name("FILES")
                          .takes_value(true)
                          .multiple(true))
+                    .arg(Arg::with_name("output-name")
+                        .help("the name of the output file; - writes the bucket to stdout")
+                        .required_unless("estimate")
+                        .short("o")
+                        .long("output-name")
+                        .value_name("OUTPUT-NAME")
+                        .takes_value(true)
+                        .multiple(false))
+                    .arg(Arg::with_name("progress-interval")
+                         .help("print progress to stderr every SECS seconds")
+                         .long("progress-interval")
+                         .value_name("SECS")
+                         .takes_value(true))
+                    .arg(Arg::with_name("codec")
+                         .help("how to compress the output; the inputs are read with their own codec")
+                         .long("codec")
+                         .value_name("CODEC")
+                         .possible_values(&["lz4", "zstd", "none", "adaptive"])
+                         .takes_value(true))
+                    .arg(Arg::with_name("block-size")
+                         .help("group the values of consecutive keys into lz4 blocks of about BYTES bytes")
+                         .long("block-size")
+                         .value_name("BYTES")
+                         .takes_value(true))
+                    .arg(Arg::with_name("timestamp")
+                         .help("stamp the output with this Unix timestamp, or with the time of the merge (now), or with the older (min) or newer (max) timestamp of the inputs, or with that of the first input (input1); defaults to $SOURCE_DATE_EPOCH if set, else max")
+                         .long("timestamp")
+                         .value_name("SECS|now|min|max|input1")
+                         .takes_value(true))
+                    .arg(Arg::with_name("sync")
+                         .help("sync the output to disk before exiting")
+                         .long("sync"))
+                    .arg(Arg::with_name("dedup-inputs")
+                         .help("skip the inputs naming a file given before instead of failing")
+                         .long("dedup-inputs"))
+                    .arg(Arg::with_name("validate-after-write")
+                         .help("read the output back once written and remove it if it doesn't read back")
+                         .long("validate-after-write"))
+                    .arg(Arg::with_name("estimate")
+                         .help("read only the indexes of the inputs and report the keys and index sizes of the merge")
+                         .long("estimate"))
+                    .arg(Arg::with_name("lowmem")
+                         .help("build the sparse index in a first pass over the inputs' indexes instead of holding every key in memory")
+                         .long("lowmem"))
+                    .arg(Arg::with_name("max-values-per-key")
+                         .help("keep at most N values per key, after the union of the inputs; set RUST_LOG=info to list the keys capped")
+                         .long("max-values-per-key")
+                         .value_name("N")
+                         .takes_value(true))
+                    .arg(Arg::with_name("cap-policy")
+                         .help("which values a capped key keeps: the smallest ones, or the largest ones to drop the oldest of increasing values")
+                         .long("cap-policy")
+                         .value_name("POLICY")
+                         .possible_values(&["smallest", "largest"])
+                         .requires("max-values-per-key")
+                         .takes_value(true))
+                    .arg(Arg::with_name("index-kind")
+                         .help("how lookups in the output search its dense index: through the sparse index (sparse-dense), or read whole into memory on the first lookup (flat), which suits small buckets queried often")
+                         .long("index-kind")
+                         .value_name("KIND")
+                         .possible_values(&["sparse-dense", "flat"])
+                         .takes_value(true))
+                    .arg(Arg::with_name("varint-values")
+                         .help("write each value as a varint instead of in 16 bytes, which shrinks buckets of mostly small values")
+                         .long("varint-values"))
+                    .arg(Arg::with_name("max-output-size")
+                         .help("split the output into buckets of at most about BYTES bytes over consecutive key ranges, named OUTPUT-NAME with .0, .1 and so on before its extension")
+                         .long("max-output-size")
+                         .value_name("BYTES")
+                         .takes_value(true))
+                    .arg(Arg::with_name("dry-run")
+                         .help("report what would be written without writing anything")
+                         .long("dry-run")))
+        .subcommand(SubCommand::with_name("cat")
+                    .about("Concatenates buckets whose keys are disjoint and in ascending order, without recompressing their values.")
+                    .arg(Arg::with_name("input-files")
+                         .help("the files to concatenate, in key order.")
+                         .required(true)
+                         .value_name("FILES")
+                         .takes_value(true)
+                         .multiple(true))
                     .arg(Arg::with_name("output-name")
                         .help("the name of the output file")
                         .required(true)
@@ -44,7 +435,29 @@ fn main() {
                         .long("output-name")
                         .value_name("OUTPUT-NAME")
                         .takes_value(true)
-                        .multiple(false)))
+                        .multiple(false))
+                    .arg(Arg::with_name("progress-interval")
+                         .help("print progress to stderr every SECS seconds")
+                         .long("progress-interval")
+                         .value_name("SECS")
+                         .takes_value(true))
+                    .arg(Arg::with_name("timestamp")
+                         .help("stamp the output with this Unix timestamp instead of the current time; defaults to $SOURCE_DATE_EPOCH if set")
+                         .long("timestamp")
+                         .value_name("SECS")
+                         .takes_value(true))
+                    .arg(Arg::with_name("sync")
+                         .help("sync the output to disk before exiting")
+                         .long("sync"))
+                    .arg(Arg::with_name("dedup-inputs")
+                         .help("skip the inputs naming a file given before instead of failing")
+                         .long("dedup-inputs"))
+                    .arg(Arg::with_name("validate-after-write")
+                         .help("read the output back once written and remove it if it doesn't read back")
+                         .long("validate-after-write"))
+                    .arg(Arg::with_name("dry-run")
+                         .help("report what would be written without writing anything")
+                         .long("dry-run")))
         .subcommand(SubCommand::with_name("delete")
                     .about("Duplicates the input files without including the provided values")
                     .arg(Arg::with_name("values")
@@ -54,8 +467,26 @@ fn main() {
                         .value_name("VALUES")
                         .takes_value(true)
                         .multiple(true))
+                    .arg(Arg::with_name("keep-empty")
+                         .help("keep the keys left without values, as empty sets, instead of dropping them")
+                         .long("keep-empty"))
+                    .arg(Arg::with_name("units")
+                         .help("accept values with a unit suffix: k, m, g, t, p, e for powers of 1000, ki, mi, gi, ti, pi, ei for powers of 1024")
+                         .long("units"))
+                    .arg(Arg::with_name("drop-key-range")
+                         .help("also drop every key from MIN to MAX inclusive, decimal or 0x-prefixed hex")
+                         .long("drop-key-range")
+                         .value_name("MIN:MAX")
+                         .takes_value(true))
+                    .arg(Arg::with_name("skip-bad-files")
+                         .help("warn about the files that fail and exit with 0 if any other file succeeded")
+                         .long("skip-bad-files"))
+                    .arg(Arg::with_name("fail-fast")
+                         .help("stop at the first file that fails")
+                         .long("fail-fast")
+                         .conflicts_with("skip-bad-files"))
                     .arg(Arg::with_name("input-files")
-                         .help("the list of files to delete in.")
+                         .help("the list of files to delete in, each paired with the output file at the same position.")
                          .required(true)
                          .value_name("INPUT-FILES")
                          .takes_value(true)
@@ -67,9 +498,113 @@ fn main() {
                          .required(true)
                          .value_name("OUTPUT_FILES")
                          .takes_value(true)
-                         .multiple(true)))
+                         .multiple(true))
+                    .arg(Arg::with_name("progress-interval")
+                         .help("print progress to stderr every SECS seconds")
+                         .long("progress-interval")
+                         .value_name("SECS")
+                         .takes_value(true))
+                    .arg(Arg::with_name("timestamp")
+                         .help("stamp the output with this Unix timestamp instead of the current time; defaults to $SOURCE_DATE_EPOCH if set")
+                         .long("timestamp")
+                         .value_name("SECS")
+                         .takes_value(true))
+                    .arg(Arg::with_name("preserve-mtime")
+                         .help("give each output the modification time and, unless --timestamp is given, the header timestamp of its input")
+                         .long("preserve-mtime"))
+                    .arg(Arg::with_name("sync")
+                         .help("sync the output to disk before exiting")
+                         .long("sync"))
+                    .arg(Arg::with_name("validate-after-write")
+                         .help("read the output back once written and remove it if it doesn't read back")
+                         .long("validate-after-write"))
+                    .arg(Arg::with_name("dry-run")
+                         .help("report what would be written without writing anything")
+                         .long("dry-run")))
+        .subcommand(SubCommand::with_name("repair")
+                    .about("Copies the readable entries of a corrupt bucket into a new bucket")
+                    .arg(Arg::with_name("input-file")
+                         .help("the bucket to repair.")
+                         .required(true)
+                         .value_name("FILE")
+                         .takes_value(true))
+                    .arg(Arg::with_name("output-name")
+                        .help("the name of the output file")
+                        .required(true)
+                        .short("o")
+                        .long("output-name")
+                        .value_name("OUTPUT-NAME")
+                        .takes_value(true)
+                        .multiple(false))
+                    .arg(Arg::with_name("timestamp")
+                         .help("stamp the output with this Unix timestamp instead of the current time; defaults to $SOURCE_DATE_EPOCH if set")
+                         .long("timestamp")
+                         .value_name("SECS")
+                         .takes_value(true))
+                    .arg(Arg::with_name("sync")
+                         .help("sync the output to disk before exiting")
+                         .long("sync"))
+                    .arg(Arg::with_name("validate-after-write")
+                         .help("read the output back once written and remove it if it doesn't read back")
+                         .long("validate-after-write"))
+                    .arg(Arg::with_name("dry-run")
+                         .help("report what would be written without writing anything")
+                         .long("dry-run")))
+        .subcommand(SubCommand::with_name("rehash")
+                    .about("Copies a bucket under new keys, such as after a change of hash function, unioning the values of keys given the same new key")
+                    .arg(Arg::with_name("input-file")
+                         .help("the bucket to rehash.")
+                         .required(true)
+                         .value_name("FILE")
+                         .takes_value(true))
+                    .arg(Arg::with_name("output-name")
+                        .help("the name of the output file")
+                        .required(true)
+                        .short("o")
+                        .long("output-name")
+                        .value_name("OUTPUT-NAME")
+                        .takes_value(true)
+                        .multiple(false))
+                    .arg(Arg::with_name("mapping")
+                         .help("read the new key of each key from FILE, one OLD,NEW pair per line; - reads stdin")
+                         .required(true)
+                         .long("mapping")
+                         .value_name("FILE")
+                         .takes_value(true))
+                    .arg(Arg::with_name("codec")
+                         .help("how to compress the output")
+                         .long("codec")
+                         .value_name("CODEC")
+                         .possible_values(&["lz4", "zstd", "none", "adaptive"])
+                         .takes_value(true))
+                    .arg(Arg::with_name("timestamp")
+                         .help("stamp the output with this Unix timestamp instead of the current time; defaults to $SOURCE_DATE_EPOCH if set")
+                         .long("timestamp")
+                         .value_name("SECS")
+                         .takes_value(true))
+                    .arg(Arg::with_name("sync")
+                         .help("sync the output to disk before exiting")
+                         .long("sync"))
+                    .arg(Arg::with_name("validate-after-write")
+                         .help("read the output back once written and remove it if it doesn't read back")
+                         .long("validate-after-write"))
+                    .arg(Arg::with_name("dry-run")
+                         .help("report what would be written without writing anything")
+                         .long("dry-run")))
         .subcommand(SubCommand::with_name("query")
                     .about("Queries the database to retrieve the values associated with the provided key")
+                    .arg(Arg::with_name("key-format")
+                         .help("write keys in decimal or in hex")
+                         .long("key-format")
+                         .value_name("FORMAT")
+                         .possible_values(&["dec", "hex"])
+                         .takes_value(true))
+                    .arg(Arg::with_name("value-format")
+                         .help("write values in decimal or in hex")
+                         .long("value-format")
+                         .value_name("FORMAT")
+                         .possible_values(&["dec", "hex"])
+                         .takes_value(true))
                     .arg(Arg::with_name("dbdir")
                         .short("-d")
                         .long("--db-dir")
@@ -84,26 +619,63 @@ fn main() {
                         .value_name("KEY")
                         .takes_value(true)
                         .multiple(true))
+                    .arg(Arg::with_name("keys-file")
+                         .help("also look up the keys in FILE, one per line; - reads them from stdin")
+                         .long("keys-file")
+                         .value_name("FILE")
+                         .takes_value(true))
                     .arg(Arg::with_name("start-date")
-                         .help("format: %Y-%m-%d")
+                         .help("format: %Y-%m-%d (UTC); without it, every bucket up to --end-date is searched")
                          .short("-s")
                          .long("--start-date")
                          .takes_value(true))
                     .arg(Arg::with_name("end-date")
-                         .help("format %Y-%m-%d")
+                         .help("format %Y-%m-%d (UTC); without it, every bucket from --start-date on is searched")
                          .short("-e")
                          .long("--end-date")
-                         .takes_value(true)));
-
-    let matches = app.get_matches();
-    match matches.subcommand() {
-        ("json-dump", Some(matches)) => subcommands::json_dump::main(matches),
-        ("query-bucket", Some(matches)) => subcommands::query_bucket::main(matches),
-        ("merge", Some(matches)) => subcommands::merge::main(matches),
-        ("delete", Some(matches)) => subcommands::delete::main(matches),
-        ("query", Some(matches)) => subcommands::query::main(matches),
-        _ => {
-            println!("{}", matches.usage());
-        }
-    }
+                         .takes_value(true))
+                    .arg(Arg::with_name("range")
+                         .help("format: %Y-%m-%d:%Y-%m-%d (UTC); replaces --start-date and --end-date")
+                         .short("-r")
+                         .long("--range")
+                         .takes_value(true)
+                         .conflicts_with_all(&["start-date", "end-date"]))
+                    .arg(Arg::with_name("sort")
+                         .help("print each value once, in increasing order, instead of bucket by bucket")
+                         .long("--sort"))
+                    .arg(Arg::with_name("stats")
+                         .help("print to stderr how many buckets each query searched and how much it read")
+                         .long("--stats"))
+                    .arg(Arg::with_name("strict")
+                         .help("fail if any bucket in the database cannot be read")
+                         .long("--strict"))
+                    .arg(Arg::with_name("recursive")
+                         .help("also load the buckets in the subdirectories of the database")
+                         .long("--recursive"))
+                    .arg(Arg::with_name("retries")
+                         .help("retry a bucket up to N times when reading it fails with a transient I/O error")
+                         .long("retries")
+                         .value_name("N")
+                         .takes_value(true))
+                    .arg(Arg::with_name("retry-backoff")
+                         .help("wait MS milliseconds before the first retry, twice as long before each next one; 100 by default")
+                         .long("retry-backoff")
+                         .value_name("MS")
+                         .takes_value(true))
+                    .arg(Arg::with_name("output-format")
+                         .help("write text, or a stream of bincoded (key, values) pairs")
+                         .long("output-format")
+                         .value_name("FORMAT")
+                         .possible_values(&["text", "bincode"])
+                         .takes_value(true))
+                    .arg(Arg::with_name("output-file")
+                         .help("write the results to FILE instead of stdout")
+                         .long("output-file")
+                         .value_name("FILE")
+                         .takes_value(true))
+                    .arg(Arg::with_name("max-open-files")
+                         .help("keep at most N bucket files open at once")
+                         .long("--max-open-files")
+                         .value_name("N")
+                         .takes_value(true)))
 }
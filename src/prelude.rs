@@ -1,5 +1,5 @@
 use std::mem;
-use std::io::{Seek, SeekFrom};
+use std::io::Seek;
 
 // Re-export everything in the error module.
 pub use crate::error::*;
@@ -13,11 +13,29 @@ pub type Value = u128;
 /// The number of bits in a key.
 pub const HASHED_KEY_SIZE: usize = mem::size_of::<HashedKey>();
 
+/// How bucket headers record the `HashedKey` type: the width in bytes
+/// of the unsigned integer.
+pub const HASHED_KEY_TYPE_TAG: u8 = mem::size_of::<HashedKey>() as u8;
+
+/// How bucket headers record the `Value` type: the width in bytes of
+/// the unsigned integer.
+pub const VALUE_TYPE_TAG: u8 = mem::size_of::<Value>() as u8;
+
+/// The size of the insertion timestamp kept with each value of a
+/// timestamped bucket.
+pub const TIMESTAMP_SIZE: usize = mem::size_of::<i64>();
+
 /// The magic number used to identify a binstore's bucket.
 pub const MAGIC: u32 = 0x594e4e4a;
 
 /// The current version of the binstore file format.
-pub const VERSION: u32 = 0;
+///
+/// Version 1 adds the header extension and the choice of dense index
+/// encoding.  Version 2 marks buckets that older builds would misread:
+/// grouped blocks, another codec, a value layout, the columnar or
+/// compressed dense index, or a log.  A new field that changes how the
+/// data is decoded takes a new version.
+pub const VERSION: u32 = 2;
 
 /// The default step from one entry to the next in the sparse index.
 pub const DEFAULT_SPARSE_INDEX_STEP: usize =
@@ -26,8 +44,11 @@ pub const DEFAULT_SPARSE_INDEX_STEP: usize =
 /// The level of compression for LZ4.
 pub const COMPRESSION_LEVEL: u32 = 10;
 
+/// The level of compression for zstd.
+pub const ZSTD_COMPRESSION_LEVEL: i32 = 3;
+
 /// Return the current offset in a file.
 pub fn tell<S: Seek>(s: &mut S) -> Result<u64> {
-    let offset = s.seek(SeekFrom::Current(0))?;
+    let offset = s.stream_position()?;
     return Ok(offset);
 }
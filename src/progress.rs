@@ -0,0 +1,83 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A callback receiving `(processed, total)` as a long operation
+/// advances.
+#[derive(Clone)]
+pub struct Progress(Arc<dyn Fn(u64, u64) + Send + Sync>);
+
+impl Progress {
+    pub fn new<F: Fn(u64, u64) + Send + Sync + 'static>(f: F) -> Progress {
+        Progress(Arc::new(f))
+    }
+
+    /// Prints `processed/total (pct%) ETA=...` to stderr at most once
+    /// every `interval`, and once more when the operation completes.
+    pub fn every(interval: Duration) -> Progress {
+        let start = Instant::now();
+        let last_report = Mutex::new(start);
+        Progress::new(move |processed, total| {
+            let mut last_report = last_report.lock().expect("progress lock");
+            if processed < total && last_report.elapsed() < interval {
+                return;
+            }
+            *last_report = Instant::now();
+            eprintln!("{}", format_progress(processed, total, start.elapsed()));
+        })
+    }
+
+    pub fn update(&self, processed: u64, total: u64) {
+        (self.0)(processed, total);
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Progress")
+    }
+}
+
+/// Formats a progress line; the ETA assumes the remaining work goes
+/// at the same pace as the work done so far.
+pub fn format_progress(processed: u64, total: u64, elapsed: Duration) -> String {
+    let pct = if total == 0 {
+        100.0
+    } else {
+        processed as f64 * 100.0 / total as f64
+    };
+    let eta = if processed == 0 {
+        "?".to_string()
+    } else {
+        let remaining = total.saturating_sub(processed) as f64 / processed as f64;
+        format_duration(elapsed.mul_f64(remaining))
+    };
+    format!("{}/{} ({:.1}%) ETA={}", processed, total, pct, eta)
+}
+
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{}h{:02}m{:02}s", h, m, s)
+    } else if m > 0 {
+        format!("{}m{:02}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_progress_eta() {
+        assert_eq!(format_progress(0, 100, Duration::from_secs(5)), "0/100 (0.0%) ETA=?");
+        assert_eq!(format_progress(25, 100, Duration::from_secs(10)), "25/100 (25.0%) ETA=30s");
+        assert_eq!(format_progress(1, 3, Duration::from_secs(90)), "1/3 (33.3%) ETA=3m00s");
+        assert_eq!(format_progress(10, 1000, Duration::from_secs(100)), "10/1000 (1.0%) ETA=2h45m00s");
+        assert_eq!(format_progress(100, 100, Duration::from_secs(42)), "100/100 (100.0%) ETA=0s");
+        assert_eq!(format_progress(0, 0, Duration::from_secs(0)), "0/0 (100.0%) ETA=?");
+    }
+}
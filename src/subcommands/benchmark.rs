@@ -0,0 +1,124 @@
+use clap::{ArgMatches, value_t};
+use binstore::bucket::Bucket;
+use binstore::prelude::*;
+use super::SplitMix64;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// What `benchmark` measured.
+#[derive(Debug)]
+struct Summary {
+    lookups: usize,
+    found: usize,
+    p50: Duration,
+    p95: Duration,
+    p99: Duration,
+    /// Lookups per second.
+    throughput: f64,
+}
+
+pub fn main(matches: &ArgMatches) {
+    super::exit(run(matches));
+}
+
+/// Runs the lookups and returns the exit code.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    let lookups = value_t!(matches, "lookups", usize).unwrap_or_else(|e| {
+        super::usage_error(format_args!("invalid number of lookups: {}", e));
+    });
+    let seed = super::seed(matches);
+    let filename = super::path(matches, "input-file").unwrap();
+
+    match benchmark(&filename, lookups, seed) {
+        Ok(summary) => {
+            println!("{}: {} lookups ({} found), seed {}", filename.display(), summary.lookups, summary.found, seed);
+            println!("p50: {:?}, p95: {:?}, p99: {:?}", summary.p50, summary.p95, summary.p99);
+            println!("throughput: {:.0} lookups/s", summary.throughput);
+            Ok(0)
+        }
+        Err(e) => {
+            eprintln!("binstore: {}: {}", filename.display(), e);
+            Ok(e.exit_code())
+        }
+    }
+}
+
+/// Times `lookups` lookups of keys picked from `seed` in `filename`,
+/// after a tenth as many lookups to warm up.  Half the keys are keys of
+/// the bucket; the others are random and almost surely absent.
+fn benchmark(filename: &Path, lookups: usize, seed: u64) -> Result<Summary> {
+    let mut bucket = Bucket::open(filename)?.check_headers()?;
+    let keys = bucket.index_entries()?
+        .map(|entry| entry.map(|entry| entry.key))
+        .collect::<Result<Vec<HashedKey>>>()?;
+    let mut bucket = bucket.prepare()?;
+
+    let mut rng = SplitMix64(seed);
+    let mut next_key = || {
+        let n = rng.next();
+        if n & 1 == 0 && !keys.is_empty() {
+            keys[(n >> 1) as usize % keys.len()]
+        } else {
+            rng.next()
+        }
+    };
+
+    for _ in 0 .. lookups / 10 {
+        bucket.get(next_key())?;
+    }
+
+    let mut found = 0;
+    let mut latencies = Vec::with_capacity(lookups);
+    let t = Instant::now();
+    for _ in 0 .. lookups {
+        let key = next_key();
+        let lookup = Instant::now();
+        if bucket.get(key)?.is_some() {
+            found += 1;
+        }
+        latencies.push(lookup.elapsed());
+    }
+    let elapsed = t.elapsed();
+
+    latencies.sort_unstable();
+    let percentile = |p: usize| {
+        match latencies.len() {
+            0 => Duration::default(),
+            n => latencies[(n * p / 100).min(n - 1)],
+        }
+    };
+    Ok(Summary {
+        lookups,
+        found,
+        p50: percentile(50),
+        p95: percentile(95),
+        p99: percentile(99),
+        throughput: if elapsed > Duration::default() { lookups as f64 / elapsed.as_secs_f64() } else { 0.0 },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use binstore::bucket;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::iter::FromIterator;
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn benchmark_smoke() {
+        let mut bmap = BTreeMap::new();
+        for key in 0 .. 1000_u64 {
+            bmap.insert(key * 11, BTreeSet::from_iter(0 .. 5_u128));
+        }
+        let tmp = NamedTempFile::new().unwrap();
+        bucket::create(tmp.path(), &bmap).expect("create");
+
+        let summary = benchmark(tmp.path(), 1000, 42).expect("benchmark");
+        assert_eq!(summary.lookups, 1000);
+        assert!(summary.found > 0 && summary.found < 1000, "found: {}", summary.found);
+        assert!(summary.p50 > Duration::default());
+        assert!(summary.p50 <= summary.p95 && summary.p95 <= summary.p99);
+        assert!(summary.throughput > 0.0);
+    }
+}
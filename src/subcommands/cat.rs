@@ -0,0 +1,25 @@
+use clap::ArgMatches;
+use binstore::prelude::*;
+
+pub fn main(matches: &ArgMatches) {
+    super::exit(run(matches));
+}
+
+/// Concatenates the buckets and returns the exit code.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    let filenames = super::paths(matches, "input-files");
+    if filenames.is_empty() {
+        super::usage_error("missing input file");
+    }
+    let output_name = super::path(matches, "output-name").unwrap_or_else(|| {
+        super::usage_error("missing output name");
+    });
+
+    let options = super::create_options(matches);
+    let report = binstore::bucket::cat(&filenames, output_name.clone(), &options)?;
+    if options.dry_run {
+        eprintln!("binstore: dry run: would write {} entries ({} bytes) to {}",
+                  report.num_entries, report.size, output_name.display());
+    }
+    Ok(0)
+}
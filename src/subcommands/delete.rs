@@ -1,45 +1,132 @@
 use clap::{ArgMatches, values_t};
-use binstore::prelude::Value;
-use std::process;
+use binstore::bucket::CreateOptions;
+use binstore::prelude::*;
+use super::{Batch, UnitValue};
+use std::path::PathBuf;
 
 pub fn main(matches: &ArgMatches) {
-    let input_files: Vec<String> = match values_t!(matches, "input-files", String) {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("binstore: invalid input file: {}", e);
-            process::exit(1)
-        }
-    };
+    super::exit(run(matches));
+}
 
-    let output_files: Vec<String> = match values_t!(matches, "output-files", String) {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("binstore: invalid output file: {}", e);
-            process::exit(1)
-        }
-    };
+/// Deletes the values from the buckets and returns the exit code.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    // Taken literally, as each input is paired with the output file at
+    // the same position.
+    let input_files = super::paths(matches, "input-files");
+    let output_files = super::paths(matches, "output-files");
 
-    let values: Vec<Value> = match values_t!(matches, "values", Value) {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("hydroxyde: invalid values: {}", e);
-            process::exit(1);
-        }
-    };
+    // A key range alone is enough to delete something.
+    let values: Vec<Value> = if !matches.is_present("values") && matches.is_present("drop-key-range") {
+        Ok(Vec::new())
+    } else if matches.is_present("units") {
+        values_t!(matches, "values", UnitValue).map(|v| v.into_iter().map(|UnitValue(value)| value).collect())
+    } else {
+        values_t!(matches, "values", Value)
+    }.unwrap_or_else(|e| {
+        super::usage_error(format_args!("invalid values: {}", e));
+    });
 
     if input_files.len() != output_files.len() {
-        eprintln!("binstore: number of input files does not match number of output files");
-        process::exit(1)
+        super::usage_error("number of input files does not match number of output files")
     }
 
-    let files: Vec<(String, String)> = input_files.into_iter().zip(output_files).collect();
+    let files: Vec<(PathBuf, PathBuf)> = input_files.into_iter().zip(output_files).collect();
 
-    let mut ret = 0;
-    for (input, output) in &files {
-        if let Err(e) = binstore::bucket::delete(input, output, &values) {
-            ret = 1;
-            eprintln!("binstore: {}: {}", input, e);
+    let options = super::create_options(matches);
+    let mut batch = Batch::from_matches(matches);
+    delete_all(&files, &values, &options, &mut batch);
+    Ok(batch.exit_code())
+}
+
+/// Writes each output of `files` from its input without `values`,
+/// reporting to `batch`.
+fn delete_all(files: &[(PathBuf, PathBuf)], values: &[Value], options: &CreateOptions, batch: &mut Batch) {
+    for (input, output) in files {
+        match binstore::bucket::delete_with_options(input, output, values, options) {
+            Ok(report) => {
+                if options.dry_run {
+                    eprintln!("binstore: dry run: {}: would remove {} values, emptying {} keys, drop {} keys, and write {} entries ({} bytes) to {}",
+                              input.display(), report.values_removed, report.keys_emptied, report.keys_dropped,
+                              report.output.num_entries, report.output.size, output.display());
+                }
+                batch.succeed();
+            }
+            Err(e) => {
+                if !batch.fail(input, &e, e.exit_code()) {
+                    return;
+                }
+            }
         }
     }
-    process::exit(ret);
+}
+
+#[cfg(test)]
+mod tests {
+    use binstore::bucket::{self, Bucket};
+    use binstore::prelude::*;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::ffi::{OsStr, OsString};
+    use std::fs;
+    use super::*;
+    use crate::subcommands::BatchMode;
+
+    /// `run` with the arguments of `binstore delete`.
+    fn run_with(args: &[&OsStr]) -> Result<i32> {
+        let args = ["binstore", "delete"].iter().map(OsString::from).chain(args.iter().map(OsString::from));
+        let matches = crate::app().get_matches_from(args);
+        run(matches.subcommand_matches("delete").unwrap())
+    }
+
+    #[test]
+    fn run_exit_codes() {
+        let dir = tempfile::tempdir().unwrap();
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 10_u64).map(|key| (key, (0 .. 3).collect())).collect();
+        let good = dir.path().join("good.binstore");
+        bucket::create(&good, &bmap).expect("create");
+        let bad = dir.path().join("bad.binstore");
+        fs::write(&bad, b"not a bucket").unwrap();
+        let out = dir.path().join("out.binstore");
+
+        assert_eq!(run_with(&[good.as_os_str(), "-v".as_ref(), "1".as_ref(), "-o".as_ref(), out.as_os_str()]).expect("run"), 0);
+        let map = Bucket::open(&out).and_then(|b| b.check_headers()).expect("open").to_map().expect("to_map");
+        assert_eq!(map[&0], [0, 2].iter().cloned().collect());
+        assert_eq!(run_with(&[bad.as_os_str(), "-v".as_ref(), "1".as_ref(), "-o".as_ref(), out.as_os_str()]).expect("run"), EXIT_CORRUPT);
+        let missing = dir.path().join("missing.binstore");
+        assert_eq!(run_with(&[missing.as_os_str(), "-v".as_ref(), "1".as_ref(), "-o".as_ref(), out.as_os_str()]).expect("run"), EXIT_IO);
+    }
+
+    #[test]
+    fn bad_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 10_u64).map(|key| (key, (0 .. 3).collect())).collect();
+        let good = dir.path().join("good.binstore");
+        bucket::create(&good, &bmap).expect("create");
+        let bad = dir.path().join("bad.binstore");
+        fs::write(&bad, b"not a bucket").unwrap();
+        let files = vec![
+            (bad.clone(), dir.path().join("bad.out")),
+            (good.clone(), dir.path().join("good.out")),
+        ];
+
+        let run = |mode, files: &[(PathBuf, PathBuf)]| {
+            for (_, output) in files {
+                let _ = fs::remove_file(output);
+            }
+            let mut batch = Batch::new(mode);
+            delete_all(files, &[1], &CreateOptions::default(), &mut batch);
+            batch.exit_code()
+        };
+
+        assert_eq!(run(BatchMode::KeepGoing, &files), EXIT_CORRUPT);
+        assert!(dir.path().join("good.out").exists());
+        assert_eq!(run(BatchMode::SkipBadFiles, &files), 0);
+        let map = Bucket::open(dir.path().join("good.out")).and_then(|b| b.check_headers()).expect("open")
+            .to_map().expect("to_map");
+        assert_eq!(map[&0], [0, 2].iter().cloned().collect());
+        assert_eq!(run(BatchMode::FailFast, &files), EXIT_CORRUPT);
+        assert!(!dir.path().join("good.out").exists());
+
+        // Skipping bad files still fails when none of them is good.
+        assert_eq!(run(BatchMode::SkipBadFiles, &files[.. 1]), EXIT_CORRUPT);
+    }
 }
@@ -0,0 +1,84 @@
+use clap::{ArgMatches, value_t};
+use binstore::bucket::Bucket;
+use binstore::prelude::*;
+use log::warn;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub fn main(matches: &ArgMatches) {
+    super::exit(run(matches));
+}
+
+/// Prints the buckets holding the key and returns the exit code.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    let key: HashedKey = match value_t!(matches, "key", HashedKey) {
+        Ok(key) => key,
+        Err(e) => {
+            super::usage_error(format_args!("invalid key: {}", e));
+        }
+    };
+    let dbdir = super::path(matches, "db-dir").unwrap();
+
+    let paths = match find(&dbdir, key) {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("binstore: {}: {}", dbdir.display(), e);
+            return Ok(e.exit_code());
+        }
+    };
+    if matches.is_present("json") {
+        println!("{}", serde_json::json!(paths));
+    } else {
+        for path in &paths {
+            println!("{}", path.display());
+        }
+    }
+    Ok(0)
+}
+
+/// The buckets in `dir` that contain `key`, sorted by path.  Files
+/// that aren't valid buckets are logged and skipped.
+fn find(dir: &Path, key: HashedKey) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            continue;
+        }
+        let mut bucket = match Bucket::open(&path).and_then(|b| b.check_headers()) {
+            Ok(bucket) => bucket,
+            Err(e) => {
+                warn!("could not load bucket from file {:?} with error: {}", &path, e);
+                continue;
+            }
+        };
+        if bucket.contains(key)? {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use binstore::bucket;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::iter::FromIterator;
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn find_reports_matching_files() {
+        let dir = tempdir().unwrap();
+        for (name, keys) in &[("a.binstore", vec![1, 42]), ("b.binstore", vec![7]), ("c.binstore", vec![42, 100])] {
+            let bmap: BTreeMap<HashedKey, BTreeSet<Value>> =
+                keys.iter().map(|&key| (key, BTreeSet::from_iter(0 .. 3_u128))).collect();
+            bucket::create(dir.path().join(name), &bmap).expect("create");
+        }
+
+        let found = find(dir.path(), 42).expect("find");
+        assert_eq!(found, vec![dir.path().join("a.binstore"), dir.path().join("c.binstore")]);
+        assert!(find(dir.path(), 43).expect("find").is_empty());
+    }
+}
@@ -0,0 +1,105 @@
+use binstore::prelude::{HashedKey, Value};
+use clap::ArgMatches;
+use std::fmt;
+
+/// How a number is written in text output.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NumberFormat {
+    #[default]
+    Dec,
+    /// Lowercase hex after `0x`, as `--key` and `--keys-file` read it.
+    Hex,
+}
+
+impl NumberFormat {
+    fn from_matches(matches: &ArgMatches, name: &str) -> NumberFormat {
+        match matches.value_of(name) {
+            Some("dec") | None => NumberFormat::Dec,
+            Some("hex") => NumberFormat::Hex,
+            Some(format) => {
+                super::usage_error(format_args!("unknown {}: {}", name, format));
+            }
+        }
+    }
+}
+
+/// How text output writes keys and values, from `--key-format` and
+/// `--value-format`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TextFormat {
+    pub keys: NumberFormat,
+    pub values: NumberFormat,
+}
+
+impl TextFormat {
+    pub fn from_matches(matches: &ArgMatches) -> TextFormat {
+        TextFormat {
+            keys: NumberFormat::from_matches(matches, "key-format"),
+            values: NumberFormat::from_matches(matches, "value-format"),
+        }
+    }
+
+    pub fn key(&self, key: HashedKey) -> Number {
+        Number(key as u128, self.keys)
+    }
+
+    pub fn value(&self, value: Value) -> Number {
+        Number(value, self.values)
+    }
+
+    /// The values as a list, written like a `Vec`.
+    pub fn list<'a, I: IntoIterator<Item = &'a Value>>(&self, values: I) -> Vec<Number> {
+        values.into_iter().map(|value| self.value(*value)).collect()
+    }
+
+    /// The values as a set, written like a `BTreeSet`.
+    pub fn set<'a, I: IntoIterator<Item = &'a Value>>(&self, values: I) -> Set {
+        Set(self.list(values))
+    }
+}
+
+/// A key or a value, written in its `NumberFormat` by both `Display`
+/// and `Debug`.
+pub struct Number(u128, NumberFormat);
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.1 {
+            NumberFormat::Dec => write!(f, "{}", self.0),
+            NumberFormat::Hex => write!(f, "0x{:x}", self.0),
+        }
+    }
+}
+
+impl fmt::Debug for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+pub struct Set(Vec<Number>);
+
+impl fmt::Debug for Set {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(&self.0).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use super::*;
+
+    #[test]
+    fn dec_matches_debug() {
+        let values: BTreeSet<Value> = [1, 20, u128::MAX].iter().cloned().collect();
+        let text = TextFormat::default();
+        assert_eq!(format!("{:?}", text.set(&values)), format!("{:?}", values));
+        assert_eq!(format!("{:?}", text.list(&values)), format!("{:?}", values.iter().collect::<Vec<_>>()));
+        assert_eq!(text.key(u64::MAX).to_string(), u64::MAX.to_string());
+
+        let text = TextFormat { keys: NumberFormat::Hex, values: NumberFormat::Dec };
+        assert_eq!(text.key(255).to_string(), "0xff");
+        assert_eq!(format!("{:?}", Some(text.set(&values))), format!("{:?}", Some(&values)));
+    }
+}
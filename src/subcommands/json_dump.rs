@@ -1,25 +1,105 @@
-use clap::{ArgMatches, values_t};
+use clap::{ArgMatches, value_t};
 use binstore::prelude::*;
-use binstore::bucket;
+use binstore::bucket::{Bucket, Initial, VersionRange};
 use serde::Serialize;
+use super::format::TextFormat;
 use std::collections::BTreeSet;
-use std::io::{self, BufWriter, stdout, Write};
-use std::io::{SeekFrom, Seek};
-use lz4::{Decoder};
+use std::io::{self, BufWriter, Read, stdout, Write};
+use std::path::{Path, PathBuf};
+
+/// Which entries `json-dump` writes; entries must pass every filter
+/// that is set.
+#[derive(Debug, Default)]
+struct Filter {
+    min_key: Option<HashedKey>,
+    max_key: Option<HashedKey>,
+    min_values: Option<usize>,
+    contains_value: Option<Value>,
+}
+
+impl Filter {
+    fn from_matches(matches: &ArgMatches) -> Filter {
+        fn optional<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+            matches.value_of(name)?;
+            match value_t!(matches, name, T) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    super::usage_error(format_args!("invalid {}: {}", name, e));
+                }
+            }
+        }
+        Filter {
+            min_key: optional(matches, "min-key"),
+            max_key: optional(matches, "max-key"),
+            min_values: optional(matches, "min-values"),
+            contains_value: optional(matches, "contains-value"),
+        }
+    }
+
+    /// Whether entries with `key` may pass; checked before their
+    /// values are decompressed.
+    fn key_matches(&self, key: HashedKey) -> bool {
+        self.min_key.map_or(true, |min| key >= min) && self.max_key.map_or(true, |max| key <= max)
+    }
+
+    fn values_match(&self, values: &BTreeSet<Value>) -> bool {
+        self.min_values.map_or(true, |n| values.len() >= n)
+            && self.contains_value.map_or(true, |v| values.contains(&v))
+    }
+}
 
 pub fn main(matches: &ArgMatches) {
-    let filenames = values_t!(matches, "input-files", String).unwrap_or(vec![]);
-    let mut ret = 0;
+    super::exit(run(matches));
+}
+
+/// Dumps the buckets and returns the exit code.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    let filenames = input_files(super::paths(matches, "input-files"), atty::is(atty::Stream::Stdin))
+        .unwrap_or_else(|message| super::usage_error(message));
+    let filenames = super::expand_inputs(&filenames)?;
+    let filter = Filter::from_matches(matches);
+    let text = TextFormat::from_matches(matches);
+    let versions = super::accepted_versions(matches);
+    let skip_errors = matches.is_present("skip-errors");
+    let jsonl = matches.value_of("format") == Some("jsonl");
+    let mut batch = super::Batch::from_matches(matches);
     for filename in filenames {
-        match dump(&filename) {
-            Ok(()) => { }
-            Err(e) => {
-                eprintln!("binstore: {}", e);
-                ret = 1;
+        let go_on = match dump(&filename, &filter, text, versions, skip_errors, jsonl, BufWriter::new(stdout().lock())) {
+            Ok(0) => {
+                batch.succeed();
+                true
             }
+            Ok(skipped) => batch.fail(&filename, format_args!("skipped {} entries", skipped), EXIT_CORRUPT),
+            Err(e) => batch.fail(&filename, &e, e.exit_code()),
+        };
+        if !go_on {
+            break;
         }
     }
-    std::process::exit(ret);
+    Ok(batch.exit_code())
+}
+
+/// The files to dump: without any, a bucket piped to stdin, so that
+/// forgetting the files in a script fails rather than dumps nothing.
+fn input_files(filenames: Vec<PathBuf>, stdin_is_terminal: bool) -> std::result::Result<Vec<PathBuf>, &'static str> {
+    if !filenames.is_empty() {
+        return Ok(filenames);
+    }
+    if stdin_is_terminal {
+        return Err("no input files; give FILES, or pipe a bucket to stdin");
+    }
+    Ok(vec![PathBuf::from("-")])
+}
+
+/// Opens the bucket of `filename` twice; `-` reads the whole bucket
+/// from `stdin` first, as it cannot be seeked.
+fn open_twice<R: Read>(filename: &Path, mut stdin: R) -> Result<(Bucket<Initial>, Bucket<Initial>)> {
+    if filename.as_os_str() != "-" {
+        return Ok((Bucket::open(filename)?, Bucket::open(filename)?));
+    }
+    let mut bytes = Vec::new();
+    stdin.read_to_end(&mut bytes)?;
+    Ok((Bucket::from_bytes(bytes.clone()), Bucket::from_bytes(bytes)))
 }
 
 struct LargeNumberAsStrings;
@@ -34,62 +114,239 @@ impl serde_json::ser::Formatter for LargeNumberAsStrings {
     }
 }
 
+/// Keys and values are written as strings, in the `TextFormat` of
+/// the dump.
 #[derive(Serialize)]
 struct ValueEntry {
-    key: HashedKey,
+    key: String,
     absolute_offset: u64,
-    values: BTreeSet<Value>,
+    values: Vec<String>,
 }
 
-fn dump(filename: &str) -> Result<()> {
-    let stdout = stdout();
-    let stdout = stdout.lock();
-    let mut stdout = BufWriter::new(stdout);
+/// A line of a `jsonl` dump.
+#[derive(Serialize)]
+struct KeyValues {
+    key: String,
+    values: Vec<String>,
+}
 
-    let mut json_serializer = serde_json::Serializer::with_formatter(&mut stdout, LargeNumberAsStrings{});
+/// Writes `value` as JSON, with its numbers as strings.
+fn write_json<W: Write, T: Serialize>(w: &mut W, value: &T) -> Result<()> {
+    value.serialize(&mut serde_json::Serializer::with_formatter(w, LargeNumberAsStrings{}))?;
+    Ok(())
+}
 
-    let bucket = bucket::Bucket::open(filename)?;
-    let mut bucket = bucket.check_headers()?;
+/// Returns how many entries were skipped: with `skip_errors`, the
+/// entries whose values cannot be read are left out with a warning
+/// instead of ending the dump.  With `jsonl`, only the keys and their
+/// values are written, one key per line.
+fn dump<W: Write>(filename: &Path, filter: &Filter, text: TextFormat, versions: VersionRange,
+                  skip_errors: bool, jsonl: bool, mut w: W) -> Result<u64> {
+    // Open the bucket twice: once to have a cursor in the dense
+    // index; once to have a cursor in the data section.
+    let (bucket, data) = open_twice(filename, io::stdin().lock())?;
+    let mut bucket = bucket.check_headers_accepting(versions)?;
+    let mut data = data.check_headers_accepting(versions)?;
 
-    // Dump header
-    bucket.header.serialize(&mut json_serializer)?;
+    if !jsonl {
+        // Dump header
+        write_json(&mut w, &bucket.header)?;
 
-    // Dump sparse index
-    let si: bucket::SparseIndex = bincode::deserialize_from(&mut bucket.file)?;
-    si.serialize(&mut json_serializer)?;
+        // Dump sparse index
+        let si = bucket.read_sparse_index()?;
+        write_json(&mut w, &si)?;
+    }
 
     // Dump dense index
-    let num_entries =
-        (bucket.header.data_base_offset - bucket.header.di_base_offset) / (bucket::INDEX_ENTRY_SIZE as u64);
-
-    for _ in 0 .. num_entries {
-        // Decode Dense Index entry
-        let di_entry: bucket::IndexEntry = bincode::deserialize_from(&mut bucket.file)?;
-
-        // Save current position
-        let curr_pos = bucket.file.seek(SeekFrom::Current(0))?;
-
-        // Go to the offset where the values associated with this index entry are.
-        let abs_offset = bucket.header.data_base_offset + di_entry.offset;
-        bucket.file.seek(SeekFrom::Start(abs_offset))?;
-
-        // Decode the lz4 payload.
-        let mut bincode: Vec<u8> = Vec::new();
-        let mut lz4_decoder = Decoder::new(&mut bucket.file)?;
-        io::copy(&mut lz4_decoder, &mut bincode)?;
-        let u8_ref: &[u8] = bincode.as_ref();
-        let values: BTreeSet<Value> = bincode::deserialize_from(u8_ref)?;
-
-        // Go back to where we came from.
-        bucket.file.seek(SeekFrom::Start(curr_pos))?;
-
-        let entry = ValueEntry {
-            key: di_entry.key,
-            absolute_offset: abs_offset,
-            values: values
+    let mut skipped = 0;
+    for di_entry in bucket.index_entries()? {
+        let di_entry = di_entry?;
+        if !filter.key_matches(di_entry.key) {
+            continue;
+        }
+        let values = match data.read_values_at(di_entry.offset) {
+            Ok(values) => values,
+            Err(e) if skip_errors => {
+                eprintln!("binstore: {}: skipping key {} at offset {}: {}",
+                          filename.display(), text.key(di_entry.key), di_entry.offset, e);
+                skipped += 1;
+                continue;
+            }
+            Err(e) => return Err(e),
         };
-        entry.serialize(&mut json_serializer)?;
+        if !filter.values_match(&values) {
+            continue;
+        }
+
+        let key = text.key(di_entry.key).to_string();
+        let values = values.iter().map(|value| text.value(*value).to_string()).collect();
+        if jsonl {
+            write_json(&mut w, &KeyValues { key, values })?;
+            w.write_all(b"\n")?;
+        } else {
+            let absolute_offset = data.header.data_base_offset + data.header.data_block_offset(di_entry.offset);
+            write_json(&mut w, &ValueEntry { key, absolute_offset, values })?;
+        }
     }
+    w.flush()?;
 
-    return Ok(());
+    return Ok(skipped);
+}
+
+#[cfg(test)]
+mod tests {
+    use binstore::bucket;
+    use std::collections::{BTreeMap, BTreeSet};
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn dump_empty_bucket() {
+        let tmp = NamedTempFile::new().unwrap();
+        bucket::create(tmp.path(), &BTreeMap::new()).expect("create");
+
+        let mut out = Vec::new();
+        dump(tmp.path(), &Filter::default(), TextFormat::default(), VersionRange::default(), false, false, &mut out)
+            .expect("dump");
+        let json = parse(&out);
+        // Only the header and the sparse index.
+        assert_eq!(json.len(), 2);
+        assert_eq!(json[0]["num_entries"], serde_json::json!("0"));
+    }
+
+    fn parse(out: &[u8]) -> Vec<serde_json::Value> {
+        serde_json::Deserializer::from_slice(out)
+            .into_iter()
+            .collect::<std::result::Result<_, _>>()
+            .expect("json")
+    }
+
+    /// The keys of the entries dumped with `filter`.
+    fn dumped_keys(filename: &Path, filter: &Filter) -> Vec<HashedKey> {
+        let mut out = Vec::new();
+        dump(filename, filter, TextFormat::default(), VersionRange::default(), false, false, &mut out).expect("dump");
+        parse(&out)[2 ..].iter()
+            .map(|entry| entry["key"].as_str().unwrap().parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn filters() {
+        // Key k holds the values 0 .. k.
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> =
+            (0 .. 10_u64).map(|key| (key, (0 .. key as Value).collect())).collect();
+        let tmp = NamedTempFile::new().unwrap();
+        bucket::create(tmp.path(), &bmap).expect("create");
+        let filename = tmp.path();
+
+        assert_eq!(dumped_keys(filename, &Filter::default()), (0 .. 10).collect::<Vec<_>>());
+        let filter = Filter { min_values: Some(8), ..Filter::default() };
+        assert_eq!(dumped_keys(filename, &filter), vec![8, 9]);
+        let filter = Filter { contains_value: Some(6), ..Filter::default() };
+        assert_eq!(dumped_keys(filename, &filter), vec![7, 8, 9]);
+        let filter = Filter { min_key: Some(2), max_key: Some(4), ..Filter::default() };
+        assert_eq!(dumped_keys(filename, &filter), vec![2, 3, 4]);
+
+        let filter = Filter {
+            max_key: Some(8),
+            min_values: Some(3),
+            contains_value: Some(4),
+            ..Filter::default()
+        };
+        assert_eq!(dumped_keys(filename, &filter), vec![5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn jsonl() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> =
+            (0 .. 20_u64).map(|key| (key << 60 | key, (0 .. key as Value).map(|value| value << 100).collect())).collect();
+        let tmp = NamedTempFile::new().unwrap();
+        bucket::create(tmp.path(), &bmap).expect("create");
+
+        let filter = Filter { min_values: Some(3), ..Filter::default() };
+        let mut out = Vec::new();
+        dump(tmp.path(), &filter, TextFormat::default(), VersionRange::default(), false, true, &mut out).expect("dump");
+        let text = String::from_utf8(out).unwrap();
+        let mut dumped = BTreeMap::new();
+        for line in text.lines() {
+            let entry: serde_json::Value = serde_json::from_str(line).expect("json line");
+            assert_eq!(entry.as_object().unwrap().len(), 2);
+            let key: HashedKey = entry["key"].as_str().unwrap().parse().unwrap();
+            let values: BTreeSet<Value> = entry["values"].as_array().unwrap().iter()
+                .map(|value| value.as_str().unwrap().parse().unwrap())
+                .collect();
+            dumped.insert(key, values);
+        }
+        let expected: BTreeMap<HashedKey, BTreeSet<Value>> = bmap.into_iter().filter(|(_, values)| values.len() >= 3).collect();
+        assert_eq!(dumped, expected);
+        assert_eq!(text.lines().count(), 17);
+    }
+
+    #[test]
+    fn offsets_match_locate_offset() {
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> =
+            (0 .. 50_u64).map(|key| (key * 3, (0 .. 1 + key as Value).collect())).collect();
+        for &block_size in &[0, 256] {
+            let tmp = NamedTempFile::new().unwrap();
+            let options = bucket::CreateOptions { block_size, ..bucket::CreateOptions::default() };
+            bucket::create_with_options(tmp.path(), &bmap, &options).expect("create");
+
+            let mut out = Vec::new();
+            dump(tmp.path(), &Filter::default(), TextFormat::default(), VersionRange::default(), false, false, &mut out)
+                .expect("dump");
+            let mut bucket = bucket::Bucket::open(tmp.path()).unwrap().check_headers().unwrap();
+            for entry in &parse(&out)[2 ..] {
+                let key: HashedKey = entry["key"].as_str().unwrap().parse().unwrap();
+                let offset: u64 = entry["absolute_offset"].as_str().unwrap().parse().unwrap();
+                assert_eq!(bucket.locate_offset(key).expect("locate_offset"), Some(offset));
+            }
+            assert_eq!(bucket.locate_offset(1).expect("locate_offset"), None);
+        }
+    }
+
+    #[test]
+    fn no_input_files() {
+        let files = vec![PathBuf::from("a.binstore")];
+        assert_eq!(input_files(files.clone(), true), Ok(files));
+        assert!(input_files(Vec::new(), true).is_err());
+        assert_eq!(input_files(Vec::new(), false), Ok(vec![PathBuf::from("-")]));
+
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 10_u64).map(|key| (key, BTreeSet::from([1]))).collect();
+        let tmp = NamedTempFile::new().unwrap();
+        bucket::create(tmp.path(), &bmap).expect("create");
+        let piped = std::fs::read(tmp.path()).unwrap();
+        let (bucket, data) = open_twice(Path::new("-"), &piped[..]).expect("open_twice");
+        assert_eq!(bucket.check_headers().unwrap().to_map().expect("to_map"), bmap);
+        assert_eq!(data.check_headers().unwrap().header.num_entries, 10);
+    }
+
+    #[test]
+    fn skip_errors() {
+        use std::io::{Seek, SeekFrom};
+
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> =
+            (0 .. 10_u64).map(|key| (key, (0 .. 100 + key as Value).collect())).collect();
+        let tmp = NamedTempFile::new().unwrap();
+        bucket::create(tmp.path(), &bmap).expect("create");
+        let filename = tmp.path();
+
+        // Break the lz4 frame of the block of key 4.
+        let mut bucket = bucket::Bucket::open(filename).unwrap().check_headers().unwrap();
+        let entry = bucket.index_entries().unwrap().nth(4).unwrap().unwrap();
+        let mut f = tmp.reopen().unwrap();
+        f.seek(SeekFrom::Start(bucket.header.data_base_offset + entry.offset)).unwrap();
+        f.write_all(&[0; 4]).unwrap();
+
+        let mut out = Vec::new();
+        assert!(dump(filename, &Filter::default(), TextFormat::default(), VersionRange::default(), false, false, &mut out).is_err());
+
+        let mut out = Vec::new();
+        let skipped = dump(filename, &Filter::default(), TextFormat::default(), VersionRange::default(), true, false, &mut out)
+            .expect("dump");
+        assert_eq!(skipped, 1);
+        let keys: Vec<HashedKey> = parse(&out)[2 ..].iter()
+            .map(|entry| entry["key"].as_str().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(keys, vec![0, 1, 2, 3, 5, 6, 7, 8, 9]);
+    }
 }
@@ -1,25 +1,122 @@
-use clap::{ArgMatches, values_t};
-use std::process;
+use clap::ArgMatches;
+use binstore::bucket::{CreateOptions, WriteReport};
+use binstore::prelude::*;
+use std::io::{self, Cursor, Write};
+use std::path::{Path, PathBuf};
 
 pub fn main(matches: &ArgMatches) {
-    let filenames = values_t!(matches, "input-files", String).unwrap_or_else(|_| {
-        eprintln!("binstore: missing input file");
-        process::exit(1);
-    });
+    super::exit(run(matches));
+}
 
-    let output_name = values_t!(matches, "output-name", String).unwrap_or_else(|_| {
-        eprintln!("binstore: missing output name");
-        process::exit(1);
-    });
+/// Merges the two buckets and returns the exit code.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    let filenames = super::paths(matches, "input-files");
 
     if filenames.len() != 2 {
-        eprintln!("binstore: exactly two filenames must be provided");
-        process::exit(1);
+        super::usage_error("exactly two filenames must be provided");
+    }
+
+    let options = super::create_options(matches);
+    if matches.is_present("estimate") {
+        let estimate = binstore::bucket::estimate_merge(&filenames[0], &filenames[1], &options)?;
+        println!("{} keys, {} of them in both buckets", estimate.unique_keys, estimate.overlapping_keys);
+        println!("sparse index: {} bytes, dense index: about {} bytes",
+                 estimate.sparse_index_size, estimate.dense_index_size);
+        return Ok(0);
+    }
+
+    let output_name = super::path(matches, "output-name").unwrap_or_else(|| {
+        super::usage_error("missing output name");
+    });
+    if let Some(max_size) = matches.value_of("max-output-size") {
+        let max_size = max_size.parse::<u64>().unwrap_or_else(|e| {
+            super::usage_error(format_args!("invalid max output size: {}", e));
+        });
+        if output_name.as_os_str() == "-" {
+            super::usage_error("cannot split an output written to stdout");
+        }
+        let shard_name = |n| shard_name(&output_name, n);
+        let reports = binstore::bucket::merge_split(&filenames[0], &filenames[1], shard_name, max_size, &options)?;
+        if options.dry_run {
+            for (n, report) in reports.iter().enumerate() {
+                eprintln!("binstore: dry run: would write {} entries ({} bytes) to {}",
+                          report.num_entries, report.size, shard_name(n).display());
+            }
+        }
+        return Ok(0);
+    }
+    let report = if output_name.as_os_str() == "-" {
+        let stdout = io::stdout();
+        merge_to_stream(&filenames[0], &filenames[1], &mut stdout.lock(), &options)?
+    } else {
+        binstore::bucket::merge_with_options(&filenames[0], &filenames[1], &output_name, &options)?
+    };
+    if options.dry_run {
+        eprintln!("binstore: dry run: would write {} entries ({} bytes) to {}",
+                  report.num_entries, report.size, output_name.display());
     }
+    Ok(0)
+}
 
-    if let Err(e) = binstore::bucket::merge(&filenames[0], &filenames[1], &output_name[0]) {
-        eprintln!("binstore: {}", e);
-        process::exit(1);
+/// The name of shard `n` of a merge split from `output`: `out.binstore`
+/// becomes `out.0.binstore`, `out.1.binstore` and so on.
+fn shard_name(output: &Path, n: usize) -> PathBuf {
+    let mut name = output.file_stem().unwrap_or_default().to_owned();
+    name.push(format!(".{}", n));
+    if let Some(extension) = output.extension() {
+        name.push(".");
+        name.push(extension);
     }
- }
+    output.with_file_name(name)
+}
 
+/// Merges into memory, since the header and the index are written
+/// last by seeking back, then copies the finished bucket to `out`.
+fn merge_to_stream<W: Write>(filename1: &Path, filename2: &Path, out: &mut W, options: &CreateOptions) -> Result<WriteReport> {
+    if options.dry_run {
+        return binstore::bucket::merge_with_options(filename1, filename2, Path::new("-"), options);
+    }
+    let mut bucket = Cursor::new(Vec::new());
+    let report = binstore::bucket::merge_into(filename1, filename2, &mut bucket, options)?;
+    out.write_all(bucket.get_ref())?;
+    out.flush()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use binstore::bucket::{self, Bucket};
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::fs;
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn shard_names() {
+        assert_eq!(shard_name(Path::new("out/day.binstore"), 0), PathBuf::from("out/day.0.binstore"));
+        assert_eq!(shard_name(Path::new("day"), 12), PathBuf::from("day.12"));
+    }
+
+    #[test]
+    fn merge_to_stdout() {
+        let bmap_1: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 100_u64).map(|key| (key * 2, (0 .. 3).collect())).collect();
+        let bmap_2: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 100_u64).map(|key| (key * 3, (5 .. 7).collect())).collect();
+        let tmp_1 = NamedTempFile::new().unwrap();
+        let tmp_2 = NamedTempFile::new().unwrap();
+        bucket::create(tmp_1.path(), &bmap_1).expect("create");
+        bucket::create(tmp_2.path(), &bmap_2).expect("create");
+        let (file_1, file_2) = (tmp_1.path(), tmp_2.path());
+
+        let options = CreateOptions { timestamp: Some(0), ..CreateOptions::default() };
+        let merged = NamedTempFile::new().unwrap();
+        bucket::merge_with_options(file_1, file_2, merged.path(), &options).expect("merge");
+        let mut piped = Vec::new();
+        merge_to_stream(file_1, file_2, &mut piped, &options).expect("merge_to_stream");
+        assert_eq!(piped, fs::read(merged.path()).unwrap());
+
+        let mut bucket = Bucket::from_reader(Box::new(Cursor::new(piped)), "-".into())
+            .check_headers()
+            .expect("check_headers");
+        assert_eq!(bucket.to_map().expect("to_map").len(), 166);
+    }
+}
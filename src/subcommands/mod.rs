@@ -1,5 +1,539 @@
+pub mod benchmark;
+pub mod cat;
 pub mod delete;
+pub mod find;
+pub mod format;
 pub mod json_dump;
 pub mod merge;
+pub mod peek;
+pub mod prune;
 pub mod query;
 pub mod query_bucket;
+pub mod rehash;
+pub mod repair;
+pub mod stats;
+pub mod tail;
+pub mod verify;
+pub mod warm;
+
+use binstore::bucket::{Bucket, Codec, CreateOptions, MAX_BLOCK_SIZE, MergeTimestamp, VersionRange};
+use binstore::prelude::{Error, EXIT_IO, EXIT_USAGE, HashedKey, Value};
+use binstore::progress::Progress;
+use format::TextFormat;
+use clap::ArgMatches;
+use log::warn;
+use std::collections::BTreeSet;
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Prints `e` and exits with its `Error::exit_code`.
+pub fn report_error(e: &Error) -> ! {
+    eprintln!("binstore: {}", e);
+    process::exit(e.exit_code());
+}
+
+/// Exits with the status of a subcommand's `run`: the exit code it
+/// returns, or that of its error once printed.
+pub fn exit(status: binstore::prelude::Result<i32>) -> ! {
+    match status {
+        Ok(code) => process::exit(code),
+        Err(e) => report_error(&e),
+    }
+}
+
+/// Prints `message` about invalid arguments and exits with
+/// `EXIT_USAGE`.
+pub fn usage_error<D: fmt::Display>(message: D) -> ! {
+    eprintln!("binstore: {}", message);
+    process::exit(EXIT_USAGE);
+}
+
+/// Builds the options for the buckets written by a subcommand.
+pub fn create_options(matches: &ArgMatches) -> CreateOptions {
+    let mut options = CreateOptions {
+        dry_run: matches.is_present("dry-run"),
+        keep_empty: matches.is_present("keep-empty"),
+        sync: matches.is_present("sync"),
+        preserve_mtime: matches.is_present("preserve-mtime"),
+        lowmem: matches.is_present("lowmem"),
+        validate_after_write: matches.is_present("validate-after-write"),
+        dedup_inputs: matches.is_present("dedup-inputs"),
+        ..CreateOptions::default()
+    };
+    if let Some(secs) = matches.value_of("progress-interval") {
+        match secs.parse::<u64>() {
+            Ok(secs) => options.progress = Some(Progress::every(Duration::from_secs(secs))),
+            Err(e) => {
+                usage_error(format_args!("invalid progress interval: {}", e));
+            }
+        }
+    }
+    let timestamp = matches.value_of("timestamp")
+        .map(str::to_string)
+        .or_else(|| env::var("SOURCE_DATE_EPOCH").ok());
+    if let Some(secs) = timestamp {
+        // `merge` also takes the name of a MergeTimestamp.
+        match (secs.parse::<i64>(), secs.parse::<MergeTimestamp>()) {
+            (Ok(secs), _) => options.timestamp = Some(secs),
+            (_, Ok(policy)) => options.merge_timestamp = policy,
+            (Err(e), _) => {
+                usage_error(format_args!("invalid timestamp: {}", e));
+            }
+        }
+    }
+    match matches.value_of("codec") {
+        Some("lz4") | None => {}
+        Some("zstd") => options.codec = Codec::Zstd,
+        Some("none") => options.codec = Codec::Uncompressed,
+        Some("adaptive") => options.codec = Codec::Adaptive,
+        Some(codec) => {
+            usage_error(format_args!("unknown codec: {}", codec));
+        }
+    }
+    if let Some(max) = matches.value_of("max-values-per-key") {
+        match max.parse::<usize>() {
+            Ok(0) => {
+                usage_error("max values per key must be at least 1");
+            }
+            Ok(max) => options.max_values_per_key = Some(max),
+            Err(e) => {
+                usage_error(format_args!("invalid max values per key: {}", e));
+            }
+        }
+    }
+    if let Some(kind) = matches.value_of("index-kind") {
+        match kind.parse() {
+            Ok(kind) => options.index_kind = kind,
+            Err(e) => {
+                usage_error(format_args!("invalid index kind: {}", e));
+            }
+        }
+    }
+    options.varint_values = matches.is_present("varint-values");
+    if let Some(policy) = matches.value_of("cap-policy") {
+        match policy.parse() {
+            Ok(policy) => options.cap_policy = policy,
+            Err(e) => {
+                usage_error(format_args!("invalid cap policy: {}", e));
+            }
+        }
+    }
+    if let Some(range) = matches.value_of("drop-key-range") {
+        let keys = range.split_once(':')
+            .ok_or_else(|| "expected MIN:MAX".to_string())
+            .and_then(|(min, max)| Ok((parse_key(min).map_err(|e| e.to_string())?,
+                                       parse_key(max).map_err(|e| e.to_string())?)));
+        match keys {
+            Ok((min, max)) if min <= max => options.drop_keys = Some(min ..= max),
+            Ok(_) => {
+                usage_error("key range MIN must not be greater than MAX");
+            }
+            Err(e) => {
+                usage_error(format_args!("invalid key range: {}", e));
+            }
+        }
+    }
+    if let Some(bytes) = matches.value_of("block-size") {
+        match bytes.parse::<u64>() {
+            Ok(bytes) if bytes <= MAX_BLOCK_SIZE => options.block_size = bytes,
+            Ok(_) => {
+                usage_error(format_args!("block size must be at most {} bytes", MAX_BLOCK_SIZE));
+            }
+            Err(e) => {
+                usage_error(format_args!("invalid block size: {}", e));
+            }
+        }
+    }
+    options
+}
+
+/// How `query` and `query-bucket` write the values they find.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text(TextFormat),
+    /// A stream of bincoded `(HashedKey, BTreeSet<Value>)` pairs, for
+    /// other programs to read back.
+    Bincode,
+}
+
+impl OutputFormat {
+    pub fn from_matches(matches: &ArgMatches) -> OutputFormat {
+        match matches.value_of("output-format") {
+            Some("text") | None => OutputFormat::Text(TextFormat::from_matches(matches)),
+            Some("bincode") => OutputFormat::Bincode,
+            Some(format) => {
+                usage_error(format_args!("unknown output format: {}", format));
+            }
+        }
+    }
+}
+
+/// Writes `key` and its values as one pair of the bincode output
+/// format.
+pub fn write_bincode<W: Write>(out: &mut W, key: HashedKey, values: &BTreeSet<Value>) -> binstore::prelude::Result<()> {
+    binstore::bucket::bincode_config().serialize_into(out, &(key, values))?;
+    Ok(())
+}
+
+/// The files given to the argument `name`, as the OS passed them, so
+/// that names which aren't UTF-8 still open.
+pub fn paths(matches: &ArgMatches, name: &str) -> Vec<PathBuf> {
+    matches.values_of_os(name).map(|v| v.map(PathBuf::from).collect()).unwrap_or_default()
+}
+
+/// The bucket files named by `patterns`.  A directory stands for the
+/// buckets in it, and a glob such as `db/*.binstore` for the buckets it
+/// matches, both in name order; the files among them that aren't
+/// buckets are logged and skipped.  Other names, and globs matching
+/// nothing, are kept as they are for the subcommand to report.
+pub fn expand_inputs(patterns: &[PathBuf]) -> binstore::prelude::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let mut found = Vec::new();
+        if pattern.is_dir() {
+            for entry in fs::read_dir(pattern)? {
+                found.push(entry?.path());
+            }
+            found.sort();
+        } else if let Some(glob) = pattern.to_str().filter(|glob| !pattern.exists() && glob.contains(&['*', '?', '['][..])) {
+            // A name that isn't a valid pattern is a plain name.
+            if let Ok(paths) = glob::glob(glob) {
+                for path in paths {
+                    found.push(path.map_err(io::Error::from)?);
+                }
+            }
+            if found.is_empty() {
+                files.push(pattern.clone());
+                continue;
+            }
+        } else {
+            files.push(pattern.clone());
+            continue;
+        }
+        for path in found {
+            if path.is_dir() {
+                continue;
+            }
+            match Bucket::read_header(&path) {
+                Ok(_) => files.push(path),
+                Err(e) => warn!("skipping file {:?}, which is not a bucket: {}", &path, e),
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Like `paths`, for an argument taking one file.
+pub fn path(matches: &ArgMatches, name: &str) -> Option<PathBuf> {
+    matches.value_of_os(name).map(PathBuf::from)
+}
+
+/// Where a subcommand writes its results: the file of `--output-file`,
+/// or stdout.  Log messages go to stderr, so stdout only ever holds
+/// the results.
+pub fn output(matches: &ArgMatches) -> Box<dyn Write> {
+    match path(matches, "output-file") {
+        Some(path) => match File::create(&path) {
+            Ok(f) => Box::new(BufWriter::new(f)),
+            Err(e) => {
+                eprintln!("binstore: {}: {}", path.display(), e);
+                process::exit(EXIT_IO);
+            }
+        },
+        None => Box::new(BufWriter::new(io::stdout())),
+    }
+}
+
+/// Parses a key written in decimal, or in hex after `0x`.
+pub fn parse_key(s: &str) -> Result<HashedKey, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => HashedKey::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// Reads one key per line; blank lines are skipped.
+pub fn read_keys<R: BufRead>(r: R) -> Result<Vec<HashedKey>, String> {
+    let mut keys = Vec::new();
+    for (i, line) in r.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        keys.push(parse_key(line).map_err(|e| format!("line {}: {}: {}", i + 1, line, e))?);
+    }
+    Ok(keys)
+}
+
+/// The keys of `--key`, followed by those of `--keys-file`, which is
+/// read from stdin when it is `-`.
+pub fn keys(matches: &ArgMatches) -> Vec<HashedKey> {
+    let mut keys = Vec::new();
+    for key in matches.values_of("key").into_iter().flatten() {
+        match parse_key(key) {
+            Ok(key) => keys.push(key),
+            Err(e) => {
+                usage_error(format_args!("invalid hash: {}: {}", key, e));
+            }
+        }
+    }
+    let read = match path(matches, "keys-file") {
+        Some(path) if path.as_os_str() == "-" => read_keys(io::stdin().lock()),
+        Some(path) => File::open(path).map_err(|e| e.to_string()).and_then(|f| read_keys(BufReader::new(f))),
+        None => Ok(Vec::new()),
+    };
+    match read {
+        Ok(more) => keys.extend(more),
+        Err(e) => {
+            usage_error(format_args!("cannot read keys file: {}", e));
+        }
+    }
+    keys
+}
+
+/// The versions of `--accept-version-range`, or only the current one.
+pub fn accepted_versions(matches: &ArgMatches) -> VersionRange {
+    match matches.value_of("accept-version-range") {
+        Some(range) => range.parse().unwrap_or_else(|e| {
+            usage_error(format_args!("invalid version range: {}", e));
+        }),
+        None => VersionRange::default(),
+    }
+}
+
+/// How a subcommand working through several files turns their
+/// failures into its exit status: by default every file is tried and
+/// the status is that of the last failure.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BatchMode {
+    #[default]
+    KeepGoing,
+    /// `--skip-bad-files`: failures are warnings, and the status is 0
+    /// as long as one file succeeded.
+    SkipBadFiles,
+    /// `--fail-fast`: stop at the first failure.
+    FailFast,
+}
+
+/// The exit status of a subcommand run over several files.
+#[derive(Debug)]
+pub struct Batch {
+    mode: BatchMode,
+    ret: i32,
+    succeeded: bool,
+}
+
+impl Batch {
+    pub fn new(mode: BatchMode) -> Batch {
+        Batch { mode, ret: 0, succeeded: false }
+    }
+
+    pub fn from_matches(matches: &ArgMatches) -> Batch {
+        if matches.is_present("skip-bad-files") {
+            Batch::new(BatchMode::SkipBadFiles)
+        } else if matches.is_present("fail-fast") {
+            Batch::new(BatchMode::FailFast)
+        } else {
+            Batch::new(BatchMode::KeepGoing)
+        }
+    }
+
+    pub fn succeed(&mut self) {
+        self.succeeded = true;
+    }
+
+    /// Reports that `filename` failed with `message` and exit status
+    /// `code`; returns whether to go on with the next file.
+    pub fn fail<D: fmt::Display>(&mut self, filename: &Path, message: D, code: i32) -> bool {
+        self.ret = code;
+        match self.mode {
+            BatchMode::SkipBadFiles => eprintln!("binstore: warning: skipping {}: {}", filename.display(), message),
+            _ => eprintln!("binstore: {}: {}", filename.display(), message),
+        }
+        self.mode != BatchMode::FailFast
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        if self.mode == BatchMode::SkipBadFiles && self.succeeded {
+            return 0;
+        }
+        self.ret
+    }
+}
+
+/// The number of threads of the global `--threads`; 0, the default,
+/// uses one per core.
+pub fn threads(matches: &ArgMatches) -> usize {
+    match matches.value_of("threads") {
+        Some(n) => n.parse::<usize>().unwrap_or_else(|e| {
+            usage_error(format_args!("invalid number of threads: {}", e));
+        }),
+        None => 0,
+    }
+}
+
+/// The rayon pool the subcommands run their parallel work on.
+pub fn thread_pool(threads: usize) -> rayon::ThreadPoolBuilder {
+    rayon::ThreadPoolBuilder::new().num_threads(threads)
+}
+
+/// The seed of `--seed`, or one from the clock.
+pub fn seed(matches: &ArgMatches) -> u64 {
+    match matches.value_of("seed") {
+        Some(seed) => seed.parse::<u64>().unwrap_or_else(|e| {
+            usage_error(format_args!("invalid seed: {}", e));
+        }),
+        None => SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0),
+    }
+}
+
+/// A tiny seedable generator, for subcommands that pick things at
+/// random but must pick the same ones again given the same seed.
+pub struct SplitMix64(pub u64);
+
+impl SplitMix64 {
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        return z ^ (z >> 31);
+    }
+}
+
+/// A `Value` that may be written with a unit suffix: `k`, `m`, `g`,
+/// `t`, `p` and `e` multiply by powers of 1000, and `ki`, `mi`, `gi`,
+/// `ti`, `pi` and `ei` by powers of 1024.  Only whole numbers are
+/// accepted, so `1.5k` is rejected rather than rounded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnitValue(pub Value);
+
+#[derive(Debug, PartialEq)]
+pub enum UnitValueError {
+    Malformed(String),
+    UnknownUnit(String),
+    Overflow(String),
+}
+
+impl fmt::Display for UnitValueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UnitValueError::Malformed(ref s) => write!(f, "malformed value: {:?}", s),
+            UnitValueError::UnknownUnit(ref s) => write!(f, "unknown unit: {:?}", s),
+            UnitValueError::Overflow(ref s) => write!(f, "value too large: {:?}", s),
+        }
+    }
+}
+
+impl FromStr for UnitValue {
+    type Err = UnitValueError;
+
+    fn from_str(s: &str) -> Result<UnitValue, UnitValueError> {
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (digits, unit) = s.split_at(split);
+        if digits.is_empty() {
+            return Err(UnitValueError::Malformed(s.to_string()));
+        }
+        let (base, exponent): (Value, u32) = match unit.to_ascii_lowercase().as_str() {
+            "" => (1, 0),
+            "k" => (1000, 1),
+            "m" => (1000, 2),
+            "g" => (1000, 3),
+            "t" => (1000, 4),
+            "p" => (1000, 5),
+            "e" => (1000, 6),
+            "ki" => (1024, 1),
+            "mi" => (1024, 2),
+            "gi" => (1024, 3),
+            "ti" => (1024, 4),
+            "pi" => (1024, 5),
+            "ei" => (1024, 6),
+            _ if unit.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                return Err(UnitValueError::UnknownUnit(unit.to_string()));
+            }
+            _ => return Err(UnitValueError::Malformed(s.to_string())),
+        };
+        digits.parse::<Value>().ok()
+            .and_then(|n| n.checked_mul(base.pow(exponent)))
+            .map(UnitValue)
+            .ok_or_else(|| UnitValueError::Overflow(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_dirs_and_globs() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("db");
+        fs::create_dir_all(db.join("nested")).unwrap();
+        for name in &["b.binstore", "a.binstore", "c.bak", "nested/d.binstore"] {
+            binstore::bucket::create(db.join(name), &Default::default()).expect("create");
+        }
+        fs::write(db.join("notes.binstore"), "not a bucket").unwrap();
+
+        // Subdirectories and files that aren't buckets are skipped.
+        assert_eq!(expand_inputs(std::slice::from_ref(&db)).expect("expand_inputs"),
+                   vec![db.join("a.binstore"), db.join("b.binstore"), db.join("c.bak")]);
+        let glob = db.join("*.binstore");
+        assert_eq!(expand_inputs(&[glob, db.join("c.bak")]).expect("expand_inputs"),
+                   vec![db.join("a.binstore"), db.join("b.binstore"), db.join("c.bak")]);
+        let nested = db.join("*/*.binstore");
+        assert_eq!(expand_inputs(&[nested]).expect("expand_inputs"), vec![db.join("nested/d.binstore")]);
+
+        // Names matching nothing are left for the subcommand to report.
+        let missing = vec![db.join("missing.binstore"), db.join("*.missing"), PathBuf::from("-")];
+        assert_eq!(expand_inputs(&missing).expect("expand_inputs"), missing);
+    }
+
+    #[test]
+    fn threads_option() {
+        use clap::{App, Arg, SubCommand};
+
+        let app = || {
+            App::new("binstore")
+                .arg(Arg::with_name("threads").long("threads").takes_value(true).global(true))
+                .subcommand(SubCommand::with_name("query"))
+        };
+        let matches = app().get_matches_from(vec!["binstore", "--threads", "3", "query"]);
+        assert_eq!(threads(&matches), 3);
+        let matches = app().get_matches_from(vec!["binstore", "query", "--threads", "2"]);
+        assert_eq!(threads(&matches), 2);
+        let matches = app().get_matches_from(vec!["binstore", "query"]);
+        assert_eq!(threads(&matches), 0);
+
+        let pool = thread_pool(3).build().expect("build");
+        assert_eq!(pool.install(rayon::current_num_threads), 3);
+    }
+
+    #[test]
+    fn unit_value_from_str() {
+        assert_eq!("42".parse(), Ok(UnitValue(42)));
+        assert_eq!("1k".parse(), Ok(UnitValue(1000)));
+        assert_eq!("1K".parse(), Ok(UnitValue(1000)));
+        assert_eq!("1ki".parse(), Ok(UnitValue(1024)));
+        assert_eq!("2m".parse(), Ok(UnitValue(2_000_000)));
+        assert_eq!("3gi".parse(), Ok(UnitValue(3 << 30)));
+        // Values are u128, so even 999 exa fits.
+        assert_eq!("999e".parse(), Ok(UnitValue(999 * 10_u128.pow(18))));
+        assert_eq!(Value::MAX.to_string().parse(), Ok(UnitValue(Value::MAX)));
+
+        assert!(matches!("400000000000000000000e".parse::<UnitValue>(), Err(UnitValueError::Overflow(_))));
+        assert!(matches!("340282366920938463463374607431768211456".parse::<UnitValue>(), Err(UnitValueError::Overflow(_))));
+        assert!(matches!("1x".parse::<UnitValue>(), Err(UnitValueError::UnknownUnit(_))));
+        assert!(matches!("1kb".parse::<UnitValue>(), Err(UnitValueError::UnknownUnit(_))));
+        assert!(matches!("1.5k".parse::<UnitValue>(), Err(UnitValueError::Malformed(_))));
+        assert!(matches!("k".parse::<UnitValue>(), Err(UnitValueError::Malformed(_))));
+        assert!(matches!("-1".parse::<UnitValue>(), Err(UnitValueError::Malformed(_))));
+        assert!(matches!("".parse::<UnitValue>(), Err(UnitValueError::Malformed(_))));
+    }
+}
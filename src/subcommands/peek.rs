@@ -0,0 +1,78 @@
+use clap::ArgMatches;
+use binstore::bucket::{Bucket, VersionRange};
+use binstore::prelude::*;
+use chrono::prelude::*;
+use serde_json::json;
+use std::path::Path;
+
+pub fn main(matches: &ArgMatches) {
+    super::exit(run(matches));
+}
+
+/// Prints the headers of the buckets and returns the exit code.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    let filenames = super::expand_inputs(&super::paths(matches, "input-files"))?;
+    let raw = matches.is_present("raw");
+    let versions = super::accepted_versions(matches);
+
+    let mut ret = 0;
+    for filename in &filenames {
+        match peek(filename, raw, versions) {
+            Ok(header) => println!("{}", header),
+            Err(e) => {
+                ret = e.exit_code();
+                eprintln!("binstore: {}: {}", filename.display(), e);
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// The header of `filename` as JSON; unless `raw` is set, the file
+/// name and the creation time as a UTC datetime are added.
+fn peek(filename: &Path, raw: bool, versions: VersionRange) -> Result<serde_json::Value> {
+    let bucket_header = Bucket::open(filename)?.check_headers_accepting(versions)?.header;
+    let mut header = serde_json::to_value(&bucket_header)?;
+    if !raw {
+        let datetime = Utc.timestamp(bucket_header.timestamp, 0);
+        header["file"] = json!(filename.display().to_string());
+        header["datetime"] = json!(datetime.format("%Y-%m-%d %H:%M:%S %z").to_string());
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use binstore::bucket;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::iter::FromIterator;
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn peek_matches_header() {
+        let mut bmap = BTreeMap::new();
+        for key in 0 .. 10_u64 {
+            bmap.insert(key, BTreeSet::from_iter(0 .. (key as Value)));
+        }
+        let tmp = NamedTempFile::new().unwrap();
+        bucket::create(tmp.path(), &bmap).expect("create");
+        let filename = tmp.path();
+
+        let header = Bucket::open(filename).expect("open").check_headers().expect("check_headers").header;
+
+        let raw = peek(filename, true, VersionRange::default()).expect("peek");
+        assert_eq!(raw, serde_json::to_value(&header).unwrap());
+        assert_eq!(raw["num_entries"], json!(10));
+        // Nothing in the bucket needs the header extension.
+        assert_eq!(raw["version"], json!(0));
+        assert_eq!(raw["data_base_offset"], json!(header.data_base_offset));
+        assert!(raw.get("datetime").is_none());
+
+        let decorated = peek(filename, false, VersionRange::default()).expect("peek");
+        assert_eq!(decorated["num_entries"], json!(10));
+        assert_eq!(decorated["timestamp"], json!(header.timestamp));
+        assert_eq!(decorated["file"], json!(filename.display().to_string()));
+        assert!(decorated["datetime"].is_string());
+    }
+}
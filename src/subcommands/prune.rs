@@ -0,0 +1,194 @@
+use clap::ArgMatches;
+use binstore::bucket::Bucket;
+use binstore::db;
+use binstore::prelude::*;
+use chrono::prelude::*;
+use log::warn;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A bucket that `prune` removes.
+#[derive(Debug, PartialEq)]
+struct Pruned {
+    path: PathBuf,
+    date: Date<Utc>,
+    size: u64,
+}
+
+pub fn main(matches: &ArgMatches) {
+    super::exit(run(matches));
+}
+
+/// Prunes the old buckets, or lists them on a dry run, and returns
+/// the exit code.  Each bucket is listed once it is pruned, so after a
+/// failure the list tells which ones were.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    let dbdir = super::path(matches, "db-dir").unwrap();
+    let before = db::parse_date(matches.value_of("before").unwrap()).unwrap_or_else(|e| {
+        super::usage_error(format_args!("invalid date: {}", e));
+    });
+    let archive_dir = super::path(matches, "archive-dir");
+    let apply = matches.is_present("apply");
+
+    let pruned = match select(&dbdir, before) {
+        Ok(pruned) => pruned,
+        Err(e) => {
+            eprintln!("binstore: {}: {}", dbdir.display(), e);
+            return Ok(e.exit_code());
+        }
+    };
+    let verb = match (apply, &archive_dir) {
+        (false, _) => "dry run: would prune",
+        (true, None) => "removed",
+        (true, Some(_)) => "archived",
+    };
+    let (mut count, mut size) = (0, 0);
+    for bucket in &pruned {
+        if apply {
+            if let Err(e) = remove(bucket, archive_dir.as_deref()) {
+                eprintln!("binstore: {}: {}", bucket.path.display(), e);
+                eprintln!("binstore: {} {} buckets ({} bytes) before the error", verb, count, size);
+                return Ok(e.exit_code());
+            }
+        }
+        println!("{}\t{}\t{}", bucket.path.display(), bucket.date.format("%Y-%m-%d"), bucket.size);
+        count += 1;
+        size += bucket.size;
+    }
+    eprintln!("binstore: {} {} buckets ({} bytes){}", verb, count, size,
+              if apply { "" } else { "; pass --apply to prune them" });
+    Ok(0)
+}
+
+/// The buckets of `dir` dated before `before`, dated like `Db` dates
+/// them, in date order.  Files that aren't valid buckets are logged and
+/// kept.
+fn select(dir: &Path, before: Date<Utc>) -> Result<Vec<Pruned>> {
+    let mut pruned = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            continue;
+        }
+        let header = match Bucket::read_header(&path) {
+            Ok(header) => header,
+            Err(e) => {
+                warn!("could not read header of file {:?} with error: {}", &path, e);
+                continue;
+            }
+        };
+        let date = Utc.timestamp(header.timestamp, 0).date();
+        if date < before {
+            pruned.push(Pruned { size: fs::metadata(&path)?.len(), path, date });
+        }
+    }
+    pruned.sort_by(|a, b| a.date.cmp(&b.date).then_with(|| a.path.cmp(&b.path)));
+    Ok(pruned)
+}
+
+/// Removes the file of `bucket`, or with `archive_dir`, moves it
+/// there; a file already in `archive_dir` under the same name is left
+/// alone, and the bucket isn't pruned.
+fn remove(bucket: &Pruned, archive_dir: Option<&Path>) -> Result<()> {
+    let dir = match archive_dir {
+        Some(dir) => dir,
+        None => return Ok(fs::remove_file(&bucket.path)?),
+    };
+    let archived = dir.join(bucket.path.file_name().expect("a file"));
+    // Unlike renaming, linking and creating a new file fail if the
+    // archived file exists.  Linking fails across file systems, so
+    // copy instead.
+    match fs::hard_link(&bucket.path, &archived) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(already_archived(&archived)),
+        Err(_) => {
+            let mut archive = match OpenOptions::new().write(true).create_new(true).open(&archived) {
+                Ok(archive) => archive,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(already_archived(&archived)),
+                Err(e) => return Err(e.into()),
+            };
+            io::copy(&mut File::open(&bucket.path)?, &mut archive)?;
+        }
+    }
+    fs::remove_file(&bucket.path)?;
+    Ok(())
+}
+
+fn already_archived(archived: &Path) -> Error {
+    io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is already archived", archived.display())).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use binstore::bucket::{self, CreateOptions};
+    use std::collections::BTreeMap;
+    use std::ffi::{OsStr, OsString};
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn prune_before_date() {
+        let dir = tempdir().unwrap();
+        for day in 1 .. 6 {
+            // Late in the evening in UTC, which is the next day in
+            // zones east of it.
+            let timestamp = Utc.ymd(2024, 1, day).and_hms(23, 30, 0).timestamp();
+            let options = CreateOptions { timestamp: Some(timestamp), ..CreateOptions::default() };
+            bucket::create_with_options(dir.path().join(format!("{}.binstore", day)), &BTreeMap::new(), &options).expect("create");
+        }
+        fs::write(dir.path().join("notes.txt"), b"not a bucket").expect("write");
+
+        let pruned = select(dir.path(), db::parse_date("2024-01-03").unwrap()).expect("select");
+        let paths: Vec<PathBuf> = pruned.iter().map(|bucket| bucket.path.clone()).collect();
+        assert_eq!(paths, vec![dir.path().join("1.binstore"), dir.path().join("2.binstore")]);
+        assert_eq!(pruned[0].date, Utc.ymd(2024, 1, 1));
+        assert_eq!(pruned[0].size, fs::metadata(dir.path().join("1.binstore")).unwrap().len());
+        assert!(select(dir.path(), db::parse_date("2024-01-01").unwrap()).expect("select").is_empty());
+
+        let archive = tempdir().unwrap();
+        for bucket in &pruned {
+            remove(bucket, Some(archive.path())).expect("remove");
+        }
+        assert!(!dir.path().join("1.binstore").exists());
+        assert!(archive.path().join("2.binstore").exists());
+        assert!(dir.path().join("3.binstore").exists());
+
+        let pruned = select(dir.path(), db::parse_date("2024-01-05").unwrap()).expect("select");
+        assert_eq!(pruned.len(), 2);
+        for bucket in &pruned {
+            remove(bucket, None).expect("remove");
+        }
+        let mut left: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        left.sort();
+        assert_eq!(left, vec!["5.binstore", "notes.txt"]);
+    }
+
+    /// `run` with the arguments of `binstore prune`.
+    fn run_with(args: &[&OsStr]) -> Result<i32> {
+        let args = ["binstore", "prune"].iter().map(OsString::from).chain(args.iter().map(OsString::from));
+        let matches = crate::app().get_matches_from(args);
+        run(matches.subcommand_matches("prune").unwrap())
+    }
+
+    #[test]
+    fn archive_keeps_existing_files() {
+        let dir = tempdir().unwrap();
+        for day in 1 .. 4 {
+            let timestamp = Utc.ymd(2024, 1, day).and_hms(12, 0, 0).timestamp();
+            let options = CreateOptions { timestamp: Some(timestamp), ..CreateOptions::default() };
+            bucket::create_with_options(dir.path().join(format!("{}.binstore", day)), &BTreeMap::new(), &options).expect("create");
+        }
+        let archive = tempdir().unwrap();
+        fs::write(archive.path().join("2.binstore"), b"archived earlier").expect("write");
+
+        let code = run_with(&["--before".as_ref(), "2024-01-03".as_ref(), "--apply".as_ref(),
+                              "--archive-dir".as_ref(), archive.path().as_os_str(), dir.path().as_os_str()]).expect("run");
+        assert_eq!(code, EXIT_IO);
+        assert!(archive.path().join("1.binstore").exists());
+        assert!(!dir.path().join("1.binstore").exists());
+        assert!(dir.path().join("2.binstore").exists());
+        assert_eq!(fs::read(archive.path().join("2.binstore")).unwrap(), b"archived earlier");
+        assert!(dir.path().join("3.binstore").exists());
+    }
+}
@@ -1,69 +1,116 @@
-use clap::{ArgMatches, values_t};
+use chrono::{Date, Utc};
+use clap::ArgMatches;
 use binstore::db::*;
 use binstore::prelude::*;
-use std::process;
-use chrono::*;
+use super::OutputFormat;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::ops::Bound;
+use std::time::Duration;
 
 pub fn main(matches: &ArgMatches) {
-    let dbdir = matches.value_of("dbdir").unwrap();
-    let start_date_str = matches.value_of("start-date").unwrap();
-    let end_date_str = matches.value_of("end-date").unwrap();
+    super::exit(run(matches));
+}
 
-    let start_date = match parse_date(start_date_str) {
-        Ok(date) => date,
-        Err(e) => {
-            eprintln!("binstore: cannot parse start date: {}", e);
-            process::exit(1);
+/// Looks the keys up in the database and returns the exit code.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    let dbdir = super::path(matches, "dbdir").unwrap();
+    let range: (Bound<Date<Utc>>, Bound<Date<Utc>>) = match matches.value_of("range") {
+        Some(range) => match range.parse::<DateRange>() {
+            Ok(range) => (Bound::Included(range.start), Bound::Included(range.end)),
+            Err(e) => {
+                super::usage_error(format_args!("cannot parse date range: {}", e));
+            }
+        },
+        None => {
+            // Either date may be left out for a range open on that side.
+            let bound = |name: &str| match matches.value_of(name).map(parse_date) {
+                Some(Ok(date)) => Bound::Included(date),
+                Some(Err(e)) => {
+                    super::usage_error(format_args!("cannot parse {}: {}", name.replace('-', " "), e));
+                }
+                None => Bound::Unbounded,
+            };
+            let range = (bound("start-date"), bound("end-date"));
+            if range == (Bound::Unbounded, Bound::Unbounded) {
+                super::usage_error("missing start date or end date");
+            }
+            range
         }
     };
 
-    let end_date = match parse_date(end_date_str) {
-        Ok(date) => date,
-        Err(e) => {
-            eprintln!("binstore: cannot parse end date: {}", e);
-            process::exit(1);
-        }
-    };
+    let hashes: Vec<HashedKey> = super::keys(matches);
 
-    let hashes: Vec<HashedKey> = match values_t!(matches, "hash", HashedKey) {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("binstore: invalid hash: {}", e);
-            process::exit(1);
+    let max_open_files = match matches.value_of("max-open-files").map(str::parse::<usize>) {
+        Some(Ok(n)) => n,
+        Some(Err(e)) => {
+            super::usage_error(format_args!("invalid maximum number of open files: {}", e));
         }
+        None => DEFAULT_MAX_OPEN_FILES,
     };
 
-    let path = std::path::PathBuf::from(dbdir);
+    let mut retry = RetryPolicy::default();
+    if let Some(n) = matches.value_of("retries") {
+        retry.max_attempts = n.parse::<u32>().map(|n| n.saturating_add(1)).unwrap_or_else(|e| {
+            super::usage_error(format_args!("invalid number of retries: {}", e));
+        });
+    }
+    if let Some(ms) = matches.value_of("retry-backoff") {
+        retry.backoff = ms.parse().map(Duration::from_millis).unwrap_or_else(|e| {
+            super::usage_error(format_args!("invalid retry backoff: {}", e));
+        });
+    }
+
+    let db = match (matches.is_present("recursive"), matches.is_present("strict")) {
+        (false, false) => Db::open(dbdir),
+        (false, true) => Db::open_strict(dbdir),
+        (true, false) => Db::open_recursive(dbdir),
+        (true, true) => Db::open_recursive_strict(dbdir),
+    };
 
+    let sort = matches.is_present("sort");
+    let print_stats = matches.is_present("stats");
+    let format = OutputFormat::from_matches(matches);
+    let mut out = super::output(matches);
     let mut ret = 0;
-    match Db::open(path) {
+    match db {
         Ok(mut db) => {
+            db.set_max_open_files(max_open_files);
+            db.set_retry_policy(retry);
             for hash in &hashes {
-                match db.query(*hash, start_date, end_date) {
-                    Ok(tifas) => {
-                        println!("{}: {:?}", hash, tifas);
+                match db.query_bounds_verbose(*hash, range) {
+                    Ok((mut tifas, stats)) => {
+                        if print_stats {
+                            eprintln!("binstore: {}: searched {} buckets, skipped {}, read {} bytes",
+                                      hash, stats.buckets_searched, stats.buckets_skipped, stats.bytes_read);
+                        }
+                        if sort {
+                            tifas = tifas.into_iter().collect::<BTreeSet<Value>>().into_iter().collect();
+                        }
+                        let written = match format {
+                            OutputFormat::Text(text) => {
+                                writeln!(out, "{}: {:?}", text.key(*hash), text.list(&tifas)).map_err(Error::from)
+                            }
+                            OutputFormat::Bincode => super::write_bincode(&mut out, *hash, &tifas.into_iter().collect()),
+                        };
+                        written?;
                     },
                     Err(e) => {
-                        eprintln!("Jenny: {}", e);
-                        ret = 1;
+                        eprintln!("binstore: {}", e);
+                        ret = e.exit_code();
                     }
                 }
             }
         },
         Err(e) => {
-            eprintln!("Jenny: could not open database: {}", e);
-            process::exit(1);
+            eprintln!("binstore: could not open database: {}", e);
+            return Ok(e.exit_code());
         }
     }
 
-    process::exit(ret);
-}
-
-fn parse_date(s: &str) -> Result<Date<Local>> {
-    let naive = NaiveDate::parse_from_str(s, "%Y-%m-%d")?;
-    match TimeZone::from_local_date(&Local, &naive) {
-        LocalResult::Single(date) => Ok(date),
-        _ => Err(Error::DateParseError)
+    if let Err(e) = out.flush() {
+        eprintln!("binstore: {}", e);
+        ret = EXIT_IO;
     }
+    Ok(ret)
 }
-
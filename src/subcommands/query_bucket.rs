@@ -1,61 +1,421 @@
-use clap::{ArgMatches, values_t};
+use clap::ArgMatches;
 use binstore::error::*;
 use binstore::bucket::*;
 use binstore::prelude::*;
 use log::debug;
-use std::process;
+use super::OutputFormat;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
+/// The values of `--since` and `--until`: values inserted before
+/// `since`, or at or after `until`, are dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct TimeWindow {
+    since: Option<i64>,
+    until: Option<i64>,
+}
+
+impl TimeWindow {
+    fn from_matches(matches: &ArgMatches) -> TimeWindow {
+        let secs = |name: &str| {
+            matches.value_of(name).map(|secs| secs.parse::<i64>().unwrap_or_else(|e| {
+                super::usage_error(format_args!("invalid {}: {}", name, e));
+            }))
+        };
+        TimeWindow { since: secs("since"), until: secs("until") }
+    }
+
+    fn is_set(&self) -> bool {
+        self.since.is_some() || self.until.is_some()
+    }
+
+    fn contains(&self, timestamp: i64) -> bool {
+        self.since.map_or(true, |since| timestamp >= since) && self.until.map_or(true, |until| timestamp < until)
+    }
+}
+
 pub fn main(matches: &ArgMatches) {
-    let hashes: Vec<HashedKey> = match values_t!(matches, "hash", HashedKey) {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("binstore: invalid hash: {}", e);
-            process::exit(1);
-        }
-    };
+    super::exit(run(matches));
+}
 
-    let filenames: Vec<String> = match values_t!(matches, "input-files", String) {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("binstore: invalid input file: {}", e);
-            process::exit(1)
-        }
-    };
+/// Looks the keys up in the buckets and returns the exit code.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    let hashes: Vec<HashedKey> = super::keys(matches);
+
+    let filenames = super::expand_inputs(&super::paths(matches, "input-files"))?;
 
-    let mut ret = 0;
+    let versions = super::accepted_versions(matches);
+    if matches.is_present("quiet") {
+        return Ok(quiet_status(&filenames, &hashes, matches.is_present("any"), versions));
+    }
+
+    let format = OutputFormat::from_matches(matches);
+    let window = TimeWindow::from_matches(matches);
+    let show_offset = matches.is_present("show-offset");
+    let mut out = super::output(matches);
+    let mut batch = super::Batch::from_matches(matches);
     for filename in &filenames {
-        if let Err(e) = multi_query(filename, &hashes) {
-            ret = 1;
-            eprintln!("binstore: {}: {}", filename, e);
+        match multi_query(filename, &hashes, format, versions, window, show_offset, &mut out) {
+            Ok(()) => batch.succeed(),
+            Err(e) => {
+                if !batch.fail(filename, &e, e.exit_code()) {
+                    break;
+                }
+            }
         }
     }
-    process::exit(ret);
+    let mut ret = batch.exit_code();
+    if let Err(e) = out.flush() {
+        eprintln!("binstore: {}", e);
+        ret = EXIT_IO;
+    }
+    Ok(ret)
 }
 
-fn multi_query(filename: &str, hashes: &[HashedKey]) -> Result<()> {
+/// The exit status of a `--quiet` query: 0 if every key (or with
+/// `any`, at least one key) is in one of the files, 1 otherwise, and 2
+/// if a file can't be read.  Only the indexes are read.
+fn quiet_status(filenames: &[PathBuf], hashes: &[HashedKey], any: bool, versions: VersionRange) -> i32 {
+    let mut found = vec![false; hashes.len()];
+    for filename in filenames {
+        let bucket = Bucket::open(filename).and_then(|b| b.check_headers_accepting(versions)).and_then(|b| b.prepare());
+        let mut bucket = match bucket {
+            Ok(bucket) => bucket,
+            Err(e) => {
+                debug!("{}: {}", filename.display(), e);
+                return 2;
+            }
+        };
+        for (hash, found) in hashes.iter().zip(found.iter_mut()) {
+            if *found {
+                continue;
+            }
+            match bucket.contains(*hash) {
+                Ok(contains) => *found = contains,
+                Err(e) => {
+                    debug!("{}: {}", filename.display(), e);
+                    return 2;
+                }
+            }
+        }
+    }
+    let present = if any {
+        found.iter().any(|&found| found)
+    } else {
+        found.iter().all(|&found| found)
+    };
+    if present { 0 } else { 1 }
+}
+
+/// Writes the values of the `hashes` found in `filename` to `out`,
+/// those appended to its log included.  The text format also prints
+/// `None` for keys between two sparse index entries that aren't in the
+/// bucket; the bincode format only writes the keys found.  With a
+/// `window`, the bucket must be timestamped, and only the values
+/// inserted in the window are kept, which leaves out those of the log.
+/// With `show_offset`, the text format also prints where the data block
+/// of each key found starts in the file.
+fn multi_query<W: Write>(filename: &Path, hashes: &[HashedKey], format: OutputFormat, versions: VersionRange,
+                         window: TimeWindow, show_offset: bool, out: &mut W) -> Result<()> {
     let t = Instant::now();
     let bucket = Bucket::open(filename)?;
-    debug!("opened {} in {:?}", filename, t.elapsed());
+    debug!("opened {} in {:?}", filename.display(), t.elapsed());
 
     let t = Instant::now();
-    let mut bucket = bucket.check_headers()?;
+    let mut bucket = bucket.check_headers_accepting(versions)?;
     debug!("checked headers in {:?}", t.elapsed());
 
     let t = Instant::now();
     let si = bucket.read_sparse_index()?;
     debug!("read sparse index in {:?}", t.elapsed());
 
+    let mut log: BTreeMap<HashedKey, BTreeSet<Value>> = BTreeMap::new();
+    for (key, value) in bucket.read_log()? {
+        log.entry(key).or_default().insert(value);
+    }
+
     for hash in hashes {
         let t = Instant::now();
         let maybe_range = si.try_get(*hash);
         debug!("sparse index lookup: {:?}", t.elapsed());
-        if let Some((off1, off2)) = maybe_range {
-            let v = bucket.try_get(*hash, off1, off2)?;
-            println!("{}: {}: {:?}", filename, *hash, v);
+        let logged = if window.is_set() { None } else { log.get(hash) };
+        if maybe_range.is_none() && logged.is_none() {
+            continue;
+        }
+        let mut v = match maybe_range {
+            Some((off1, off2)) if window.is_set() => {
+                bucket.try_get_timestamped(*hash, off1, off2)?.map(|values| {
+                    values.into_iter()
+                        .filter(|&(_, timestamp)| window.contains(timestamp))
+                        .map(|(value, _)| value)
+                        .collect()
+                })
+            }
+            Some((off1, off2)) => bucket.try_get(*hash, off1, off2)?,
+            None => None,
+        };
+        if let Some(logged) = logged {
+            v.get_or_insert_with(BTreeSet::new).extend(logged);
+        }
+        match (format, v) {
+            (OutputFormat::Text(text), Some(v)) if show_offset => match bucket.locate_offset(*hash)? {
+                Some(offset) => writeln!(out, "{}: {} at {}: {:?}", filename.display(), text.key(*hash), offset, Some(text.set(&v)))?,
+                // Only in the log.
+                None => writeln!(out, "{}: {}: {:?}", filename.display(), text.key(*hash), Some(text.set(&v)))?,
+            },
+            (OutputFormat::Text(text), v) => {
+                writeln!(out, "{}: {}: {:?}", filename.display(), text.key(*hash), v.map(|v| text.set(&v)))?
+            }
+            (OutputFormat::Bincode, Some(v)) => super::write_bincode(out, *hash, &v)?,
+            (OutputFormat::Bincode, None) => {}
         }
         debug!("searched key {} in {:?}", hash, t.elapsed());
     }
 
     return Ok(());
 }
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use crate::subcommands::format::{NumberFormat, TextFormat};
+    use std::collections::{BTreeMap, BTreeSet};
+    use super::*;
+    use std::ffi::{OsStr, OsString};
+    use std::fs;
+    use tempfile::NamedTempFile;
+
+    /// `run` with the arguments of `binstore query-bucket`.
+    fn run_with(args: &[&OsStr]) -> Result<i32> {
+        let args = ["binstore", "query-bucket"].iter().map(OsString::from).chain(args.iter().map(OsString::from));
+        let matches = crate::app().get_matches_from(args);
+        run(matches.subcommand_matches("query-bucket").unwrap())
+    }
+
+    #[test]
+    fn run_exit_codes() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = dir.path().join("good.binstore");
+        create(&good, &BTreeMap::from([(1, BTreeSet::from([10, 11]))])).expect("create");
+        let bad = dir.path().join("bad.binstore");
+        fs::write(&bad, b"not a bucket").unwrap();
+        let missing = dir.path().join("missing.binstore");
+        let out = dir.path().join("out.txt");
+        let output_file = [OsStr::new("--output-file"), out.as_os_str()];
+
+        assert_matches!(run_with(&[&output_file[..], &[good.as_os_str(), "-k".as_ref(), "1".as_ref()]].concat()), Ok(0));
+        assert_eq!(fs::read_to_string(&out).unwrap(), format!("{}: 1: Some({{10, 11}})\n", good.display()));
+        assert_matches!(run_with(&[&output_file[..], &[bad.as_os_str(), "-k".as_ref(), "1".as_ref()]].concat()), Ok(EXIT_CORRUPT));
+        assert_matches!(run_with(&[&output_file[..], &[missing.as_os_str(), "-k".as_ref(), "1".as_ref()]].concat()), Ok(EXIT_IO));
+        assert_matches!(run_with(&["-q".as_ref(), good.as_os_str(), "-k".as_ref(), "1".as_ref()]), Ok(0));
+        assert_matches!(run_with(&["-q".as_ref(), good.as_os_str(), "-k".as_ref(), "2".as_ref()]), Ok(1));
+    }
+
+    #[test]
+    fn query_appended_keys() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1_u64, (10 .. 12_u128).collect());
+        bmap.insert(5_u64, (10 .. 12_u128).collect());
+        create(tmp.path(), &bmap).expect("create");
+        append(tmp.path(), 1, 7).expect("append");
+        append(tmp.path(), 3, 8).expect("append");
+        append(tmp.path(), 9, 9).expect("append");
+        let filename = tmp.path();
+
+        let mut out = Vec::new();
+        multi_query(filename, &[1, 3, 4, 9], OutputFormat::Text(TextFormat::default()), VersionRange::default(), TimeWindow::default(), true, &mut out).expect("multi_query");
+        let offset = Bucket::open(filename).and_then(|b| b.check_headers()).and_then(|mut b| b.locate_offset(1))
+            .expect("locate_offset")
+            .expect("offset");
+        let name = filename.display();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   format!("{0}: 1 at {1}: Some({{7, 10, 11}})\n{0}: 3: Some({{8}})\n{0}: 4: None\n{0}: 9: Some({{9}})\n", name, offset));
+
+        let mut out = Vec::new();
+        multi_query(filename, &[9], OutputFormat::Bincode, VersionRange::default(), TimeWindow::default(), false, &mut out).expect("multi_query");
+        let read: (HashedKey, BTreeSet<Value>) = bincode_config().deserialize(&out).expect("deserialize");
+        assert_eq!(read, (9, BTreeSet::from([9])));
+
+        let files = vec![filename.to_path_buf()];
+        assert_eq!(quiet_status(&files, &[9], false, VersionRange::default()), 0);
+        assert_eq!(quiet_status(&files, &[4], false, VersionRange::default()), 1);
+    }
+
+    #[test]
+    fn query_empty_bucket() {
+        let tmp = NamedTempFile::new().unwrap();
+        create(tmp.path(), &BTreeMap::new()).expect("create");
+
+        let mut out = Vec::new();
+        multi_query(tmp.path(), &[0, 1, u64::MAX], OutputFormat::Text(TextFormat::default()), VersionRange::default(), TimeWindow::default(), false, &mut out).expect("multi_query");
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn hex_keys() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut bmap = BTreeMap::new();
+        bmap.insert(255_u64, (10 .. 12_u128).collect());
+        create(tmp.path(), &bmap).expect("create");
+        let filename = tmp.path();
+
+        let text = TextFormat { keys: NumberFormat::Hex, values: NumberFormat::Dec };
+        let mut out = Vec::new();
+        multi_query(filename, &[255], OutputFormat::Text(text), VersionRange::default(), TimeWindow::default(), false, &mut out).expect("multi_query");
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}: 0xff: Some({{10, 11}})\n", filename.display()));
+
+        let text = TextFormat { keys: NumberFormat::Hex, values: NumberFormat::Hex };
+        let mut out = Vec::new();
+        multi_query(filename, &[255], OutputFormat::Text(text), VersionRange::default(), TimeWindow::default(), false, &mut out).expect("multi_query");
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}: 0xff: Some({{0xa, 0xb}})\n", filename.display()));
+
+        let offset = Bucket::open(filename).and_then(|b| b.check_headers()).and_then(|mut b| b.locate_offset(255))
+            .expect("locate_offset")
+            .expect("offset");
+        let mut out = Vec::new();
+        multi_query(filename, &[255], OutputFormat::Text(text), VersionRange::default(), TimeWindow::default(), true, &mut out).expect("multi_query");
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}: 0xff at {}: Some({{0xa, 0xb}})\n", filename.display(), offset));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_filename() {
+        use clap::{App, Arg};
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(OsStr::from_bytes(b"bucket-\xff.binstore"));
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1_u64, (10 .. 12_u128).collect());
+        create(&path, &bmap).expect("create");
+
+        let app = App::new("query-bucket").arg(Arg::with_name("input-files").multiple(true));
+        let matches = app.get_matches_from(vec![OsStr::new("query-bucket"), path.as_os_str()]);
+        let filenames = super::super::paths(&matches, "input-files");
+        assert_eq!(filenames, vec![path.clone()]);
+
+        assert_eq!(quiet_status(&filenames, &[1], false, VersionRange::default()), 0);
+        let mut out = Vec::new();
+        let text = OutputFormat::Text(TextFormat::default());
+        multi_query(&filenames[0], &[1], text, VersionRange::default(), TimeWindow::default(), false, &mut out).expect("multi_query");
+        assert!(String::from_utf8_lossy(&out).ends_with(": 1: Some({10, 11})\n"));
+    }
+
+    #[test]
+    fn bincode_output() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut bmap = BTreeMap::new();
+        for key in 0 .. 100_u64 {
+            bmap.insert(key * 2, (0 .. key as u128).collect());
+        }
+        create(tmp.path(), &bmap).expect("create");
+
+        let keys: Vec<HashedKey> = (0 .. 20).collect();
+        let mut out = Vec::new();
+        multi_query(tmp.path(), &keys, OutputFormat::Bincode, VersionRange::default(), TimeWindow::default(), false, &mut out).expect("multi_query");
+
+        let mut read = BTreeMap::new();
+        let mut r = out.as_slice();
+        while !r.is_empty() {
+            let (key, values): (HashedKey, BTreeSet<Value>) = bincode_config().deserialize_from(&mut r).expect("deserialize");
+            read.insert(key, values);
+        }
+        let expected: BTreeMap<_, _> = bmap.into_iter().filter(|(key, _)| *key < 20).collect();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn keys_file() {
+        let tmp = NamedTempFile::new().unwrap();
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> =
+            (0 .. 1000_u64).map(|key| (key * 3, (0 .. key as Value % 4 + 1).collect())).collect();
+        create(tmp.path(), &bmap).expect("create");
+
+        let mut keys_file = String::new();
+        for key in (0 .. 1500_u64).step_by(5) {
+            if key % 2 == 0 {
+                keys_file.push_str(&format!("{}\n", key));
+            } else {
+                keys_file.push_str(&format!("0x{:x}\n\n", key));
+            }
+        }
+        let keys = super::super::read_keys(keys_file.as_bytes()).expect("read_keys");
+        assert_eq!(keys, (0 .. 1500).step_by(5).collect::<Vec<_>>());
+
+        let mut out = Vec::new();
+        multi_query(tmp.path(), &keys, OutputFormat::Bincode, VersionRange::default(), TimeWindow::default(), false, &mut out).expect("multi_query");
+        let mut read = BTreeMap::new();
+        let mut r = out.as_slice();
+        while !r.is_empty() {
+            let (key, values): (HashedKey, BTreeSet<Value>) = bincode_config().deserialize_from(&mut r).expect("deserialize");
+            read.insert(key, values);
+        }
+        let expected: BTreeMap<_, _> = bmap.into_iter().filter(|(key, _)| key % 5 == 0 && *key < 1500).collect();
+        assert_eq!(read.len(), 100);
+        assert_eq!(read, expected);
+
+        assert!(super::super::read_keys("1\nnope\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn time_window() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut entries = BTreeMap::new();
+        entries.insert(1_u64, (0 .. 10_u128).map(|value| (value, 100 + value as i64 * 10)).collect::<BTreeMap<_, _>>());
+        create_timestamped(tmp.path(), &entries, &CreateOptions::default()).expect("create_timestamped");
+        let filename = tmp.path();
+
+        let query = |window: TimeWindow| {
+            let mut out = Vec::new();
+            multi_query(filename, &[1], OutputFormat::Bincode, VersionRange::default(), window, false, &mut out).expect("multi_query");
+            let (_, values): (HashedKey, BTreeSet<Value>) = bincode_config().deserialize(&out).expect("deserialize");
+            values
+        };
+        assert_eq!(query(TimeWindow::default()), (0 .. 10).collect());
+        assert_eq!(query(TimeWindow { since: Some(150), until: None }), (5 .. 10).collect());
+        assert_eq!(query(TimeWindow { since: None, until: Some(130) }), (0 .. 3).collect());
+        assert_eq!(query(TimeWindow { since: Some(120), until: Some(150) }), (2 .. 5).collect());
+        assert!(query(TimeWindow { since: Some(1000), until: None }).is_empty());
+
+        // Deleting values keeps the timestamps of the others.
+        let deleted = NamedTempFile::new().unwrap();
+        delete(tmp.path(), deleted.path(), &[6]).expect("delete");
+        let mut out = Vec::new();
+        multi_query(deleted.path(), &[1], OutputFormat::Bincode, VersionRange::default(),
+                    TimeWindow { since: Some(150), until: None }, false, &mut out).expect("multi_query");
+        let (_, values): (HashedKey, BTreeSet<Value>) = bincode_config().deserialize(&out).expect("deserialize");
+        assert_eq!(values, [5, 7, 8, 9].iter().cloned().collect());
+
+        // Plain buckets have no timestamps to filter on.
+        create(tmp.path(), &BTreeMap::from([(1, BTreeSet::from([1]))])).expect("create");
+        let window = TimeWindow { since: Some(0), until: None };
+        assert_matches!(multi_query(filename, &[1], OutputFormat::Bincode, VersionRange::default(), window, false, &mut Vec::new()),
+                        Err(Error::NotTimestamped));
+    }
+
+    #[test]
+    fn quiet_exit_status() {
+        let tmp_1 = NamedTempFile::new().unwrap();
+        let tmp_2 = NamedTempFile::new().unwrap();
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1_u64, (0 .. 3_u128).collect());
+        create(tmp_1.path(), &bmap).expect("create");
+        bmap.clear();
+        bmap.insert(2_u64, (0 .. 3_u128).collect());
+        create(tmp_2.path(), &bmap).expect("create");
+        let files = vec![tmp_1.path().to_path_buf(), tmp_2.path().to_path_buf()];
+
+        assert_eq!(quiet_status(&files[.. 1], &[1], false, VersionRange::default()), 0);
+        assert_eq!(quiet_status(&files[.. 1], &[2], false, VersionRange::default()), 1);
+        assert_eq!(quiet_status(&files[.. 1], &[1, 2], false, VersionRange::default()), 1);
+        assert_eq!(quiet_status(&files[.. 1], &[1, 2], true, VersionRange::default()), 0);
+        assert_eq!(quiet_status(&files[.. 1], &[3, 4], true, VersionRange::default()), 1);
+        // Keys may be found in different files.
+        assert_eq!(quiet_status(&files, &[1, 2], false, VersionRange::default()), 0);
+        assert_eq!(quiet_status(&[PathBuf::from("/nonexistent")], &[1], false, VersionRange::default()), 2);
+    }
+}
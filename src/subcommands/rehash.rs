@@ -0,0 +1,78 @@
+use clap::ArgMatches;
+use binstore::prelude::{HashedKey, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+pub fn main(matches: &ArgMatches) {
+    super::exit(run(matches));
+}
+
+/// Rewrites the bucket under the new keys and returns the exit code.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    let input = super::path(matches, "input-file").unwrap_or_else(|| {
+        super::usage_error("missing input file");
+    });
+    let output = super::path(matches, "output-name").unwrap_or_else(|| {
+        super::usage_error("missing output name");
+    });
+    let read = match super::path(matches, "mapping") {
+        Some(path) if path.as_os_str() == "-" => read_mapping(io::stdin().lock()),
+        Some(path) => File::open(path).map_err(|e| e.to_string()).and_then(|f| read_mapping(BufReader::new(f))),
+        None => super::usage_error("missing mapping"),
+    };
+    let mapping = read.unwrap_or_else(|e| {
+        super::usage_error(format_args!("cannot read mapping: {}", e));
+    });
+
+    let options = super::create_options(matches);
+    match binstore::bucket::rehash(&input, &output, &mapping, &options) {
+        Ok(report) => {
+            if options.dry_run {
+                eprintln!("binstore: dry run: would write {} entries ({} bytes) to {}",
+                          report.num_entries, report.size, output.display());
+            }
+            Ok(0)
+        }
+        Err(e) => {
+            eprintln!("binstore: {}: {}", input.display(), e);
+            Ok(e.exit_code())
+        }
+    }
+}
+
+/// Reads one `OLD,NEW` pair of keys per line, each in decimal or in
+/// hex after `0x`; blank lines are skipped.  A key mapped twice must
+/// be mapped to the same new key both times.
+fn read_mapping<R: BufRead>(r: R) -> std::result::Result<BTreeMap<HashedKey, HashedKey>, String> {
+    let mut mapping = BTreeMap::new();
+    for (i, line) in r.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (old, new) = line.split_once(',')
+            .ok_or_else(|| format!("line {}: {}: expected OLD,NEW", i + 1, line))?;
+        let parse = |key: &str| super::parse_key(key.trim()).map_err(|e| format!("line {}: {}: {}", i + 1, line, e));
+        let (old, new) = (parse(old)?, parse(new)?);
+        if mapping.insert(old, new).map_or(false, |before| before != new) {
+            return Err(format!("line {}: key {} is mapped twice", i + 1, old));
+        }
+    }
+    Ok(mapping)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapping_file() {
+        let mapping = read_mapping("1,2\n\n0x10, 0xff\n3,2\n1,2\n".as_bytes()).expect("read_mapping");
+        assert_eq!(mapping, BTreeMap::from([(1, 2), (16, 255), (3, 2)]));
+        assert!(read_mapping("1,2\n1,3\n".as_bytes()).unwrap_err().starts_with("line 2"));
+        assert!(read_mapping("1\n".as_bytes()).is_err());
+        assert!(read_mapping("1,x\n".as_bytes()).is_err());
+    }
+}
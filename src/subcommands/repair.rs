@@ -0,0 +1,33 @@
+use clap::ArgMatches;
+use binstore::prelude::*;
+
+pub fn main(matches: &ArgMatches) {
+    super::exit(run(matches));
+}
+
+/// Repairs the bucket and returns the exit code.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    let input = super::path(matches, "input-file").unwrap_or_else(|| {
+        super::usage_error("missing input file");
+    });
+
+    let output = super::path(matches, "output-name").unwrap_or_else(|| {
+        super::usage_error("missing output name");
+    });
+
+    let options = super::create_options(matches);
+    match binstore::bucket::repair_with_options(&input, &output, &options) {
+        Ok(report) => {
+            if options.dry_run {
+                eprintln!("binstore: dry run: {}: would recover {} entries, dropping {}", input.display(), report.recovered, report.dropped);
+            } else {
+                println!("{}: recovered {} entries, dropped {}", input.display(), report.recovered, report.dropped);
+            }
+            Ok(0)
+        }
+        Err(e) => {
+            eprintln!("binstore: {}: {}", input.display(), e);
+            Ok(e.exit_code())
+        }
+    }
+}
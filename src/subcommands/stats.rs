@@ -0,0 +1,352 @@
+use clap::ArgMatches;
+use binstore::bucket::{Bucket, VersionRange};
+use binstore::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// What `stats` reports for a bucket.
+#[derive(Debug, Serialize)]
+struct Stats {
+    file: String,
+    num_entries: u64,
+    file_size: u64,
+    data_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<CompressionStats>,
+}
+
+/// Buckets with the same contents, such as accidental copies.
+#[derive(Debug, Serialize, PartialEq)]
+struct Duplicates {
+    file_size: u64,
+    files: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CompressionStats {
+    compressed_bytes: u64,
+    uncompressed_bytes: u64,
+    ratio: f64,
+}
+
+pub fn main(matches: &ArgMatches) {
+    super::exit(run(matches));
+}
+
+/// Prints the statistics of the buckets, or their duplicates, and
+/// returns the exit code.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    let filenames = super::expand_inputs(&super::paths(matches, "input-files"))?;
+    let compression = matches.is_present("compression-stats");
+    let versions = super::accepted_versions(matches);
+    let prometheus = matches.value_of("format") == Some("prometheus");
+    if matches.is_present("find-duplicates") {
+        return Ok(report_duplicates(&filenames, versions));
+    }
+
+    let mut ret = 0;
+    // The metrics are grouped by name, so they are written once every
+    // bucket is read.
+    let mut all = Vec::new();
+    for filename in &filenames {
+        let result = stats(filename, compression, versions);
+        match result {
+            Ok(stats) if prometheus => all.push(stats),
+            Ok(stats) => match serde_json::to_string(&stats) {
+                Ok(json) => println!("{}", json),
+                Err(e) => {
+                    ret = EXIT_FAILURE;
+                    eprintln!("binstore: {}: {}", filename.display(), e);
+                }
+            },
+            Err(e) => {
+                ret = e.exit_code();
+                eprintln!("binstore: {}: {}", filename.display(), e);
+            }
+        }
+    }
+    if prometheus {
+        print!("{}", to_prometheus(&all));
+    }
+    Ok(ret)
+}
+
+/// Prints a JSON object for each group of identical buckets among
+/// `filenames` and returns the exit code.
+fn report_duplicates(filenames: &[PathBuf], versions: VersionRange) -> i32 {
+    let mut ret = 0;
+    let mut fingerprints = Vec::new();
+    for filename in filenames {
+        match fingerprint(filename, versions) {
+            Ok(fingerprint) => fingerprints.push((filename.clone(), fingerprint)),
+            Err(e) => {
+                ret = e.exit_code();
+                eprintln!("binstore: {}: {}", filename.display(), e);
+            }
+        }
+    }
+    match find_duplicates(fingerprints) {
+        Ok(groups) => {
+            for group in &groups {
+                match serde_json::to_string(group) {
+                    Ok(json) => println!("{}", json),
+                    Err(e) => {
+                        ret = EXIT_FAILURE;
+                        eprintln!("binstore: {}", e);
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            ret = e.exit_code();
+            eprintln!("binstore: {}", e);
+        }
+    }
+    ret
+}
+
+/// The size and key fingerprint of a bucket: identical buckets have
+/// the same, so only buckets that share them need comparing.
+fn fingerprint(filename: &Path, versions: VersionRange) -> Result<(u64, u64)> {
+    let mut bucket = Bucket::open(filename)?.check_headers_accepting(versions)?;
+    Ok((bucket.file_len()?, bucket.key_fingerprint()?))
+}
+
+/// Groups the files with the same contents, given their fingerprints;
+/// files with the same fingerprint are compared byte for byte.  Each
+/// group lists its files in order, and the groups are sorted by their
+/// first file.
+fn find_duplicates(fingerprints: Vec<(PathBuf, (u64, u64))>) -> Result<Vec<Duplicates>> {
+    let mut candidates: BTreeMap<(u64, u64), Vec<PathBuf>> = BTreeMap::new();
+    for (file, fingerprint) in fingerprints {
+        candidates.entry(fingerprint).or_default().push(file);
+    }
+    let mut groups = Vec::new();
+    for ((file_size, _), mut files) in candidates {
+        files.sort();
+        files.dedup();
+        while files.len() > 1 {
+            let first = files.remove(0);
+            let mut same = vec![first.display().to_string()];
+            let mut rest = Vec::new();
+            for file in files {
+                if same_contents(&first, &file)? {
+                    same.push(file.display().to_string());
+                } else {
+                    rest.push(file);
+                }
+            }
+            if same.len() > 1 {
+                groups.push(Duplicates { file_size, files: same });
+            }
+            files = rest;
+        }
+    }
+    groups.sort_by(|a, b| a.files[0].cmp(&b.files[0]));
+    Ok(groups)
+}
+
+/// Whether the files `a` and `b`, of the same size, hold the same bytes.
+fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    let mut buf_a = [0; 8192];
+    let mut buf_b = [0; 8192];
+    loop {
+        let len = read_full(&mut a, &mut buf_a)?;
+        if len != read_full(&mut b, &mut buf_b)? || buf_a[.. len] != buf_b[.. len] {
+            return Ok(false);
+        }
+        if len == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Reads until `buf` is full or the end of `r`.
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match r.read(&mut buf[len ..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// The statistics in the Prometheus text exposition format, each
+/// metric labelled with the file of its bucket.
+fn to_prometheus(all: &[Stats]) -> String {
+    type Metric = (&'static str, &'static str, fn(&Stats) -> Option<f64>);
+    const METRICS: &[Metric] = &[
+        ("binstore_num_entries", "The number of keys of the bucket.", |s| Some(s.num_entries as f64)),
+        ("binstore_file_bytes", "The size of the bucket file.", |s| Some(s.file_size as f64)),
+        ("binstore_data_bytes", "The size of the compressed data section.", |s| Some(s.data_size as f64)),
+        ("binstore_uncompressed_data_bytes", "The size of the data section once decompressed.",
+         |s| s.compression.as_ref().map(|c| c.uncompressed_bytes as f64)),
+        ("binstore_compression_ratio", "The uncompressed size of the data section over its compressed size.",
+         |s| s.compression.as_ref().map(|c| c.ratio)),
+    ];
+    let mut out = String::new();
+    for (name, help, value) in METRICS {
+        let values: Vec<(&str, f64)> = all.iter()
+            .filter_map(|s| value(s).map(|v| (s.file.as_str(), v)))
+            .collect();
+        if values.is_empty() {
+            continue;
+        }
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} gauge", name).unwrap();
+        for (file, v) in values {
+            writeln!(out, "{}{{file=\"{}\"}} {}", name, escape_label(file), v).unwrap();
+        }
+    }
+    out
+}
+
+/// Escapes a label value as the Prometheus text format requires.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Gathers the statistics of `filename`; with `compression`, every
+/// data block is decompressed to measure the compression ratio.
+fn stats(filename: &Path, compression: bool, versions: VersionRange) -> Result<Stats> {
+    let mut bucket = Bucket::open(filename)?.check_headers_accepting(versions)?;
+    let data_size = bucket.data_size()?;
+    let compression = if compression {
+        let uncompressed_bytes = bucket.uncompressed_data_size()?;
+        Some(CompressionStats {
+            compressed_bytes: data_size,
+            uncompressed_bytes,
+            ratio: if data_size > 0 { uncompressed_bytes as f64 / data_size as f64 } else { 0.0 },
+        })
+    } else {
+        None
+    };
+    Ok(Stats {
+        file: filename.display().to_string(),
+        num_entries: bucket.header.num_entries,
+        file_size: bucket.file_len()?,
+        data_size,
+        compression,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use binstore::bucket;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::iter::FromIterator;
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn compression_ratio() {
+        let mut bmap = BTreeMap::new();
+        for key in 0 .. 10_u64 {
+            bmap.insert(key, BTreeSet::from_iter(0 .. 1000_u128));
+        }
+        let tmp = NamedTempFile::new().unwrap();
+        bucket::create(tmp.path(), &bmap).expect("create");
+        let filename = tmp.path();
+
+        let plain = stats(filename, false, VersionRange::default()).expect("stats");
+        assert_eq!(plain.num_entries, 10);
+        assert!(plain.compression.is_none());
+
+        let stats = stats(filename, true, VersionRange::default()).expect("stats");
+        let compression = stats.compression.expect("compression stats");
+        assert_eq!(compression.compressed_bytes, stats.data_size);
+        // Each set is bincoded as its length and 16 bytes per value.
+        assert_eq!(compression.uncompressed_bytes, 10 * (8 + 1000 * 16));
+        assert!(compression.ratio > 1.0, "ratio: {}", compression.ratio);
+    }
+
+    #[test]
+    fn prometheus_format() {
+        let mut bmap = BTreeMap::new();
+        bmap.insert(1_u64, BTreeSet::from_iter(0 .. 100_u128));
+        let tmp = NamedTempFile::new().unwrap();
+        bucket::create(tmp.path(), &bmap).expect("create");
+        let filename = tmp.path();
+
+        let mut all = vec![stats(filename, false, VersionRange::default()).expect("stats")];
+        all.push(Stats { file: "a \"b\"\\c".to_string(), ..stats(filename, true, VersionRange::default()).expect("stats") });
+        let text = to_prometheus(&all);
+
+        let mut names = Vec::new();
+        for line in text.lines() {
+            if line.starts_with('#') {
+                let fields: Vec<&str> = line.splitn(4, ' ').collect();
+                assert!(fields[1] == "HELP" || (fields[1] == "TYPE" && fields[3] == "gauge"), "{}", line);
+                continue;
+            }
+            // name{file="..."} value
+            let (series, value) = line.rsplit_once(' ').expect("value");
+            value.parse::<f64>().expect("number");
+            let (name, labels) = series.split_once('{').expect("labels");
+            assert!(name.chars().all(|c| c.is_ascii_lowercase() || c == '_'), "{}", name);
+            assert!(labels.starts_with("file=\"") && labels.ends_with("\"}"), "{}", labels);
+            names.push(name);
+        }
+        assert_eq!(names, vec![
+            "binstore_num_entries", "binstore_num_entries",
+            "binstore_file_bytes", "binstore_file_bytes",
+            "binstore_data_bytes", "binstore_data_bytes",
+            "binstore_uncompressed_data_bytes",
+            "binstore_compression_ratio",
+        ]);
+        assert!(text.contains(&format!("binstore_num_entries{{file=\"{}\"}} 1\n", filename.display())));
+        assert!(text.contains(r#"binstore_uncompressed_data_bytes{file="a \"b\"\\c"} 1608"#));
+    }
+
+    #[test]
+    fn find_duplicate_buckets() {
+        let dir = tempfile::tempdir().unwrap();
+        let bmap: BTreeMap<u64, BTreeSet<u128>> = (0 .. 100_u64).map(|key| (key, BTreeSet::from_iter(0 .. key as u128))).collect();
+        let options = bucket::CreateOptions { codec: bucket::Codec::Uncompressed, timestamp: Some(1_600_000_000), ..bucket::CreateOptions::default() };
+        bucket::create_with_options(dir.path().join("a.binstore"), &bmap, &options).expect("create");
+        std::fs::copy(dir.path().join("a.binstore"), dir.path().join("c.binstore")).unwrap();
+        // Same keys and size, other values.
+        let mut other = bmap.clone();
+        other.insert(50, BTreeSet::from_iter(1 .. 51_u128));
+        bucket::create_with_options(dir.path().join("b.binstore"), &other, &options).expect("create");
+        std::fs::write(dir.path().join("notes.txt"), "not a bucket").unwrap();
+
+        let files = crate::subcommands::expand_inputs(&[dir.path().to_path_buf()]).expect("expand_inputs");
+        assert_eq!(files.len(), 3);
+        let fingerprints = files.into_iter()
+            .map(|file| {
+                let fingerprint = fingerprint(&file, VersionRange::default()).expect("fingerprint");
+                (file, fingerprint)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(fingerprints[0].1, fingerprints[1].1);
+        let groups = find_duplicates(fingerprints).expect("find_duplicates");
+        assert_eq!(groups, vec![Duplicates {
+            file_size: std::fs::metadata(dir.path().join("a.binstore")).unwrap().len(),
+            files: vec![dir.path().join("a.binstore").display().to_string(), dir.path().join("c.binstore").display().to_string()],
+        }]);
+    }
+
+    #[test]
+    fn empty_bucket() {
+        let tmp = NamedTempFile::new().unwrap();
+        bucket::create(tmp.path(), &BTreeMap::new()).expect("create");
+
+        let stats = stats(tmp.path(), true, VersionRange::default()).expect("stats");
+        assert_eq!(stats.num_entries, 0);
+        assert_eq!(stats.data_size, 0);
+        let compression = stats.compression.expect("compression stats");
+        assert_eq!(compression.uncompressed_bytes, 0);
+        assert_eq!(compression.ratio, 0.0);
+    }
+}
@@ -0,0 +1,106 @@
+use clap::{ArgMatches, value_t};
+use binstore::bucket::Bucket;
+use binstore::prelude::*;
+use chrono::prelude::*;
+use log::warn;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What `tail` prints about a bucket.
+#[derive(Debug, Serialize, PartialEq)]
+struct Recent {
+    path: PathBuf,
+    timestamp: i64,
+    date: String,
+    num_entries: u64,
+}
+
+pub fn main(matches: &ArgMatches) {
+    super::exit(run(matches));
+}
+
+/// Prints the most recent buckets and returns the exit code.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    let count = value_t!(matches, "count", usize).unwrap_or_else(|e| {
+        super::usage_error(format_args!("invalid count: {}", e));
+    });
+    let dbdir = super::path(matches, "db-dir").unwrap();
+
+    let recent = match tail(&dbdir, count) {
+        Ok(recent) => recent,
+        Err(e) => {
+            eprintln!("binstore: {}: {}", dbdir.display(), e);
+            return Ok(e.exit_code());
+        }
+    };
+    if matches.is_present("json") {
+        println!("{}", serde_json::json!(recent));
+    } else {
+        for bucket in &recent {
+            println!("{}\t{}\t{}", bucket.path.display(), bucket.date, bucket.num_entries);
+        }
+    }
+    Ok(0)
+}
+
+/// The `count` buckets of `dir` with the latest creation timestamps,
+/// latest first.  Only the headers are read; files that aren't valid
+/// buckets are logged and skipped.
+fn tail(dir: &Path, count: usize) -> Result<Vec<Recent>> {
+    let mut recent = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            continue;
+        }
+        let header = match Bucket::read_header(&path) {
+            Ok(header) => header,
+            Err(e) => {
+                warn!("could not read header of file {:?} with error: {}", &path, e);
+                continue;
+            }
+        };
+        recent.push(Recent {
+            date: Utc.timestamp(header.timestamp, 0).format("%Y-%m-%d").to_string(),
+            timestamp: header.timestamp,
+            num_entries: header.num_entries,
+            path,
+        });
+    }
+    recent.sort_by(|a, b| b.timestamp.cmp(&a.timestamp).then_with(|| a.path.cmp(&b.path)));
+    recent.truncate(count);
+    Ok(recent)
+}
+
+#[cfg(test)]
+mod tests {
+    use binstore::bucket::{self, CreateOptions};
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::iter::FromIterator;
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn latest_buckets_first() {
+        let dir = tempdir().unwrap();
+        let days = [3, 1, 4, 2, 5];
+        for (i, &day) in days.iter().enumerate() {
+            let bmap: BTreeMap<HashedKey, BTreeSet<Value>> =
+                (0 .. day).map(|key| (key, BTreeSet::from_iter(0 .. 3_u128))).collect();
+            let timestamp = Utc.ymd(2024, 1, day as u32).and_hms(12, 0, 0).timestamp();
+            let options = CreateOptions { timestamp: Some(timestamp), ..CreateOptions::default() };
+            bucket::create_with_options(dir.path().join(format!("{}.binstore", i)), &bmap, &options).expect("create");
+        }
+        fs::write(dir.path().join("notes.txt"), b"not a bucket").expect("write");
+
+        let recent = tail(dir.path(), 3).expect("tail");
+        let dates: Vec<&str> = recent.iter().map(|bucket| bucket.date.as_str()).collect();
+        assert_eq!(dates, vec!["2024-01-05", "2024-01-04", "2024-01-03"]);
+        assert_eq!(recent[0].path, dir.path().join("4.binstore"));
+        assert_eq!(recent[0].num_entries, 5);
+
+        assert_eq!(tail(dir.path(), 10).expect("tail").len(), 5);
+        assert!(tail(dir.path(), 0).expect("tail").is_empty());
+    }
+}
@@ -0,0 +1,178 @@
+use clap::ArgMatches;
+use binstore::bucket::{Bucket, IndexEntry, VersionRange};
+use binstore::prelude::*;
+use super::SplitMix64;
+use std::path::Path;
+
+/// What `verify` checked in a bucket.
+#[derive(Debug, PartialEq)]
+struct Report {
+    num_entries: u64,
+    num_blocks: u64,
+    checked_blocks: u64,
+}
+
+pub fn main(matches: &ArgMatches) {
+    super::exit(run(matches));
+}
+
+/// Verifies the buckets and returns the exit code.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    let filenames = super::expand_inputs(&super::paths(matches, "input-files"))?;
+    let sample = matches.value_of("sample").map(|n| {
+        n.parse::<u64>().unwrap_or_else(|e| {
+            super::usage_error(format_args!("invalid sample size: {}", e));
+        })
+    });
+    let seed = super::seed(matches);
+    let versions = super::accepted_versions(matches);
+
+    let mut ret = 0;
+    for filename in &filenames {
+        match verify(filename, sample, seed, versions) {
+            Ok(report) => {
+                println!("{}: ok: {} entries, {} of {} data blocks checked",
+                         filename.display(), report.num_entries, report.checked_blocks, report.num_blocks);
+            }
+            Err(e) => {
+                ret = e.exit_code();
+                eprintln!("binstore: {}: {}", filename.display(), e);
+            }
+        }
+    }
+    if sample.is_some() && matches.value_of("seed").is_none() {
+        eprintln!("binstore: sampled with --seed {}", seed);
+    }
+    Ok(ret)
+}
+
+/// Checks the header, the sparse index and the order of the whole
+/// dense index of `filename`, then decompresses its data blocks: all
+/// of them, or `sample` of them picked at random from `seed`.
+fn verify(filename: &Path, sample: Option<u64>, seed: u64, versions: VersionRange) -> Result<Report> {
+    let mut bucket = Bucket::open(filename)?.check_headers_accepting(versions)?.prepare()?.into_bucket();
+    let header = bucket.header.clone();
+    bucket.verify_data_ordering()?;
+
+    // One entry per data block; the entries of a grouped block all
+    // decompress the same frame.
+    let mut blocks: Vec<IndexEntry> = Vec::new();
+    let mut num_entries = 0;
+    for entry in bucket.index_entries()? {
+        let entry = entry?;
+        num_entries += 1;
+        let block = header.data_block_offset(entry.offset);
+        if blocks.last().map_or(true, |last| header.data_block_offset(last.offset) != block) {
+            blocks.push(entry);
+        }
+    }
+
+    let indices = match sample {
+        Some(n) => sample_indices(blocks.len(), n as usize, seed),
+        None => (0 .. blocks.len()).collect(),
+    };
+    for &i in &indices {
+        bucket.read_values_vec_at(blocks[i].offset)?;
+    }
+
+    Ok(Report {
+        num_entries,
+        num_blocks: blocks.len() as u64,
+        checked_blocks: indices.len() as u64,
+    })
+}
+
+/// Picks `n` distinct indices below `len`, in increasing order so the
+/// blocks are read front to back; the same seed picks the same ones.
+fn sample_indices(len: usize, n: usize, seed: u64) -> Vec<usize> {
+    let mut rng = SplitMix64(seed);
+    let mut indices: Vec<usize> = (0 .. len).collect();
+    let n = n.min(len);
+    // A partial Fisher-Yates shuffle.
+    for i in 0 .. n {
+        let j = i + (rng.next() % (len - i) as u64) as usize;
+        indices.swap(i, j);
+    }
+    indices.truncate(n);
+    indices.sort_unstable();
+    return indices;
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use binstore::bucket;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::iter::FromIterator;
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn sample_bucket() -> NamedTempFile {
+        let mut bmap = BTreeMap::new();
+        for key in 0 .. 20_u64 {
+            bmap.insert(key * 3, BTreeSet::from_iter(0 .. 10 + key as u128));
+        }
+        let tmp = NamedTempFile::new().unwrap();
+        bucket::create(tmp.path(), &bmap).expect("create");
+        return tmp;
+    }
+
+    fn overwrite(tmp: &NamedTempFile, offset: u64, bytes: &[u8]) {
+        let mut f = tmp.reopen().expect("reopen");
+        f.seek(SeekFrom::Start(offset)).expect("seek");
+        f.write_all(bytes).expect("write");
+    }
+
+    #[test]
+    fn sample_is_reproducible() {
+        assert_eq!(sample_indices(100, 10, 7), sample_indices(100, 10, 7));
+        assert_eq!(sample_indices(100, 10, 7).len(), 10);
+        assert_eq!(sample_indices(5, 10, 7), vec![0, 1, 2, 3, 4]);
+        assert!(sample_indices(0, 10, 7).is_empty());
+    }
+
+    #[test]
+    fn sampled_corrupt_block_is_caught() {
+        let tmp = sample_bucket();
+        let filename = tmp.path();
+        let report = verify(filename, Some(3), 42, VersionRange::default()).expect("verify");
+        assert_eq!(report, Report { num_entries: 20, num_blocks: 20, checked_blocks: 3 });
+
+        // Break the lz4 frame of the first block the seed picks.
+        let mut bucket = Bucket::open(filename).unwrap().check_headers().unwrap();
+        let entries = bucket.index_entries().unwrap().collect::<Result<Vec<_>>>().unwrap();
+        let picked = entries[sample_indices(entries.len(), 3, 42)[0]];
+        overwrite(&tmp, bucket.header.data_base_offset + picked.offset, &[0; 4]);
+
+        assert!(verify(filename, Some(3), 42, VersionRange::default()).is_err());
+        assert!(verify(filename, None, 0, VersionRange::default()).is_err());
+    }
+
+    #[test]
+    fn index_order_is_always_checked() {
+        let tmp = sample_bucket();
+        let filename = tmp.path();
+
+        // Swap the keys of the first two entries of the dense index.
+        let header = Bucket::read_header(filename).expect("read_header");
+        overwrite(&tmp, header.di_base_offset, &3_u64.to_le_bytes());
+        overwrite(&tmp, header.di_base_offset + 16, &0_u64.to_le_bytes());
+
+        assert_matches!(verify(filename, Some(0), 42, VersionRange::default()), Err(Error::CorruptIndex));
+    }
+
+    #[test]
+    fn truncated_data_section_is_caught() {
+        let tmp = sample_bucket();
+        let filename = tmp.path();
+
+        // Cut the file where the block of the last key starts.
+        let mut bucket = Bucket::open(filename).unwrap().check_headers().unwrap();
+        let cut = bucket.locate_offset(57).expect("locate_offset").expect("last key");
+        tmp.as_file().set_len(cut).expect("set_len");
+
+        let result = verify(filename, None, 0, VersionRange::default());
+        assert_matches!(result, Err(Error::IoError(ref e)) if e.kind() == std::io::ErrorKind::UnexpectedEof);
+    }
+}
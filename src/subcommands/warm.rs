@@ -0,0 +1,114 @@
+use chrono::{Date, Utc};
+use clap::ArgMatches;
+use binstore::bucket::Bucket;
+use binstore::db::{self, Db};
+use binstore::prelude::*;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+
+pub fn main(matches: &ArgMatches) {
+    super::exit(run(matches));
+}
+
+/// Reads the indexes of the buckets and returns the exit code.
+pub fn run(matches: &ArgMatches) -> Result<i32> {
+    let dbdir = super::path(matches, "dbdir").unwrap();
+    let bound = |name: &str| match matches.value_of(name).map(db::parse_date) {
+        Some(Ok(date)) => Bound::Included(date),
+        Some(Err(e)) => {
+            super::usage_error(format_args!("cannot parse {}: {}", name.replace('-', " "), e));
+        }
+        None => Bound::Unbounded,
+    };
+    let range = (bound("start-date"), bound("end-date"));
+    let full = matches.is_present("full");
+
+    let warmed = Db::open(&dbdir).and_then(|db| warm(&db, range, full));
+    let warmed = match warmed {
+        Ok(warmed) => warmed,
+        Err(e) => {
+            eprintln!("binstore: {}: {}", dbdir.display(), e);
+            return Ok(e.exit_code());
+        }
+    };
+    for (path, bytes) in &warmed {
+        println!("{}\t{}", path.display(), bytes);
+    }
+    let bytes: u64 = warmed.iter().map(|(_, bytes)| bytes).sum();
+    eprintln!("binstore: read {} bytes from {} buckets", bytes, warmed.len());
+    Ok(0)
+}
+
+/// Reads the indexes of the buckets of `db` in `range`, and with
+/// `full` the whole files, so that the OS caches them; returns how
+/// many bytes were read from each.
+fn warm<R: RangeBounds<Date<Utc>>>(db: &Db, range: R, full: bool) -> Result<Vec<(PathBuf, u64)>> {
+    let mut warmed = Vec::new();
+    for (date, path) in db {
+        if range.contains(date) {
+            warmed.push((path.to_path_buf(), warm_bucket(path, full)?));
+        }
+    }
+    Ok(warmed)
+}
+
+/// Reads the sections of the bucket at `path` that lookups read
+/// without decompressing values: the header and the indexes before the
+/// data section, and whatever follows it, such as a dense index written
+/// after the data and the log.
+fn warm_bucket(path: &Path, full: bool) -> Result<u64> {
+    let mut bucket = Bucket::open(path)?.check_headers()?;
+    let len = bucket.file_len()?;
+    let ranges = if full {
+        vec![(0, len)]
+    } else {
+        let data_end = bucket.header.data_base_offset + bucket.data_size()?;
+        vec![(0, bucket.header.data_base_offset), (data_end, len)]
+    };
+    let mut file = File::open(path)?;
+    let mut bytes = 0;
+    for (start, end) in ranges {
+        file.seek(SeekFrom::Start(start))?;
+        bytes += io::copy(&mut (&mut file).take(end - start), &mut io::sink())?;
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use binstore::bucket::{self, CreateOptions, DenseIndexEncoding};
+    use chrono::TimeZone;
+    use std::collections::{BTreeMap, BTreeSet};
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn warm_date_range() {
+        let dir = tempdir().unwrap();
+        let bmap: BTreeMap<HashedKey, BTreeSet<Value>> = (0 .. 1_000_u64).map(|key| (key, (0 .. 20).collect())).collect();
+        for day in 1 .. 5 {
+            let timestamp = Utc.ymd(2024, 1, day).and_hms(12, 0, 0).timestamp();
+            // Day 2 has its dense index after the data.
+            let dense_index = if day == 2 { DenseIndexEncoding::Varint } else { DenseIndexEncoding::Fixed };
+            let options = CreateOptions { timestamp: Some(timestamp), dense_index, ..CreateOptions::default() };
+            bucket::create_with_options(dir.path().join(format!("{}.binstore", day)), &bmap, &options).expect("create");
+        }
+        let db = Db::open(dir.path()).expect("open");
+
+        let range = db::parse_date("2024-01-02").unwrap() ..= db::parse_date("2024-01-03").unwrap();
+        let warmed = warm(&db, range.clone(), false).expect("warm");
+        let paths: Vec<&Path> = warmed.iter().map(|(path, _)| path.as_path()).collect();
+        assert_eq!(paths, vec![dir.path().join("2.binstore"), dir.path().join("3.binstore")]);
+        for (path, bytes) in &warmed {
+            let mut bucket = Bucket::open(path).unwrap().check_headers().unwrap();
+            let len = std::fs::metadata(path).unwrap().len();
+            assert_eq!(*bytes, len - bucket.data_size().unwrap());
+        }
+
+        let full = warm(&db, range, true).expect("warm");
+        assert_eq!(full[0].1, std::fs::metadata(dir.path().join("2.binstore")).unwrap().len());
+        assert_eq!(warm(&db, .., false).expect("warm").len(), 4);
+    }
+}
@@ -0,0 +1,33 @@
+//! Spans around the hot paths, for the `tracing` feature.  Without
+//! it, `span!` gives a `NoSpan` and the spans compile to nothing; the
+//! `log` messages are written either way.
+
+/// Enters a `tracing` debug span with the arguments of
+/// `tracing::debug_span!`; fields left `Empty` are filled in with
+/// `record`.
+#[cfg(feature = "tracing")]
+macro_rules! span {
+    ($($args:tt)*) => {
+        tracing::debug_span!($($args)*).entered()
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! span {
+    ($($args:tt)*) => {
+        $crate::trace::NoSpan
+    };
+}
+
+pub(crate) use span;
+
+/// What `span!` gives without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct NoSpan;
+
+#[cfg(not(feature = "tracing"))]
+impl NoSpan {
+    pub(crate) fn record<V>(&self, _field: &str, _value: V) -> &Self {
+        self
+    }
+}